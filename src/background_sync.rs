@@ -0,0 +1,115 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::Error as IOError;
+use std::sync::{Arc, Mutex, Condvar};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Fsyncs the WAL on a background thread every interval, if anything has been written since the
+/// last sync, for WriteConcern::Periodic. The thread is stopped & joined on drop, after a last sync.
+pub struct BackgroundSync {
+    shared: Arc<(Mutex<SyncState>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+struct SyncState {
+    written_lsn: u64,         // LSN of the last write to the WAL
+    synced_lsn: u64,          // LSN of the last write the thread has synced
+    sync_now: bool,           // someone is waiting, so don't wait for the interval
+    stop: bool,               // the thread should sync one last time, then exit
+    error: Option<IOError>,   // the last failed sync, until it is reported
+}
+
+impl BackgroundSync {
+    /// Starts the thread, syncing the WAL through its own handle to the file
+    pub fn new(wal_file: File, interval: Duration, synced_lsn: u64) -> BackgroundSync {
+        let state = SyncState{written_lsn: synced_lsn, synced_lsn: synced_lsn, sync_now: false, stop: false, error: None};
+        let shared = Arc::new((Mutex::new(state), Condvar::new()));
+        let thread_shared = shared.clone();
+
+        let thread = thread::spawn(move || {
+            let (ref lock, ref cvar) = *thread_shared;
+            let mut state = lock.lock().unwrap();
+
+            loop {
+                if ! state.stop && ! state.sync_now {
+                    state = cvar.wait_timeout(state, interval).unwrap().0;
+                }
+
+                let lsn = state.written_lsn;
+
+                if lsn > state.synced_lsn {
+                    // don't hold up writers while syncing
+                    drop(state);
+                    let result = wal_file.sync_all();
+                    state = lock.lock().unwrap();
+
+                    match result {
+                        Ok(_) => state.synced_lsn = ::std::cmp::max(state.synced_lsn, lsn),
+                        Err(e) => state.error = Some(e)
+                    }
+                }
+
+                state.sync_now = false;
+                cvar.notify_all();
+
+                if state.stop {
+                    return;
+                }
+            }
+        });
+
+        return BackgroundSync{shared: shared, thread: Some(thread)};
+    }
+
+    /// Records that writes up to lsn are in the WAL, waiting to be synced
+    pub fn written(&self, lsn: u64) {
+        self.shared.0.lock().unwrap().written_lsn = lsn;
+    }
+
+    /// Returns the LSN up to which the WAL has been synced
+    pub fn synced_lsn(&self) -> u64 {
+        return self.shared.0.lock().unwrap().synced_lsn;
+    }
+
+    /// Returns the error from a failed sync, if there has been one since the last call
+    pub fn take_error(&self) -> Result<(), Box<Error>> {
+        match self.shared.0.lock().unwrap().error.take() {
+            Some(e) => Err(From::from(e)),
+            None => Ok( () )
+        }
+    }
+
+    /// Has the thread sync now, and waits until it has synced up to lsn
+    pub fn wait_synced(&self, lsn: u64) -> Result<(), Box<Error>> {
+        let (ref lock, ref cvar) = *self.shared;
+        let mut state = lock.lock().unwrap();
+
+        while state.synced_lsn < lsn && state.error.is_none() {
+            state.sync_now = true;
+            cvar.notify_all();
+            state = cvar.wait(state).unwrap();
+        }
+
+        return match state.error.take() {
+            Some(e) => Err(From::from(e)),
+            None => Ok( () )
+        };
+    }
+}
+
+impl Drop for BackgroundSync {
+    fn drop(&mut self) {
+        {
+            let (ref lock, ref cvar) = *self.shared;
+
+            lock.lock().unwrap().stop = true;
+            cvar.notify_all();
+        }
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use scoped::PrefixKey;
+
+use rustc_serialize::{Encodable, Decodable, Encoder, Decoder};
+
+use std::borrow::Borrow;
+use std::ops::Deref;
+
+/// Follows a 0 byte in the key, standing for the 0 itself
+const ESCAPED_ZERO: u8 = 0xff;
+
+/// Follows a 0 byte to mark the end of the key
+const TERMINATOR: u8 = 0x01;
+
+/// A byte string key that encodes in order. A Vec<u8> key is encoded with its length in front,
+/// so [2] encodes after [1, 1] even though it sorts before it. Bytes are written as they are,
+/// with each 0 written as 0 0xff, and the key ends with 0 0x01, so comparing the encodings
+/// bytewise gives the same order as comparing the keys. An n byte key takes at most 2n + 2
+/// bytes, so size key_size for the longest key with that in mind.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Bytes(pub Vec<u8>);
+
+impl Bytes {
+    pub fn as_slice(&self) -> &[u8] {
+        return &self.0;
+    }
+}
+
+impl Encodable for Bytes {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        for &byte in &self.0 {
+            try!(s.emit_u8(byte));
+
+            if byte == 0 {
+                try!(s.emit_u8(ESCAPED_ZERO));
+            }
+        }
+
+        try!(s.emit_u8(0));
+
+        return s.emit_u8(TERMINATOR);
+    }
+}
+
+impl Decodable for Bytes {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Bytes, D::Error> {
+        let mut bytes = Vec::new();
+
+        loop {
+            let byte = try!(d.read_u8());
+
+            if byte != 0 {
+                bytes.push(byte);
+                continue;
+            }
+
+            match try!(d.read_u8()) {
+                ESCAPED_ZERO => bytes.push(0),
+                TERMINATOR => return Ok(Bytes(bytes)),
+                _ => return Err(d.error("Bytes key has a 0 that is neither escaped nor the end"))
+            }
+        }
+    }
+}
+
+impl PrefixKey for Bytes {
+    fn with_prefix(&self, prefix: &Bytes) -> Bytes {
+        return Bytes(self.0.with_prefix(&prefix.0));
+    }
+
+    fn strip_prefix(&self, prefix: &Bytes) -> Option<Bytes> {
+        return self.0.strip_prefix(&prefix.0).map(Bytes);
+    }
+}
+
+impl Deref for Bytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        return &self.0;
+    }
+}
+
+impl AsRef<[u8]> for Bytes {
+    fn as_ref(&self) -> &[u8] {
+        return &self.0;
+    }
+}
+
+// derived Ord on the Vec is the same as Ord on the slice, as Borrow needs
+impl Borrow<[u8]> for Bytes {
+    fn borrow(&self) -> &[u8] {
+        return &self.0;
+    }
+}
+
+impl From<Vec<u8>> for Bytes {
+    fn from(bytes: Vec<u8>) -> Bytes {
+        return Bytes(bytes);
+    }
+}
+
+impl <'a> From<&'a [u8]> for Bytes {
+    fn from(bytes: &'a [u8]) -> Bytes {
+        return Bytes(bytes.to_vec());
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use testutil::TempDir;
+    use ::{BTree, Bytes};
+
+    use bincode::SizeLimit;
+    use bincode::rustc_serialize::{encode, decode};
+
+    fn keys() -> Vec<Bytes> {
+        return vec![vec![], vec![0], vec![0, 0], vec![0, 1], vec![0, 0xff], vec![1], vec![1, 0], vec![1, 1],
+                    vec![0xff], vec![0xff, 0], vec![0xff, 0xff], vec![0xff, 0xff, 0xff]].into_iter().map(Bytes).collect();
+    }
+
+    #[test]
+    fn encodes_in_order() {
+        let keys = keys();
+
+        for key in &keys {
+            let encoded = encode(key, SizeLimit::Infinite).unwrap();
+
+            assert_eq!(&decode::<Bytes>(&encoded).unwrap(), key);
+        }
+
+        for pair in keys.windows(2) {
+            assert!(pair[0] < pair[1]);
+            assert!(encode(&pair[0], SizeLimit::Infinite).unwrap() < encode(&pair[1], SizeLimit::Infinite).unwrap());
+        }
+
+        assert!(decode::<Bytes>(&[1, 0, 2]).is_err());
+        assert!(decode::<Bytes>(&[1, 2]).is_err());  // never ends
+    }
+
+    #[test]
+    fn through_wal_and_compaction() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        {
+            let mut btree = BTree::<Bytes, u8>::new(&file_path, 8, 1).unwrap();
+
+            for (i, key) in keys().into_iter().enumerate() {
+                btree.insert(key, i as u8).unwrap();
+
+                if i == 5 {
+                    btree.compact().unwrap();
+                }
+            }
+        }
+
+        let mut btree = BTree::<Bytes, u8>::new(&file_path, 8, 1).unwrap();
+
+        for (i, key) in keys().iter().enumerate() {
+            assert_eq!(btree.get(key).unwrap(), Some([i as u8].iter().cloned().collect()));
+        }
+
+        let prefixed: Vec<Bytes> = btree.scan_prefix(&[0xff]).unwrap().into_iter().map(|(key, _)| key).collect();
+
+        assert_eq!(prefixed, keys()[8..].to_vec());
+
+        btree.compact().unwrap();
+
+        let prefixed: Vec<Bytes> = btree.scan_prefix(&[0]).unwrap().into_iter().map(|(key, _)| key).collect();
+
+        assert_eq!(prefixed, keys()[1..5].to_vec());
+        assert_eq!(btree.scan_prefix(&[]).unwrap().len(), keys().len());
+        assert!(btree.scan_prefix(&[2]).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,68 @@
+use sst_writer::crc32c;
+use xxh3::xxh3_64;
+
+use sha2::{Sha256, Digest};
+
+/// What each record of the WAL & tree file is checked with. The checksum of a record's bytes is written
+/// after them, and a record whose bytes no longer match it is corrupt, even if it still decodes. The kind
+/// is stamped at the start of the file, so it is read with the one it was written with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumKind {
+    None,    // no checksum, the formats from before checksums
+    Crc32c,  // 4 bytes, catches torn & flipped bits cheaply
+    Xxh3,    // 8 bytes, fast without special instructions
+    Sha256,  // 32 bytes, for when a cryptographic hash is wanted
+}
+
+impl ChecksumKind {
+    /// The bytes the checksum adds to each record
+    pub fn size(self) -> usize {
+        return match self {
+            ChecksumKind::None => 0,
+            ChecksumKind::Crc32c => 4,
+            ChecksumKind::Xxh3 => 8,
+            ChecksumKind::Sha256 => 32
+        };
+    }
+
+    /// Returns the checksum of the bytes
+    pub fn checksum(self, bytes: &[u8]) -> Vec<u8> {
+        return match self {
+            ChecksumKind::None => Vec::new(),
+            ChecksumKind::Crc32c => crc32c(0, bytes).to_be_bytes().to_vec(),
+            ChecksumKind::Xxh3 => xxh3_64(bytes).to_be_bytes().to_vec(),
+            ChecksumKind::Sha256 => Sha256::digest(bytes).to_vec()
+        };
+    }
+
+    /// Returns true if the record, which ends with its checksum, matches it
+    pub fn verify(self, record: &[u8]) -> bool {
+        if record.len() < self.size() {
+            return false;
+        }
+
+        let (bytes, checksum) = record.split_at(record.len() - self.size());
+
+        return self.checksum(bytes) == checksum;
+    }
+}
+
+/// The number a kind is stamped as, in the top byte of the stamp's record size
+pub fn stamp_id(checksum: ChecksumKind) -> u8 {
+    return match checksum {
+        ChecksumKind::None => 0,
+        ChecksumKind::Crc32c => 1,
+        ChecksumKind::Sha256 => 2,
+        ChecksumKind::Xxh3 => 3
+    };
+}
+
+pub fn from_stamp_id(id: u8) -> Option<ChecksumKind> {
+    return match id {
+        0 => Some(ChecksumKind::None),
+        1 => Some(ChecksumKind::Crc32c),
+        2 => Some(ChecksumKind::Sha256),
+        3 => Some(ChecksumKind::Xxh3),
+        _ => None
+    };
+}
@@ -0,0 +1,204 @@
+use wal_file::decode_record;
+
+use bincode::SizeLimit;
+use bincode::rustc_serialize::encode;
+use rustc_serialize::{Encodable, Decodable, Encoder, Decoder};
+
+use std::cmp::Ordering;
+use std::error::Error;
+use std::fmt;
+use std::marker::PhantomData;
+
+/// Turns values of T into bytes and back, for storing a T some other way than bincode,
+/// say as flatbuffers or a fixed layout. Wrap the T in Coded to store it with the codec.
+pub trait Codec<T> {
+    /// Appends the value's bytes to buff
+    fn encode(&self, value: &T, buff: &mut Vec<u8>) -> Result<(), Box<Error>>;
+
+    /// Reads a value back from exactly the bytes encode wrote for it
+    fn decode(&self, bytes: &[u8]) -> Result<T, Box<Error>>;
+}
+
+/// Encodes values the way the tree does when there is no codec
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BincodeCodec;
+
+impl <T: Encodable + Decodable> Codec<T> for BincodeCodec {
+    fn encode(&self, value: &T, buff: &mut Vec<u8>) -> Result<(), Box<Error>> {
+        buff.extend(try!(encode(value, SizeLimit::Infinite)));
+
+        Ok( () )
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T, Box<Error>> {
+        return Ok(try!(decode_record(bytes)));
+    }
+}
+
+/// A key or value stored with codec C, everywhere the tree stores keys & values: in the WAL,
+/// the tree file & everything read from them. The codec's bytes are stored with their length
+/// in front, as 8 bytes, so size the key or value for the longest encoding plus 8. A T is encoded
+/// when it is wrapped, so a codec that fails does so in Coded::new and not part way through a write.
+/// Coded values are ordered & compared as their T, whatever the order of their bytes.
+pub struct Coded<T, C: Codec<T> + Default = BincodeCodec> {
+    value: T,
+    bytes: Vec<u8>,
+    _c_marker: PhantomData<C>,
+}
+
+impl <T, C: Codec<T> + Default> Coded<T,C> {
+    pub fn new(value: T) -> Result<Coded<T,C>, Box<Error>> {
+        let mut bytes = Vec::new();
+
+        try!(C::default().encode(&value, &mut bytes));
+
+        return Ok(Coded{value: value, bytes: bytes, _c_marker: PhantomData});
+    }
+
+    pub fn get(&self) -> &T {
+        return &self.value;
+    }
+
+    pub fn into_inner(self) -> T {
+        return self.value;
+    }
+
+    /// The bytes the codec encoded the value as
+    pub fn bytes(&self) -> &[u8] {
+        return &self.bytes;
+    }
+}
+
+impl <T, C: Codec<T> + Default> Encodable for Coded<T,C> {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        return s.emit_seq(self.bytes.len(), |s| {
+            for (i, &byte) in self.bytes.iter().enumerate() {
+                try!(s.emit_seq_elt(i, |s| s.emit_u8(byte)));
+            }
+
+            Ok( () )
+        });
+    }
+}
+
+impl <T, C: Codec<T> + Default> Decodable for Coded<T,C> {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Coded<T,C>, D::Error> {
+        let bytes = try!(d.read_seq(|d, len| {
+            let mut bytes = Vec::with_capacity(len);
+
+            for i in 0..len {
+                bytes.push(try!(d.read_seq_elt(i, |d| d.read_u8())));
+            }
+
+            Ok(bytes)
+        }));
+
+        return match C::default().decode(&bytes) {
+            Ok(value) => Ok(Coded{value: value, bytes: bytes, _c_marker: PhantomData}),
+            Err(e) => Err(d.error(&e.to_string()))
+        };
+    }
+}
+
+impl <T: Clone, C: Codec<T> + Default> Clone for Coded<T,C> {
+    fn clone(&self) -> Coded<T,C> {
+        return Coded{value: self.value.clone(), bytes: self.bytes.clone(), _c_marker: PhantomData};
+    }
+}
+
+impl <T: fmt::Debug, C: Codec<T> + Default> fmt::Debug for Coded<T,C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return self.value.fmt(f);
+    }
+}
+
+impl <T: Ord, C: Codec<T> + Default> PartialEq for Coded<T,C> {
+    fn eq(&self, other: &Coded<T,C>) -> bool {
+        return self.cmp(other) == Ordering::Equal;
+    }
+}
+
+impl <T: Ord, C: Codec<T> + Default> Eq for Coded<T,C> {}
+
+impl <T: Ord, C: Codec<T> + Default> PartialOrd for Coded<T,C> {
+    fn partial_cmp(&self, other: &Coded<T,C>) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+impl <T: Ord, C: Codec<T> + Default> Ord for Coded<T,C> {
+    fn cmp(&self, other: &Coded<T,C>) -> Ordering {
+        return self.value.cmp(&other.value);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use testutil::TempDir;
+    use ::{BTree, Codec, Coded};
+
+    use std::error::Error;
+
+    /// A point that isn't Encodable, stored as its coordinates little-endian
+    #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+    struct Point {
+        x: u16,
+        y: u16,
+    }
+
+    #[derive(Default)]
+    struct PointCodec;
+
+    impl Codec<Point> for PointCodec {
+        fn encode(&self, point: &Point, buff: &mut Vec<u8>) -> Result<(), Box<Error>> {
+            if point.x == u16::MAX {
+                return Err(From::from("x is out of range"));
+            }
+
+            buff.extend_from_slice(&point.x.to_le_bytes());
+            buff.extend_from_slice(&point.y.to_le_bytes());
+
+            Ok( () )
+        }
+
+        fn decode(&self, bytes: &[u8]) -> Result<Point, Box<Error>> {
+            if bytes.len() != 4 {
+                return Err(From::from("a point is 4 bytes"));
+            }
+
+            return Ok(Point{x: u16::from_le_bytes([bytes[0], bytes[1]]), y: u16::from_le_bytes([bytes[2], bytes[3]])});
+        }
+    }
+
+    fn point(x: u16, y: u16) -> Coded<Point, PointCodec> {
+        return Coded::new(Point{x: x, y: y}).unwrap();
+    }
+
+    #[test]
+    fn through_wal_and_compaction() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        assert_eq!(point(1, 0x0203).bytes(), &[1, 0, 3, 2]);
+        assert!(Coded::<Point, PointCodec>::new(Point{x: u16::MAX, y: 0}).is_err());
+
+        {
+            let mut btree = BTree::<Coded<Point, PointCodec>, Coded<String>>::new(&file_path, 8 + 4, 8 + 8 + 8).unwrap();
+
+            btree.insert(point(2, 1), Coded::new("b".to_owned()).unwrap()).unwrap();
+            btree.insert(point(1, 5), Coded::new("a".to_owned()).unwrap()).unwrap();
+            btree.compact().unwrap();
+            btree.insert(point(1, 7), Coded::new("c".to_owned()).unwrap()).unwrap();
+        }
+
+        let btree = BTree::<Coded<Point, PointCodec>, Coded<String>>::new(&file_path, 8 + 4, 8 + 8 + 8).unwrap();
+        let pairs: Vec<(Point, Vec<String>)> = btree.to_sorted_vec().unwrap().into_iter()
+            .map(|(key, values)| (key.into_inner(), values.into_iter().map(|value| value.into_inner()).collect()))
+            .collect();
+
+        assert_eq!(pairs, vec![(Point{x: 1, y: 5}, vec!["a".to_owned()]),
+                               (Point{x: 1, y: 7}, vec!["c".to_owned()]),
+                               (Point{x: 2, y: 1}, vec!["b".to_owned()])]);
+    }
+}
@@ -0,0 +1,449 @@
+use ::{BTree, KeyType, ValueType, ReadConcern, DurabilityToken};
+use error::BTreeError;
+
+use wal_file::KeyValuePair;
+
+use std::error::Error;
+use std::ops::Bound;
+
+/// A position in the sorted sequence of (key,value) pairs of a BTree, merged across
+/// the in-memory and on-disk parts. Unlike an iterator, a cursor can be re-positioned
+/// with seek() at any point, and stepped in either direction.
+///
+/// The tree's ReadConcern decides which parts the cursor walks over.
+///
+/// The cursor holds the tree's only borrow, so writes through put() and delete_current()
+/// are the only changes it can see. They go through the WAL like any other write, and
+/// because the cursor tracks its position by (key,value) it stays valid across them,
+/// even when one triggers a compaction.
+///
+/// When the cursor isn't on a pair (it's new, or has stepped off either end)
+/// move_next() and move_prev() behave like seek_to_first() and seek_to_last().
+pub struct Cursor<'a, K: KeyType + 'a, V: ValueType + 'a> {
+    tree: &'a mut BTree<K,V>,
+    current: Option<KeyValuePair<K,V>>,  // the pair we're on, None when unpositioned
+}
+
+impl <'a, K: KeyType, V: ValueType> Cursor<'a,K,V> {
+    pub fn new(tree: &'a mut BTree<K,V>) -> Cursor<'a,K,V> {
+        return Cursor{tree: tree, current: None};
+    }
+
+    /// Returns the pair the cursor is on
+    pub fn current(&self) -> Option<(&K, &V)> {
+        return self.current.as_ref().map(|kv| (&kv.key, &kv.value));
+    }
+
+    /// Moves to the first pair whose key is at least `key`
+    pub fn seek(&mut self, key: &K) -> Result<Option<(&K, &V)>, Box<Error>> {
+        try!(self.tree.ensure_replayed());
+
+        let mem = self.tree.mem_tree.seek(key).map(to_pair).filter(|_| self.use_mem());
+        let index = try!(self.tree.tree_file.partition_point(|k| k < key));
+        let disk = try!(self.next_on_disk(index));
+
+        self.current = smallest(mem, disk);
+
+        return Ok(self.current());
+    }
+
+    /// Moves to the first pair in the tree
+    pub fn seek_to_first(&mut self) -> Result<Option<(&K, &V)>, Box<Error>> {
+        try!(self.tree.ensure_replayed());
+
+        let mem = self.tree.mem_tree.successor(None).map(to_pair).filter(|_| self.use_mem());
+        let disk = try!(self.next_on_disk(0));
+
+        self.current = smallest(mem, disk);
+
+        return Ok(self.current());
+    }
+
+    /// Moves to the last pair in the tree
+    pub fn seek_to_last(&mut self) -> Result<Option<(&K, &V)>, Box<Error>> {
+        try!(self.tree.ensure_replayed());
+
+        let mem = self.tree.mem_tree.predecessor(None).map(to_pair).filter(|_| self.use_mem());
+        let count = try!(self.tree.tree_file.count());
+        let disk = try!(self.prev_on_disk(count));
+
+        self.current = largest(mem, disk);
+
+        return Ok(self.current());
+    }
+
+    /// Moves to the next pair
+    pub fn move_next(&mut self) -> Result<Option<(&K, &V)>, Box<Error>> {
+        let next = match self.current {
+            None => return self.seek_to_first(),
+            Some(ref cur) => {
+                let mem = self.tree.mem_tree.successor(Some((&cur.key, &cur.value))).map(to_pair).filter(|_| self.use_mem());
+                let index = try!(self.tree.tree_file.partition_point_pairs(|kv| kv <= cur));
+
+                smallest(mem, try!(self.next_on_disk(index)))
+            }
+        };
+
+        self.current = next;
+
+        return Ok(self.current());
+    }
+
+    /// Moves to the previous pair
+    pub fn move_prev(&mut self) -> Result<Option<(&K, &V)>, Box<Error>> {
+        let prev = match self.current {
+            None => return self.seek_to_last(),
+            Some(ref cur) => {
+                let mem = self.tree.mem_tree.predecessor(Some((&cur.key, &cur.value))).map(to_pair).filter(|_| self.use_mem());
+                let index = try!(self.tree.tree_file.partition_point_pairs(|kv| kv < cur));
+                let disk = try!(self.prev_on_disk(index));
+
+                largest(mem, disk)
+            }
+        };
+
+        self.current = prev;
+
+        return Ok(self.current());
+    }
+
+    /// Adds a value to the key the cursor is on, leaving the cursor where it is
+    pub fn put(&mut self, value: V) -> Result<DurabilityToken, Box<Error>> {
+        let key = match self.current {
+            Some(ref cur) => cur.key.clone(),
+            None => return Err(From::from(BTreeError::CursorUnpositioned))
+        };
+
+        return self.tree.insert(key, value);
+    }
+
+    /// Deletes the pair the cursor is on, and moves to the next pair
+    pub fn delete_current(&mut self) -> Result<Option<(&K, &V)>, Box<Error>> {
+        let kv = match self.current {
+            Some(ref cur) => KeyValuePair{key: cur.key.clone(), value: cur.value.clone()},
+            None => return Err(From::from(BTreeError::CursorUnpositioned))
+        };
+
+        try!(self.tree.delete(kv.key, kv.value));
+
+        // our position is still the deleted pair, so this finds the one after it
+        return self.move_next();
+    }
+
+    fn use_mem(&self) -> bool {
+        return self.tree.read_concern != ReadConcern::DiskOnly;
+    }
+
+    /// Returns the first pair on disk at or after index that hasn't been deleted.
+    /// None if there isn't one, or the read concern says not to look at the disk.
+    fn next_on_disk(&self, index: u64) -> Result<Option<KeyValuePair<K,V>>, Box<Error>> {
+        if ! self.tree.reads_disk() {
+            return Ok(None);
+        }
+
+        for i in index..try!(self.tree.tree_file.count()) {
+            let kv = try!(self.tree.tree_file.read_record(i));
+
+            if ! self.tree.deleted.contains(&kv.key, &kv.value) {
+                return Ok(Some(kv));
+            }
+        }
+
+        return Ok(None);
+    }
+
+    /// Returns the last pair on disk before index that hasn't been deleted.
+    /// None if there isn't one, or the read concern says not to look at the disk.
+    fn prev_on_disk(&self, index: u64) -> Result<Option<KeyValuePair<K,V>>, Box<Error>> {
+        if ! self.tree.reads_disk() {
+            return Ok(None);
+        }
+
+        for i in (0..index).rev() {
+            let kv = try!(self.tree.tree_file.read_record(i));
+
+            if ! self.tree.deleted.contains(&kv.key, &kv.value) {
+                return Ok(Some(kv));
+            }
+        }
+
+        return Ok(None);
+    }
+}
+
+/// A range scan that hands out its pairs in chunks, see BTree::range_chunked.
+///
+/// Each chunk starts just after the last pair handed out, wherever that pair is now,
+/// so the scan stays correct across writes and compactions between chunks. Pairs written
+/// in between chunks are seen if they sort after the last pair handed out, and are not if
+/// they sort before it. Pairs deleted in between chunks are not handed out.
+pub struct RangeChunks<K: KeyType, V: ValueType> {
+    start: Bound<K>,
+    end: Bound<K>,
+    chunk: usize,
+    last: Option<KeyValuePair<K,V>>,  // the last pair handed out, None if nothing has been yet
+    done: bool,                       // we've reached the end of the range
+}
+
+impl <K: KeyType, V: ValueType> RangeChunks<K,V> {
+    pub fn new(start: Bound<K>, end: Bound<K>, chunk: usize) -> RangeChunks<K,V> {
+        return RangeChunks{start: start, end: end, chunk: chunk, last: None, done: false};
+    }
+
+    /// Reads the next chunk of up to chunk pairs, an empty chunk means the scan is done
+    pub fn next_chunk(&mut self, tree: &mut BTree<K,V>) -> Result<Vec<(K,V)>, Box<Error>> {
+        let mut pairs = Vec::new();
+
+        if self.done || self.chunk == 0 {
+            return Ok(pairs);
+        }
+
+        let mut cursor = tree.cursor();
+
+        let mut pair = match self.last {
+            Some(ref last) => {
+                // picks up after the last pair, even if it's no longer in the tree
+                cursor.current = Some(KeyValuePair{key: last.key.clone(), value: last.value.clone()});
+                try!(cursor.move_next()).map(|(k, v)| (k.clone(), v.clone()))
+            },
+            None => match self.start {
+                Bound::Included(ref key) => try!(cursor.seek(key)).map(|(k, v)| (k.clone(), v.clone())),
+                Bound::Excluded(ref key) => {
+                    let mut pair = try!(cursor.seek(key)).map(|(k, v)| (k.clone(), v.clone()));
+
+                    while pair.as_ref().map_or(false, |&(ref k, _)| k == key) {
+                        pair = try!(cursor.move_next()).map(|(k, v)| (k.clone(), v.clone()));
+                    }
+
+                    pair
+                },
+                Bound::Unbounded => try!(cursor.seek_to_first()).map(|(k, v)| (k.clone(), v.clone()))
+            }
+        };
+
+        while let Some((key, value)) = pair {
+            let in_range = match self.end {
+                Bound::Included(ref end) => key <= *end,
+                Bound::Excluded(ref end) => key < *end,
+                Bound::Unbounded => true
+            };
+
+            if ! in_range {
+                break;
+            }
+
+            pairs.push((key, value));
+
+            if pairs.len() == self.chunk {
+                break;
+            }
+
+            pair = try!(cursor.move_next()).map(|(k, v)| (k.clone(), v.clone()));
+        }
+
+        if pairs.len() < self.chunk {
+            self.done = true;
+        }
+
+        self.last = pairs.last().map(|&(ref k, ref v)| KeyValuePair{key: k.clone(), value: v.clone()});
+
+        return Ok(pairs);
+    }
+}
+
+fn to_pair<K: KeyType, V: ValueType>((key, value): (&K, &V)) -> KeyValuePair<K,V> {
+    return KeyValuePair{key: key.clone(), value: value.clone()};
+}
+
+fn smallest<K: KeyType, V: ValueType>(a: Option<KeyValuePair<K,V>>, b: Option<KeyValuePair<K,V>>) -> Option<KeyValuePair<K,V>> {
+    match (a, b) {
+        (Some(a), Some(b)) => if b < a { Some(b) } else { Some(a) },
+        (a, None) => a,
+        (None, b) => b
+    }
+}
+
+fn largest<K: KeyType, V: ValueType>(a: Option<KeyValuePair<K,V>>, b: Option<KeyValuePair<K,V>>) -> Option<KeyValuePair<K,V>> {
+    match (a, b) {
+        (Some(a), Some(b)) => if b > a { Some(b) } else { Some(a) },
+        (a, None) => a,
+        (None, b) => b
+    }
+}
+
+
+#[cfg(test)]
+#[allow(unused_must_use)]
+mod tests {
+    use testutil::TempDir;
+    use std::ops::Bound;
+    use ::BTree;
+
+    #[test]
+    fn walk_both_ways() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        // interleave what is on disk with what is in memory
+        btree.insert(1, 1).unwrap();
+        btree.insert(3, 1).unwrap();
+        btree.insert(3, 3).unwrap();
+        btree.compact().unwrap();
+        btree.insert(2, 1).unwrap();
+        btree.insert(3, 2).unwrap();
+        btree.insert(3, 3).unwrap(); // in both
+        btree.insert(4, 1).unwrap();
+
+        let expected = vec![(1, 1), (2, 1), (3, 1), (3, 2), (3, 3), (4, 1)];
+
+        let mut cursor = btree.cursor();
+        let mut forward = Vec::new();
+
+        while let Some((k, v)) = cursor.move_next().unwrap() {
+            forward.push((*k, *v));
+        }
+
+        assert_eq!(forward, expected);
+
+        let mut backward = Vec::new();
+
+        while let Some((k, v)) = cursor.move_prev().unwrap() {
+            backward.push((*k, *v));
+        }
+
+        backward.reverse();
+
+        assert_eq!(backward, expected);
+    }
+
+    #[test]
+    fn seek() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        {
+            let mut cursor = btree.cursor();
+
+            assert_eq!(cursor.seek_to_first().unwrap(), None);
+            assert_eq!(cursor.seek_to_last().unwrap(), None);
+        }
+
+        btree.insert(2, 1).unwrap();
+        btree.insert(6, 1).unwrap();
+        btree.compact().unwrap();
+        btree.insert(4, 1).unwrap();
+
+        let mut cursor = btree.cursor();
+
+        assert_eq!(cursor.seek(&3).unwrap(), Some((&4, &1)));
+        assert_eq!(cursor.move_next().unwrap(), Some((&6, &1)));
+        assert_eq!(cursor.seek(&0).unwrap(), Some((&2, &1)));
+        assert_eq!(cursor.seek(&5).unwrap(), Some((&6, &1)));
+        assert_eq!(cursor.move_prev().unwrap(), Some((&4, &1)));
+        assert_eq!(cursor.current(), Some((&4, &1)));
+        assert_eq!(cursor.seek(&7).unwrap(), None);
+        assert_eq!(cursor.seek_to_first().unwrap(), Some((&2, &1)));
+        assert_eq!(cursor.seek_to_last().unwrap(), Some((&6, &1)));
+    }
+
+    #[test]
+    fn put_and_delete() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        btree.insert(1, 1).unwrap();
+        btree.insert(2, 1).unwrap();
+        btree.compact().unwrap();
+        btree.insert(3, 1).unwrap();
+
+        {
+            let mut cursor = btree.cursor();
+
+            assert!(cursor.put(5).is_err()); // not on a pair yet
+
+            // scan and fix: give every key a 2, and drop the 1s from the first two
+            cursor.seek_to_first().unwrap();
+            cursor.put(2).unwrap();
+            assert_eq!(cursor.delete_current().unwrap(), Some((&1, &2)));
+            assert_eq!(cursor.move_next().unwrap(), Some((&2, &1)));
+            cursor.put(2).unwrap();
+            assert_eq!(cursor.delete_current().unwrap(), Some((&2, &2)));
+            assert_eq!(cursor.move_next().unwrap(), Some((&3, &1)));
+            cursor.put(2).unwrap();
+
+            assert_eq!(cursor.seek_to_first().unwrap(), Some((&1, &2)));
+            assert_eq!(cursor.move_prev().unwrap(), None);
+        }
+
+        let expected = vec![(1, 2), (2, 2), (3, 1), (3, 2)];
+
+        // the same after a compaction, and after replaying the WAL
+        for _ in 0..2 {
+            {
+                let mut cursor = btree.cursor();
+                let mut pairs = Vec::new();
+
+                while let Some((k, v)) = cursor.move_next().unwrap() {
+                    pairs.push((*k, *v));
+                }
+
+                assert_eq!(pairs, expected);
+            }
+
+            btree.compact().unwrap();
+        }
+
+        btree.insert(1, 1).unwrap();
+        btree.delete(1, 1).unwrap();
+        btree.delete(2, 2).unwrap();
+
+        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        let mut cursor = btree.cursor();
+
+        assert_eq!(cursor.seek(&2).unwrap(), Some((&3, &1)));
+        assert_eq!(cursor.move_prev().unwrap(), Some((&1, &2)));
+    }
+
+    #[test]
+    fn range_chunked() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        for key in 1..7 {
+            btree.insert(key, 1).unwrap();
+        }
+
+        btree.compact().unwrap();
+        btree.insert(3, 2).unwrap(); // two values for 3, one on disk one in memory
+
+        let mut chunks = btree.range_chunked(2..6, 2);
+
+        assert_eq!(chunks.next_chunk(&mut btree).unwrap(), vec![(2, 1), (3, 1)]);
+
+        // behind the scan, so not visited; ahead of it, so visited
+        btree.insert(2, 2).unwrap();
+        btree.insert(5, 2).unwrap();
+        btree.compact().unwrap();
+
+        assert_eq!(chunks.next_chunk(&mut btree).unwrap(), vec![(3, 2), (4, 1)]);
+
+        btree.delete(5, 1).unwrap();
+
+        assert_eq!(chunks.next_chunk(&mut btree).unwrap(), vec![(5, 2)]);
+        assert!(chunks.next_chunk(&mut btree).unwrap().is_empty());
+
+        let mut chunks = btree.range_chunked((Bound::Excluded(2), Bound::Included(3)), 10);
+
+        assert_eq!(chunks.next_chunk(&mut btree).unwrap(), vec![(3, 1), (3, 2)]);
+        assert!(chunks.next_chunk(&mut btree).unwrap().is_empty());
+    }
+}
@@ -1,8 +1,16 @@
-use wal_file::{RecordFile, RecordFileIterator, KeyValuePair};
+use bincode::SizeLimit;
+use bincode::rustc_serialize::{encode, encoded_size, decode_from, EncodingError};
+
+use wal_file::{RecordFile, KeyValuePair, decode_record};
+use checksum::ChecksumKind;
 
 use ::{KeyType, ValueType};
+use error::BTreeError;
 
+use std::cell::Cell;
 use std::error::Error;
+use std::fs;
+use std::collections::BTreeSet;
 // use std::iter::Filter;
 
 /*
@@ -45,21 +53,64 @@ struct Node<K: KeyType, V: ValueType> {
 /// |-------------------------------------------|
 
 
+/// Decodes a value that failed to decode as V, say because it was written with an older version of V.
+/// It is given the value's bytes followed by the record's padding.
+pub type ValueFallback<V> = fn(&[u8]) -> Result<V, Box<Error>>;
+
 // total hack to get things going
 pub struct OnDiskBTree<K: KeyType, V: ValueType> {
-    file: RecordFile<K,V>
+    file: RecordFile<K,V>,
+    value_fallback: Option<ValueFallback<V>>,
 }
 
 pub struct OnDiskBTreeIterator<'a, K: KeyType + 'a, V: ValueType + 'a> {
-    record_iterator: RecordFileIterator<'a,K,V>
+    tree: &'a OnDiskBTree<K,V>,
+    index: u64,  // the next record to read
+    count: u64,  // the number of records when the iterator was created
+}
+
+/// Ends the items at the first error, keeping it in `error`, so records that can fail to be read can be merged
+/// with pairs that can't. Take the error back out once the items are done, to tell an error from the end.
+pub struct UntilError<'e, I> {
+    iter: I,
+    error: &'e Cell<Option<Box<Error>>>,
+    done: bool,  // an error has been kept, so there are no more items
+}
+
+/// Walks the records of the tree file as (key bytes, value bytes) pairs, see BTree::iter_raw
+pub struct RawIter<'a, K: KeyType + 'a, V: ValueType + 'a> {
+    tree: &'a OnDiskBTree<K,V>,
+    index: u64,  // the next record to read
+    count: u64,  // the number of records when the iterator was created
 }
 
 
 impl <K: KeyType, V: ValueType> OnDiskBTree<K,V> {
     pub fn new(file_path: String, key_size: usize, value_size: usize) -> Result<OnDiskBTree<K,V>, Box<Error>> {
-        return Ok(OnDiskBTree{file: try!(RecordFile::new(&file_path, key_size, value_size))});
+        return OnDiskBTree::with_checksum(file_path, key_size, value_size, ChecksumKind::None);
     }
 
+    /// Like new, with the records of a new file checked with checksum, see RecordFile::new_tree_file
+    pub fn with_checksum(file_path: String, key_size: usize, value_size: usize, checksum: ChecksumKind) -> Result<OnDiskBTree<K,V>, Box<Error>> {
+        return Ok(OnDiskBTree{file: try!(RecordFile::new_tree_file(&file_path, key_size, value_size, checksum)), value_fallback: None});
+    }
+
+    /// Opens an existing tree file without being able to write to it, see RecordFile::open_tree_file_read_only
+    pub fn open_read_only(file_path: &String, key_size: usize, value_size: usize) -> Result<OnDiskBTree<K,V>, Box<Error>> {
+        return Ok(OnDiskBTree{file: try!(RecordFile::open_tree_file_read_only(file_path, key_size, value_size)), value_fallback: None});
+    }
+
+    /// What the records are checked with, ChecksumKind::None for a tree file without a stamp
+    pub fn checksum(&self) -> ChecksumKind {
+        return self.file.checksum();
+    }
+
+    /// Sets what decodes values that fail to decode as V
+    pub fn set_value_fallback(&mut self, value_fallback: Option<ValueFallback<V>>) {
+        self.value_fallback = value_fallback;
+    }
+
+    #[cfg(test)]
     pub fn is_new(&self) -> Result<bool, Box<Error>> {
         return self.file.is_new();
     }
@@ -69,10 +120,30 @@ impl <K: KeyType, V: ValueType> OnDiskBTree<K,V> {
         return self.file.count();
     }
 
+    /// Returns the size of the B+Tree in bytes
+    pub fn size(&self) -> Result<u64, Box<Error>> {
+        return self.file.size();
+    }
+
     pub fn insert_record(&mut self, kv: &KeyValuePair<K,V>) -> Result<(), Box<Error>> {
         return self.file.insert_record(kv);
     }
 
+    /// Returns the metadata of the open tree file, see RecordFile::metadata
+    pub fn metadata(&self) -> Result<fs::Metadata, Box<Error>> {
+        return self.file.metadata();
+    }
+
+    /// Flushes the tree file to disk
+    pub fn sync(&self) -> Result<(), Box<Error>> {
+        return self.file.sync();
+    }
+
+    /// Hints that records start up to end will be read soon, see RecordFile::will_need
+    pub fn will_need(&self, start: u64, end: u64) {
+        self.file.will_need(start, end);
+    }
+
 /*
     fn get(&self, key: &K) -> bool { //Box<Filter<RecordFileIterator<K,V>, fn(KeyValuePair<K,V>) -> bool>> {
         // return Box::new(self.into_iter().filter(|rec| &rec.key == key));
@@ -80,25 +151,282 @@ impl <K: KeyType, V: ValueType> OnDiskBTree<K,V> {
     }
 */
 
-    pub fn contains_key(&self, key: &K) -> bool {
-        return true;
+    /// Checks for a key by binary searching the sorted records, decoding only their keys
+    pub fn contains_key(&self, key: &K) -> Result<bool, Box<Error>> {
+        let index = try!(self.partition_point(|k| k < key));
+
+        if index == try!(self.count()) {
+            return Ok(false);
+        }
+
+        return Ok(&try!(self.read_key(index)) == key);
     }
-}
 
-impl <'a, K: KeyType, V: ValueType> IntoIterator for &'a mut OnDiskBTree<K,V> {
-    type Item = KeyValuePair<K,V>;
-    type IntoIter = OnDiskBTreeIterator<'a, K,V>;
+    /// Reads the bytes of the record at `index`, failing with CorruptTreeRecord if they don't
+    /// match their checksum, which is left off
+    fn read_checked(&self, index: u64) -> Result<Vec<u8>, Box<Error>> {
+        let mut buff = try!(self.file.read_record_bytes(index));
+        let checksum = self.file.checksum();
+
+        if ! checksum.verify(&buff) {
+            return Err(From::from(BTreeError::CorruptTreeRecord{index: index}));
+        }
+
+        let payload_size = buff.len() - checksum.size();
+
+        buff.truncate(payload_size);
+
+        return Ok(buff);
+    }
+
+    /// Reads just the key of the record at `index`, so it works before a value fallback is set
+    pub fn read_key(&self, index: u64) -> Result<K, Box<Error>> {
+        return match decode_key_only(&try!(self.read_checked(index))) {
+            Ok(key) => Ok(key),
+            Err(_) => Err(From::from(BTreeError::CorruptTreeRecord{index: index}))
+        };
+    }
+
+    /// Reads the record at `index`, in sorted order
+    pub fn read_record(&self, index: u64) -> Result<KeyValuePair<K,V>, Box<Error>> {
+        let buff = try!(self.read_checked(index));
+
+        if let Ok(kv) = decode_record(&buff) {
+            return Ok(kv);
+        }
+
+        // the key decodes as usual, and the fallback gets a go at the value
+        let corrupt = BTreeError::CorruptTreeRecord{index: index};
+
+        match self.value_fallback {
+            Some(value_fallback) => {
+                let mut reader = &buff[..];
+                let key = match decode_from(&mut reader, SizeLimit::Bounded(buff.len() as u64)) {
+                    Ok(key) => key,
+                    Err(_) => return Err(From::from(corrupt))
+                };
+
+                return Ok(KeyValuePair{key: key, value: try!(value_fallback(reader))});
+            },
+            None => return Err(From::from(corrupt))
+        }
+    }
+
+    /// Reads the record at `index` as its key's encoding and its value's encoding. Only the key
+    /// is decoded, to find where it ends, so the value's bytes still have the record's padding on the end.
+    pub fn read_raw_record(&self, index: u64) -> Result<(Vec<u8>, Vec<u8>), Box<Error>> {
+        let mut buff = try!(self.read_checked(index));
+
+        let key_len = {
+            let mut reader = &buff[..];
+
+            try!(decode_from::<_, K>(&mut reader, SizeLimit::Bounded(buff.len() as u64)));
+
+            buff.len() - reader.len()
+        };
+
+        let value_bytes = buff.split_off(key_len);
+
+        return Ok((buff, value_bytes));
+    }
+
+    /// Returns an iterator over the raw records, in sorted order
+    pub fn iter_raw(&self) -> Result<RawIter<K,V>, Box<Error>> {
+        return Ok(RawIter{tree: self, index: 0, count: try!(self.count())});
+    }
+
+    /// Returns an iterator over the records, in sorted order
+    pub fn iter(&self) -> Result<OnDiskBTreeIterator<K,V>, Box<Error>> {
+        return Ok(self.iter_range(0, try!(self.count())));
+    }
+
+    /// Returns an iterator over the records from index start up to end
+    pub fn iter_range(&self, start: u64, end: u64) -> OnDiskBTreeIterator<K,V> {
+        return OnDiskBTreeIterator{tree: self, index: start, count: end};
+    }
+
+    /// Binary searches for the index of the first record whose key `is_before` is false for, decoding
+    /// only the keys. `is_before` must be true for a (possibly empty) prefix of the records, and false after.
+    pub fn partition_point<F: Fn(&K) -> bool>(&self, is_before: F) -> Result<u64, Box<Error>> {
+        let mut low = 0;
+        let mut high = try!(self.count());
+
+        while low < high {
+            let mid = low + (high - low) / 2;
+
+            if is_before(&try!(self.read_key(mid))) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        return Ok(low);
+    }
+
+    /// Same as partition_point, but `is_before` is given whole records, for searches that order by value too
+    pub fn partition_point_pairs<F: Fn(&KeyValuePair<K,V>) -> bool>(&self, is_before: F) -> Result<u64, Box<Error>> {
+        let mut low = 0;
+        let mut high = try!(self.count());
+
+        while low < high {
+            let mid = low + (high - low) / 2;
+
+            if is_before(&try!(self.read_record(mid))) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
 
-    fn into_iter(self) -> Self::IntoIter {
-        OnDiskBTreeIterator{record_iterator: self.file.into_iter()}
+        return Ok(low);
     }
+
+    /// Returns all the values stored on disk for the key
+    pub fn get_values(&self, key: &K) -> Result<BTreeSet<V>, Box<Error>> {
+        let mut values = BTreeSet::new();
+        let count = try!(self.count());
+
+        for i in try!(self.partition_point(|k| k < key))..count {
+            let kv = try!(self.read_record(i));
+
+            if &kv.key != key {
+                break;
+            }
+
+            values.insert(kv.value);
+        }
+
+        return Ok(values);
+    }
+
+    /// Returns the byte offsets of the records get_values reads for the key, in the order it reads them:
+    /// those the binary search probes, then the key's records, then the one after that ends the scan
+    pub fn lookup_path(&self, key: &K) -> Result<Vec<u64>, Box<Error>> {
+        let mut path = Vec::new();
+        let mut low = 0;
+        let mut high = try!(self.count());
+        let count = high;
+
+        while low < high {
+            let mid = low + (high - low) / 2;
+
+            path.push(self.file.record_offset(mid));
+
+            if &try!(self.read_key(mid)) < key {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        for i in low..count {
+            path.push(self.file.record_offset(i));
+
+            if &try!(self.read_key(i)) != key {
+                break;
+            }
+        }
+
+        return Ok(path);
+    }
+
+    /// Scans every record for the value, stopping at the first match that isn't deleted. This is O(N)
+    pub fn contains_value<F: Fn(&KeyValuePair<K,V>) -> bool>(&self, value: &V, is_deleted: F) -> Result<bool, Box<Error>> {
+        for i in 0..try!(self.count()) {
+            let kv = try!(self.read_record(i));
+
+            if &kv.value == value && ! is_deleted(&kv) {
+                return Ok(true);
+            }
+        }
+
+        return Ok(false);
+    }
+
+    /// Returns, in order, the keys of every record holding the value that isn't deleted. This is O(N)
+    pub fn value_to_keys<F: Fn(&KeyValuePair<K,V>) -> bool>(&self, value: &V, is_deleted: F) -> Result<Vec<K>, Box<Error>> {
+        let mut keys = Vec::new();
+
+        for i in 0..try!(self.count()) {
+            let kv = try!(self.read_record(i));
+
+            if &kv.value == value && ! is_deleted(&kv) {
+                keys.push(kv.key);
+            }
+        }
+
+        return Ok(keys);
+    }
+}
+
+/// Encodes just the key portion of a record, failing with RecordTooLarge if it won't fit in max_key_size.
+/// The key is the leading field of a record, so no padding is added here.
+pub fn encode_key_only<K: KeyType>(key: &K, max_key_size: usize) -> Result<Vec<u8>, BTreeError> {
+    return match encode(key, SizeLimit::Bounded(max_key_size as u64)) {
+        Ok(buff) => Ok(buff),
+        Err(EncodingError::SizeLimit) => Err(BTreeError::RecordTooLarge{size: encoded_size(key) as usize, limit: max_key_size}),
+        Err(_) => Err(BTreeError::Unencodable)
+    };
+}
+
+/// Decodes the key from the front of an encoded record, leaving the value untouched
+pub fn decode_key_only<K: KeyType>(buf: &[u8]) -> Result<K, BTreeError> {
+    let mut reader = buf;
+
+    return decode_from(&mut reader, SizeLimit::Bounded(buf.len() as u64)).map_err(|_| BTreeError::Undecodable);
 }
 
 impl <'a, K: KeyType, V: ValueType> Iterator for OnDiskBTreeIterator<'a,K,V> {
-    type Item = KeyValuePair<K,V>;
+    type Item = Result<KeyValuePair<K,V>, Box<Error>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        return self.record_iterator.next();
+        if self.index >= self.count {
+            return None;
+        }
+
+        self.index += 1;
+
+        return Some(self.tree.read_record(self.index - 1));
+    }
+}
+
+/// Wraps the items so they end at the first error, which is kept in `error`
+pub fn until_error<T, I: Iterator<Item=Result<T, Box<Error>>>>(iter: I, error: &Cell<Option<Box<Error>>>) -> UntilError<I> {
+    return UntilError{iter: iter, error: error, done: false};
+}
+
+impl <'e, T, I: Iterator<Item=Result<T, Box<Error>>>> Iterator for UntilError<'e,I> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.done {
+            return None;
+        }
+
+        match self.iter.next() {
+            Some(Ok(item)) => Some(item),
+            Some(Err(e)) => {
+                self.error.set(Some(e));
+                self.done = true;
+                None
+            },
+            None => None
+        }
+    }
+}
+
+impl <'a, K: KeyType, V: ValueType> Iterator for RawIter<'a,K,V> {
+    type Item = Result<(Vec<u8>, Vec<u8>), Box<Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.count {
+            return None;
+        }
+
+        self.index += 1;
+
+        return Some(self.tree.read_raw_record(self.index - 1));
     }
 }
 
@@ -168,3 +496,119 @@ impl <'a, K: KeyType, V: ValueType> Iterator for OnDiskBTreeIterator<'a,K,V> {
 
 
 */
+
+
+#[cfg(test)]
+#[allow(unused_must_use)]
+mod tests {
+    use testutil::TempDir;
+    use std::fs;
+    use wal_file::KeyValuePair;
+    use disk_btree::{OnDiskBTree, encode_key_only, decode_key_only};
+    use bincode::rustc_serialize::decode;
+    use rand::{thread_rng, Rng};
+    use error::BTreeError;
+
+    #[test]
+    fn key_only_round_trip() {
+        let buff = encode_key_only(&"hello".to_owned(), 15).unwrap();
+        let key: String = decode_key_only(&buff).unwrap();
+
+        assert!(key == "hello");
+
+        // too big for the max key size
+        assert_eq!(encode_key_only(&"hello world".to_owned(), 8), Err(BTreeError::RecordTooLarge{size: 19, limit: 8}));
+        assert_eq!(decode_key_only::<String>(&[0, 0, 0, 0, 0, 0, 0, 9, b'a']), Err(BTreeError::Undecodable));
+    }
+
+    #[test]
+    fn searches_decode_only_keys() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut tree = OnDiskBTree::<u64, String>::new(file_path.to_owned(), 8, 15).unwrap();
+
+        for i in 0..10 {
+            tree.insert_record(&KeyValuePair{key: i * 2, value: "value".to_owned()}).unwrap();
+        }
+
+        // give every value a length prefix longer than the record, so no value decodes
+        let mut bytes = fs::read(&file_path).unwrap();
+
+        for i in 0..10 {
+            bytes[i * 23 + 8] = 0xff;
+        }
+
+        fs::write(&file_path, &bytes).unwrap();
+
+        assert!(tree.read_record(4).is_err());
+        assert_eq!(tree.partition_point(|key| *key < 7).unwrap(), 4);
+        assert!(tree.contains_key(&8).unwrap());
+        assert!(! tree.contains_key(&9).unwrap());
+        assert_eq!(tree.lookup_path(&8).unwrap().len(), 6);
+    }
+
+    #[test]
+    fn contains_key() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut tree = OnDiskBTree::<u64, String>::new(file_path.to_owned(), 8, 15).unwrap();
+
+        assert!(! tree.contains_key(&3).unwrap());
+
+        for i in 0..10 {
+            tree.insert_record(&KeyValuePair{key: i * 2, value: "value".to_owned()}).unwrap();
+        }
+
+        assert!(tree.contains_key(&0).unwrap());
+        assert!(tree.contains_key(&8).unwrap());
+        assert!(tree.contains_key(&18).unwrap());
+        assert!(! tree.contains_key(&3).unwrap());
+        assert!(! tree.contains_key(&19).unwrap());
+    }
+
+    #[test]
+    fn random_bytes() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        // length prefixes of random bytes are mostly huge
+        let mut buff = vec![0; 40 * 1000];
+
+        thread_rng().fill_bytes(&mut buff);
+        fs::write(&file_path, &buff).unwrap();
+
+        let tree = OnDiskBTree::<String, Vec<u64>>::new(file_path.to_owned(), 20, 20).unwrap();
+
+        for index in 0..tree.count().unwrap() {
+            if let Err(e) = tree.read_record(index) {
+                assert_eq!(*e.downcast::<BTreeError>().unwrap(), BTreeError::CorruptTreeRecord{index: index});
+            }
+        }
+    }
+
+    #[test]
+    fn iter_raw() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut tree = OnDiskBTree::<u16, String>::new(file_path.to_owned(), 2, 12).unwrap();
+
+        tree.insert_record(&KeyValuePair{key: 1, value: "a".to_owned()}).unwrap();
+        tree.insert_record(&KeyValuePair{key: 2, value: "bc".to_owned()}).unwrap();
+
+        let records: Vec<_> = tree.iter_raw().unwrap().map(|r| r.unwrap()).collect();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].0, vec![0, 1]);
+        assert_eq!(records[1].0, vec![0, 2]);
+
+        // the value is followed by padding, which decoding ignores
+        assert_eq!(records[1].1.len(), 12);
+
+        let value: String = decode(&records[1].1).unwrap();
+
+        assert_eq!(value, "bc");
+    }
+}
@@ -0,0 +1,108 @@
+use std::error::Error;
+use std::fmt;
+
+/// Errors specific to the BTree, as opposed to I/O or encoding errors.
+/// These are returned boxed like every other error, so use `downcast_ref` to match on them.
+#[derive(Debug, PartialEq)]
+pub enum BTreeError {
+    /// The WAL was stamped with a different record size than the one it was opened with
+    WalRecordSizeMismatch { expected: usize, found: usize },
+    /// The WAL has no stamp, and replaying legacy WALs wasn't allowed
+    UnstampedWal,
+    /// A cursor was asked to change the pair it is on, but it isn't on one
+    CursorUnpositioned,
+    /// Another BTree held the lock on the tree for longer than the timeout
+    LockTimeout,
+    /// A compaction would have grown the tree file past its size cap
+    FileSizeLimit { limit: u64 },
+    /// A key was written that is outside the tree's key bounds
+    OutOfBounds,
+    /// The record at index in the WAL didn't decode
+    CorruptWalRecord { index: u64 },
+    /// The record at index in the tree file failed its checksum or didn't decode
+    CorruptTreeRecord { index: u64 },
+    /// The tree file was stamped with a different record size than the one it was opened with
+    TreeRecordSizeMismatch { expected: usize, found: usize },
+    /// A read was made before the WAL of a tree opened with lazy_replay was replayed
+    NotReplayed,
+    /// A key's Ord isn't consistent: it disagrees with itself, or with the keys around it
+    ComparatorInconsistency,
+    /// The disk is full: a write of needed bytes didn't fit in the available bytes. Nothing was
+    /// changed, so the same write can be tried again once space is freed.
+    NoSpace { needed: u64, available: u64 },
+    /// A record of size bytes was bigger than the limit on records, the key size + value size or max_record_bytes
+    RecordTooLarge { size: usize, limit: usize },
+    /// A key or value couldn't be encoded
+    Unencodable,
+    /// A key or value couldn't be decoded
+    Undecodable,
+    /// Keys that had to come in order came with one lower than the key before it
+    KeysOutOfOrder,
+    /// The WAL or tree file is stamped with a checksum this version doesn't know
+    UnknownChecksum { id: u8 },
+}
+
+impl fmt::Display for BTreeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BTreeError::WalRecordSizeMismatch { expected, found } =>
+                write!(f, "WAL record size mismatch: expected {} bytes, found {} bytes", expected, found),
+            BTreeError::UnstampedWal =>
+                write!(f, "WAL has no record size stamp"),
+            BTreeError::CursorUnpositioned =>
+                write!(f, "Cursor is not positioned on a pair"),
+            BTreeError::LockTimeout =>
+                write!(f, "Timed out waiting for the lock on the tree"),
+            BTreeError::FileSizeLimit { limit } =>
+                write!(f, "Tree file would grow past its limit of {} bytes", limit),
+            BTreeError::OutOfBounds =>
+                write!(f, "Key is outside the tree's key bounds"),
+            BTreeError::CorruptWalRecord { index } =>
+                write!(f, "WAL record {} is corrupt", index),
+            BTreeError::CorruptTreeRecord { index } =>
+                write!(f, "Tree file record {} is corrupt", index),
+            BTreeError::TreeRecordSizeMismatch { expected, found } =>
+                write!(f, "Tree file record size mismatch: expected {} bytes, found {} bytes", expected, found),
+            BTreeError::NotReplayed =>
+                write!(f, "The WAL hasn't been replayed yet"),
+            BTreeError::ComparatorInconsistency =>
+                write!(f, "Keys are ordered inconsistently"),
+            BTreeError::NoSpace { needed, available } =>
+                write!(f, "Not enough disk space: needed {} bytes, {} bytes available", needed, available),
+            BTreeError::RecordTooLarge { size, limit } =>
+                write!(f, "Record of {} bytes is larger than the limit of {} bytes", size, limit),
+            BTreeError::Unencodable =>
+                write!(f, "Couldn't encode the key or value"),
+            BTreeError::Undecodable =>
+                write!(f, "Couldn't decode the key or value"),
+            BTreeError::KeysOutOfOrder =>
+                write!(f, "A key came after a higher one"),
+            BTreeError::UnknownChecksum { id } =>
+                write!(f, "WAL is stamped with unknown checksum {}", id),
+        }
+    }
+}
+
+impl Error for BTreeError {
+    fn description(&self) -> &str {
+        match *self {
+            BTreeError::WalRecordSizeMismatch { .. } => "WAL record size mismatch",
+            BTreeError::UnstampedWal => "WAL has no record size stamp",
+            BTreeError::CursorUnpositioned => "Cursor is not positioned on a pair",
+            BTreeError::LockTimeout => "Timed out waiting for the lock on the tree",
+            BTreeError::FileSizeLimit { .. } => "Tree file would grow past its limit",
+            BTreeError::OutOfBounds => "Key is outside the tree's key bounds",
+            BTreeError::CorruptWalRecord { .. } => "WAL record is corrupt",
+            BTreeError::CorruptTreeRecord { .. } => "Tree file record is corrupt",
+            BTreeError::TreeRecordSizeMismatch { .. } => "Tree file record size mismatch",
+            BTreeError::NotReplayed => "The WAL hasn't been replayed yet",
+            BTreeError::ComparatorInconsistency => "Keys are ordered inconsistently",
+            BTreeError::NoSpace { .. } => "Not enough disk space",
+            BTreeError::RecordTooLarge { .. } => "Record is too large",
+            BTreeError::Unencodable => "Couldn't encode the key or value",
+            BTreeError::Undecodable => "Couldn't decode the key or value",
+            BTreeError::KeysOutOfOrder => "A key came after a higher one",
+            BTreeError::UnknownChecksum { .. } => "WAL is stamped with an unknown checksum",
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use std::cmp::Ordering;
+
+/// An f64 that can be used as a key. Floats aren't Ord because of NaN, so this orders
+/// them by the IEEE 754 total order: -NaN < -inf < ... < -0.0 < 0.0 < ... < inf < NaN.
+/// Equality follows the same order, so -0.0 and 0.0 are different keys, and a NaN equals itself.
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone, Copy)]
+pub struct TotalOrdF64(pub f64);
+
+/// An f32 that can be used as a key, ordered the same way as TotalOrdF64
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone, Copy)]
+pub struct TotalOrdF32(pub f32);
+
+impl PartialEq for TotalOrdF64 {
+    fn eq(&self, other: &TotalOrdF64) -> bool {
+        return self.cmp(other) == Ordering::Equal;
+    }
+}
+
+impl Eq for TotalOrdF64 {}
+
+impl PartialOrd for TotalOrdF64 {
+    fn partial_cmp(&self, other: &TotalOrdF64) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+impl Ord for TotalOrdF64 {
+    fn cmp(&self, other: &TotalOrdF64) -> Ordering {
+        return self.0.total_cmp(&other.0);
+    }
+}
+
+impl PartialEq for TotalOrdF32 {
+    fn eq(&self, other: &TotalOrdF32) -> bool {
+        return self.cmp(other) == Ordering::Equal;
+    }
+}
+
+impl Eq for TotalOrdF32 {}
+
+impl PartialOrd for TotalOrdF32 {
+    fn partial_cmp(&self, other: &TotalOrdF32) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+impl Ord for TotalOrdF32 {
+    fn cmp(&self, other: &TotalOrdF32) -> Ordering {
+        return self.0.total_cmp(&other.0);
+    }
+}
@@ -0,0 +1,97 @@
+use sha2::{Sha256, Digest};
+
+use std::error::Error;
+use std::io::ErrorKind;
+use std::io::Error as IOError;
+
+/// A Bloom filter over a tree's keys, see BTree::export_key_filter. It answers whether a key might
+/// be in the tree without opening it: false means the key definitely isn't there, true means it
+/// probably is. Keys are given as their bincode encoding, the same bytes BTree::raw_get takes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyFilter {
+    num_hashes: u8,
+    bits: Vec<u8>,
+}
+
+impl KeyFilter {
+    /// Makes an empty filter sized for num_keys keys at bits_per_key bits each
+    pub fn new(num_keys: usize, bits_per_key: u8) -> KeyFilter {
+        let num_bits = ::std::cmp::max(num_keys * bits_per_key as usize, 64);
+
+        // k = bits per key * ln 2 gives the fewest false positives
+        let num_hashes = ::std::cmp::max((bits_per_key as f64 * 0.69).round() as u8, 1);
+
+        return KeyFilter{num_hashes: num_hashes, bits: vec![0; (num_bits + 7) / 8]};
+    }
+
+    pub fn insert(&mut self, key_bytes: &[u8]) {
+        for bit in self.bit_indexes(key_bytes) {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    /// Checks if the key might be in the filter; there are no false negatives
+    pub fn contains(&self, key_bytes: &[u8]) -> bool {
+        return self.bit_indexes(key_bytes).iter().all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0);
+    }
+
+    /// Serializes the filter as the number of hashes, then the bit array
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.bits.len() + 1);
+
+        bytes.push(self.num_hashes);
+        bytes.extend_from_slice(&self.bits);
+
+        return bytes;
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<KeyFilter, Box<Error>> {
+        if bytes.len() < 2 || bytes[0] == 0 {
+            return Err(From::from(IOError::new(ErrorKind::InvalidData, "Not a serialized key filter")));
+        }
+
+        return Ok(KeyFilter{num_hashes: bytes[0], bits: bytes[1..].to_vec()});
+    }
+
+    /// Derives the key's bits from two halves of its SHA-256, by double hashing
+    fn bit_indexes(&self, key_bytes: &[u8]) -> Vec<usize> {
+        let digest = Sha256::digest(key_bytes);
+        let mut halves = [0u64; 2];
+
+        for (i, half) in halves.iter_mut().enumerate() {
+            for byte in &digest[i * 8..(i + 1) * 8] {
+                *half = (*half << 8) | *byte as u64;
+            }
+        }
+
+        let num_bits = (self.bits.len() * 8) as u64;
+
+        return (0..self.num_hashes as u64).map(|i| (halves[0].wrapping_add(i.wrapping_mul(halves[1])) % num_bits) as usize).collect();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use ::KeyFilter;
+
+    #[test]
+    fn no_false_negatives() {
+        let mut filter = KeyFilter::new(1000, 10);
+
+        for i in 0..1000u32 {
+            filter.insert(&i.to_be_bytes());
+        }
+
+        let filter = KeyFilter::from_bytes(&filter.to_bytes()).unwrap();
+
+        assert!((0..1000u32).all(|i| filter.contains(&i.to_be_bytes())));
+
+        // about 1% at 10 bits per key
+        let false_positives = (1000..11000u32).filter(|i| filter.contains(&i.to_be_bytes())).count();
+
+        assert!(false_positives < 300, "{} false positives", false_positives);
+
+        assert!(KeyFilter::from_bytes(&[]).is_err());
+    }
+}
@@ -2,22 +2,91 @@ extern crate bincode;
 extern crate rustc_serialize;
 extern crate rand;
 extern crate itertools;
+extern crate sha2;
+extern crate libc;
 
+mod error;
+mod checksum;
 mod wal_file;
 mod multi_map;
 mod disk_btree;
+mod cursor;
+mod write_batch;
+mod scoped;
+mod float_key;
+mod bytes_key;
+mod background_sync;
+mod value_set_view;
+mod memory_btree;
+mod set_on_disk;
+mod versioned;
+mod key_filter;
+mod transaction;
+mod sst_writer;
+mod reader;
+mod sled_compat;
+mod codec;
+mod xxh3;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testutil;
 
-use wal_file::{KeyValuePair, RecordFile};
+pub use error::BTreeError;
+pub use checksum::ChecksumKind;
+pub use cursor::{Cursor, RangeChunks};
+pub use write_batch::WriteBatch;
+pub use scoped::{PrefixKey, ScopedTree, ScopedIter};
+pub use float_key::{TotalOrdF64, TotalOrdF32};
+pub use bytes_key::Bytes;
+pub use value_set_view::{ValueSetView, ValueRefs};
+pub use memory_btree::MemoryOnlyBTree;
+pub use set_on_disk::{BTreeSetOnDisk, SetIter};
+pub use versioned::{VersionedBTree, Version};
+pub use key_filter::KeyFilter;
+pub use transaction::PreparedTransaction;
+pub use reader::TreeReader;
+pub use sled_compat::{SledCompatLayer, IVec};
+pub use codec::{Codec, BincodeCodec, Coded};
+
+use wal_file::{RecordFile, split_transaction_id};
 use multi_map::MultiMap;
-use disk_btree::OnDiskBTree;
+use disk_btree::{OnDiskBTree, until_error, encode_key_only};
+use background_sync::BackgroundSync;
+use sst_writer::TableWriter;
+
+pub use disk_btree::{ValueFallback, RawIter};
+pub use wal_file::{KeyValuePair, WALRecord, WalReader};
 
 use rustc_serialize::{Encodable, Decodable};
+use bincode::SizeLimit;
+use bincode::rustc_serialize::{encode, decode_from, encoded_size};
+use sha2::{Sha256, Digest};
 
+use std::cell::Cell;
 use std::error::Error;
-use itertools::merge;
+use std::cmp::Ordering;
+use std::fmt::Debug;
+use std::collections::{BTreeMap, BTreeSet, Bound};
+use std::fs;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{ErrorKind, Seek, SeekFrom, Write};
+use std::io::Error as IOError;
+use std::iter;
+use std::panic;
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::ops::RangeBounds;
+use std::thread;
+use std::time::{Duration, Instant};
+use itertools::{merge, kmerge, Itertools};
 
 const MAX_MEMORY_ITEMS: usize = 1000;
 
+/// How many of the tree file's records range_count_approx samples, evenly spaced. Each bound of a
+/// range is placed to within half the gap between two samples, so at 256 the estimate is off by at
+/// most 0.4% of the tree file's records, which is within 10% for ranges over 4% of the file. More
+/// samples would narrow that, at the cost of a key in memory & a read on open and after each compaction
+/// for every sample.
+const RECORD_SAMPLES: u64 = 256;
+
 // specify the types for the keys & values
 pub trait KeyType: Ord + Encodable + Decodable + Clone {}
 pub trait ValueType: Ord + Encodable + Decodable + Clone  {}
@@ -27,210 +96,5244 @@ pub trait ValueType: Ord + Encodable + Decodable + Clone  {}
 impl<T> KeyType for T where T: Ord + Encodable + Decodable + Clone {}
 impl<T> ValueType for T where T: Ord + Encodable + Decodable + Clone {}
 
+/// How hard the BTree works to get writes onto disk before returning
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WriteConcern {
+    None,  // never fsync, leave it to the OS
+    WAL,   // fsync the WAL on every insert
+    Full,  // fsync the WAL on every insert, and the tree file & WAL after a compaction
+    Periodic(Duration),  // fsync the WAL on a background thread, at most this long after an insert
+}
+
+/// Which parts of the BTree reads look at
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReadConcern {
+    MemOnly,   // only what is in the mem_tree
+    DiskOnly,  // only the tree file, ignoring the mem_tree
+    Merged,    // both the mem_tree and the tree file
+}
+
+/// What happened during a compaction
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactionStats {
+    pub input_mem_records: u64,   // records merged in from the mem_tree
+    pub input_disk_records: u64,  // records merged in from the old tree file
+    pub output_records: u64,      // records written to the new tree file
+    pub tombstones_dropped: u64,  // deleted pairs that were left out of the new tree file
+    pub bytes_written: u64,       // size of the new tree file
+    pub duration: Duration,       // how long the whole compaction took
+    pub generation: u64,          // number of compactions done through this BTree, including this one
+}
+
+/// What a bulk load did
+#[derive(Debug, Clone, PartialEq)]
+pub struct BulkLoadStats {
+    pub records: u64,         // records written to the tree file
+    pub runs_created: usize,  // sorted runs written to disk, counting those merged from other runs
+    pub merge_passes: usize,  // passes merging runs, the last of them into the tree file
+    pub duration: Duration,   // how long the whole load took
+}
+
+/// Passed to compaction hooks, see BTree::register_compaction_hook
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompactionEvent {
+    /// A compaction is about to start, with this many bytes in the WAL
+    BeforeCompaction { wal_bytes: u64 },
+    /// A compaction finished, shrinking the tree file & WAL together by bytes_freed (0 if they grew)
+    AfterCompaction { duration: Duration, bytes_freed: u64 },
+}
+
+/// Sent to watchers of a range for each pair written in it, see BTree::watch_range
+#[derive(Debug, Clone, PartialEq)]
+pub enum WriteEvent<K: KeyType, V: ValueType> {
+    Insert { key: K, value: V },
+    Delete { key: K, value: V },
+}
+
+/// Decides when a BTree should merge its WAL & mem_tree into the tree file.
+/// Without one, a BTree compacts once it holds more than MAX_MEMORY_ITEMS pairs in memory.
+/// Policies must be Send & Sync so a BTree can be shared between threads behind a lock.
+pub trait CompactionPolicy: Send + Sync {
+    fn should_compact(&self, wal_bytes: u64, tree_bytes: u64, mem_entries: usize) -> bool;
+}
+
+/// Compacts when the WAL is more than the given fraction of the tree file's size.
+/// A non-empty WAL and an empty tree file always compacts.
+pub struct RatioPolicy(pub f64);
+
+impl CompactionPolicy for RatioPolicy {
+    fn should_compact(&self, wal_bytes: u64, tree_bytes: u64, _mem_entries: usize) -> bool {
+        return wal_bytes as f64 / tree_bytes as f64 > self.0;
+    }
+}
+
+/// Compacts when the WAL is larger than the given number of bytes
+pub struct AbsolutePolicy(pub u64);
+
+impl CompactionPolicy for AbsolutePolicy {
+    fn should_compact(&self, wal_bytes: u64, _tree_bytes: u64, _mem_entries: usize) -> bool {
+        return wal_bytes > self.0;
+    }
+}
+
+/// Identifies a write by its log sequence number, the count of WAL writes made through
+/// this BTree since it was opened. Pass it to BTree::wait_durable to make sure the write is on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DurabilityToken(u64);
+
+impl DurabilityToken {
+    pub fn lsn(&self) -> u64 {
+        return self.0;
+    }
+}
+
+/// A known-good tree file, for checking readers written in other languages against.
+/// The tree file starts with a 16 byte stamp: "BTreeDat", then a big-endian u64 of key_size + value_size
+/// with the checksum's id in its top byte (1 for CRC32C). Then comes a run of records sorted by key
+/// then value, each the bincode (big-endian) encoding of the key followed by the value, zero-padded
+/// out to key_size + value_size bytes, then the big-endian CRC32C of those bytes. A tree file written
+/// without checksums has no stamp and no checksums, only the records.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncodedTestVector {
+    pub key_size: usize,   // the key size the tree was created with
+    pub value_size: usize, // the value size the tree was created with
+    pub key: u32,          // the one key in the tree
+    pub value: u32,        // its one value
+    pub hex: &'static str, // the contents of the tree file
+}
+
+const TEST_VECTOR_HEX: &'static str = "4254726565446174010000000000000adeadbeef0000002a00003a6bf5cf";
+
+/// Returns the tree file of a BTree<u32, u32> created with a key size of 4 and
+/// a value size of 6, holding the single pair (0xdeadbeef, 42)
+pub fn encode_test_vector() -> EncodedTestVector {
+    return EncodedTestVector{key_size: 4, value_size: 6, key: 0xdeadbeef, value: 42, hex: TEST_VECTOR_HEX};
+}
+
+/// The most runs merged at once unless the builder says otherwise, to keep down how many files are open
+const MERGE_FAN_IN: usize = 64;
+
+/// Writes pairs that come in key order into a new tree file, syncing it, and returns how many
+/// records it holds. Each key's values are sorted & deduplicated as they're gathered, so only
+/// the values of one key are held at once.
+fn write_tree_file<K, V, I>(path: &String, key_size: usize, value_size: usize, checksum: ChecksumKind, pairs: I) -> Result<u64, Box<Error>>
+    where K: KeyType, V: ValueType, I: Iterator<Item=(K, V)> {
+    let mut tree_file = try!(OnDiskBTree::<K,V>::with_checksum(path.to_owned(), key_size, value_size, checksum));
+    let mut key_values: Option<(K, BTreeSet<V>)> = None;
+    let mut records = 0;
+
+    // the None on the end writes out the last key
+    for pair in pairs.map(Some).chain(iter::once(None)) {
+        let same_key = match (&key_values, &pair) {
+            (&Some((ref last_key, _)), &Some((ref key, _))) if key < last_key => return Err(From::from(BTreeError::KeysOutOfOrder)),
+            (&Some((ref last_key, _)), &Some((ref key, _))) => key == last_key,
+            _ => false
+        };
+
+        if same_key {
+            key_values.as_mut().unwrap().1.insert(pair.unwrap().1);
+            continue;
+        }
+
+        if let Some((key, values)) = key_values.take() {
+            for value in values {
+                try!(tree_file.insert_record(&KeyValuePair{key: key.clone(), value: value}));
+                records += 1;
+            }
+        }
+
+        key_values = pair.map(|(key, value)| (key, iter::once(value).collect()));
+    }
+
+    try!(tree_file.sync());
+
+    return Ok(records);
+}
+
+/// Writes pairs in any order into a new tree file: they're sorted run_pairs at a time, each run
+/// written to its own file beside the tree file, then the runs are merged, fan_in at a time,
+/// until one last merge writes the tree file. A pair in several runs is written once. Only the tree
+/// file's records are checked with checksum, as the runs last no longer than the sort.
+fn sort_into_tree_file<K, V, I>(path: &String, key_size: usize, value_size: usize, checksum: ChecksumKind, pairs: I, run_pairs: usize, fan_in: usize) -> Result<BulkLoadStats, Box<Error>>
+    where K: KeyType, V: ValueType, I: Iterator<Item=(K, V)> {
+    let start = Instant::now();
+    let mut run_paths = Vec::new();
+    let written = write_sorted_runs(path, key_size, value_size, pairs, run_pairs, &mut run_paths).and_then(|mut runs| {
+        let mut merge_passes = 0;
+
+        while runs.len() > fan_in {
+            let mut merged = Vec::new();
+
+            for group in runs.chunks(fan_in) {
+                let run_error = Cell::new(None);
+                let run_iters = try!(group.iter().map(|run| run.iter().map(|iter| until_error(iter, &run_error))).collect::<Result<Vec<_>, _>>());
+                let run = try!(write_run(path, key_size, value_size, kmerge(run_iters).dedup(), &mut run_paths));
+
+                if let Some(e) = run_error.take() {
+                    return Err(e);
+                }
+
+                merged.push(run);
+            }
+
+            runs = merged;
+            merge_passes += 1;
+        }
+
+        let run_error = Cell::new(None);
+        let run_iters = try!(runs.iter().map(|run| run.iter().map(|iter| until_error(iter, &run_error))).collect::<Result<Vec<_>, _>>());
+        let records = try!(write_tree_file(path, key_size, value_size, checksum, kmerge(run_iters).map(|kv| (kv.key, kv.value))));
+
+        if let Some(e) = run_error.take() {
+            return Err(e);
+        }
+
+        if ! runs.is_empty() {
+            merge_passes += 1;
+        }
+
+        return Ok(BulkLoadStats{records: records, runs_created: run_paths.len(), merge_passes: merge_passes, duration: start.elapsed()});
+    });
+
+    // the runs are only needed until they're merged
+    for run_path in run_paths {
+        if let Err(e) = fs::remove_file(&run_path) {
+            if written.is_ok() {
+                return Err(From::from(e));
+            }
+        }
+    }
+
+    return written;
+}
+
+/// How many pairs of key_size + value_size fit in memory_budget bytes, at least 1, for sorting a run
+fn sort_run_pairs(memory_budget: usize, key_size: usize, value_size: usize) -> usize {
+    return ::std::cmp::max(memory_budget / ::std::cmp::max(key_size + value_size, 1), 1);
+}
+
+/// Writes the pairs out sorted, run_pairs to a file
+fn write_sorted_runs<K, V, I>(path: &String, key_size: usize, value_size: usize, mut pairs: I, run_pairs: usize, run_paths: &mut Vec<String>) -> Result<Vec<OnDiskBTree<K,V>>, Box<Error>>
+    where K: KeyType, V: ValueType, I: Iterator<Item=(K, V)> {
+    let mut runs = Vec::new();
+    let mut buff = Vec::new();
+
+    loop {
+        buff.extend(pairs.by_ref().take(run_pairs));
+
+        if buff.is_empty() {
+            return Ok(runs);
+        }
+
+        buff.sort();
+        buff.dedup();
+
+        runs.push(try!(write_run(path, key_size, value_size, buff.drain(..).map(|(key, value)| KeyValuePair{key: key, value: value}), run_paths)));
+    }
+}
+
+/// Writes sorted records to a new run file beside path, adding it to run_paths
+fn write_run<K, V, I>(path: &String, key_size: usize, value_size: usize, records: I, run_paths: &mut Vec<String>) -> Result<OnDiskBTree<K,V>, Box<Error>>
+    where K: KeyType, V: ValueType, I: Iterator<Item=KeyValuePair<K,V>> {
+    let run_path = format!("{}.run{}", path, run_paths.len());
+
+    run_paths.push(run_path.to_owned());
+
+    let mut run = try!(OnDiskBTree::<K,V>::new(run_path, key_size, value_size));
+
+    for record in records {
+        try!(run.insert_record(&record));
+    }
+
+    return Ok(run);
+}
+
+/// Returns the number of bytes a key or value encodes to, which is what key_size & value_size count
+pub fn encode_size<T: Encodable>(value: &T) -> Result<usize, BTreeError> {
+    return encode(value, SizeLimit::Infinite).map(|buff| buff.len()).map_err(|_| BTreeError::Unencodable);
+}
+
+/// Returns the most bytes any of the samples encodes to, or 0 if there are none. Pass the longest
+/// keys or values you expect, say the longest string and the largest collection, to size a tree.
+pub fn max_encoded_size<T: Encodable>(samples: &[T]) -> Result<usize, BTreeError> {
+    let mut max_size = 0;
+
+    for sample in samples {
+        max_size = ::std::cmp::max(max_size, try!(encode_size(sample)));
+    }
+
+    return Ok(max_size);
+}
+
+/// How much disk space a BTree is using, across its tree file & WAL
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DiskUsage {
+    pub logical_bytes: u64,    // the length of the files
+    pub allocated_bytes: u64,  // the space the filesystem has set aside for them
+}
+
+/// How much the WAL repeats itself, to help decide whether a compaction is worth it
+#[derive(Debug, Clone, PartialEq)]
+pub struct BloatReport {
+    pub wal_entries: u64,          // records in the WAL, including batch markers
+    pub unique_keys_in_wal: usize, // distinct keys those records touch
+    pub wal_duplicate_ratio: f64,  // wal_entries / unique_keys_in_wal - 1, 0 for an empty WAL
+    pub wal_size_bytes: u64,       // size of the WAL's records, not counting its stamp
+}
+
+/// Every record in the WAL, and how they add up, see BTree::wal_audit
+#[derive(Clone, PartialEq)]
+pub struct WalAudit<K: KeyType, V: ValueType> {
+    pub records: Vec<(u64, WALRecord<K,V>)>, // each record with its byte offset in the WAL file, as split_wal_at takes
+    pub records_per_key: BTreeMap<K, usize>, // inserts & deletes of each key
+    pub duplicate_keys: usize,     // keys with more than one insert or delete
+    pub total_records: usize,      // records in the WAL, including batch & transaction markers
+    pub wasted_bytes: u64,         // bytes of inserts of a pair that is inserted again or deleted later on
+}
+
+/// What reading back every WAL record found, see BTree::scrub_wal
+#[derive(Debug, Clone, PartialEq)]
+pub struct WalScrub {
+    pub checksum: ChecksumKind,    // what the records were checked with
+    pub records: u64,              // whole records read, corrupt or not
+    pub corrupt: Vec<u64>,         // indexes of the records that failed their checksum or didn't decode
+    pub torn_tail: Option<u64>,    // byte offset of a partial record the WAL ends with
+}
+
+/// What reading back every tree file record found, see BTree::scrub_tree_file
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeScrub {
+    pub checksum: ChecksumKind,    // what the records were checked with, None for a tree file without a stamp
+    pub records: u64,              // records read, corrupt or not
+    pub corrupt: Vec<u64>,         // indexes of the records that failed their checksum or didn't decode
+}
+
+/// How many bytes were written to disk for the bytes inserted, see BTree::write_amplification
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriteAmpReport {
+    pub logical_bytes: u64,        // encoded keys & values inserted
+    pub wal_bytes: u64,            // records appended to the WAL, including padding, deletes & batch markers
+    pub tree_bytes: u64,           // written to new tree files by compactions
+    pub wal_amplification: f64,    // wal_bytes / logical_bytes, 0 if nothing was inserted
+    pub tree_amplification: f64,   // tree_bytes / logical_bytes, 0 if nothing was inserted
+    pub total_amplification: f64,  // (wal_bytes + tree_bytes) / logical_bytes, 0 if nothing was inserted
+}
+
 /// This struct holds all the pieces of the BTree mechanism
 pub struct BTree<K: KeyType, V: ValueType> {
     tree_file_path: String,       // the path to the tree file
     key_size: usize,              // the size of the key in bytes
     value_size: usize,            // the size of the value in bytes
-    wal_file: RecordFile<K,V>,    // write-ahead log for in-memory items
+    wal_file: RecordFile<K,V,WALRecord<K,V>>,  // write-ahead log for in-memory items
     mem_tree: MultiMap<K,V>,      // in-memory multi-map that gets merged with the on-disk BTree
+    deleted: MultiMap<K,V>,       // pairs deleted since the last compaction, which may still be on disk
     tree_file: OnDiskBTree<K,V>,  // the file backing the whole thing
+    write_concern: WriteConcern,  // when to fsync
+    read_concern: ReadConcern,    // what get, cursors & value scans look at
+    last_compaction: Option<CompactionStats>, // stats from the most recent compaction
+    compaction_policy: Option<Box<CompactionPolicy>>, // when to compact, None for MAX_MEMORY_ITEMS
+    compaction_hooks: Vec<Box<Fn(CompactionEvent) + Send + Sync>>, // called before & after each compaction
+    watchers: Vec<(Bound<K>, Bound<K>, Sender<WriteEvent<K,V>>)>, // ranges being watched, and where to send their writes
+    max_file_bytes: Option<u64>,  // the largest the tree file may grow to
+    value_fallback: Option<ValueFallback<V>>, // decodes values the tree file has in an old format
+    last_lsn: u64,                // LSN of the last write
+    durable_lsn: u64,             // every write up to this LSN is on disk
+    compacted_lsn: u64,           // LSN of the last write merged into the tree file
+    synced_tree_lsn: u64,         // LSN of the last write merged into the tree file when it was last synced
+    small_tree_threshold: Option<u64>, // keep the whole tree in memory while it has at most this many records
+    all_in_memory: bool,          // the mem_tree holds everything in the tree file as well
+    key_bounds: (Option<K>, Option<K>), // the lowest key allowed, and the key after the highest
+    logical_bytes: u64,           // encoded keys & values inserted, since the I/O counters were reset
+    tree_bytes_written: u64,      // bytes compactions wrote to tree files, since the I/O counters were reset
+    wal_bytes_at_reset: u64,      // the WAL's bytes written when the I/O counters were reset
+    background_sync: Option<BackgroundSync>, // the thread syncing the WAL for WriteConcern::Periodic, started on the first write
+    unreplayed: bool,             // opened with lazy_replay, and the WAL hasn't been replayed into memory yet
+    record_sample: Vec<K>,        // keys of evenly spaced records in the tree file, for range_count_approx
+    prepared: BTreeMap<u128, Vec<WALRecord<K,V>>>, // writes of transactions prepared but not yet committed or aborted
+    comparator_checks: bool,      // check keys' Ord is consistent as they're written
+    compaction_threads: usize,    // threads compact_parallel merges on
+    checksum: ChecksumKind,       // what the records of new tree files are checked with
+    _lock_file: Option<File>,     // holds the lock on the tree, if one was taken; released on drop
 }
 
-impl <K: KeyType, V: ValueType> BTree<K, V> {
-    pub fn new(tree_file_path: &String, key_size: usize, value_size: usize) -> Result<BTree<K,V>, Box<Error>> {
-        // create our in-memory multi-map
-        let mut mem_tree = MultiMap::<K,V>::new();
-
-        // construct the path to the WAL file for the in-memory multi-map
-        let wal_file_path = tree_file_path.to_owned() + ".wal";
+/// Holds the options used when opening a BTree
+pub struct BTreeBuilder {
+    tree_file_path: String,       // the path to the tree file
+    key_size: usize,              // the size of the key in bytes
+    value_size: usize,            // the size of the value in bytes
+    allow_unstamped_wal: bool,    // replay a WAL that has no record size stamp
+    write_concern: WriteConcern,  // when to fsync
+    read_concern: ReadConcern,    // what reads look at
+    lock_timeout: Option<Duration>, // how long to wait for the lock file, None to not lock at all
+    compaction_policy: Option<Box<CompactionPolicy>>, // when to compact
+    max_file_bytes: Option<u64>,  // the largest the tree file may grow to
+    small_tree_threshold: Option<u64>, // keep the whole tree in memory while it has at most this many records
+    lazy_replay: bool,            // put off replaying the WAL until a read needs it
+    durable_directory: bool,      // fsync the tree's directory after creating its files
+    comparator_checks: bool,      // check keys' Ord is consistent as they're written
+    compaction_threads: usize,    // threads compact_parallel merges on
+    merge_fan_in: usize,          // the most sorted runs a bulk load merges at once
+    compact_on_open_bytes: Option<u64>, // compact straight after opening if the WAL holds more than this
+    max_record_bytes: Option<usize>, // the largest key size + value size allowed
+    checksum: ChecksumKind,       // what the records of a new WAL & tree file are checked with
+    without_checksums: bool,      // ChecksumKind::None was asked for by name
+    key_bounds: Result<(Option<Vec<u8>>, Option<Vec<u8>>), BTreeError>, // the encoded key bounds, as the builder has no K
+}
 
-        // construct our WAL file
-        let mut wal_file = try!(RecordFile::<K,V>::new(&wal_file_path, key_size, value_size));
+impl BTreeBuilder {
+    pub fn new(tree_file_path: &String, key_size: usize, value_size: usize) -> BTreeBuilder {
+        return BTreeBuilder{tree_file_path: tree_file_path.clone(),
+                            key_size: key_size,
+                            value_size: value_size,
+                            allow_unstamped_wal: false,
+                            write_concern: WriteConcern::WAL,
+                            read_concern: ReadConcern::Merged,
+                            lock_timeout: None,
+                            compaction_policy: None,
+                            max_file_bytes: None,
+                            small_tree_threshold: None,
+                            lazy_replay: false,
+                            durable_directory: false,
+                            comparator_checks: cfg!(debug_assertions),
+                            compaction_threads: 1,
+                            merge_fan_in: MERGE_FAN_IN,
+                            compact_on_open_bytes: None,
+                            max_record_bytes: None,
+                            checksum: ChecksumKind::Crc32c,
+                            without_checksums: false,
+                            key_bounds: Ok((None, None))};
+    }
 
-        // if we have a WAL file, replay it into the mem_tree
-        if try!(wal_file.is_new()) {
-            for kv in &mut wal_file {
-                mem_tree.insert(kv.key, kv.value);
-            }
-        }
+    /// Allows replaying a WAL written before WALs were stamped with their record size.
+    /// Nothing can check such a WAL matches the sizes given, so only set this when you know it does.
+    pub fn allow_unstamped_wal(mut self, allow: bool) -> BTreeBuilder {
+        self.allow_unstamped_wal = allow;
+        self
+    }
 
-        // open the data file
-        let tree_file = try!(OnDiskBTree::<K,V>::new(tree_file_path.to_owned(), key_size, value_size));
+    /// Sets when writes are fsynced, defaults to WriteConcern::WAL
+    pub fn write_concern(mut self, write_concern: WriteConcern) -> BTreeBuilder {
+        self.write_concern = write_concern;
+        self
+    }
 
-        return Ok(BTree{tree_file_path: tree_file_path.clone(),
-                        key_size: key_size,
-                        value_size: value_size,
-                        tree_file: tree_file,
-                        wal_file: wal_file,
-                        mem_tree: mem_tree});
+    /// Sets what reads look at, defaults to ReadConcern::Merged
+    pub fn read_concern(mut self, read_concern: ReadConcern) -> BTreeBuilder {
+        self.read_concern = read_concern;
+        self
     }
 
-    /// Inserts a key into the BTree
-    pub fn insert(&mut self, key: K, value: V) -> Result<(), Box<Error>> {
-        let record = KeyValuePair{key: key, value: value};
+    /// Takes an exclusive lock on the tree before opening it, so no other BTree can open it
+    /// at the same time. Gives up with BTreeError::LockTimeout if the lock isn't free within
+    /// the timeout. By default no lock is taken.
+    pub fn lock_timeout(mut self, timeout: Duration) -> BTreeBuilder {
+        self.lock_timeout = Some(timeout);
+        self
+    }
 
-        // should wrap this in a read-write lock
-        try!(self.wal_file.insert_record(&record));
+    /// Sets when writes trigger a compaction, defaults to having more than MAX_MEMORY_ITEMS in memory
+    pub fn compaction_policy<P: CompactionPolicy + 'static>(mut self, policy: P) -> BTreeBuilder {
+        self.compaction_policy = Some(Box::new(policy));
+        self
+    }
 
-        let KeyValuePair{key, value} = record;
+    /// Caps the size of the tree file. A compaction that would go over the cap fails with
+    /// BTreeError::FileSizeLimit, leaving the tree file and WAL as they were. As inserts
+    /// compact when the memory is full, they'll fail too once the cap is hit.
+    pub fn max_file_bytes(mut self, max_file_bytes: u64) -> BTreeBuilder {
+        self.max_file_bytes = Some(max_file_bytes);
+        self
+    }
 
-        let size = self.mem_tree.insert(key, value);
+    /// Keeps the whole tree in memory while the tree file has at most this many records.
+    /// Reads are then answered from memory, and compactions write the tree file straight from it.
+    /// The tree moves back to the tree file when a compaction leaves it bigger than this, and into
+    /// memory again if one leaves it smaller. Use u64::MAX to always keep it in memory.
+    pub fn small_tree_threshold(mut self, records: u64) -> BTreeBuilder {
+        self.small_tree_threshold = Some(records);
+        self
+    }
 
-        if size > MAX_MEMORY_ITEMS {
-            try!(self.compact());
-        }
+    /// Opens without replaying the WAL, so a tree with a long WAL opens straight away.
+    /// Writes can be made right away; the replay happens on the first compaction, cursor
+    /// seek or other call that needs the tree's contents, or on ensure_replayed. Until then
+    /// reads through &self, like get, fail with BTreeError::NotReplayed.
+    pub fn lazy_replay(mut self, lazy: bool) -> BTreeBuilder {
+        self.lazy_replay = lazy;
+        self
+    }
 
-        return Ok( () );
+    /// Fsyncs the tree's directory after open creates the tree file or WAL, as until then a crash
+    /// can lose the new files however the writes to them were synced. Compactions always sync the
+    /// directory after renaming the new tree file into place, unless the WriteConcern is None.
+    pub fn durable_directory(mut self, durable: bool) -> BTreeBuilder {
+        self.durable_directory = durable;
+        self
     }
 
+    /// Checks that keys' Ord is consistent as they are written: each insert or delete checks its key
+    /// against itself & its neighbour in memory, and compactions check each key they write against
+    /// the two before it. An inconsistent Ord fails the write with BTreeError::ComparatorInconsistency,
+    /// before anything goes to disk. On by default in debug builds.
+    pub fn check_comparator(mut self, check: bool) -> BTreeBuilder {
+        self.comparator_checks = check;
+        self
+    }
 
-    pub fn get(&self, key: &K) -> Option<std::collections::btree_set::Iter<V>> {
-        self.mem_tree.get(key).map(|btree| btree)
+    /// Sets how many threads BTree::compact_parallel splits its merge across, 1 by default
+    pub fn compaction_threads(mut self, threads: usize) -> BTreeBuilder {
+        self.compaction_threads = threads;
+        self
     }
 
-    /// Merges the records on disk with the records in memory
-    fn compact(&mut self) -> Result<(), Box<Error>>{
-        // create a new on-disk BTree
-        let mut new_tree_file = try!(OnDiskBTree::<K,V>::new(self.tree_file_path.to_owned() + ".new", self.key_size, self.value_size));
+    /// Sets the most sorted runs bulk_load_unsorted & an external sort in transform_into merge at
+    /// once, each run being an open file, 64 by default. Fewer than 2 is taken as 2.
+    pub fn merge_fan_in(mut self, fan_in: usize) -> BTreeBuilder {
+        self.merge_fan_in = fan_in;
+        self
+    }
 
-        // get an iterator for the in-memory items
-        let mem_iter = self.mem_tree.into_iter();
+    /// Compacts as soon as the tree is opened if its WAL holds more than bytes of records, so a tree
+    /// that is opened often doesn't replay the same large WAL each time. BTree::last_compaction says
+    /// whether it happened. With lazy_replay, the compaction replays the WAL first.
+    pub fn compact_on_open_if_wal_exceeds(mut self, bytes: u64) -> BTreeBuilder {
+        self.compact_on_open_bytes = Some(bytes);
+        self
+    }
 
-        // get an iterator to the on-disk items
-        let disk_iter = self.tree_file.into_iter();
+    /// Refuses to open the tree if key_size + value_size is more than bytes, so a size worked out
+    /// at runtime can't make every record, and the buffers read into, that big
+    pub fn max_record_bytes(mut self, bytes: usize) -> BTreeBuilder {
+        self.max_record_bytes = Some(bytes);
+        self
+    }
 
-        for kv in merge(mem_iter, disk_iter) {
-            try!(new_tree_file.insert_record(&kv));
-        }
+    /// Limits the keys that can be written to the range lower..upper from the moment the tree is opened,
+    /// as BTree::set_key_bounds does. K must be the key type the tree is opened with. Opening checks the keys
+    /// already in the tree too, and fails with BTreeError::OutOfBounds if any are outside the bounds.
+    /// The bounds are not saved with the tree, so every open that should keep them must pass them again,
+    /// otherwise the tree is opened unbounded.
+    pub fn key_bounds<K: KeyType>(mut self, lower: Option<K>, upper: Option<K>) -> BTreeBuilder {
+        let encode_bound = |bound: Option<K>| match bound {
+            Some(key) => encode(&key, SizeLimit::Infinite).map(Some),
+            None => Ok(None)
+        };
 
-        Ok( () )
+        self.key_bounds = match (encode_bound(lower), encode_bound(upper)) {
+            (Ok(lower), Ok(upper)) => Ok((lower, upper)),
+            _ => Err(BTreeError::Unencodable)
+        };
+        self
     }
-}
 
+    /// Sets what the records of a new WAL, and of the tree files compactions write, are checked with.
+    /// A WAL that already has records, and the tree file, keep the checksum they are stamped with, so to
+    /// switch, open the tree like this and compact it. Defaults to ChecksumKind::Crc32c. Asking for None
+    /// here is an error: use without_checksums, so that it's plain the records are left unchecked.
+    pub fn checksum(mut self, checksum: ChecksumKind) -> BTreeBuilder {
+        self.checksum = checksum;
+        self
+    }
 
-#[cfg(test)]
-#[allow(unused_must_use)]
-mod tests {
-    use std::fs;
-    use std::fs::OpenOptions;
-    use ::BTree;
-    use rand::{thread_rng, Rng};
-    use std::collections::BTreeSet;
+    /// Writes a new WAL & tree files without checksums, so a corrupt record is only caught if it no
+    /// longer decodes. These are the formats from before checksums.
+    pub fn without_checksums(mut self) -> BTreeBuilder {
+        self.checksum = ChecksumKind::None;
+        self.without_checksums = true;
+        self
+    }
 
-    pub fn gen_temp_name() -> String {
-        let file_name: String = thread_rng().gen_ascii_chars().take(10).collect();
+    /// Creates the tree from pairs in any order, as from a file that isn't sorted. The pairs are
+    /// sorted in runs of about memory_budget bytes of records, each written to a file beside the
+    /// tree. The runs are merged into the tree file, which is written in full before the tree is
+    /// opened. A pair given more than once is stored once, as it would be inserted. The tree
+    /// mustn't have a tree file or WAL yet.
+    pub fn bulk_load_unsorted<K, V, I>(self, pairs: I, memory_budget: usize) -> Result<(BTree<K,V>, BulkLoadStats), Box<Error>>
+        where K: KeyType, V: ValueType, I: IntoIterator<Item=(K, V)> {
+        let (key_size, value_size, checksum) = (self.key_size, self.value_size, self.checksum);
+        let run_pairs = sort_run_pairs(memory_budget, key_size, value_size);
+        let fan_in = ::std::cmp::max(self.merge_fan_in, 2);
 
-        return String::from("/tmp/") + &file_name + &String::from(".btr");
+        return self.open_after_writing(|new_tree_file_path| sort_into_tree_file(new_tree_file_path, key_size, value_size, checksum, pairs.into_iter(), run_pairs, fan_in));
     }
 
-    fn remove_files(file_path: String) {
-        fs::remove_file(&file_path);
-        fs::remove_file(file_path + ".wal");
-    }
+    /// Writes a tree file for a tree that doesn't exist yet with write, given the path to write it
+    /// to, then renames it into place & opens the tree
+    fn open_after_writing<K, V, T, F>(self, write: F) -> Result<(BTree<K,V>, T), Box<Error>>
+        where K: KeyType, V: ValueType, F: FnOnce(&String) -> Result<T, Box<Error>> {
+        let tree_file_path = self.tree_file_path.to_owned();
 
-    #[test]
-    fn new_blank_file() {
-        let file_path = gen_temp_name();
+        if fs::metadata(&tree_file_path).is_ok() || fs::metadata(tree_file_path.to_owned() + ".wal").is_ok() {
+            return Err(From::from(IOError::new(ErrorKind::AlreadyExists, "The tree already exists")));
+        }
 
-        let btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+        let new_tree_file_path = tree_file_path.to_owned() + ".new";
 
-        // make sure our two files were created
-        let btf = OpenOptions::new().read(true).write(false).create(false).open(&file_path).unwrap();
-        assert!(btf.metadata().unwrap().len() == 0);
+        // clear out anything left over from a failed attempt
+        if let Err(e) = fs::remove_file(&new_tree_file_path) {
+            if e.kind() != ErrorKind::NotFound {
+                return Err(From::from(e));
+            }
+        }
 
-        let wal = OpenOptions::new().read(true).write(false).create(false).open(file_path.to_owned() + ".wal").unwrap();
-        assert!(wal.metadata().unwrap().len() == 0);
+        let written = match write(&new_tree_file_path) {
+            Ok(written) => written,
+            Err(e) => {
+                try!(fs::remove_file(&new_tree_file_path));
+                return Err(e);
+            }
+        };
 
-        // make sure they think they're new too
-        assert!(btree.wal_file.is_new().unwrap());
-        assert!(btree.wal_file.count().unwrap() == 0);
+        try!(fs::rename(&new_tree_file_path, &tree_file_path));
 
-        assert!(btree.tree_file.is_new().unwrap());
-        assert!(btree.tree_file.count().unwrap() == 0);
+        if self.write_concern != WriteConcern::None {
+            try!(wal_file::sync_dir(&tree_file_path));
+        }
 
-        remove_files(file_path); // remove files assuming it all went well
+        return Ok((try!(self.open()), written));
     }
 
-    #[test]
-    fn new_existing_file() {
-        let file_path = gen_temp_name();
+    pub fn open<K: KeyType, V: ValueType>(self) -> Result<BTree<K,V>, Box<Error>> {
+        if self.checksum == ChecksumKind::None && ! self.without_checksums {
+            return Err(From::from(IOError::new(ErrorKind::InvalidInput, "Use without_checksums to turn off checksums")));
+        }
 
-        // scoped so it is cleaned up
-        { BTree::<u8, u8>::new(&file_path, 1, 1).unwrap(); }
+        if let Some(limit) = self.max_record_bytes {
+            let size = self.key_size.saturating_add(self.value_size);
 
-        let btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+            if size > limit {
+                return Err(From::from(BTreeError::RecordTooLarge{size: size, limit: limit}));
+            }
+        }
 
-        // check our file lengths from the struct
-        assert!(btree.tree_file.count().unwrap() == 0);
-        assert!(btree.wal_file.count().unwrap() == 0);
+        let decode_bound = |bound: Option<Vec<u8>>| -> Result<Option<K>, Box<Error>> {
+            match bound {
+                Some(bytes) => Ok(Some(try!(decode_exactly::<K>(&bytes)))),
+                None => Ok(None)
+            }
+        };
+
+        let key_bounds = match self.key_bounds {
+            Ok((lower, upper)) => (try!(decode_bound(lower)), try!(decode_bound(upper))),
+            Err(e) => return Err(From::from(e))
+        };
+
+        // lock before reading anything, as the holder could be in the middle of a compaction
+        let lock_file = match self.lock_timeout {
+            Some(timeout) => Some(try!(acquire_lock(&(self.tree_file_path.to_owned() + ".lock"), timeout, false))),
+            None => None
+        };
+
+        // create our in-memory multi-maps
+        let mut mem_tree = MultiMap::<K,V>::new();
+        let mut deleted = MultiMap::<K,V>::new();
+
+        // construct the path to the WAL file for the in-memory multi-map
+        let wal_file_path = self.tree_file_path.to_owned() + ".wal";
+
+        // opening the files creates any that are missing
+        let creating = fs::metadata(&wal_file_path).is_err() || fs::metadata(&self.tree_file_path).is_err();
+
+        // construct our WAL file, checking it was written with the same record size
+        let mut wal_file = match RecordFile::<K,V,WALRecord<K,V>>::new_stamped_with_checksum(&wal_file_path, self.key_size, self.value_size, Some(self.checksum)) {
+            Ok(wal_file) => wal_file,
+            Err(e) => {
+                if ! self.allow_unstamped_wal || e.downcast_ref::<BTreeError>() != Some(&BTreeError::UnstampedWal) {
+                    return Err(e);
+                }
+
+                try!(wal_file::upgrade_unstamped::<K,V>(&wal_file_path, self.key_size, self.value_size, self.checksum))
+            }
+        };
+
+        // if we have a WAL file, replay it into the mem_tree, unless that is put off
+        let unreplayed = self.lazy_replay && ! try!(wal_file.is_new());
+
+        let mut prepared = BTreeMap::new();
+
+        if ! unreplayed && ! try!(wal_file.is_new()) {
+            prepared = try!(wal_file.replay_committed(|record| apply_record(&mut mem_tree, &mut deleted, record)));
+        }
+
+        // open the data file
+        let tree_file = try!(OnDiskBTree::<K,V>::with_checksum(self.tree_file_path.to_owned(), self.key_size, self.value_size, self.checksum));
+
+        if self.durable_directory && creating {
+            try!(wal_file::sync_dir(&self.tree_file_path));
+        }
+
+        let mut btree = BTree{tree_file_path: self.tree_file_path,
+                        key_size: self.key_size,
+                        value_size: self.value_size,
+                        tree_file: tree_file,
+                        wal_file: wal_file,
+                        mem_tree: mem_tree,
+                        deleted: deleted,
+                        write_concern: self.write_concern,
+                        read_concern: self.read_concern,
+                        last_compaction: None,
+                        compaction_policy: self.compaction_policy,
+                        compaction_hooks: Vec::new(),
+                        watchers: Vec::new(),
+                        max_file_bytes: self.max_file_bytes,
+                        value_fallback: None,
+                        last_lsn: 0,
+                        durable_lsn: 0,
+                        compacted_lsn: 0,
+                        synced_tree_lsn: 0,
+                        small_tree_threshold: self.small_tree_threshold,
+                        all_in_memory: false,
+                        key_bounds: key_bounds,
+                        logical_bytes: 0,
+                        tree_bytes_written: 0,
+                        wal_bytes_at_reset: 0,
+                        background_sync: None,
+                        unreplayed: unreplayed,
+                        record_sample: Vec::new(),
+                        prepared: prepared,
+                        comparator_checks: self.comparator_checks,
+                        compaction_threads: ::std::cmp::max(self.compaction_threads, 1),
+                        checksum: self.checksum,
+                        _lock_file: lock_file};
 
-        remove_files(file_path); // remove files assuming it all went well
+        btree.sample_records();
+
+        if ! unreplayed {
+            try!(btree.load_if_small());
+        }
+
+        // what a lazy open leaves in the WAL is checked when it is replayed
+        try!(btree.check_tree_file_key_bounds());
+
+        if ! unreplayed {
+            try!(btree.check_memory_key_bounds());
+        }
+
+        if let Some(threshold) = self.compact_on_open_bytes {
+            if try!(btree.wal_file.size()) > threshold {
+                try!(btree.compact());
+            }
+        }
+
+        return Ok(btree);
     }
+}
 
-    #[test]
-    fn insert_new_u8() {
-        let file_path = gen_temp_name();
+impl <K: PrefixKey, V: ValueType> BTree<K, V> {
+    /// Returns a view of just the keys that start with prefix, with the prefix taken off
+    pub fn scoped(&mut self, prefix: K) -> ScopedTree<K,V> {
+        return ScopedTree::new(self, prefix);
+    }
+}
 
-        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+impl <V: ValueType> BTree<Bytes, V> {
+    /// Returns every pair whose key starts with prefix, in order
+    pub fn scan_prefix(&mut self, prefix: &[u8]) -> Result<Vec<(Bytes, V)>, Box<Error>> {
+        let mut pairs = Vec::new();
+        let mut cursor = self.cursor();
+        let mut pair = try!(cursor.seek(&Bytes::from(prefix))).map(|(k, v)| (k.clone(), v.clone()));
 
-        btree.insert(2, 3).unwrap(); // insert into a new file
+        while let Some((key, value)) = pair {
+            if ! key.starts_with(prefix) {
+                break;
+            }
 
-        assert!(btree.wal_file.count().unwrap() == 1);
-        assert!(btree.mem_tree.contains_key(&2));
+            pairs.push((key, value));
+            pair = try!(cursor.move_next()).map(|(k, v)| (k.clone(), v.clone()));
+        }
 
-        remove_files(file_path); // remove files assuming it all went well
+        return Ok(pairs);
     }
+}
 
-    #[test]
-    fn insert_new_str() {
-        let file_path = gen_temp_name();
+impl <K: KeyType + Debug, V: ValueType + Debug> BTree<K, V> {
+    /// Writes out every record in the tree file as its offset, its bytes in hex & what they decode to.
+    /// The tree file has no header, just the records one after another in key order.
+    pub fn debug_dump<W: Write>(&self, mut writer: W) -> Result<(), Box<Error>> {
+        let count = try!(self.tree_file.count());
 
-        let mut btree = BTree::<String, String>::new(&file_path, 15, 15).unwrap();
+        try!(writeln!(writer, "tree file {}: {} records, key size {}, value size {}", self.tree_file_path, count, self.key_size, self.value_size));
 
-        // insert into a new file
-        btree.insert("Hello".to_owned(), "World".to_owned()).unwrap();
+        let mut offset = 0;
 
-        assert!(! btree.wal_file.is_new().unwrap());
-        assert!(btree.mem_tree.contains_key(&String::from("Hello")));
+        for index in 0..count {
+            let (key_bytes, value_bytes) = try!(self.tree_file.read_raw_record(index));
+            let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ");
+
+            try!(write!(writer, "{:08x}  key [{}]  value [{}]  ", offset, hex(&key_bytes), hex(&value_bytes)));
+
+            match self.tree_file.read_record(index) {
+                Ok(kv) => try!(writeln!(writer, "{:?} => {:?}", kv.key, kv.value)),
+                Err(e) => try!(writeln!(writer, "error: {}", e))
+            }
+
+            offset += key_bytes.len() + value_bytes.len();
+        }
 
-        remove_files(file_path); // remove files assuming it all went well
+        Ok( () )
     }
+}
 
-    #[test]
-    fn get_returns_an_iter() {
-        let file_path = gen_temp_name();
+impl <K: KeyType + Send + Sync, V: ValueType + Send + Sync> BTree<K, V> {
+    /// Looks up the keys on a thread per CPU, returning each key with its values, in the order given.
+    /// Reads are positional, so the threads share the tree's file handles without moving each other's
+    /// place in the file. Elsewhere than unix reads have to seek, so they're done on one thread.
+    pub fn parallel_get(&self, keys: Vec<K>) -> Result<Vec<(K, Option<BTreeSet<V>>)>, Box<Error>> {
+        try!(self.check_replayed());
 
-        // setup tree
-        let mut btree = BTree::<String, String>::new(&file_path, 15, 15).unwrap();
+        let threads = if cfg!(unix) { thread::available_parallelism().map(|n| n.get()).unwrap_or(1) } else { 1 };
+        let chunk_size = ::std::cmp::max((keys.len() + threads - 1) / threads, 1);
 
-        // expected return set
-        let mut expected: BTreeSet<String> = BTreeSet::new();
-        expected.insert("World".to_string());
+        // errors can't be sent between threads, so a thread returns which lookup failed, to be redone here
+        let chunks: Vec<Result<Vec<Option<BTreeSet<V>>>, usize>> = thread::scope(|scope| {
+            let handles: Vec<_> = keys.chunks(chunk_size).enumerate().map(|(chunk, chunk_keys)| scope.spawn(move || {
+                let mut values = Vec::with_capacity(chunk_keys.len());
 
-        btree.insert("Hello".to_owned(), "World".to_owned());
+                for (i, key) in chunk_keys.iter().enumerate() {
+                    match self.get(key) {
+                        Ok(key_values) => values.push(key_values),
+                        Err(_) => return Err(chunk * chunk_size + i)
+                    }
+                }
 
-        // get the set at the hello key
-        let set_at_hello: Vec<String> = btree.get(&"Hello".to_string()).unwrap().cloned().collect();
+                return Ok(values);
+            })).collect();
+
+            handles.into_iter().map(|handle| match handle.join() {
+                Ok(values) => values,
+                Err(panic) => panic::resume_unwind(panic)
+            }).collect()
+        });
 
-        assert_eq!(set_at_hello, ["World".to_string()]);
+        let mut results = Vec::with_capacity(keys.len());
 
-        remove_files(file_path); // remove files assuming it all went well
+        for chunk in chunks {
+            match chunk {
+                Ok(values) => results.extend(values),
+                Err(failed) => {
+                    try!(self.get(&keys[failed]));
+                    return Err(From::from(IOError::new(ErrorKind::Other, "Lookup failed on another thread")));
+                }
+            }
+        }
+
+        return Ok(keys.into_iter().zip(results).collect());
     }
 
-    #[test]
-    fn insert_multiple() {
-        let file_path = gen_temp_name();
+    /// Compacts as compact does, but with the merge split across the builder's compaction_threads.
+    /// The keys are split into contiguous ranges at evenly spaced records of the tree file (or keys of
+    /// the mem_tree, if the tree file is empty), and each range is merged on its own thread into a
+    /// segment file. The segments are then joined, in order, into the new tree file, which comes out
+    /// the same as compact would write it. Compactions started by writes are still done on one thread,
+    /// as writes don't need K & V to be Send. Like parallel_get, this is done on one thread elsewhere than
+    /// unix. Comparator checks only look within each range, and errors other than BTreeErrors come
+    /// back as just their message.
+    pub fn compact_parallel(&mut self) -> Result<CompactionStats, Box<Error>> {
+        let threads = if cfg!(unix) { self.compaction_threads } else { 1 };
 
-        let mut btree = BTree::<String, String>::new(&file_path, 15, 15).unwrap();
+        if threads <= 1 {
+            return self.compact();
+        }
 
-        // insert into a new file
-        btree.insert("Hello".to_owned(), "World".to_owned()).unwrap();
-        assert!(! btree.wal_file.is_new().unwrap());
+        let (key_size, value_size) = (self.key_size, self.value_size);
+        let (new_tree_file_path, bytes_before, start) = try!(self.begin_rewrite(key_size, value_size));
 
-        btree.insert("Hello".to_owned(), "Everyone".to_owned()).unwrap();
-        assert!(! btree.wal_file.is_new().unwrap());
+        let mut splits: Vec<K> = Vec::new();
+
+        {
+            let candidates: Vec<&K> = if self.record_sample.is_empty() { self.mem_tree.keys().collect() } else { self.record_sample.iter().collect() };
+
+            for i in 1..threads {
+                if let Some(&key) = candidates.get(i * candidates.len() / threads) {
+                    if splits.last().map_or(true, |last| last < key) {
+                        splits.push(key.clone());
+                    }
+                }
+            }
+        }
+
+        // where each range starts & ends in the tree file
+        let disk_records = if self.all_in_memory { 0 } else { try!(self.tree_file.count()) };
+        let mut disk_bounds = vec![0];
+
+        for split in &splits {
+            disk_bounds.push(if self.all_in_memory { 0 } else { try!(self.tree_file.partition_point(|k| k < split)) });
+        }
+
+        disk_bounds.push(disk_records);
+
+        let segment_paths: Vec<String> = (0..splits.len() + 1).map(|i| format!("{}.{}", new_tree_file_path, i)).collect();
+        let max_records = self.max_tree_records(key_size, value_size);
+
+        let merged: Vec<Result<MergeCounts, Box<Error + Send + Sync>>> = {
+            let (mem_tree, tree_file, deleted, comparator_checks, checksum) = (&self.mem_tree, &self.tree_file, &self.deleted, self.comparator_checks, self.checksum);
+            let splits = &splits;
+            let disk_bounds = &disk_bounds;
+
+            thread::scope(|scope| {
+                let handles: Vec<_> = segment_paths.iter().enumerate().map(|(i, segment_path)| scope.spawn(move || {
+                    let start = if i == 0 { Bound::Unbounded } else { Bound::Included(&splits[i - 1]) };
+                    let end = if i == splits.len() { Bound::Unbounded } else { Bound::Excluded(&splits[i]) };
+
+                    let merged = OnDiskBTree::<K,V>::with_checksum(segment_path.to_owned(), key_size, value_size, checksum).and_then(|mut segment| {
+                        merge_records(&mut segment,
+                                      mem_tree.range_pairs((start, end)),
+                                      tree_file.iter_range(disk_bounds[i], disk_bounds[i + 1]),
+                                      deleted,
+                                      |_, value| value,
+                                      comparator_checks,
+                                      max_records)
+                    });
+
+                    // errors can't be sent between threads, so only BTreeErrors are kept as they are
+                    return merged.map_err(|e| -> Box<Error + Send + Sync> {
+                        match e.downcast::<BTreeError>() {
+                            Ok(e) => e,
+                            Err(e) => From::from(e.to_string())
+                        }
+                    });
+                })).collect();
+
+                handles.into_iter().map(|handle| match handle.join() {
+                    Ok(merged) => merged,
+                    Err(panic) => panic::resume_unwind(panic)
+                }).collect()
+            })
+        };
+
+        let remove_segments = |paths: &[String]| for path in paths {
+            let _ = fs::remove_file(path);
+        };
+
+        let mut total = MergeCounts{input_mem_records: 0, input_disk_records: 0, output_records: 0, tombstones_dropped: 0, over_limit: None, out_of_order: false};
+
+        for merged in merged {
+            match merged {
+                Ok(merged) => {
+                    total.input_mem_records += merged.input_mem_records;
+                    total.input_disk_records += merged.input_disk_records;
+                    total.output_records += merged.output_records;
+                    total.tombstones_dropped += merged.tombstones_dropped;
+                    total.over_limit = total.over_limit.or(merged.over_limit);
+                    total.out_of_order |= merged.out_of_order;
+                },
+                Err(e) => {
+                    remove_segments(&segment_paths);
+                    return Err(e);
+                }
+            }
+        }
+
+        if let Some(max_records) = max_records {
+            if total.output_records > max_records {
+                total.over_limit = Some(max_records);
+            }
+        }
+
+        // the first segment becomes the new tree file, and the records of each of the others, past its stamp,
+        // are appended to it then it's removed, so there's never more than one segment's worth of records on
+        // disk twice. A merge that stopped early leaves the new tree file to finish_rewrite to drop.
+        let stopped = total.over_limit.is_some() || total.out_of_order;
+        let stamp_size = wal_file::tree_stamp_size(self.checksum);
+        let joined = fs::rename(&segment_paths[0], &new_tree_file_path).and_then(|_| {
+            let mut new_tree_file = try!(OpenOptions::new().append(true).open(&new_tree_file_path));
+
+            for segment_path in &segment_paths[1..] {
+                if ! stopped {
+                    let mut segment = try!(File::open(segment_path));
+
+                    try!(segment.seek(SeekFrom::Start(stamp_size)));
+                    try!(::std::io::copy(&mut segment, &mut new_tree_file));
+                }
+
+                try!(fs::remove_file(segment_path));
+            }
+
+            if self.write_concern == WriteConcern::Full && ! stopped {
+                try!(new_tree_file.sync_all());
+            }
+
+            Ok( () )
+        });
+
+        if let Err(e) = joined {
+            remove_segments(&segment_paths);
+            let _ = fs::remove_file(&new_tree_file_path);
+            return Err(From::from(e));
+        }
+
+        return self.finish_rewrite(&new_tree_file_path, key_size, value_size, total, bytes_before, start);
+    }
+}
+
+impl <K: KeyType + Copy + Into<f64>, V: ValueType> BTree<K, V> {
+    /// Counts the keys in each of buckets evenly sized ranges between the smallest & largest key.
+    /// Each bucket is (smallest key in it, largest key in it, number of keys), and empty buckets are left out.
+    /// See key_histogram for keys that aren't numbers.
+    pub fn numeric_key_histogram(&self, buckets: usize) -> Result<Vec<(K, K, usize)>, Box<Error>> {
+        let keys = try!(self.live_keys());
+
+        if buckets == 0 || keys.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let min: f64 = keys[0].into();
+        let max: f64 = keys[keys.len() - 1].into();
+        let width = (max - min) / buckets as f64;
+        let mut histogram: Vec<(usize, K, K, usize)> = Vec::new();
+
+        // the keys are sorted, so each bucket's keys are next to each other
+        for key in keys {
+            let offset: f64 = key.into() - min;
+            let bucket = if width > 0.0 { ((offset / width) as usize).min(buckets - 1) } else { 0 };
+
+            match histogram.last_mut() {
+                Some(&mut (last_bucket, _, ref mut last, ref mut count)) if last_bucket == bucket => {
+                    *last = key;
+                    *count += 1;
+                    continue;
+                },
+                _ => ()
+            }
+
+            histogram.push((bucket, key, key, 1));
+        }
+
+        return Ok(histogram.into_iter().map(|(_, first, last, count)| (first, last, count)).collect());
+    }
+}
+
+/// Decodes bytes that must hold exactly one encoded T
+fn decode_exactly<T: Decodable>(bytes: &[u8]) -> Result<T, Box<Error>> {
+    let mut reader = bytes;
+    let t = try!(decode_from(&mut reader, SizeLimit::Bounded(bytes.len() as u64)));
+
+    if ! reader.is_empty() {
+        return Err(From::from(IOError::new(ErrorKind::InvalidData, "Trailing bytes after the encoded key or value")));
+    }
+
+    return Ok(t);
+}
+
+/// What merging the mem_tree & tree file into a new tree file came to
+struct MergeCounts {
+    input_mem_records: u64,   // records merged in from the mem_tree
+    input_disk_records: u64,  // records merged in from the old tree file
+    output_records: u64,      // records written to the new tree file
+    tombstones_dropped: u64,  // deleted pairs that were left out
+    over_limit: Option<u64>,  // the merge stopped at this many records, the most the file may hold
+    out_of_order: bool,       // the merge stopped at a key that broke the order, see check_comparator
+}
+
+/// Merges the pairs from memory & disk, both in order, into new_tree_file, leaving out deleted pairs
+/// and mapping each value. It stops early if the new file would hold more than max_records, or if
+/// comparator_checks finds a key out of order with the two written before it, and fails if a record
+/// on disk can't be read.
+fn merge_records<K, V, M, D, F>(new_tree_file: &mut OnDiskBTree<K,V>, mem_iter: M, disk_iter: D, deleted: &MultiMap<K,V>, mut map: F,
+                                comparator_checks: bool, max_records: Option<u64>) -> Result<MergeCounts, Box<Error>>
+    where K: KeyType, V: ValueType, M: Iterator<Item=KeyValuePair<K,V>>, D: Iterator<Item=Result<KeyValuePair<K,V>, Box<Error>>>, F: FnMut(K, V) -> V {
+    let mut input_mem_records = 0;
+    let mut input_disk_records = 0;
+    let mut output_records = 0;
+    let mut tombstones_dropped = 0;
+    let mut over_limit = None;
+    let mut out_of_order = false;
+    let disk_error = Cell::new(None);
+
+    {
+        let mem_iter = mem_iter.inspect(|_| input_mem_records += 1);
+
+        // leaving out those on disk that were deleted
+        let disk_iter = until_error(disk_iter, &disk_error)
+                                 .inspect(|_| input_disk_records += 1)
+                                 .filter(|kv| if deleted.contains(&kv.key, &kv.value) {
+                                     tombstones_dropped += 1;
+                                     false
+                                 } else {
+                                     true
+                                 });
+
+        // mapping can reorder or merge a key's values, so they're collected a key at a time;
+        // the None on the end writes out the last key
+        let mut key_values: Option<(K, BTreeSet<V>)> = None;
+        let mut written: (Option<K>, Option<K>) = (None, None);  // the last two keys written, newest last
+
+        'merge: for kv in merge(mem_iter, disk_iter).dedup().map(Some).chain(iter::once(None)) {
+            let next_key = match (&key_values, &kv) {
+                (&Some((ref key, _)), &Some(ref kv)) => *key != kv.key,
+                (&Some(_), &None) => true,
+                _ => false
+            };
+
+            if next_key {
+                let (key, values) = key_values.take().unwrap();
+
+                if comparator_checks {
+                    let follows = |before: &Option<K>| before.as_ref().map_or(true, |before| {
+                        before.cmp(&key) == Ordering::Less && key.cmp(before) == Ordering::Greater
+                    });
+
+                    if ! follows(&written.0) || ! follows(&written.1) {
+                        out_of_order = true;
+                        break 'merge;
+                    }
+
+                    written = (written.1.take(), Some(key.clone()));
+                }
+
+                for value in values {
+                    if let Some(max_records) = max_records {
+                        if output_records + 1 > max_records {
+                            over_limit = Some(max_records);
+                            break 'merge;
+                        }
+                    }
+
+                    try!(new_tree_file.insert_record(&KeyValuePair{key: key.clone(), value: value}));
+                    output_records += 1;
+                }
+            }
+
+            if let Some(kv) = kv {
+                let value = map(kv.key.clone(), kv.value);
+
+                match key_values {
+                    Some((_, ref mut values)) => { values.insert(value); },
+                    None => key_values = Some((kv.key, iter::once(value).collect()))
+                }
+            }
+        }
+    }
+
+    // a record that can't be read ends the records from disk early, and the new file would be missing the rest
+    if let Some(e) = disk_error.take() {
+        return Err(e);
+    }
+
+    return Ok(MergeCounts{input_mem_records: input_mem_records,
+                          input_disk_records: input_disk_records,
+                          output_records: output_records,
+                          tombstones_dropped: tombstones_dropped,
+                          over_limit: over_limit,
+                          out_of_order: out_of_order});
+}
+
+/// Applies an insert or delete from the WAL to the in-memory maps
+fn apply_record<K: KeyType, V: ValueType>(mem_tree: &mut MultiMap<K,V>, deleted: &mut MultiMap<K,V>, record: WALRecord<K,V>) {
+    match record {
+        WALRecord::Insert(kv) => {
+            // checked first, as deleting takes copies of the pair
+            if deleted.contains(&kv.key, &kv.value) {
+                deleted.delete(kv.key.clone(), kv.value.clone());
+            }
+
+            mem_tree.insert(kv.key, kv.value);
+        },
+        WALRecord::Delete(kv) => {
+            mem_tree.delete(kv.key.clone(), kv.value.clone());
+            deleted.insert(kv.key, kv.value);
+        },
+        _ => ()
+    }
+}
+
+#[cfg(unix)]
+fn allocated_bytes(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+
+    return metadata.blocks() * 512;  // blocks are always 512 bytes, whatever the filesystem's block size
+}
+
+#[cfg(not(unix))]
+fn allocated_bytes(metadata: &fs::Metadata) -> u64 {
+    return metadata.len();
+}
+
+/// Locks the lock file, exclusively or shared with other readers, retrying with exponential backoff until the timeout is up
+fn acquire_lock(lock_file_path: &String, timeout: Duration, shared: bool) -> Result<File, Box<Error>> {
+    let lock_file = try!(OpenOptions::new().read(true).write(true).create(true).truncate(false).open(lock_file_path));
+    let start = Instant::now();
+    let mut backoff = Duration::from_millis(1);
+
+    loop {
+        let locked = if shared { lock_file.try_lock_shared() } else { lock_file.try_lock() };
+
+        match locked {
+            Ok(_) => return Ok(lock_file),
+            Err(TryLockError::WouldBlock) => (),
+            Err(TryLockError::Error(e)) => return Err(From::from(e))
+        }
+
+        let elapsed = start.elapsed();
+
+        if elapsed >= timeout {
+            return Err(From::from(BTreeError::LockTimeout));
+        }
+
+        // don't sleep past the timeout
+        thread::sleep(::std::cmp::min(backoff, timeout - elapsed));
+        backoff *= 2;
+    }
+}
+
+impl <K: KeyType, V: ValueType> BTree<K, V> {
+    pub fn new(tree_file_path: &String, key_size: usize, value_size: usize) -> Result<BTree<K,V>, Box<Error>> {
+        return BTreeBuilder::new(tree_file_path, key_size, value_size).open();
+    }
+
+    /// Opens the BTree holding an exclusive lock on it, waiting up to timeout for
+    /// whoever has it open to let go. See BTreeBuilder::lock_timeout.
+    pub fn open_with_lock_timeout(tree_file_path: &String, timeout: Duration, key_size: usize, value_size: usize) -> Result<BTree<K,V>, Box<Error>> {
+        return BTreeBuilder::new(tree_file_path, key_size, value_size).lock_timeout(timeout).open();
+    }
+
+    /// Inserts a key into the BTree
+    pub fn insert(&mut self, key: K, value: V) -> Result<DurabilityToken, Box<Error>> {
+        try!(self.check_write(&key));
+
+        let record = WALRecord::Insert(KeyValuePair{key: key, value: value});
+
+        // should wrap this in a read-write lock
+        let token = try!(self.append_to_wal(&record));
+
+        self.count_logical_bytes(&record);
+        self.notify_watchers(&record);
+        apply_record(&mut self.mem_tree, &mut self.deleted, record);
+
+        try!(self.compact_if_needed());
+
+        return Ok(token);
+    }
+
+    /// Inserts a new key/value pair, then returns a view of the key's values. The view borrows
+    /// the values in memory rather than copying them, and only reads the tree file when asked to.
+    pub fn insert_and_get(&mut self, key: K, value: V) -> Result<ValueSetView<K,V>, Box<Error>> {
+        try!(self.ensure_replayed());
+
+        try!(self.insert(key.clone(), value));
+
+        return Ok(ValueSetView::new(self, key));
+    }
+
+    /// Inserts every one of the values for the key, as one batch, so with one fsync and all or none
+    /// of them replayed after a crash. Returns the number of distinct values written.
+    pub fn insert_many_values<I: IntoIterator<Item=V>>(&mut self, key: K, values: I) -> Result<usize, Box<Error>> {
+        let values: BTreeSet<V> = values.into_iter().collect();
+        let mut batch = WriteBatch::new();
+
+        for value in values.iter() {
+            batch.insert(key.clone(), value.clone());
+        }
+
+        try!(self.apply(&batch));
+
+        return Ok(values.len());
+    }
+
+    /// Deletes a value from the set of values for a key. It is removed from memory right away,
+    /// and any copy in the tree file is dropped during the next compaction.
+    pub fn delete(&mut self, key: K, value: V) -> Result<DurabilityToken, Box<Error>> {
+        try!(self.check_write(&key));
+
+        let record = WALRecord::Delete(KeyValuePair{key: key, value: value});
+
+        let token = try!(self.append_to_wal(&record));
+
+        self.notify_watchers(&record);
+        apply_record(&mut self.mem_tree, &mut self.deleted, record);
+
+        try!(self.compact_if_needed());
+
+        return Ok(token);
+    }
+
+    /// Applies all the writes in a batch, or none of them if there's a crash part way through.
+    /// The batch goes into the WAL between start & end markers with a single fsync, and a
+    /// replay drops a batch that is missing its end. Any compaction waits until the whole batch is in.
+    /// The whole batch shares one LSN.
+    pub fn apply(&mut self, batch: &WriteBatch<K,V>) -> Result<DurabilityToken, Box<Error>> {
+        if batch.is_empty() {
+            return Ok(DurabilityToken(self.last_lsn));
+        }
+
+        for record in batch.records() {
+            match *record {
+                WALRecord::Insert(ref kv) | WALRecord::Delete(ref kv) => try!(self.check_write(&kv.key)),
+                _ => ()
+            }
+        }
+
+        let mut records = Vec::with_capacity(batch.records().len() + 2);
+
+        records.push(WALRecord::BatchStart);
+        records.extend(batch.records().cloned());
+        records.push(WALRecord::BatchEnd);
+
+        // one write, so if it fails it is cut off whole
+        try!(self.check_background_sync());
+        try!(self.wal_file.insert_records(&records));
+
+        let token = try!(self.wal_appended());
+
+        for record in batch.records() {
+            self.count_logical_bytes(record);
+            self.notify_watchers(record);
+            apply_record(&mut self.mem_tree, &mut self.deleted, record.clone());
+        }
+
+        try!(self.compact_if_needed());
+
+        return Ok(token);
+    }
+
+    /// Deletes every value of the key, returning the values it had, or None if it had none. The values
+    /// are read with get and then deleted as one batch. That's two steps: &mut self keeps other writes
+    /// through this BTree out from between them, but not writes from another handle on the same files,
+    /// so open the tree with a lock_timeout if other processes write to it too.
+    pub fn remove_all_values(&mut self, key: &K) -> Result<Option<BTreeSet<V>>, Box<Error>> {
+        try!(self.ensure_replayed());
+
+        let values = match try!(self.get(key)) {
+            Some(values) => values,
+            None => return Ok(None)
+        };
+
+        let mut batch = WriteBatch::new();
+
+        for value in values.iter() {
+            batch.delete(key.clone(), value.clone());
+        }
+
+        try!(self.apply(&batch));
+
+        return Ok(Some(values));
+    }
+
+    /// Deletes every value of all the keys, with all the tombstones going into the WAL in one write.
+    /// The deletes are applied like a batch, so a crash part way through drops all of them.
+    /// Returns the number of keys that had values, in memory or on disk.
+    pub fn delete_batch<I: IntoIterator<Item=K>>(&mut self, keys: I) -> Result<u64, Box<Error>> {
+        try!(self.ensure_replayed());
+
+        let keys: BTreeSet<K> = keys.into_iter().collect();
+        let mut records = vec![WALRecord::BatchStart];
+        let mut present = 0;
+
+        // nothing is changed until all the values have been read
+        for key in keys {
+            try!(self.check_write(&key));
+
+            if let Some(values) = try!(self.get(&key)) {
+                present += 1;
+
+                for value in values {
+                    records.push(WALRecord::Delete(KeyValuePair{key: key.clone(), value: value}));
+                }
+            }
+        }
+
+        if present == 0 {
+            return Ok(0);
+        }
+
+        records.push(WALRecord::BatchEnd);
+
+        try!(self.check_background_sync());
+        try!(self.wal_file.insert_records(&records));
+        try!(self.wal_appended());
+
+        for record in records {
+            self.notify_watchers(&record);
+            apply_record(&mut self.mem_tree, &mut self.deleted, record);
+        }
+
+        try!(self.compact_if_needed());
+
+        return Ok(present);
+    }
+
+    /// Truncates the WAL file to offset, and returns the records that came after. Those writes are undone
+    /// in memory too, as if they had never been made. The offset is from the start of the file, as WalReader
+    /// gives them, so must be past the stamp and fall on a record boundary; if it falls inside a batch,
+    /// the half of the batch left in the WAL is dropped.
+    pub fn split_wal_at(&mut self, offset: u64) -> Result<Vec<WALRecord<K,V>>, Box<Error>> {
+        let first = match self.wal_file.record_index(offset) {
+            Some(first) => first,
+            None => return Err(From::from(IOError::new(ErrorKind::InvalidInput, "WAL offset isn't on a record boundary")))
+        };
+
+        let mut records = Vec::new();
+
+        for index in first..try!(self.wal_file.count()) {
+            records.push(try!(self.wal_file.read_record(index)));
+        }
+
+        if records.is_empty() {
+            return Ok(records);
+        }
+
+        try!(self.wal_file.truncate(first));
+
+        if self.write_concern != WriteConcern::None {
+            try!(self.wal_file.sync());
+        }
+
+        // rebuild memory from what is left
+        try!(self.replay_wal());
+
+        return Ok(records);
+    }
+
+    /// Replays the WAL now, if it was put off by lazy_replay
+    pub fn ensure_replayed(&mut self) -> Result<(), Box<Error>> {
+        if self.unreplayed {
+            try!(self.replay_wal());
+        }
+
+        Ok( () )
+    }
+
+    /// Rebuilds memory from the whole WAL. The WAL is in LSN order, so writes made since a lazy
+    /// open land after the older writes to the same keys, and win over them.
+    fn replay_wal(&mut self) -> Result<(), Box<Error>> {
+        let mut mem_tree = MultiMap::new();
+        let mut deleted = MultiMap::new();
+
+        self.prepared = try!(self.wal_file.replay_committed(|record| apply_record(&mut mem_tree, &mut deleted, record)));
+
+        self.mem_tree = mem_tree;
+        self.deleted = deleted;
+        self.all_in_memory = false;
+        self.unreplayed = false;
+
+        try!(self.load_if_small());
+
+        return self.check_memory_key_bounds();
+    }
+
+    /// Prepares a transaction, the first phase of a two phase commit. The batch's writes go into the
+    /// WAL behind a Prepare marker, and are synced whatever the write concern, but aren't applied.
+    /// They are applied when the returned PreparedTransaction is committed, and dropped if it is
+    /// aborted. A transaction that is neither, because the handle was dropped or the process died,
+    /// stays prepared, through compactions & restarts, until prepared_transaction gets it back.
+    /// The WAL stores the id in 16 bytes, so this needs a key size + value size of at least 16.
+    pub fn prepare(&mut self, transaction_id: u128, batch: &WriteBatch<K,V>) -> Result<PreparedTransaction<K,V>, Box<Error>> {
+        try!(self.ensure_replayed());
+
+        if self.key_size + self.value_size < 16 {
+            return Err(From::from(IOError::new(ErrorKind::InvalidInput, "Transactions need a key size + value size of at least 16 bytes")));
+        }
+
+        if self.prepared.contains_key(&transaction_id) {
+            return Err(From::from(IOError::new(ErrorKind::AlreadyExists, "Transaction is already prepared")));
+        }
+
+        let (high, low) = split_transaction_id(transaction_id);
+        let mut writes = Vec::new();
+
+        for record in batch.records() {
+            match *record {
+                WALRecord::Insert(ref kv) | WALRecord::Delete(ref kv) => try!(self.check_write(&kv.key)),
+                _ => continue
+            }
+
+            writes.push(record.clone());
+        }
+
+        let mut records = vec![WALRecord::Prepare(high, low)];
+
+        records.extend(writes.iter().cloned());
+        records.push(WALRecord::BatchEnd);
+
+        try!(self.check_background_sync());
+        try!(self.wal_file.insert_records(&records));
+
+        let token = try!(self.wal_appended());
+
+        try!(self.wait_durable(token));
+
+        self.prepared.insert(transaction_id, writes);
+
+        return Ok(PreparedTransaction::new(self, transaction_id));
+    }
+
+    /// Returns the ids of the transactions that are prepared, but not yet committed or aborted
+    pub fn prepared_transactions(&self) -> Result<Vec<u128>, Box<Error>> {
+        try!(self.check_replayed());
+
+        return Ok(self.prepared.keys().cloned().collect());
+    }
+
+    /// Gets back a prepared transaction to commit or abort it, say after a restart
+    pub fn prepared_transaction(&mut self, transaction_id: u128) -> Result<Option<PreparedTransaction<K,V>>, Box<Error>> {
+        try!(self.ensure_replayed());
+
+        if ! self.prepared.contains_key(&transaction_id) {
+            return Ok(None);
+        }
+
+        return Ok(Some(PreparedTransaction::new(self, transaction_id)));
+    }
+
+    /// Writes the transaction's Commit or Abort marker, and applies its writes if it is committed
+    fn finish_transaction(&mut self, transaction_id: u128, commit: bool) -> Result<DurabilityToken, Box<Error>> {
+        let (high, low) = split_transaction_id(transaction_id);
+        let marker = if commit { WALRecord::Commit(high, low) } else { WALRecord::Abort(high, low) };
+
+        let token = try!(self.append_to_wal(&marker));
+        let writes = self.prepared.remove(&transaction_id).unwrap_or_else(Vec::new);
+
+        if commit {
+            for record in writes {
+                self.count_logical_bytes(&record);
+                self.notify_watchers(&record);
+                apply_record(&mut self.mem_tree, &mut self.deleted, record);
+            }
+        }
+
+        try!(self.compact_if_needed());
+
+        return Ok(token);
+    }
+
+    /// Writes the prepared transactions to the WAL again, after a compaction has emptied it
+    fn log_prepared(&mut self) -> Result<(), Box<Error>> {
+        let records = self.prepared_records();
+
+        if records.is_empty() {
+            return Ok( () );
+        }
+
+        try!(self.wal_file.insert_records(&records));
+
+        if self.write_concern != WriteConcern::None {
+            try!(self.wal_file.sync());
+        }
+
+        Ok( () )
+    }
+
+    /// Returns the WAL records that prepare the prepared transactions again
+    fn prepared_records(&self) -> Vec<WALRecord<K,V>> {
+        let mut records = Vec::new();
+
+        for (&transaction_id, writes) in &self.prepared {
+            let (high, low) = split_transaction_id(transaction_id);
+
+            records.push(WALRecord::Prepare(high, low));
+            records.extend(writes.iter().cloned());
+            records.push(WALRecord::BatchEnd);
+        }
+
+        return records;
+    }
+
+    /// Rewrites the WAL with only the last write of each pair, dropping the writes it undoes or repeats,
+    /// so there is less to replay. Batch markers go too, along with anything a crash cut off, as the
+    /// new WAL is written in full then renamed over the old one; prepared transactions are kept as
+    /// they are. Memory & the tree file aren't touched, which makes this much cheaper than a compaction,
+    /// though it can't drop deletes of pairs that may be in the tree file. Returns the bytes saved.
+    pub fn wal_deduplicate(&mut self) -> Result<u64, Box<Error>> {
+        try!(self.ensure_replayed());
+        try!(self.check_background_sync());
+
+        let size_before = try!(self.wal_file.size());
+        let mut latest = BTreeMap::new();   // the last write of each pair, and where it was
+        let mut position = 0;
+
+        try!(self.wal_file.replay_committed(|record| {
+            let pair = match record {
+                WALRecord::Insert(ref kv) | WALRecord::Delete(ref kv) => (kv.key.clone(), kv.value.clone()),
+                _ => return
+            };
+
+            latest.insert(pair, (position, record));
+            position += 1;
+        }));
+
+        let mut records: Vec<(usize, WALRecord<K,V>)> = latest.into_iter().map(|(_, write)| write).collect();
+
+        records.sort_by_key(|&(position, _)| position);
+
+        let mut records: Vec<WALRecord<K,V>> = records.into_iter().map(|(_, record)| record).collect();
+
+        records.extend(self.prepared_records());
+
+        let wal_file_path = self.tree_file_path.to_owned() + ".wal";
+        let new_wal_file_path = wal_file_path.to_owned() + ".new";
+
+        // clear out anything left over from a failed attempt
+        if let Err(e) = fs::remove_file(&new_wal_file_path) {
+            if e.kind() != ErrorKind::NotFound {
+                return Err(From::from(e));
+            }
+        }
+
+        // scoped so the new file is closed before we rename it
+        {
+            let mut new_wal_file = try!(RecordFile::<K,V,WALRecord<K,V>>::new_stamped_with_checksum(&new_wal_file_path, self.key_size, self.value_size, Some(self.wal_file.checksum())));
+
+            if let Err(e) = new_wal_file.insert_records(&records).and_then(|_| new_wal_file.sync()) {
+                let _ = fs::remove_file(&new_wal_file_path);
+                return Err(e);
+            }
+        }
+
+        // the background thread syncs the old WAL through its own handle, so is started again on the next write
+        self.background_sync = None;
+
+        try!(fs::rename(&new_wal_file_path, &wal_file_path));
+
+        if self.write_concern != WriteConcern::None {
+            try!(wal_file::sync_dir(&wal_file_path));
+        }
+
+        let mut wal_file = try!(RecordFile::<K,V,WALRecord<K,V>>::new_stamped(&wal_file_path, self.key_size, self.value_size));
+
+        wal_file.carry_bytes_written(&self.wal_file);
+        self.wal_file = wal_file;
+
+        // everything is in a synced WAL now, as far as a synced tree file goes
+        if self.write_concern != WriteConcern::None && self.synced_tree_lsn == self.compacted_lsn {
+            self.durable_lsn = self.last_lsn;
+        }
+
+        return Ok(size_before.saturating_sub(try!(self.wal_file.size())));
+    }
+
+    /// Fails if the WAL hasn't been replayed yet, for reads that can't replay it themselves
+    fn check_replayed(&self) -> Result<(), Box<Error>> {
+        if self.unreplayed {
+            return Err(From::from(BTreeError::NotReplayed));
+        }
+
+        Ok( () )
+    }
+
+    /// Imports the writes from another tree's WAL, which must be stamped with the same key & value sizes.
+    /// The WAL is only read, so it can belong to a tree that is still open. Writes that wouldn't change
+    /// anything, inserts of pairs the tree has and deletes it has already made, are skipped, and the rest
+    /// are applied as one batch. Returns the number of writes imported.
+    pub fn merge_into_wal(&mut self, external_wal_path: &str) -> Result<usize, Box<Error>> {
+        try!(self.ensure_replayed());
+
+        let mut external_wal = try!(WalReader::<K,V>::open(external_wal_path, self.key_size, self.value_size));
+        let mut records = Vec::new();
+
+        try!(external_wal.replay_committed(|record| records.push(record)));
+
+        let mut batch = WriteBatch::new();
+
+        for record in records {
+            match record {
+                WALRecord::Insert(kv) => if ! try!(self.contains_pair(&kv.key, &kv.value)) {
+                    batch.insert(kv.key, kv.value);
+                },
+                WALRecord::Delete(kv) => if ! self.deleted.contains(&kv.key, &kv.value) {
+                    batch.delete(kv.key, kv.value);
+                },
+                _ => ()
+            }
+        }
+
+        try!(self.apply(&batch));
+
+        return Ok(batch.len());
+    }
+
+    /// Returns true if the pair is in the tree, in memory or in the tree file and not deleted
+    fn contains_pair(&self, key: &K, value: &V) -> Result<bool, Box<Error>> {
+        if self.mem_tree.contains(key, value) {
+            return Ok(true);
+        }
+
+        if self.all_in_memory || self.deleted.contains(key, value) {
+            return Ok(false);
+        }
+
+        return Ok(try!(self.tree_file.get_values(key)).contains(value));
+    }
+
+    /// Inserts a key & value that are already bincode encoded, skipping the encode step.
+    /// They still get decoded for the mem_tree, which checks each is exactly one encoded
+    /// key or value. Returns the number of bytes written to the WAL.
+    pub fn raw_insert(&mut self, key_bytes: &[u8], value_bytes: &[u8]) -> Result<usize, Box<Error>> {
+        if key_bytes.len() > self.key_size || value_bytes.len() > self.value_size {
+            return Err(From::from(IOError::new(ErrorKind::InvalidData, "Key or value is larger than the key or value size")));
+        }
+
+        let key = try!(decode_exactly::<K>(key_bytes));
+        let value = try!(decode_exactly::<V>(value_bytes));
+
+        try!(self.check_write(&key));
+        try!(self.check_background_sync());
+
+        let written = try!(self.wal_file.insert_raw_record(wal_file::raw_insert_record(key_bytes, value_bytes)));
+
+        try!(self.wal_appended());
+
+        self.logical_bytes += (key_bytes.len() + value_bytes.len()) as u64;
+
+        let record = WALRecord::Insert(KeyValuePair{key: key, value: value});
+
+        self.notify_watchers(&record);
+        apply_record(&mut self.mem_tree, &mut self.deleted, record);
+
+        try!(self.compact_if_needed());
+
+        return Ok(written);
+    }
+
+    /// Returns an iterator over the records in the tree file as their key's & value's bincode
+    /// encodings, in sorted order. Only keys are decoded, to find where they end, so each value's
+    /// bytes are followed by the record's padding. It doesn't see anything written since the
+    /// last compaction, so compact first for a complete copy.
+    pub fn iter_raw(&self) -> Result<RawIter<K,V>, Box<Error>> {
+        return self.tree_file.iter_raw();
+    }
+
+    /// Looks up a key that is already bincode encoded, returning the bincode encodings of its
+    /// values in order. Values are decoded in memory and padded on disk, so their encodings
+    /// are rebuilt here rather than copied out of the files.
+    pub fn raw_get(&self, key_bytes: &[u8]) -> Result<Option<Vec<Vec<u8>>>, Box<Error>> {
+        let key = try!(decode_exactly::<K>(key_bytes));
+
+        match try!(self.get(&key)) {
+            Some(values) => {
+                let mut value_bytes = Vec::new();
+
+                for value in values {
+                    value_bytes.push(try!(encode(&value, SizeLimit::Infinite)));
+                }
+
+                return Ok(Some(value_bytes));
+            },
+            None => return Ok(None)
+        }
+    }
+
+    /// Writes a record to the WAL, syncing it if the write concern asks for it
+    fn append_to_wal(&mut self, record: &WALRecord<K,V>) -> Result<DurabilityToken, Box<Error>> {
+        try!(self.check_background_sync());
+
+        try!(self.wal_file.insert_record(record));
+
+        return self.wal_appended();
+    }
+
+    /// Assigns an LSN to the record just written to the WAL, and syncs it if the write concern asks for it
+    fn wal_appended(&mut self) -> Result<DurabilityToken, Box<Error>> {
+        if let WriteConcern::Periodic(interval) = self.write_concern {
+            if self.background_sync.is_none() {
+                let wal_file = try!(self.wal_file.try_clone_file());
+
+                self.background_sync = Some(BackgroundSync::new(wal_file, interval, self.last_lsn));
+            }
+        }
+
+        self.last_lsn += 1;
+
+        match self.write_concern {
+            WriteConcern::None => (),
+            WriteConcern::Periodic(_) => {
+                if let Some(ref background_sync) = self.background_sync {
+                    background_sync.written(self.last_lsn);
+                }
+
+                self.durable_lsn = self.durable_lsn();
+            },
+            WriteConcern::WAL | WriteConcern::Full => {
+                try!(self.wal_file.sync());
+
+                // writes merged into an unsynced tree file aren't durable, whatever the WAL says
+                if self.synced_tree_lsn == self.compacted_lsn {
+                    self.durable_lsn = self.last_lsn;
+                }
+            }
+        }
+
+        return Ok(DurabilityToken(self.last_lsn));
+    }
+
+    /// Adds an insert's encoded key & value to the bytes inserted, for write_amplification
+    fn count_logical_bytes(&mut self, record: &WALRecord<K,V>) {
+        if let WALRecord::Insert(ref kv) = *record {
+            self.logical_bytes += encoded_size(kv);
+        }
+    }
+
+    /// Returns the error from the last failed background sync, if it hasn't been returned already
+    fn check_background_sync(&self) -> Result<(), Box<Error>> {
+        match self.background_sync {
+            Some(ref background_sync) => background_sync.take_error(),
+            None => Ok( () )
+        }
+    }
+
+    /// Syncs the WAL & tree file if the write isn't already on disk
+    pub fn wait_durable(&mut self, token: DurabilityToken) -> Result<(), Box<Error>> {
+        if token.0 <= self.durable_lsn() {
+            return Ok( () );
+        }
+
+        // the background thread can do it, unless the tree file needs a sync too
+        if let Some(ref background_sync) = self.background_sync {
+            if self.synced_tree_lsn == self.compacted_lsn {
+                try!(background_sync.wait_synced(token.0));
+
+                self.durable_lsn = ::std::cmp::max(self.durable_lsn, token.0);
+
+                return Ok( () );
+            }
+        }
+
+        try!(self.check_background_sync());
+        try!(self.tree_file.sync());
+        try!(self.wal_file.sync());
+
+        self.synced_tree_lsn = self.compacted_lsn;
+        self.durable_lsn = self.last_lsn;
+
+        return Ok( () );
+    }
+
+    /// Returns the LSN up to which every write is on disk
+    pub fn durable_lsn(&self) -> u64 {
+        // writes merged into an unsynced tree file aren't durable, whatever the WAL says
+        match self.background_sync {
+            Some(ref background_sync) if self.synced_tree_lsn == self.compacted_lsn =>
+                ::std::cmp::max(self.durable_lsn, background_sync.synced_lsn()),
+            _ => self.durable_lsn
+        }
+    }
+
+
+    /// Changes what get, cursors & value scans look at
+    pub fn set_read_concern(&mut self, read_concern: ReadConcern) {
+        self.read_concern = read_concern;
+    }
+
+    /// Limits the keys that can be written to the range lower..upper, so lower is allowed but upper isn't.
+    /// Writes to other keys fail with BTreeError::OutOfBounds. The bounds aren't saved with the tree, so
+    /// give them to BTreeBuilder::key_bounds to have them set, and checked, each time the tree is opened.
+    /// Setting them here doesn't check what is already in the tree; verify_key_bounds does that.
+    pub fn set_key_bounds(&mut self, lower: Option<K>, upper: Option<K>) {
+        self.key_bounds = (lower, upper);
+    }
+
+    /// Checks that every key already in the tree is within the key bounds
+    pub fn verify_key_bounds(&self) -> Result<(), Box<Error>> {
+        try!(self.check_replayed());
+        try!(self.check_tree_file_key_bounds());
+
+        return self.check_memory_key_bounds();
+    }
+
+    /// Checks the keys in the tree file are within the key bounds. It is sorted, so only its first & last keys need checking.
+    fn check_tree_file_key_bounds(&self) -> Result<(), Box<Error>> {
+        let count = try!(self.tree_file.count());
+
+        if count > 0 && self.key_bounds != (None, None) {
+            try!(self.check_key_bounds(&try!(self.tree_file.read_key(0))));
+            try!(self.check_key_bounds(&try!(self.tree_file.read_key(count - 1))));
+        }
+
+        Ok( () )
+    }
+
+    /// Checks the keys in memory are within the key bounds
+    fn check_memory_key_bounds(&self) -> Result<(), Box<Error>> {
+        if self.key_bounds == (None, None) {
+            return Ok( () );
+        }
+
+        for key in self.mem_tree.keys().chain(self.deleted.keys()) {
+            try!(self.check_key_bounds(key));
+        }
+
+        Ok( () )
+    }
+
+    /// Panics if the tree's own bookkeeping doesn't add up, to catch bugs in the BTree itself early;
+    /// only in debug builds, as it reads the whole tree file. It checks that:
+    ///   1. no pair is both in the mem_tree and deleted
+    ///   2. the WAL holds a whole number of records, exactly as many as it was opened with and has been
+    ///      written since. Its size can't be worked out from the pairs in memory, as the WAL also holds
+    ///      deletes, batch markers & inserts of pairs already inserted.
+    ///   3. the tree file holds a whole number of records, in order with no pair twice. The tree file
+    ///      is a sorted run of records rather than nodes, so it has no node or header size to check.
+    #[cfg(debug_assertions)]
+    pub fn assert_invariants(&self) {
+        for key in self.mem_tree.keys() {
+            for value in self.mem_tree.get(key).unwrap() {
+                assert!(! self.deleted.contains(key, value), "a pair is both in the mem_tree and deleted");
+            }
+        }
+
+        let wal_records = self.wal_file.count().expect("the WAL isn't a whole number of records");
+
+        assert_eq!(wal_records, self.wal_file.expected_count(), "the WAL holds {} records, but {} were written to it", wal_records, self.wal_file.expected_count());
+
+        let tree_records = self.tree_file.count().expect("the tree file isn't a whole number of records");
+        let mut last: Option<KeyValuePair<K,V>> = None;
+
+        for index in 0..tree_records {
+            let kv = self.tree_file.read_record(index).expect("a tree file record doesn't decode");
+
+            if let Some(ref last) = last {
+                assert!(last < &kv, "tree file record {} is out of order", index);
+            }
+
+            last = Some(kv);
+        }
+    }
+
+    /// Checks a key that is about to be written: that it is in bounds, and, if the checks are on, that its Ord behaves
+    fn check_write(&self, key: &K) -> Result<(), Box<Error>> {
+        try!(self.check_key_bounds(key));
+
+        if self.comparator_checks {
+            try!(self.check_comparator(key));
+        }
+
+        Ok( () )
+    }
+
+    /// Spot checks Ord on the key: it must equal a copy of itself, and agree with the next key in memory
+    /// about which of them comes first. A key that breaks this would leave the tree file out of order,
+    /// where binary searches can't find things, so it is turned away before it reaches the WAL.
+    fn check_comparator(&self, key: &K) -> Result<(), Box<Error>> {
+        let copy = key.clone();
+        let mut consistent = key.cmp(&copy) == Ordering::Equal && *key == copy;
+
+        if let Some((next, _)) = self.mem_tree.seek(key) {
+            let order = key.cmp(next);
+
+            consistent &= order != Ordering::Greater && next.cmp(key) == order.reverse() && (order != Ordering::Equal || key == next);
+        }
+
+        if ! consistent {
+            return Err(From::from(BTreeError::ComparatorInconsistency));
+        }
+
+        Ok( () )
+    }
+
+    /// Errors if the key is outside the key bounds
+    fn check_key_bounds(&self, key: &K) -> Result<(), Box<Error>> {
+        let below = self.key_bounds.0.as_ref().map_or(false, |lower| key < lower);
+        let above = self.key_bounds.1.as_ref().map_or(false, |upper| key >= upper);
+
+        if below || above {
+            return Err(From::from(BTreeError::OutOfBounds));
+        }
+
+        Ok( () )
+    }
+
+    /// Checks if reads need to look at the tree file, rather than just the mem_tree
+    fn reads_disk(&self) -> bool {
+        match self.read_concern {
+            ReadConcern::MemOnly => false,
+            ReadConcern::DiskOnly => true,
+            ReadConcern::Merged => ! self.all_in_memory
+        }
+    }
+
+    /// Loads the whole tree file into memory if it is under the small tree threshold
+    fn load_if_small(&mut self) -> Result<(), Box<Error>> {
+        let threshold = match self.small_tree_threshold {
+            Some(threshold) => threshold,
+            None => return Ok( () )
+        };
+
+        if self.all_in_memory || try!(self.tree_file.count()) > threshold {
+            return Ok( () );
+        }
+
+        // the whole file is read before any of it is loaded, so a record that can't be read leaves memory as it was
+        let records = try!(try!(self.tree_file.iter()).collect::<Result<Vec<_>, _>>());
+
+        for kv in records {
+            if ! self.deleted.contains(&kv.key, &kv.value) {
+                self.mem_tree.insert(kv.key, kv.value);
+            }
+        }
+
+        self.all_in_memory = true;
+
+        return Ok( () );
+    }
+
+    /// Returns the set of values for the key, or None if it has none
+    pub fn get(&self, key: &K) -> Result<Option<BTreeSet<V>>, Box<Error>> {
+        try!(self.check_replayed());
+
+        let mut values = BTreeSet::new();
+
+        if self.read_concern != ReadConcern::DiskOnly {
+            if let Some(mem_values) = self.mem_tree.get(key) {
+                values.extend(mem_values.cloned());
+            }
+        }
+
+        if self.reads_disk() {
+            let disk_values = try!(self.tree_file.get_values(key));
+
+            values.extend(disk_values.into_iter().filter(|value| ! self.deleted.contains(key, value)));
+        }
+
+        return Ok(if values.is_empty() { None } else { Some(values) });
+    }
+
+    /// Returns true if the key has a value, as get would. When none of the key's values on disk
+    /// have been deleted, the tree file is searched decoding only keys.
+    pub fn contains_key(&self, key: &K) -> Result<bool, Box<Error>> {
+        try!(self.check_replayed());
+
+        if self.read_concern != ReadConcern::DiskOnly && self.mem_tree.contains_key(key) {
+            return Ok(true);
+        }
+
+        if ! self.reads_disk() {
+            return Ok(false);
+        }
+
+        if ! self.deleted.contains_key(key) {
+            return self.tree_file.contains_key(key);
+        }
+
+        return Ok(try!(self.tree_file.get_values(key)).iter().any(|value| ! self.deleted.contains(key, value)));
+    }
+
+    /// Returns the offsets in the tree file of the records a get of the key reads, in the order it reads
+    /// them, to see the I/O a lookup does. The tree file is one sorted run of records rather than nodes,
+    /// so these are the binary search's probes and then the key's own records. Empty when get doesn't
+    /// read the tree file, as when the whole tree is in memory.
+    pub fn node_path_for_key(&self, key: &K) -> Result<Vec<u64>, Box<Error>> {
+        try!(self.check_replayed());
+
+        if ! self.reads_disk() {
+            return Ok(Vec::new());
+        }
+
+        return self.tree_file.lookup_path(key);
+    }
+
+    /// Calls f with the key's values, in order, and returns what it returns, or None if the key has no values.
+    /// Values in memory are passed by reference rather than copied out like get does.
+    pub fn with_values<R, F: FnOnce(ValueRefs<V>) -> R>(&self, key: &K, f: F) -> Result<Option<R>, Box<Error>> {
+        try!(self.check_replayed());
+
+        let mem_values = if self.read_concern != ReadConcern::DiskOnly { self.mem_tree.get(key) } else { None };
+        let mut disk_values = Vec::new();
+
+        if self.reads_disk() {
+            let skip = |value: &V| self.deleted.contains(key, value) || (mem_values.is_some() && self.mem_tree.contains(key, value));
+
+            disk_values.extend(try!(self.tree_file.get_values(key)).into_iter().filter(|value| ! skip(value)));
+        }
+
+        if mem_values.is_none() && disk_values.is_empty() {
+            return Ok(None);
+        }
+
+        return Ok(Some(f(ValueRefs::new(mem_values, &disk_values))));
+    }
+
+    /// Builds a Bloom filter over the keys that have values, serialized with KeyFilter::to_bytes.
+    /// KeyFilter::from_bytes reads it back, to check keys against without opening the tree.
+    /// The filter is built from scratch on every call.
+    pub fn export_key_filter(&self, bits_per_key: u8) -> Result<Vec<u8>, Box<Error>> {
+        let keys = try!(self.live_keys());
+        let mut filter = KeyFilter::new(keys.len(), bits_per_key);
+
+        for key in keys {
+            // a key can take up the whole of a record, when its value is small enough
+            filter.insert(&try!(encode_key_only(&key, self.key_size + self.value_size)));
+        }
+
+        return Ok(filter.to_bytes());
+    }
+
+    /// Writes the tree out as a LevelDB table, uncompressed, for LevelDB & RocksDB tools to read.
+    /// There is one entry per key: the key's bincode encoding, mapped to the bincode encoding of
+    /// a Vec of its values. Tables are sorted bytewise, which isn't always the key order (strings
+    /// are length prefixed), so the entries are sorted again in memory. Returns the bytes written.
+    pub fn export_to_leveldb_sst<W: Write>(&self, writer: W) -> Result<u64, Box<Error>> {
+        let mut entries = Vec::new();
+
+        for (key, values) in try!(self.to_sorted_vec()) {
+            let values: Vec<V> = values.into_iter().collect();
+
+            entries.push((try!(encode(&key, SizeLimit::Infinite)), try!(encode(&values, SizeLimit::Infinite))));
+        }
+
+        entries.sort();
+
+        let mut table = TableWriter::new(writer);
+
+        for (key_bytes, value_bytes) in entries {
+            try!(table.add(&key_bytes, &value_bytes));
+        }
+
+        return table.finish();
+    }
+
+    /// Returns every key with its values, in order. Each record is read once, so it costs about
+    /// as much as a walk with a cursor, but everything is held in memory at once.
+    pub fn to_sorted_vec(&self) -> Result<Vec<(K, BTreeSet<V>)>, Box<Error>> {
+        try!(self.check_replayed());
+
+        let mut entries: BTreeMap<K, BTreeSet<V>> = BTreeMap::new();
+
+        if self.read_concern != ReadConcern::DiskOnly {
+            let mut pair = self.mem_tree.successor(None);
+
+            while let Some((key, value)) = pair {
+                entries.entry(key.clone()).or_insert_with(BTreeSet::new).insert(value.clone());
+                pair = self.mem_tree.successor(Some((key, value)));
+            }
+        }
+
+        if self.reads_disk() {
+            for index in 0..try!(self.tree_file.count()) {
+                let kv = try!(self.tree_file.read_record(index));
+
+                if ! self.deleted.contains(&kv.key, &kv.value) {
+                    entries.entry(kv.key).or_insert_with(BTreeSet::new).insert(kv.value);
+                }
+            }
+        }
+
+        return Ok(entries.into_iter().collect());
+    }
+
+    /// Returns an unpositioned cursor over all the pairs in the tree
+    pub fn cursor(&mut self) -> Cursor<K,V> {
+        return Cursor::new(self);
+    }
+
+    /// Returns a scan over the pairs whose key falls in the range that is read
+    /// up to chunk pairs at a time with RangeChunks::next_chunk. The scan doesn't
+    /// borrow the tree, so writes can go in between chunks.
+    pub fn range_chunked<R: RangeBounds<K>>(&self, range: R, chunk: usize) -> RangeChunks<K,V> {
+        return RangeChunks::new(range.start_bound().cloned(), range.end_bound().cloned(), chunk);
+    }
+
+    /// Warms the page cache for the part of the tree file holding the range, so reads from it
+    /// don't wait on the disk. Only the binary searches for the ends of the range are read here;
+    /// the rest is left to the OS to read in the background. This is a hint, so it never fails,
+    /// and where the OS takes no such hints (anywhere but 64-bit Linux) it does nothing.
+    pub fn prefetch_range<R: RangeBounds<K>>(&self, range: R) {
+        if ! self.reads_disk() {
+            return;
+        }
+
+        let start = match range.start_bound() {
+            Bound::Included(key) => self.tree_file.partition_point(|k| k < key),
+            Bound::Excluded(key) => self.tree_file.partition_point(|k| k <= key),
+            Bound::Unbounded => Ok(0)
+        };
+
+        let end = match range.end_bound() {
+            Bound::Included(key) => self.tree_file.partition_point(|k| k <= key),
+            Bound::Excluded(key) => self.tree_file.partition_point(|k| k < key),
+            Bound::Unbounded => self.tree_file.count()
+        };
+
+        if let (Ok(start), Ok(end)) = (start, end) {
+            self.tree_file.will_need(start, end);
+        }
+    }
+
+    /// Estimates the number of pairs with a key in the range, without reading the tree file. The mem_tree is
+    /// counted exactly, and the tree file's share is placed between the samples of its records taken
+    /// when it was opened or last compacted, see RECORD_SAMPLES. Deletes since then aren't taken off.
+    pub fn range_count_approx<R: RangeBounds<K>>(&self, range: R) -> usize {
+        let mut count = 0;
+
+        if self.read_concern != ReadConcern::DiskOnly {
+            count += self.mem_tree.count_range((range.start_bound(), range.end_bound()));
+        }
+
+        if self.reads_disk() {
+            let start = match range.start_bound() {
+                Bound::Included(key) => self.sample_position(|sample| sample < key),
+                Bound::Excluded(key) => self.sample_position(|sample| sample <= key),
+                Bound::Unbounded => 0
+            };
+
+            let end = match range.end_bound() {
+                Bound::Included(key) => self.sample_position(|sample| sample <= key),
+                Bound::Excluded(key) => self.sample_position(|sample| sample < key),
+                Bound::Unbounded => self.tree_file.count().unwrap_or(0)
+            };
+
+            count += end.saturating_sub(start) as usize;
+        }
+
+        return count;
+    }
+
+    /// Estimates the partition point of the tree file's records from the samples. If the first j
+    /// samples are before it, it lies after the record of sample j - 1, and at or before sample j's.
+    fn sample_position<F: Fn(&K) -> bool>(&self, is_before: F) -> u64 {
+        let count = self.tree_file.count().unwrap_or(0);
+        let samples = self.record_sample.len() as u64;
+        let before = self.record_sample.iter().take_while(|sample| is_before(sample)).count() as u64;
+
+        if before == 0 {
+            return 0;
+        }
+
+        let (low, high) = ((before - 1) * count / samples, before * count / samples);
+
+        return (low + high + 1) / 2;
+    }
+
+    /// Reads the keys of evenly spaced records from the tree file, for range_count_approx.
+    /// It is only for estimates, so a record that doesn't read just leaves the tree unsampled.
+    fn sample_records(&mut self) {
+        let count = self.tree_file.count().unwrap_or(0);
+        let samples = ::std::cmp::min(count, RECORD_SAMPLES);
+
+        self.record_sample.clear();
+
+        for i in 0..samples {
+            match self.tree_file.read_key(i * count / samples) {
+                Ok(key) => self.record_sample.push(key),
+                Err(_) => {
+                    self.record_sample.clear();
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Checks if any key maps to the value. There is no index on values,
+    /// so this is an O(N) scan of memory and then the tree file.
+    pub fn contains_value(&self, value: &V) -> Result<bool, Box<Error>> {
+        try!(self.check_replayed());
+
+        if self.read_concern != ReadConcern::DiskOnly && self.mem_tree.contains_value(value) {
+            return Ok(true);
+        }
+
+        if ! self.reads_disk() {
+            return Ok(false);
+        }
+
+        return self.tree_file.contains_value(value, |kv| self.deleted.contains(&kv.key, &kv.value));
+    }
+
+    /// Returns all the keys that map to the value, in order. Like contains_value
+    /// this is an O(N) scan, as there is no inverse index.
+    pub fn value_to_keys(&self, value: &V) -> Result<Vec<K>, Box<Error>> {
+        try!(self.check_replayed());
+
+        let mem_keys = if self.read_concern != ReadConcern::DiskOnly { self.mem_tree.value_to_keys(value) } else { Vec::new() };
+        let disk_keys = if self.reads_disk() { try!(self.tree_file.value_to_keys(value, |kv| self.deleted.contains(&kv.key, &kv.value))) } else { Vec::new() };
+
+        return Ok(merge(mem_keys, disk_keys).dedup().collect());
+    }
+
+    /// Computes a SHA-256 over every (key,value) pair in sorted order, across memory and disk.
+    /// Trees with the same logical content hash the same regardless of how they got there.
+    pub fn content_hash(&mut self) -> Result<[u8; 32], Box<Error>> {
+        return self.content_hash_range(..);
+    }
+
+    /// Same as content_hash, but only over the pairs whose key falls in the range
+    pub fn content_hash_range<R: RangeBounds<K>>(&mut self, range: R) -> Result<[u8; 32], Box<Error>> {
+        try!(self.ensure_replayed());
+
+        let mut hasher = Sha256::new();
+
+        // only the records in the range are read, found by binary search
+        let disk_start = match range.start_bound() {
+            Bound::Included(key) => try!(self.tree_file.partition_point(|k| k < key)),
+            Bound::Excluded(key) => try!(self.tree_file.partition_point(|k| k <= key)),
+            Bound::Unbounded => 0
+        };
+
+        let disk_end = match range.end_bound() {
+            Bound::Included(key) => try!(self.tree_file.partition_point(|k| k <= key)),
+            Bound::Excluded(key) => try!(self.tree_file.partition_point(|k| k < key)),
+            Bound::Unbounded => try!(self.tree_file.count())
+        };
+
+        let deleted = &self.deleted;
+        let disk_error = Cell::new(None);
+        let mem_iter = self.mem_tree.range_pairs((range.start_bound(), range.end_bound()));
+        let disk_iter = until_error(self.tree_file.iter_range(disk_start, disk_end), &disk_error).filter(|kv| ! deleted.contains(&kv.key, &kv.value));
+
+        // a pair can be both in memory and on disk, so dedup the merged stream
+        for kv in merge(mem_iter, disk_iter).dedup() {
+            // encode without padding, and length-prefix it so pairs can't run together
+            let buff = try!(encode(&kv, SizeLimit::Infinite));
+
+            hasher.update(&(buff.len() as u64).to_le_bytes());
+            hasher.update(&buff);
+        }
+
+        if let Some(e) = disk_error.take() {
+            return Err(e);
+        }
+
+        let mut hash = [0; 32];
+        hash.copy_from_slice(&hasher.finalize());
+
+        return Ok(hash);
+    }
+
+    /// Merges the pairs of another BTree into this one. Keys only in other are copied over,
+    /// and for keys in both policy is given the two value sets and returns the set to keep.
+    /// Passing a policy that unions the two gives a plain union of the trees.
+    /// Returns the number of keys whose values changed.
+    pub fn merge_with_policy<F>(&mut self, other: &BTree<K,V>, policy: F) -> Result<usize, Box<Error>>
+        where F: Fn(&K, &BTreeSet<V>, &BTreeSet<V>) -> BTreeSet<V> {
+        try!(self.ensure_replayed());
+
+        let mut changed = 0;
+
+        for key in try!(other.keys()) {
+            let other_values = match try!(other.get(&key)) {
+                Some(values) => values,
+                None => continue  // all its values were deleted
+            };
+
+            let self_values = try!(self.get(&key)).unwrap_or_else(BTreeSet::new);
+
+            let merged = if self_values.is_empty() {
+                other_values
+            } else {
+                policy(&key, &self_values, &other_values)
+            };
+
+            if merged == self_values {
+                continue;
+            }
+
+            for value in self_values.difference(&merged) {
+                try!(self.delete(key.clone(), value.clone()));
+            }
+
+            for value in merged.difference(&self_values) {
+                try!(self.insert(key.clone(), value.clone()));
+            }
+
+            changed += 1;
+        }
+
+        return Ok(changed);
+    }
+
+    /// Returns the keys that have values, in order
+    fn live_keys(&self) -> Result<Vec<K>, Box<Error>> {
+        let mut live_keys = Vec::new();
+
+        for key in try!(self.keys()) {
+            if try!(self.get(&key)).is_some() {
+                live_keys.push(key);
+            }
+        }
+
+        return Ok(live_keys);
+    }
+
+    /// Returns every key in memory or on disk, including ones whose values have all been deleted
+    fn keys(&self) -> Result<BTreeSet<K>, Box<Error>> {
+        try!(self.check_replayed());
+
+        let mut keys = BTreeSet::new();
+        let mut pair = self.mem_tree.successor(None);
+
+        while let Some((key, value)) = pair {
+            keys.insert(key.clone());
+            pair = self.mem_tree.successor(Some((key, value)));
+        }
+
+        for index in 0..try!(self.tree_file.count()) {
+            keys.insert(try!(self.tree_file.read_record(index)).key);
+        }
+
+        return Ok(keys);
+    }
+
+    /// Changes the key & value sizes by rewriting the tree file with records of the new size.
+    /// The new file is swapped in once it has been fully written, so a failure
+    /// (say, a record that won't fit) leaves the tree as it was.
+    pub fn resize(&mut self, new_key_size: usize, new_value_size: usize) -> Result<(), Box<Error>> {
+        try!(self.rewrite(new_key_size, new_value_size, |_, value| value));
+
+        Ok( () )
+    }
+
+    /// Sets what decodes values in the tree file that fail to decode as V, usually because they were
+    /// written by an older version of V. It's given the value's bytes, followed by any padding.
+    /// Compacting re-encodes every value as V, after which the fallback is no longer needed.
+    pub fn on_decode_error(&mut self, value_fallback: ValueFallback<V>) {
+        self.value_fallback = Some(value_fallback);
+        self.tree_file.set_value_fallback(self.value_fallback);
+    }
+
+    /// Adds a hook that is called before & after every compaction, in the order hooks were added,
+    /// say to flush caches or record metrics. A compaction that fails gets no AfterCompaction.
+    /// Hooks must be Sync, like compaction policies, so the BTree can still be shared between threads.
+    pub fn register_compaction_hook<F: Fn(CompactionEvent) + Send + Sync + 'static>(&mut self, hook: F) {
+        self.compaction_hooks.push(Box::new(hook));
+    }
+
+    fn run_compaction_hooks(&self, event: CompactionEvent) {
+        for hook in &self.compaction_hooks {
+            hook(event.clone());
+        }
+    }
+
+    /// Returns a channel that gets every insert & delete of a pair whose key is in the range,
+    /// as it is made through this BTree. Writes replayed from the WAL aren't sent, nor are a
+    /// prepared transaction's until it commits. The watch ends once the Receiver is dropped.
+    pub fn watch_range<R: RangeBounds<K>>(&mut self, range: R) -> Receiver<WriteEvent<K,V>> {
+        let (sender, receiver) = channel();
+
+        self.watchers.push((range.start_bound().cloned(), range.end_bound().cloned(), sender));
+
+        return receiver;
+    }
+
+    /// Sends a write to the watchers of its key, dropping any whose Receiver is gone
+    fn notify_watchers(&mut self, record: &WALRecord<K,V>) {
+        if self.watchers.is_empty() {
+            return;
+        }
+
+        let (key, value, is_insert) = match *record {
+            WALRecord::Insert(ref kv) => (&kv.key, &kv.value, true),
+            WALRecord::Delete(ref kv) => (&kv.key, &kv.value, false),
+            _ => return
+        };
+
+        self.watchers.retain(|&(ref start, ref end, ref sender)| {
+            if ! (start.as_ref(), end.as_ref()).contains(&key) {
+                return true;
+            }
+
+            let event = if is_insert {
+                WriteEvent::Insert{key: key.clone(), value: value.clone()}
+            } else {
+                WriteEvent::Delete{key: key.clone(), value: value.clone()}
+            };
+
+            sender.send(event).is_ok()
+        });
+    }
+
+    /// Replaces every value with f(key, value), by compacting with f applied along the way
+    pub fn rewrite_values<F: FnMut(K, V) -> V>(&mut self, f: F) -> Result<CompactionStats, Box<Error>> {
+        let (key_size, value_size) = (self.key_size, self.value_size);
+
+        return self.rewrite(key_size, value_size, f);
+    }
+
+    /// Writes f(key, value) for every pair into a new tree opened with dest, to move to a new schema
+    /// or export a subset; pairs f returns None for are left out. The pairs stream straight into the
+    /// new tree file, one key's values at a time, so f has to keep the keys in order: a key lower than
+    /// the one before fails with KeysOutOfOrder. With external_sort of Some(memory_budget), the pairs are
+    /// instead sorted in runs of about memory_budget bytes of records on disk beside the new tree, then
+    /// merged, as bulk_load_unsorted does. dest mustn't have a tree file or WAL yet.
+    pub fn transform_into<K2, V2, F>(&mut self, dest: BTreeBuilder, mut f: F, external_sort: Option<usize>) -> Result<BTree<K2,V2>, Box<Error>>
+        where K2: KeyType, V2: ValueType, F: FnMut(K, V) -> Option<(K2, V2)> {
+        try!(self.ensure_replayed());
+
+        let (key_size, value_size, checksum) = (dest.key_size, dest.value_size, dest.checksum);
+        let fan_in = ::std::cmp::max(dest.merge_fan_in, 2);
+        let disk_records = if self.all_in_memory { 0 } else { try!(self.tree_file.count()) };
+        let (tree, _) = try!(dest.open_after_writing(|new_tree_file_path| {
+            let deleted = &self.deleted;
+            let disk_error = Cell::new(None);
+            let pairs = merge(self.mem_tree.into_iter(), until_error(self.tree_file.iter_range(0, disk_records), &disk_error))
+                .dedup()
+                .filter(|kv| ! deleted.contains(&kv.key, &kv.value))
+                .filter_map(|kv| f(kv.key, kv.value));
+
+            let records = match external_sort {
+                Some(memory_budget) => sort_into_tree_file(new_tree_file_path, key_size, value_size, checksum, pairs, sort_run_pairs(memory_budget, key_size, value_size), fan_in).map(|stats| stats.records),
+                None => write_tree_file(new_tree_file_path, key_size, value_size, checksum, pairs)
+            };
+
+            match disk_error.take() {
+                Some(e) => Err(e),
+                None => records
+            }
+        }));
+
+        return Ok(tree);
+    }
+
+    /// Replaces the values of every key with f(key, values), without compacting.
+    /// The changes go into the WAL as one batch of deletes & inserts, and reach the tree file
+    /// at the next compaction. Returns the number of keys whose values changed.
+    pub fn remap_values<F: Fn(K, BTreeSet<V>) -> BTreeSet<V>>(&mut self, f: F) -> Result<usize, Box<Error>> {
+        try!(self.ensure_replayed());
+
+        let mut batch = WriteBatch::new();
+        let mut changed = 0;
+
+        for key in try!(self.live_keys()) {
+            let old_values = try!(self.get(&key)).unwrap_or_default();
+            let new_values = f(key.clone(), old_values.clone());
+
+            if new_values == old_values {
+                continue;
+            }
+
+            for value in old_values.difference(&new_values) {
+                batch.delete(key.clone(), value.clone());
+            }
+
+            for value in new_values.difference(&old_values) {
+                batch.insert(key.clone(), value.clone());
+            }
+
+            changed += 1;
+        }
+
+        try!(self.apply(&batch));
+
+        return Ok(changed);
+    }
+
+    /// Merges the records on disk with the records in memory
+    pub fn compact(&mut self) -> Result<CompactionStats, Box<Error>>{
+        let (key_size, value_size) = (self.key_size, self.value_size);
+
+        return self.rewrite(key_size, value_size, |_, value| value);
+    }
+
+    /// Compacts if the compaction policy says to, returning the stats if it did
+    pub fn compact_if_needed(&mut self) -> Result<Option<CompactionStats>, Box<Error>> {
+        // when the whole tree is in memory, only what was written since the last compaction counts
+        let mem_entries = if self.all_in_memory {
+            try!(self.wal_file.count()) as usize
+        } else {
+            self.mem_tree.size() + self.deleted.size()
+        };
+
+        let needed = match self.compaction_policy {
+            Some(ref policy) => policy.should_compact(try!(self.wal_file.size()), try!(self.tree_file.size()), mem_entries),
+            None => mem_entries > MAX_MEMORY_ITEMS
+        };
+
+        if needed {
+            return Ok(Some(try!(self.compact())));
+        }
+
+        return Ok(None);
+    }
+
+    /// Reports how many of the WAL's records are writes to keys it has already written to
+    pub fn estimate_bloat(&self) -> Result<BloatReport, Box<Error>> {
+        let wal_entries = try!(self.wal_file.count());
+        let mut keys = BTreeSet::new();
+
+        for index in 0..wal_entries {
+            match try!(self.wal_file.read_record(index)) {
+                WALRecord::Insert(kv) | WALRecord::Delete(kv) => { keys.insert(kv.key); },
+                _ => ()
+            }
+        }
+
+        let wal_duplicate_ratio = if keys.is_empty() { 0.0 } else { wal_entries as f64 / keys.len() as f64 - 1.0 };
+
+        return Ok(BloatReport{wal_entries: wal_entries,
+                              unique_keys_in_wal: keys.len(),
+                              wal_duplicate_ratio: wal_duplicate_ratio,
+                              wal_size_bytes: try!(self.wal_file.size())});
+    }
+
+    /// Reads back every record in the WAL, checking each against its checksum, and reports those that
+    /// are corrupt. A corrupt record other than the last fails the replay, so the tree won't open again
+    /// until the WAL is mended; a corrupt last record is cut off by the replay.
+    pub fn scrub_wal(&self) -> Result<WalScrub, Box<Error>> {
+        let mut reader = try!(WalReader::<K,V>::open(&(self.tree_file_path.to_owned() + ".wal"), self.key_size, self.value_size));
+        let mut records = 0;
+        let mut corrupt = Vec::new();
+
+        for record in &mut reader {
+            if let Err(BTreeError::CorruptWalRecord{index}) = record {
+                corrupt.push(index);
+            }
+
+            records += 1;
+        }
+
+        return Ok(WalScrub{checksum: reader.checksum(), records: records, corrupt: corrupt, torn_tail: reader.torn_tail()});
+    }
+
+    /// Reads back every record in the tree file, checking each against its checksum, and reports those
+    /// that are corrupt. In a tree file without checksums, only records that no longer decode are caught.
+    pub fn scrub_tree_file(&self) -> Result<TreeScrub, Box<Error>> {
+        let records = try!(self.tree_file.count());
+        let mut corrupt = Vec::new();
+
+        for index in 0..records {
+            if let Err(e) = self.tree_file.read_record(index) {
+                match e.downcast_ref::<BTreeError>() {
+                    Some(&BTreeError::CorruptTreeRecord{..}) => corrupt.push(index),
+                    _ => return Err(e)
+                }
+            }
+        }
+
+        return Ok(TreeScrub{checksum: self.tree_file.checksum(), records: records, corrupt: corrupt});
+    }
+
+    /// Reads the whole WAL, returning each record and where it is, and what writes to the same keys
+    /// it holds. The records behind wasted_bytes could be dropped from the WAL without changing
+    /// what it replays to, though what a compaction would write is no different either way.
+    pub fn wal_audit(&self) -> Result<WalAudit<K,V>, Box<Error>> {
+        let record_size = self.wal_file.record_size() as u64;
+        let wal_entries = try!(self.wal_file.count());
+        let mut records = Vec::with_capacity(wal_entries as usize);
+        let mut records_per_key = BTreeMap::new();
+        let mut standing = BTreeSet::new();  // pairs whose last write was an insert
+        let mut superseded = 0;
+
+        for index in 0..wal_entries {
+            let record = try!(self.wal_file.read_record(index));
+
+            match record {
+                WALRecord::Insert(ref kv) | WALRecord::Delete(ref kv) => {
+                    *records_per_key.entry(kv.key.clone()).or_insert(0) += 1;
+
+                    let pair = (kv.key.clone(), kv.value.clone());
+
+                    if standing.remove(&pair) {
+                        superseded += 1;
+                    }
+
+                    if let WALRecord::Insert(_) = record {
+                        standing.insert(pair);
+                    }
+                },
+                _ => ()
+            }
+
+            records.push((self.wal_file.record_offset(index), record));
+        }
+
+        let duplicate_keys = records_per_key.values().filter(|&&count| count > 1).count();
+
+        return Ok(WalAudit{records: records,
+                           records_per_key: records_per_key,
+                           duplicate_keys: duplicate_keys,
+                           total_records: wal_entries as usize,
+                           wasted_bytes: superseded * record_size});
+    }
+
+    /// Counts the deletes in the WAL. Only the WAL is read, so it's cheap next to a compaction,
+    /// which is what clears them out of the WAL & drops the pairs they delete from the tree file.
+    pub fn tombstone_count(&self) -> Result<usize, Box<Error>> {
+        let mut tombstones = 0;
+
+        for index in 0..try!(self.wal_file.count()) {
+            if let WALRecord::Delete(_) = try!(self.wal_file.read_record(index)) {
+                tombstones += 1;
+            }
+        }
+
+        return Ok(tombstones);
+    }
+
+    /// Splits the keys, in order, into buckets runs of (nearly) the same number of keys.
+    /// Each bucket is (first key, last key, number of keys), so narrow ranges are where keys are crowded.
+    /// There are fewer buckets if there are fewer keys. See numeric_key_histogram for evenly spaced ranges.
+    pub fn key_histogram(&self, buckets: usize) -> Result<Vec<(K, K, usize)>, Box<Error>> {
+        let keys = try!(self.live_keys());
+        let mut histogram = Vec::new();
+
+        if buckets == 0 {
+            return Ok(histogram);
+        }
+
+        for bucket in 0..buckets {
+            let (start, end) = (bucket * keys.len() / buckets, (bucket + 1) * keys.len() / buckets);
+
+            if start < end {
+                histogram.push((keys[start].clone(), keys[end - 1].clone(), end - start));
+            }
+        }
+
+        return Ok(histogram);
+    }
+
+    /// Reports how many bytes went to disk per byte inserted, since the tree was opened or
+    /// reset_io_counters was last called. Inserted bytes are the encoded keys & values, before padding.
+    pub fn write_amplification(&self) -> WriteAmpReport {
+        let wal_bytes = self.wal_file.bytes_written() - self.wal_bytes_at_reset;
+        let ratio = |bytes: u64| if self.logical_bytes == 0 { 0.0 } else { bytes as f64 / self.logical_bytes as f64 };
+
+        return WriteAmpReport{logical_bytes: self.logical_bytes,
+                              wal_bytes: wal_bytes,
+                              tree_bytes: self.tree_bytes_written,
+                              wal_amplification: ratio(wal_bytes),
+                              tree_amplification: ratio(self.tree_bytes_written),
+                              total_amplification: ratio(wal_bytes + self.tree_bytes_written)};
+    }
+
+    /// Zeroes the counters behind write_amplification
+    pub fn reset_io_counters(&mut self) {
+        self.logical_bytes = 0;
+        self.tree_bytes_written = 0;
+        self.wal_bytes_at_reset = self.wal_file.bytes_written();
+    }
+
+    /// Returns how much disk space the tree file & WAL take up
+    pub fn disk_usage(&self) -> Result<DiskUsage, Box<Error>> {
+        let mut usage = DiskUsage{logical_bytes: 0, allocated_bytes: 0};
+
+        for path in &[self.tree_file_path.to_owned(), self.tree_file_path.to_owned() + ".wal"] {
+            let metadata = try!(fs::metadata(path));
+
+            usage.logical_bytes += metadata.len();
+            usage.allocated_bytes += allocated_bytes(&metadata);
+        }
+
+        return Ok(usage);
+    }
+
+    /// Returns the stats from the most recent compaction (or resize), if there has been one
+    pub fn last_compaction(&self) -> Option<&CompactionStats> {
+        self.last_compaction.as_ref()
+    }
+
+    /// Writes the merged records into a new tree file, swaps it in, then empties the WAL & mem_tree.
+    /// Every value is passed through map on the way.
+    fn rewrite<F: FnMut(K, V) -> V>(&mut self, key_size: usize, value_size: usize, map: F) -> Result<CompactionStats, Box<Error>> {
+        let (new_tree_file_path, bytes_before, start) = try!(self.begin_rewrite(key_size, value_size));
+
+        // scoped so the new file is closed before we rename it
+        let merged = {
+            // create a new on-disk BTree
+            let mut new_tree_file = try!(OnDiskBTree::<K,V>::with_checksum(new_tree_file_path.to_owned(), key_size, value_size, self.checksum));
+
+            // with the whole tree in memory there's no need to read the tree file at all
+            let disk_records = if self.all_in_memory { 0 } else { try!(self.tree_file.count()) };
+            let max_records = self.max_tree_records(key_size, value_size);
+            let merged = merge_records(&mut new_tree_file,
+                                       self.mem_tree.into_iter(),
+                                       self.tree_file.iter_range(0, disk_records),
+                                       &self.deleted,
+                                       map,
+                                       self.comparator_checks,
+                                       max_records);
+
+            if let Ok(ref merged) = merged {
+                if self.write_concern == WriteConcern::Full && merged.over_limit.is_none() && ! merged.out_of_order {
+                    try!(new_tree_file.sync());
+                }
+            }
+
+            merged
+        };
+
+        // a failed write, say on a full disk, leaves a partial file that would only take up more space
+        let merged = match merged {
+            Ok(merged) => merged,
+            Err(e) => {
+                try!(fs::remove_file(&new_tree_file_path));
+                return Err(e);
+            }
+        };
+
+        return self.finish_rewrite(&new_tree_file_path, key_size, value_size, merged, bytes_before, start);
+    }
+
+    /// Gets ready to write a new tree file: clears out any left from before, and checks there's room for it.
+    /// Returns the new file's path, the bytes the tree takes up now, and when the compaction started.
+    fn begin_rewrite(&mut self, key_size: usize, value_size: usize) -> Result<(String, u64, Instant), Box<Error>> {
+        try!(self.ensure_replayed());
+
+        // prepared transactions are carried over to the new WAL, so have to still fit in it
+        if (key_size, value_size) != (self.key_size, self.value_size) && ! self.prepared.is_empty() {
+            return Err(From::from(IOError::new(ErrorKind::InvalidInput, "Can't resize while there are prepared transactions")));
+        }
+
+        let bytes_before = try!(self.tree_file.size()) + try!(self.wal_file.size());
+
+        self.run_compaction_hooks(CompactionEvent::BeforeCompaction{wal_bytes: try!(self.wal_file.size())});
+
+        let start = Instant::now();
+        let new_tree_file_path = self.tree_file_path.to_owned() + ".new";
+
+        // clear out anything left over from a failed attempt
+        if let Err(e) = fs::remove_file(&new_tree_file_path) {
+            if e.kind() != ErrorKind::NotFound {
+                return Err(From::from(e));
+            }
+        }
+
+        // the new tree file is written in full before the old one is removed, so it needs its own space.
+        // Every pair is counted, as if none were deleted or in both memory & the tree file, to stay on the safe side
+        let disk_records = if self.all_in_memory { 0 } else { try!(self.tree_file.count()) };
+        let record_size = (key_size + value_size + self.checksum.size()) as u64;
+        let needed = wal_file::tree_stamp_size(self.checksum) + (disk_records + self.mem_tree.size() as u64) * record_size;
+
+        // the WAL is in the same directory, so on the same filesystem
+        if let Some(available) = self.wal_file.available_space() {
+            if needed > available {
+                return Err(From::from(BTreeError::NoSpace{needed: needed, available: available}));
+            }
+        }
+
+        return Ok((new_tree_file_path, bytes_before, start));
+    }
+
+    /// The most records of key_size + value_size a new tree file can hold without outgrowing max_file_bytes
+    fn max_tree_records(&self, key_size: usize, value_size: usize) -> Option<u64> {
+        let record_size = (key_size + value_size + self.checksum.size()) as u64;
+
+        return self.max_file_bytes.map(|max_file_bytes| max_file_bytes.saturating_sub(wal_file::tree_stamp_size(self.checksum)) / record_size);
+    }
+
+    /// Puts the new tree file written by a compaction in place of the old one, and empties the WAL & memory,
+    /// unless the merge had to stop part way, in which case the new file is dropped and nothing changes
+    fn finish_rewrite(&mut self, new_tree_file_path: &String, key_size: usize, value_size: usize, merged: MergeCounts, bytes_before: u64, start: Instant) -> Result<CompactionStats, Box<Error>> {
+        // the old tree file & WAL haven't been touched yet, so just drop the new file
+        if merged.over_limit.is_some() {
+            try!(fs::remove_file(new_tree_file_path));
+            return Err(From::from(BTreeError::FileSizeLimit{limit: self.max_file_bytes.unwrap_or(0)}));
+        }
+
+        if merged.out_of_order {
+            try!(fs::remove_file(new_tree_file_path));
+            return Err(From::from(BTreeError::ComparatorInconsistency));
+        }
+
+        try!(fs::rename(new_tree_file_path, &self.tree_file_path));
+
+        // the rename has to be on disk before the WAL is reset, or a crash could lose both
+        if self.write_concern != WriteConcern::None {
+            try!(wal_file::sync_dir(&self.tree_file_path));
+        }
+
+        self.tree_file = try!(OnDiskBTree::<K,V>::new(self.tree_file_path.to_owned(), key_size, value_size));
+        self.tree_file.set_value_fallback(self.value_fallback);
+        self.sample_records();
+
+        // everything in the WAL is now in the tree file, but the prepared transactions
+        try!(self.wal_file.reset(key_size, value_size));
+        try!(self.log_prepared());
+
+        if self.write_concern == WriteConcern::Full {
+            try!(self.wal_file.sync());
+        }
+
+        // the old WAL is gone, so what was durable through it is now only as durable as the tree file
+        self.compacted_lsn = self.last_lsn;
+
+        if self.write_concern == WriteConcern::Full {
+            self.synced_tree_lsn = self.last_lsn;
+            self.durable_lsn = self.last_lsn;
+        } else {
+            self.durable_lsn = ::std::cmp::min(self.durable_lsn, self.synced_tree_lsn);
+        }
+
+        // the mem_tree is now exactly what is in the tree file, so keep it if the tree is still small
+        let still_small = self.small_tree_threshold.map_or(false, |threshold| merged.output_records <= threshold);
+
+        if ! (self.all_in_memory && still_small) {
+            self.mem_tree = MultiMap::<K,V>::new();
+            self.all_in_memory = false;
+        }
+
+        self.deleted = MultiMap::<K,V>::new();
+        self.key_size = key_size;
+        self.value_size = value_size;
+
+        try!(self.load_if_small());
+
+        let stats = CompactionStats{input_mem_records: merged.input_mem_records,
+                                    input_disk_records: merged.input_disk_records,
+                                    output_records: merged.output_records,
+                                    tombstones_dropped: merged.tombstones_dropped,
+                                    bytes_written: try!(self.tree_file.metadata()).len(),
+                                    duration: start.elapsed(),
+                                    generation: self.last_compaction.as_ref().map_or(1, |stats| stats.generation + 1)};
+
+        self.tree_bytes_written += stats.bytes_written;
+        self.last_compaction = Some(stats.clone());
+
+        let bytes_after = try!(self.tree_file.size()) + try!(self.wal_file.size());
+
+        self.run_compaction_hooks(CompactionEvent::AfterCompaction{duration: stats.duration, bytes_freed: bytes_before.saturating_sub(bytes_after)});
+
+        Ok(stats)
+    }
+}
+
+
+#[cfg(test)]
+#[allow(unused_must_use)]
+mod tests {
+    use std::fs;
+    use std::fs::{File, OpenOptions};
+    use std::io::{ErrorKind, Seek, SeekFrom, Write};
+    use std::io::Error as IOError;
+    use ::{BTree, BTreeBuilder, BTreeError, WriteConcern, ReadConcern, AbsolutePolicy, RatioPolicy, WriteBatch, KeyFilter, CompactionEvent, WriteEvent, ChecksumKind, WalScrub, TreeScrub, encode_size, max_encoded_size};
+    use wal_file::{RecordFile, KeyValuePair, WALRecord, WalReader, Disk, RealDisk};
+    use disk_btree::OnDiskBTree;
+    use testutil::{TempDir, TempTree};
+    use std::cmp::Ordering;
+    use std::collections::{BTreeSet, Bound};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use bincode::SizeLimit;
+    use bincode::rustc_serialize::encode;
+
+    /// A disk that fills up: the next append writes only fail_after bytes, and any append that doesn't fit
+    /// in space writes what does, before each fails as a full disk's would
+    struct FullDisk {
+        fail_after: Option<usize>,
+        space: Option<u64>,
+    }
+
+    impl Disk for FullDisk {
+        fn write_all(&mut self, fd: &mut File, buff: &[u8]) -> Result<(), IOError> {
+            let written = match (self.fail_after.take(), self.space) {
+                (Some(written), _) => written,
+                (None, Some(space)) if buff.len() as u64 > space => space as usize,
+                _ => return fd.write_all(buff)
+            };
+
+            try!(fd.write_all(&buff[..::std::cmp::min(written, buff.len())]));
+
+            return Err(IOError::new(ErrorKind::StorageFull, "No space left on device"));
+        }
+
+        fn available_space(&self, fd: &File) -> Option<u64> {
+            return self.space.or_else(|| RealDisk.available_space(fd));
+        }
+    }
+
+    #[test]
+    fn new_blank_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        // make sure our two files were created, the tree file with only its checksum stamp
+        let btf = OpenOptions::new().read(true).write(false).create(false).open(&file_path).unwrap();
+        assert!(btf.metadata().unwrap().len() == 16);
+
+        // the WAL only has its record size stamp
+        let wal = OpenOptions::new().read(true).write(false).create(false).open(file_path.to_owned() + ".wal").unwrap();
+        assert!(wal.metadata().unwrap().len() == 16);
+
+        // make sure they think they're new too
+        assert!(btree.wal_file.is_new().unwrap());
+        assert!(btree.wal_file.count().unwrap() == 0);
+
+        assert!(btree.tree_file.is_new().unwrap());
+        assert!(btree.tree_file.count().unwrap() == 0);
+    }
+
+    #[test]
+    fn new_existing_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        // scoped so it is cleaned up
+        { BTree::<u8, u8>::new(&file_path, 1, 1).unwrap(); }
+
+        let btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        // check our file lengths from the struct
+        assert!(btree.tree_file.count().unwrap() == 0);
+        assert!(btree.wal_file.count().unwrap() == 0);
+    }
+
+    #[test]
+    fn insert_new_u8() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        btree.insert(2, 3).unwrap(); // insert into a new file
+
+        assert!(btree.wal_file.count().unwrap() == 1);
+        assert!(btree.mem_tree.contains_key(&2));
+    }
+
+    #[test]
+    fn insert_new_str() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<String, String>::new(&file_path, 15, 15).unwrap();
+
+        // insert into a new file
+        btree.insert("Hello".to_owned(), "World".to_owned()).unwrap();
+
+        assert!(! btree.wal_file.is_new().unwrap());
+        assert!(btree.mem_tree.contains_key(&String::from("Hello")));
+    }
+
+    #[test]
+    fn get_returns_a_set() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        // setup tree
+        let mut btree = BTree::<String, String>::new(&file_path, 15, 15).unwrap();
+
+        // expected return set
+        let mut expected: BTreeSet<String> = BTreeSet::new();
+        expected.insert("World".to_string());
+
+        btree.insert("Hello".to_owned(), "World".to_owned());
+
+        // get the set at the hello key
+        let set_at_hello = btree.get(&"Hello".to_string()).unwrap().unwrap();
+
+        assert_eq!(set_at_hello, expected);
+        assert!(btree.get(&"World".to_string()).unwrap().is_none());
+    }
+
+    #[test]
+    fn contains_key() {
+        let mut btree = TempTree::<u8, u8>::new(1, 1).unwrap();
+
+        btree.insert(1, 1).unwrap();
+        btree.insert(2, 1).unwrap();
+        btree.insert(2, 2).unwrap();
+        btree.insert(3, 1).unwrap();
+        btree.compact().unwrap();
+        btree.insert(4, 1).unwrap();
+
+        // 2 has a value left on disk, 3 has none
+        btree.delete(2, 1).unwrap();
+        btree.delete(3, 1).unwrap();
+
+        assert!(btree.contains_key(&1).unwrap());
+        assert!(btree.contains_key(&2).unwrap());
+        assert!(! btree.contains_key(&3).unwrap());
+        assert!(btree.contains_key(&4).unwrap());
+        assert!(! btree.contains_key(&5).unwrap());
+
+        btree.set_read_concern(ReadConcern::MemOnly);
+
+        assert!(! btree.contains_key(&1).unwrap());
+        assert!(btree.contains_key(&4).unwrap());
+    }
+
+    #[test]
+    fn insert_multiple() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<String, String>::new(&file_path, 15, 15).unwrap();
+
+        // insert into a new file
+        btree.insert("Hello".to_owned(), "World".to_owned()).unwrap();
+        assert!(! btree.wal_file.is_new().unwrap());
+
+        btree.insert("Hello".to_owned(), "Everyone".to_owned()).unwrap();
+        assert!(! btree.wal_file.is_new().unwrap());
+    }
+
+    #[test]
+    fn content_hash() {
+        let temp_dir1 = TempDir::new().unwrap();
+        let file_path1 = temp_dir1.tree_path();
+        let temp_dir2 = TempDir::new().unwrap();
+        let file_path2 = temp_dir2.tree_path();
+
+        let mut btree1 = BTree::<String, String>::new(&file_path1, 15, 15).unwrap();
+        let mut btree2 = BTree::<String, String>::new(&file_path2, 20, 20).unwrap();
+
+        // same content, different order
+        btree1.insert("Hello".to_owned(), "World".to_owned()).unwrap();
+        btree1.insert("Foo".to_owned(), "Bar".to_owned()).unwrap();
+
+        btree2.insert("Foo".to_owned(), "Bar".to_owned()).unwrap();
+        btree2.insert("Hello".to_owned(), "World".to_owned()).unwrap();
+
+        assert_eq!(btree1.content_hash().unwrap(), btree2.content_hash().unwrap());
+
+        btree2.insert("Hello".to_owned(), "Everyone".to_owned()).unwrap();
+
+        assert!(btree1.content_hash().unwrap() != btree2.content_hash().unwrap());
+
+        // the range not covering Hello still matches
+        let foo_range = "Foo".to_owned().."Goo".to_owned();
+
+        assert_eq!(btree1.content_hash_range(foo_range.clone()).unwrap(),
+                   btree2.content_hash_range(foo_range).unwrap());
+    }
+
+    #[test]
+    fn content_hash_range() {
+        let temp_dir1 = TempDir::new().unwrap();
+        let file_path1 = temp_dir1.tree_path();
+        let temp_dir2 = TempDir::new().unwrap();
+        let file_path2 = temp_dir2.tree_path();
+
+        let mut btree1 = BTree::<u8, u8>::new(&file_path1, 1, 1).unwrap();
+        let mut btree2 = BTree::<u8, u8>::new(&file_path2, 1, 1).unwrap();
+
+        // split between the tree file and the mem_tree, with a delete of a record on disk
+        for key in 1..10 {
+            btree1.insert(key, key).unwrap();
+        }
+
+        btree1.compact().unwrap();
+        btree1.insert(4, 40).unwrap();
+        btree1.delete(5, 5).unwrap();
+
+        // only the pairs of btree1 from 3 to 6
+        btree2.insert(3, 3).unwrap();
+        btree2.insert(4, 4).unwrap();
+        btree2.insert(4, 40).unwrap();
+        btree2.insert(6, 6).unwrap();
+
+        let hash = btree2.content_hash().unwrap();
+
+        assert_eq!(btree1.content_hash_range(3..7).unwrap(), hash);
+        assert_eq!(btree1.content_hash_range(3..=6).unwrap(), hash);
+        assert_eq!(btree1.content_hash_range((Bound::Excluded(2), Bound::Excluded(7))).unwrap(), hash);
+        assert!(btree1.content_hash_range(3..).unwrap() != hash);
+
+        // a backwards range holds nothing, the same as an empty tree
+        let empty_dir = TempDir::new().unwrap();
+        let mut empty = BTree::<u8, u8>::new(&empty_dir.tree_path(), 1, 1).unwrap();
+
+        assert_eq!(btree1.content_hash_range((Bound::Included(7), Bound::Excluded(3))).unwrap(), empty.content_hash().unwrap());
+        assert_eq!(btree1.content_hash_range((Bound::Excluded(4), Bound::Excluded(4))).unwrap(), empty.content_hash().unwrap());
+    }
+
+    #[test]
+    fn compact() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        btree.insert(2, 3).unwrap();
+        btree.insert(1, 3).unwrap();
+
+        let hash = btree.content_hash().unwrap();
+
+        btree.compact().unwrap();
+
+        // everything moved from the WAL to the tree file
+        assert!(btree.wal_file.count().unwrap() == 0);
+        assert!(btree.mem_tree.size() == 0);
+        assert!(btree.tree_file.count().unwrap() == 2);
+        assert!(btree.tree_file.contains_key(&1).unwrap());
+        assert_eq!(hash, btree.content_hash().unwrap());
+    }
+
+    #[test]
+    fn resize() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<String, String>::new(&file_path, 15, 15).unwrap();
+
+        btree.insert("Hello".to_owned(), "World".to_owned()).unwrap();
+
+        let hash = btree.content_hash().unwrap();
+
+        // too big for the current value size
+        assert!(btree.insert("Hello".to_owned(), "Everyone in the whole world".to_owned()).is_err());
+
+        // too small for what is already in the tree, so nothing changes
+        assert!(btree.resize(15, 4).is_err());
+        assert!(btree.wal_file.count().unwrap() == 1);
+        assert_eq!(hash, btree.content_hash().unwrap());
+
+        btree.resize(15, 40).unwrap();
+
+        assert_eq!(hash, btree.content_hash().unwrap());
+        assert!(btree.tree_file.count().unwrap() == 1);
+
+        btree.insert("Hello".to_owned(), "Everyone in the whole world".to_owned()).unwrap();
+
+        assert!(btree.wal_file.count().unwrap() == 1);
+    }
+
+    #[test]
+    fn contains_value() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        btree.insert(1, 2).unwrap();
+        btree.compact().unwrap(); // 2 is now only on disk
+        btree.insert(3, 4).unwrap();
+
+        assert!(btree.contains_value(&2).unwrap());
+        assert!(btree.contains_value(&4).unwrap());
+        assert!(! btree.contains_value(&1).unwrap());
+    }
+
+    #[test]
+    fn value_to_keys() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        btree.insert(5, 2).unwrap();
+        btree.insert(1, 2).unwrap();
+        btree.compact().unwrap();
+        btree.insert(3, 2).unwrap();
+        btree.insert(5, 2).unwrap(); // in memory and on disk
+        btree.insert(4, 7).unwrap();
+
+        assert_eq!(btree.value_to_keys(&2).unwrap(), vec![1, 3, 5]);
+        assert_eq!(btree.value_to_keys(&7).unwrap(), vec![4]);
+        assert!(btree.value_to_keys(&9).unwrap().is_empty());
+    }
+
+    #[test]
+    fn compaction_stats() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        assert!(btree.last_compaction().is_none());
+
+        btree.insert(1, 2).unwrap();
+        btree.insert(3, 4).unwrap();
+
+        let stats = btree.compact().unwrap();
+
+        assert_eq!(stats.input_mem_records, 2);
+        assert_eq!(stats.input_disk_records, 0);
+        assert_eq!(stats.output_records, 2);
+        assert_eq!(stats.bytes_written, 16 + 2 * 6);
+        assert_eq!(stats.generation, 1);
+
+        btree.insert(1, 2).unwrap(); // already on disk
+        btree.insert(5, 6).unwrap();
+
+        let stats = btree.compact().unwrap();
+
+        assert_eq!(stats.input_mem_records, 2);
+        assert_eq!(stats.input_disk_records, 2);
+        assert_eq!(stats.output_records, 3);
+        assert_eq!(stats.generation, 2);
+        assert_eq!(btree.last_compaction(), Some(&stats));
+
+        btree.delete(3, 4).unwrap();
+        btree.delete(7, 8).unwrap(); // never inserted
+
+        let stats = btree.compact().unwrap();
+
+        assert_eq!(stats.input_disk_records, 3);
+        assert_eq!(stats.output_records, 2);
+        assert_eq!(stats.tombstones_dropped, 1);
+    }
+
+    #[test]
+    fn reopen_replays_wal() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        {
+            let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+            btree.insert(2, 3).unwrap();
+        }
+
+        let btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        assert!(btree.mem_tree.contains_key(&2));
+    }
+
+    #[test]
+    fn delete() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        {
+            let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+            btree.insert(1, 2).unwrap();
+            btree.insert(1, 3).unwrap();
+            btree.compact().unwrap();
+            btree.insert(1, 4).unwrap();
+
+            btree.delete(1, 2).unwrap(); // on disk
+            btree.delete(1, 4).unwrap(); // in memory
+
+            let expected: BTreeSet<u8> = [3].iter().cloned().collect();
+
+            assert_eq!(btree.get(&1).unwrap(), Some(expected));
+            assert!(! btree.contains_value(&2).unwrap());
+
+            btree.delete(1, 3).unwrap();
+
+            assert_eq!(btree.get(&1).unwrap(), None);
+
+            btree.insert(1, 2).unwrap(); // back again
+        }
+
+        // the deletes are replayed from the WAL
+        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        let expected: BTreeSet<u8> = [2].iter().cloned().collect();
+
+        assert_eq!(btree.get(&1).unwrap(), Some(expected.clone()));
+
+        btree.compact().unwrap();
+
+        assert_eq!(btree.tree_file.count().unwrap(), 1);
+        assert_eq!(btree.get(&1).unwrap(), Some(expected));
+    }
+
+    #[test]
+    fn compaction_policy() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        {
+            // each WAL record is 1 + 1 + 4 bytes, and a 4 byte checksum
+            let mut btree: BTree<u8, u8> = BTreeBuilder::new(&file_path, 1, 1).compaction_policy(AbsolutePolicy(20)).open().unwrap();
+
+            btree.insert(1, 1).unwrap();
+            btree.insert(2, 1).unwrap();
+
+            assert!(btree.last_compaction().is_none());
+
+            btree.insert(3, 1).unwrap();
+
+            assert_eq!(btree.last_compaction().map(|stats| stats.output_records), Some(3));
+            assert!(btree.compact_if_needed().unwrap().is_none());
+        }
+
+        // the tree file's records are 3 * 6 bytes, so compact once the WAL is bigger than that
+        let mut btree: BTree<u8, u8> = BTreeBuilder::new(&file_path, 1, 1).compaction_policy(RatioPolicy(1.0)).open().unwrap();
+
+        btree.insert(4, 1).unwrap();
+
+        assert!(btree.last_compaction().is_none());
+
+        btree.insert(5, 1).unwrap();
+
+        assert_eq!(btree.last_compaction().map(|stats| stats.output_records), Some(5));
+    }
+
+    #[test]
+    fn max_file_bytes() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        // room for the stamp & two records of 2 bytes, with their checksums
+        let mut btree: BTree<u8, u8> = BTreeBuilder::new(&file_path, 1, 1).max_file_bytes(16 + 2 * 6).open().unwrap();
+
+        btree.insert(1, 1).unwrap();
+        btree.insert(2, 1).unwrap();
+        btree.compact().unwrap();
+
+        btree.insert(3, 1).unwrap();
+
+        let err = btree.compact().err().unwrap();
+
+        assert_eq!(err.downcast_ref::<BTreeError>(), Some(&BTreeError::FileSizeLimit{limit: 16 + 2 * 6}));
+
+        // nothing was lost
+        assert_eq!(btree.tree_file.count().unwrap(), 2);
+        assert_eq!(btree.wal_file.count().unwrap(), 1);
+        assert!(fs::metadata(file_path.to_owned() + ".new").is_err());
+
+        let usage = btree.disk_usage().unwrap();
+
+        assert_eq!(usage.logical_bytes, 16 + 2 * 6 + 16 + 10);
+
+        // deleting makes room again
+        btree.delete(1, 1).unwrap();
+        btree.compact().unwrap();
+
+        assert_eq!(btree.tree_file.count().unwrap(), 2);
+    }
+
+    #[test]
+    fn merge_with_policy() {
+        let temp_dir1 = TempDir::new().unwrap();
+        let file_path1 = temp_dir1.tree_path();
+        let temp_dir2 = TempDir::new().unwrap();
+        let file_path2 = temp_dir2.tree_path();
+
+        let mut btree1 = BTree::<u8, u8>::new(&file_path1, 1, 1).unwrap();
+        let mut btree2 = BTree::<u8, u8>::new(&file_path2, 1, 1).unwrap();
+
+        btree1.insert(1, 1).unwrap();
+        btree1.insert(2, 1).unwrap();
+        btree1.compact().unwrap();
+        btree1.insert(2, 2).unwrap();
+
+        btree2.insert(2, 3).unwrap();
+        btree2.insert(3, 1).unwrap();
+        btree2.compact().unwrap();
+        btree2.insert(4, 1).unwrap();
+        btree2.delete(4, 1).unwrap();
+
+        // the other tree wins
+        let changed = btree1.merge_with_policy(&btree2, |_, _, theirs| theirs.clone()).unwrap();
+
+        assert_eq!(changed, 2);
+        assert_eq!(btree1.get(&1).unwrap(), Some([1].iter().cloned().collect()));
+        assert_eq!(btree1.get(&2).unwrap(), Some([3].iter().cloned().collect()));
+        assert_eq!(btree1.get(&3).unwrap(), Some([1].iter().cloned().collect()));
+        assert_eq!(btree1.get(&4).unwrap(), None);
+
+        btree2.insert(2, 4).unwrap();
+
+        // union, where merging again changes nothing
+        let union = |_: &u8, ours: &BTreeSet<u8>, theirs: &BTreeSet<u8>| ours.union(theirs).cloned().collect();
+
+        assert_eq!(btree1.merge_with_policy(&btree2, &union).unwrap(), 1);
+        assert_eq!(btree1.merge_with_policy(&btree2, &union).unwrap(), 0);
+        assert_eq!(btree1.get(&2).unwrap(), Some([3, 4].iter().cloned().collect()));
+    }
+
+    #[test]
+    fn merge_into_wal() {
+        let temp_dir1 = TempDir::new().unwrap();
+        let file_path1 = temp_dir1.tree_path();
+        let temp_dir2 = TempDir::new().unwrap();
+        let file_path2 = temp_dir2.tree_path();
+
+        let mut btree1 = BTree::<u8, u8>::new(&file_path1, 1, 1).unwrap();
+
+        btree1.insert(1, 1).unwrap();
+
+        {
+            let mut btree2 = BTree::<u8, u8>::new(&file_path2, 1, 1).unwrap();
+
+            btree2.insert(1, 1).unwrap(); // already there
+            btree2.insert(2, 1).unwrap();
+            btree2.delete(3, 1).unwrap();
+        }
+
+        assert_eq!(btree1.merge_into_wal(&(file_path2.to_owned() + ".wal")).unwrap(), 2);
+        assert_eq!(btree1.get(&2).unwrap(), Some([1].iter().cloned().collect()));
+        assert!(btree1.deleted.contains(&3, &1));
+
+        // imported into our own WAL
+        let btree1 = BTree::<u8, u8>::new(&file_path1, 1, 1).unwrap();
+
+        assert_eq!(btree1.get(&2).unwrap(), Some([1].iter().cloned().collect()));
+
+        let temp_dir3 = TempDir::new().unwrap();
+        let file_path3 = temp_dir3.tree_path();
+
+        { BTree::<u8, u8>::new(&file_path3, 2, 1).unwrap(); }
+
+        let mut btree1 = btree1;
+        let err = btree1.merge_into_wal(&(file_path3.to_owned() + ".wal")).err().unwrap();
+
+        assert_eq!(err.downcast_ref::<BTreeError>(), Some(&BTreeError::WalRecordSizeMismatch{expected: 2, found: 3}));
+        assert!(btree1.merge_into_wal(&(file_path3.to_owned() + ".missing")).is_err());
+        assert!(fs::metadata(file_path3.to_owned() + ".missing").is_err());
+
+        // an empty file isn't stamped, and is left empty
+        let empty_path = file_path3.to_owned() + ".empty";
+
+        fs::File::create(&empty_path).unwrap();
+
+        let err = btree1.merge_into_wal(&empty_path).err().unwrap();
+
+        assert_eq!(err.downcast_ref::<BTreeError>(), Some(&BTreeError::UnstampedWal));
+        assert_eq!(fs::metadata(&empty_path).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn merge_into_wal_leaves_source() {
+        let mut btree1 = TempTree::<u8, u8>::new(1, 1).unwrap();
+        let mut btree2 = TempTree::<u8, u8>::with_options(1, 1, |builder| builder.checksum(ChecksumKind::Crc32c)).unwrap();
+
+        // on disk in btree1 already
+        btree1.insert(1, 1).unwrap();
+        btree1.compact().unwrap();
+
+        btree2.insert(1, 1).unwrap();
+        btree2.insert(2, 2).unwrap();
+        btree2.insert(3, 3).unwrap();
+
+        // btree2 is still open, with a corrupt last record and part of another after it
+        let wal_path = btree2.path().to_owned() + ".wal";
+        let record_size = btree2.wal_file.record_size() as u64;
+        let last = btree2.wal_file.record_offset(2);
+        let mut wal = OpenOptions::new().write(true).open(&wal_path).unwrap();
+
+        wal.seek(SeekFrom::Start(last + record_size - 1)).unwrap();
+        wal.write_all(&[0xff, 0]).unwrap();
+
+        let len = fs::metadata(&wal_path).unwrap().len();
+
+        assert_eq!(btree1.merge_into_wal(&wal_path).unwrap(), 1);
+        assert_eq!(btree1.get(&2).unwrap(), Some([2].iter().cloned().collect()));
+        assert_eq!(btree1.get(&3).unwrap(), None);
+        assert_eq!(fs::metadata(&wal_path).unwrap().len(), len);
+    }
+
+    #[test]
+    fn durability_tokens() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        {
+            let mut btree: BTree<u8, u8> = BTreeBuilder::new(&file_path, 1, 1).write_concern(WriteConcern::None).open().unwrap();
+
+            let token1 = btree.insert(1, 1).unwrap();
+            let token2 = btree.delete(1, 1).unwrap();
+
+            assert!(token1 < token2);
+            assert_eq!(btree.durable_lsn(), 0);
+
+            btree.wait_durable(token1).unwrap();
+
+            assert_eq!(btree.durable_lsn(), token2.lsn());
+        }
+
+        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        let token = btree.insert(2, 1).unwrap();
+
+        assert_eq!(btree.durable_lsn(), token.lsn());
+
+        // a compaction doesn't sync the new tree file
+        btree.compact().unwrap();
+
+        assert_eq!(btree.durable_lsn(), 0);
+
+        // nor do later WAL syncs make up for it
+        let token = btree.insert(3, 1).unwrap();
+
+        assert_eq!(btree.durable_lsn(), 0);
+
+        btree.wait_durable(token).unwrap();
+
+        assert_eq!(btree.durable_lsn(), token.lsn());
+
+        let token = btree.insert(4, 1).unwrap();
+
+        assert_eq!(btree.durable_lsn(), token.lsn());
+    }
+
+    #[test]
+    fn raw_insert() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u16, String>::new(&file_path, 2, 10).unwrap();
+
+        let key_bytes = encode(&7u16, SizeLimit::Infinite).unwrap();
+        let value_bytes = encode(&"hi".to_owned(), SizeLimit::Infinite).unwrap();
+
+        assert_eq!(btree.raw_insert(&key_bytes, &value_bytes).unwrap(), 2 + 10 + 4 + 4);
+        assert_eq!(btree.get(&7).unwrap(), Some(["hi".to_owned()].iter().cloned().collect()));
+
+        // too long, and not a whole key
+        assert!(btree.raw_insert(&[0, 0, 7], &value_bytes).is_err());
+        assert!(btree.raw_insert(&[0], &value_bytes).is_err());
+        assert_eq!(btree.wal_file.count().unwrap(), 1);
+
+        // replays like any other insert
+        let mut btree = BTree::<u16, String>::new(&file_path, 2, 10).unwrap();
+
+        assert_eq!(btree.get(&7).unwrap(), Some(["hi".to_owned()].iter().cloned().collect()));
+
+        btree.compact().unwrap();
+        btree.insert(7, "a".to_owned()).unwrap();
+
+        let expected = vec![encode(&"a".to_owned(), SizeLimit::Infinite).unwrap(), value_bytes];
+
+        assert_eq!(btree.raw_get(&key_bytes).unwrap(), Some(expected));
+        assert_eq!(btree.raw_get(&[0, 8]).unwrap(), None);
+        assert!(btree.raw_get(&[0]).is_err());
+    }
+
+    #[test]
+    fn schema_evolution() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        // the old schema stored a big-endian u16 whose high byte is the value, the new one a (flag, value)
+        {
+            let mut btree = BTree::<u8, u16>::new(&file_path, 1, 2).unwrap();
+
+            btree.insert(1, 5 << 8).unwrap();
+            btree.insert(2, 7 << 8).unwrap();
+            btree.compact().unwrap();
+        }
+
+        fn from_old(bytes: &[u8]) -> Result<(bool, u8), Box<::std::error::Error>> {
+            return Ok((false, bytes[0]));
+        }
+
+        let mut btree = BTree::<u8, (bool, u8)>::new(&file_path, 1, 2).unwrap();
+
+        assert!(btree.get(&1).is_err());
+
+        btree.on_decode_error(from_old);
+
+        assert_eq!(btree.get(&1).unwrap(), Some([(false, 5)].iter().cloned().collect()));
+
+        btree.insert(3, (true, 9)).unwrap();
+
+        // every value gets re-encoded in the new schema
+        let stats = btree.rewrite_values(|key, (flag, value)| (flag, value + key)).unwrap();
+
+        assert_eq!(stats.output_records, 3);
+
+        let btree = BTree::<u8, (bool, u8)>::new(&file_path, 1, 2).unwrap();
+
+        assert_eq!(btree.get(&1).unwrap(), Some([(false, 6)].iter().cloned().collect()));
+        assert_eq!(btree.get(&2).unwrap(), Some([(false, 9)].iter().cloned().collect()));
+        assert_eq!(btree.get(&3).unwrap(), Some([(true, 12)].iter().cloned().collect()));
+    }
+
+    #[test]
+    #[ignore]
+    fn replay_1m_record_wal() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        {
+            let mut wal_file = RecordFile::<u32, u32, WALRecord<u32, u32>>::new_stamped(&(file_path.to_owned() + ".wal"), 4, 4).unwrap();
+
+            for i in 0..1000000u32 {
+                wal_file.insert_record(&WALRecord::Insert(KeyValuePair{key: i / 4, value: i})).unwrap();
+            }
+        }
+
+        let start = Instant::now();
+        let btree = BTree::<u32, u32>::new(&file_path, 4, 4).unwrap();
+
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        assert_eq!(btree.mem_tree.size(), 1000000);
+    }
+
+    #[test]
+    fn estimate_bloat() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        assert_eq!(btree.estimate_bloat().unwrap().wal_duplicate_ratio, 0.0);
+
+        btree.insert(1, 1).unwrap();
+        btree.insert(1, 2).unwrap();
+        btree.delete(1, 1).unwrap();
+        btree.insert(2, 1).unwrap();
+
+        let report = btree.estimate_bloat().unwrap();
+
+        assert_eq!(report.wal_entries, 4);
+        assert_eq!(report.unique_keys_in_wal, 2);
+        assert_eq!(report.wal_duplicate_ratio, 1.0);
+        assert_eq!(report.wal_size_bytes, 4 * 10);
+    }
+
+    #[test]
+    fn wal_audit() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        btree.insert(1, 1).unwrap();
+        btree.insert(1, 1).unwrap();  // supersedes the first
+        btree.insert(1, 2).unwrap();
+        btree.insert(2, 1).unwrap();
+        btree.delete(2, 1).unwrap();  // supersedes the insert before
+
+        let mut batch = WriteBatch::new();
+
+        batch.insert(3, 3);
+        btree.apply(&batch).unwrap();
+
+        let audit = btree.wal_audit().unwrap();
+
+        assert_eq!(audit.total_records, 8);
+        assert_eq!(audit.records.iter().map(|&(offset, _)| offset).collect::<Vec<_>>(), (0..8).map(|i| 16 + i * 10).collect::<Vec<_>>());
+        assert!(audit.records[5].1 == WALRecord::BatchStart);
+        assert!(audit.records[4].1 == WALRecord::Delete(KeyValuePair{key: 2, value: 1}));
+        assert_eq!(audit.records_per_key.into_iter().collect::<Vec<_>>(), vec![(1, 3), (2, 2), (3, 1)]);
+        assert_eq!(audit.duplicate_keys, 2);
+        assert_eq!(audit.wasted_bytes, 2 * 10);
+
+        btree.compact().unwrap();
+
+        assert_eq!(btree.wal_audit().unwrap().total_records, 0);
+    }
+
+    #[test]
+    fn wal_deduplicate() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        {
+            let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+            btree.insert(9, 9).unwrap();
+            btree.compact().unwrap();
+
+            for i in 0..10 {
+                btree.insert(1, 1).unwrap();
+                btree.insert(2, i).unwrap();
+            }
+
+            btree.delete(9, 9).unwrap();  // has to stay, as the pair is in the tree file
+            btree.delete(2, 0).unwrap();
+
+            let mut batch = WriteBatch::new();
+
+            batch.insert(3, 3);
+            batch.delete(2, 1);
+            btree.apply(&batch).unwrap();
+
+            let written = btree.wal_file.bytes_written();
+
+            // 26 records down to one for each of the 13 pairs written
+            assert_eq!(btree.wal_deduplicate().unwrap(), (26 - 13) * 10);
+            assert_eq!(btree.wal_file.count().unwrap(), 13);
+            assert!(btree.wal_file.bytes_written() >= written);
+
+            btree.insert(5, 5).unwrap();
+            assert_eq!(btree.wal_deduplicate().unwrap(), 0);
+        }
+
+        let btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        assert_eq!(btree.get(&9).unwrap(), None);
+        assert_eq!(btree.get(&1).unwrap(), Some([1].iter().cloned().collect()));
+        assert_eq!(btree.get(&2).unwrap(), Some((2..10).collect()));
+        assert_eq!(btree.get(&3).unwrap(), Some([3].iter().cloned().collect()));
+        assert_eq!(btree.get(&5).unwrap(), Some([5].iter().cloned().collect()));
+
+        // prepared transactions are kept
+        let prepared_path = file_path.to_owned() + "_prepared";
+
+        {
+            let mut btree = BTree::<u64, u64>::new(&prepared_path, 8, 8).unwrap();
+            let mut transaction = WriteBatch::new();
+
+            transaction.insert(4, 4);
+            btree.insert(4, 1).unwrap();
+            btree.insert(4, 1).unwrap();
+            btree.prepare(7, &transaction).unwrap();
+
+            assert_eq!(btree.wal_deduplicate().unwrap(), 24);
+        }
+
+        let mut btree = BTree::<u64, u64>::new(&prepared_path, 8, 8).unwrap();
+
+        btree.prepared_transaction(7).unwrap().unwrap().commit().unwrap();
+
+        assert_eq!(btree.get(&4).unwrap(), Some([1, 4].iter().cloned().collect()));
+    }
+
+    #[test]
+    fn delete_batch() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        btree.insert(1, 1).unwrap();
+        btree.insert(1, 2).unwrap();
+        btree.insert(2, 1).unwrap();
+        btree.compact().unwrap();
+        btree.insert(3, 1).unwrap();
+        btree.insert(4, 1).unwrap();
+
+        let wal_count = btree.wal_file.count().unwrap();
+
+        // 1 is on disk, 3 in memory, 5 nowhere, and the 3 twice only counts once
+        assert_eq!(btree.delete_batch(vec![1, 3, 5, 3]).unwrap(), 2);
+
+        // start, 3 deletes, end
+        assert_eq!(btree.wal_file.count().unwrap(), wal_count + 5);
+        assert_eq!(btree.delete_batch(vec![5]).unwrap(), 0);
+        assert_eq!(btree.wal_file.count().unwrap(), wal_count + 5);
+
+        let btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        assert_eq!(btree.get(&1).unwrap(), None);
+        assert_eq!(btree.get(&3).unwrap(), None);
+        assert!(btree.get(&2).unwrap().is_some());
+        assert!(btree.get(&4).unwrap().is_some());
+    }
+
+    #[test]
+    fn key_histogram() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u32, u8>::new(&file_path, 4, 1).unwrap();
+
+        // crowded at the bottom
+        for key in (0..8).chain(vec![50, 99]) {
+            btree.insert(key, 1).unwrap();
+        }
+
+        btree.compact().unwrap();
+        btree.insert(100, 1).unwrap();
+        btree.insert(100, 2).unwrap();
+        btree.delete(99, 1).unwrap();
+
+        assert_eq!(btree.key_histogram(2).unwrap(), vec![(0, 4, 5), (5, 100, 5)]);
+        assert_eq!(btree.key_histogram(20).unwrap().len(), 10);
+        assert_eq!(btree.numeric_key_histogram(4).unwrap(), vec![(0, 7, 8), (50, 50, 1), (100, 100, 1)]);
+        assert_eq!(btree.numeric_key_histogram(0).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn remap_values() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        btree.insert(1, 1).unwrap();
+        btree.insert(1, 2).unwrap();
+        btree.insert(2, 4).unwrap();
+        btree.compact().unwrap();
+        btree.insert(3, 1).unwrap();
+
+        // double the odd values, and drop key 3
+        let changed = btree.remap_values(|key, values| {
+            if key == 3 { BTreeSet::new() } else { values.into_iter().map(|v| if v % 2 == 1 { v * 2 } else { v }).collect() }
+        }).unwrap();
+
+        assert_eq!(changed, 2);
+        assert_eq!(btree.tree_file.count().unwrap(), 3);
+        assert_eq!(btree.get(&1).unwrap(), Some([2].iter().cloned().collect()));
+        assert_eq!(btree.get(&2).unwrap(), Some([4].iter().cloned().collect()));
+        assert_eq!(btree.get(&3).unwrap(), None);
+
+        btree.compact().unwrap();
+
+        assert_eq!(btree.get(&1).unwrap(), Some([2].iter().cloned().collect()));
+        assert_eq!(btree.tree_file.count().unwrap(), 2);
+    }
+
+    #[test]
+    fn key_bounds() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        btree.insert(1, 1).unwrap();
+        btree.insert(9, 1).unwrap();
+        btree.compact().unwrap();
+
+        btree.set_key_bounds(Some(2), Some(9));
+
+        // written before the bounds were set
+        assert_eq!(*btree.verify_key_bounds().unwrap_err().downcast::<BTreeError>().unwrap(), BTreeError::OutOfBounds);
+
+        btree.insert(2, 1).unwrap();
+        btree.insert(8, 1).unwrap();
+
+        for key in &[1, 9] {
+            assert_eq!(*btree.insert(*key, 2).unwrap_err().downcast::<BTreeError>().unwrap(), BTreeError::OutOfBounds);
+            assert_eq!(*btree.delete(*key, 1).unwrap_err().downcast::<BTreeError>().unwrap(), BTreeError::OutOfBounds);
+        }
+
+        // a batch with one bad key writes nothing
+        let mut batch = WriteBatch::new();
+
+        batch.insert(3, 1);
+        batch.insert(9, 1);
+
+        assert!(btree.apply(&batch).is_err());
+        assert_eq!(btree.get(&3).unwrap(), None);
+        assert_eq!(btree.get(&1).unwrap(), Some([1].iter().cloned().collect()));
+
+        btree.set_key_bounds(None, None);
+        btree.delete(1, 1).unwrap();
+        btree.delete(9, 1).unwrap();
+        btree.compact().unwrap();
+        btree.set_key_bounds(Some(2), Some(9));
+
+        btree.verify_key_bounds().unwrap();
+        drop(btree);
+
+        // bounds given to the builder are set on open
+        let bounded = || BTreeBuilder::new(&file_path, 1, 1).key_bounds(Some(2u8), Some(9u8));
+        let mut btree = bounded().open::<u8, u8>().unwrap();
+
+        assert_eq!(*btree.insert(9, 1).unwrap_err().downcast::<BTreeError>().unwrap(), BTreeError::OutOfBounds);
+
+        btree.set_key_bounds(None, None);
+        btree.insert(20, 1).unwrap();
+        drop(btree);
+
+        // and opening checks the keys already in the tree file & the WAL
+        assert_eq!(*BTreeBuilder::new(&file_path, 1, 1).key_bounds(Some(3u8), None).open::<u8, u8>().err().unwrap().downcast::<BTreeError>().unwrap(),
+                   BTreeError::OutOfBounds);
+        assert_eq!(*bounded().open::<u8, u8>().err().unwrap().downcast::<BTreeError>().unwrap(), BTreeError::OutOfBounds);
+
+        let mut btree = bounded().lazy_replay(true).open::<u8, u8>().unwrap();
+
+        assert_eq!(*btree.ensure_replayed().unwrap_err().downcast::<BTreeError>().unwrap(), BTreeError::OutOfBounds);
+    }
+
+    #[test]
+    fn periodic_sync() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        {
+            let mut btree: BTree<u8, u8> = BTreeBuilder::new(&file_path, 1, 1).write_concern(WriteConcern::Periodic(Duration::from_millis(10))).open().unwrap();
+
+            // started on the first write
+            assert!(btree.background_sync.is_none());
+
+            let token = btree.insert(1, 1).unwrap();
+            let start = Instant::now();
+
+            while btree.durable_lsn() < token.lsn() {
+                assert!(start.elapsed() < Duration::from_secs(5));
+                ::std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        let start = Instant::now();
+
+        {
+            let mut btree: BTree<u8, u8> = BTreeBuilder::new(&file_path, 1, 1).write_concern(WriteConcern::Periodic(Duration::from_secs(3600))).open().unwrap();
+
+            assert!(btree.get(&1).unwrap().is_some());
+
+            let token = btree.insert(2, 1).unwrap();
+
+            assert!(btree.durable_lsn() < token.lsn());
+
+            // doesn't wait out the interval
+            btree.wait_durable(token).unwrap();
+
+            assert_eq!(btree.durable_lsn(), token.lsn());
+
+            btree.insert(3, 1).unwrap();
+        }
+
+        // and neither does the drop
+        assert!(start.elapsed() < Duration::from_secs(60));
+
+        let btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        assert!(btree.get(&3).unwrap().is_some());
+    }
+
+    #[test]
+    fn tombstone_count() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        btree.insert(1, 1).unwrap();
+        btree.insert(2, 1).unwrap();
+        btree.delete(1, 1).unwrap();
+        btree.delete_batch(vec![2]).unwrap();
+
+        assert_eq!(btree.tombstone_count().unwrap(), 2);
+
+        btree.compact().unwrap();
+
+        assert_eq!(btree.tombstone_count().unwrap(), 0);
+    }
+
+    #[test]
+    fn to_sorted_vec() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        assert_eq!(btree.to_sorted_vec().unwrap(), vec![]);
+
+        btree.insert(2, 1).unwrap();
+        btree.insert(1, 1).unwrap();
+        btree.insert(1, 2).unwrap();
+        btree.compact().unwrap();
+        btree.insert(1, 3).unwrap();
+        btree.insert(3, 1).unwrap();
+        btree.delete(1, 2).unwrap();
+        btree.delete(2, 1).unwrap();
+
+        let set = |values: &[u8]| values.iter().cloned().collect::<BTreeSet<u8>>();
+
+        assert_eq!(btree.to_sorted_vec().unwrap(), vec![(1, set(&[1, 3])), (3, set(&[1]))]);
+
+        // deletes still apply, as they do for get
+        btree.set_read_concern(ReadConcern::DiskOnly);
+
+        assert_eq!(btree.to_sorted_vec().unwrap(), vec![(1, set(&[1]))]);
+    }
+
+    #[test]
+    fn debug_dump() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u16, u8>::new(&file_path, 2, 2).unwrap();
+
+        btree.insert(1, 7).unwrap();
+        btree.insert(258, 9).unwrap();
+        btree.compact().unwrap();
+
+        let mut dump = Vec::new();
+
+        btree.debug_dump(&mut dump).unwrap();
+
+        let dump = String::from_utf8(dump).unwrap();
+        let lines: Vec<_> = dump.lines().collect();
+
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with("2 records, key size 2, value size 2"));
+        assert_eq!(lines[1], "00000000  key [00 01]  value [07 00]  1 => 7");
+        assert_eq!(lines[2], "00000004  key [01 02]  value [09 00]  258 => 9");
+    }
+
+    #[test]
+    fn write_amplification() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u8, u16>::new(&file_path, 1, 3).unwrap();
+
+        assert_eq!(btree.write_amplification().total_amplification, 0.0);
+
+        // 3 bytes of key & value each, into 12 byte WAL records
+        btree.insert(1, 1).unwrap();
+        btree.insert(2, 1).unwrap();
+        btree.delete(2, 1).unwrap();
+        btree.compact().unwrap();
+
+        let report = btree.write_amplification();
+
+        assert_eq!(report.logical_bytes, 6);
+        assert_eq!(report.wal_bytes, 36);
+        assert_eq!(report.tree_bytes, 16 + 8);
+        assert_eq!(report.total_amplification, 60.0 / 6.0);
+
+        // the counters carry on past the compaction
+        btree.raw_insert(&[3], &[0, 1]).unwrap();
+
+        assert_eq!(btree.write_amplification().logical_bytes, 9);
+        assert_eq!(btree.write_amplification().wal_bytes, 48);
+
+        btree.reset_io_counters();
+
+        let report = btree.write_amplification();
+
+        assert_eq!((report.logical_bytes, report.wal_bytes, report.tree_bytes), (0, 0, 0));
+    }
+
+    #[test]
+    fn export_key_filter() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u32, u8>::new(&file_path, 4, 1).unwrap();
+
+        for i in 0..100 {
+            btree.insert(i, 1).unwrap();
+        }
+
+        btree.compact().unwrap();
+        btree.delete(50, 1).unwrap();
+
+        let filter = KeyFilter::from_bytes(&btree.export_key_filter(10).unwrap()).unwrap();
+
+        assert!((0..100).filter(|&i| i != 50).all(|i: u32| filter.contains(&encode(&i, SizeLimit::Infinite).unwrap())));
+        assert!((1000..1100).filter(|i: &u32| filter.contains(&encode(i, SizeLimit::Infinite).unwrap())).count() < 20);
+    }
+
+    #[test]
+    fn export_to_leveldb_sst() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<String, u8>::new(&file_path, 15, 1).unwrap();
+
+        // "aa" comes before "b", but its encoding is longer so sorts after
+        btree.insert("aa".to_string(), 1).unwrap();
+        btree.insert("aa".to_string(), 2).unwrap();
+        btree.insert("b".to_string(), 1).unwrap();
+        btree.compact().unwrap();
+        btree.insert("c".to_string(), 1).unwrap();
+
+        let mut table = Vec::new();
+        let size = btree.export_to_leveldb_sst(&mut table).unwrap();
+
+        assert_eq!(size, table.len() as u64);
+        assert_eq!(&table[table.len() - 8..], &[0x57, 0xfb, 0x80, 0x8b, 0x24, 0x75, 0x47, 0xdb]);
+
+        // the keys & values are in there as bincode, uncompressed
+        let values = encode(&vec![1u8, 2u8], SizeLimit::Infinite).unwrap();
+
+        assert!(table.windows(values.len()).any(|window| window == &values[..]));
+    }
+
+    #[test]
+    fn split_wal_at() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        btree.insert(1, 1).unwrap();
+        btree.compact().unwrap();
+        btree.insert(2, 1).unwrap();
+        btree.insert(3, 1).unwrap();
+        btree.delete(1, 1).unwrap();
+
+        // where a change feed reading the WAL got to
+        let wal_path = file_path.to_owned() + ".wal";
+        let offset = WalReader::<u8, u8>::open(&wal_path, 1, 1).unwrap()
+            .map(|record| record.unwrap())
+            .find(|&(_, ref record)| *record == WALRecord::Insert(KeyValuePair{key: 3, value: 1}))
+            .unwrap().0;
+
+        assert_eq!(offset, 16 + 10);
+        assert!(btree.split_wal_at(offset + 1).is_err());
+        assert!(btree.split_wal_at(0).is_err());
+
+        let records = btree.split_wal_at(offset).unwrap();
+
+        assert!(records == vec![WALRecord::Insert(KeyValuePair{key: 3, value: 1}), WALRecord::Delete(KeyValuePair{key: 1, value: 1})]);
+
+        // undone in memory & on disk
+        assert_eq!(btree.get(&3).unwrap(), None);
+        assert!(btree.get(&1).unwrap().is_some());
+        assert!(btree.get(&2).unwrap().is_some());
+        assert_eq!(fs::metadata(&wal_path).unwrap().len(), offset);
+        assert!(btree.split_wal_at(offset).unwrap().is_empty());
+
+        let btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        assert_eq!(btree.get(&3).unwrap(), None);
+        assert!(btree.get(&1).unwrap().is_some());
+    }
+
+    #[test]
+    fn parallel_get() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u32, u32>::new(&file_path, 4, 4).unwrap();
+
+        for i in 0..1000 {
+            btree.insert(i, i * 2).unwrap();
+        }
+
+        btree.compact().unwrap();
+        btree.insert(2000, 1).unwrap();
+
+        let keys: Vec<u32> = (0..1000).rev().chain(vec![5000, 2000]).collect();
+        let results = btree.parallel_get(keys.clone()).unwrap();
+
+        assert_eq!(results.len(), keys.len());
+
+        for (&key, (found_key, values)) in keys.iter().zip(results) {
+            assert_eq!(key, found_key);
+            assert_eq!(values, btree.get(&key).unwrap());
+        }
+
+        assert!(btree.parallel_get(Vec::new()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn failed_wal_write() {
+        let mut btree = TempTree::<u32, u32>::new(4, 4).unwrap();
+
+        btree.insert(1, 1).unwrap();
+
+        btree.wal_file.set_disk(Box::new(FullDisk{fail_after: Some(5), space: None}));
+        assert!(btree.insert(2, 2).is_err());
+        assert_eq!(btree.get(&2).unwrap(), None);
+
+        let mut batch = WriteBatch::new();
+
+        batch.insert(3, 3);
+        batch.insert(4, 4);
+
+        btree.wal_file.set_disk(Box::new(FullDisk{fail_after: Some(20), space: None}));
+        assert!(btree.apply(&batch).is_err());
+        assert_eq!(btree.get(&3).unwrap(), None);
+
+        assert_eq!(btree.wal_file.count().unwrap(), 1);
+
+        btree.insert(5, 5).unwrap();
+        btree.apply(&batch).unwrap();
+
+        btree.reopen(|builder| builder).unwrap();
+
+        for key in 1..6 {
+            assert_eq!(btree.get(&key).unwrap().is_some(), key != 2, "key {}", key);
+        }
+    }
+
+    #[test]
+    fn compact_corrupt_tree_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+        let wal_path = file_path.to_owned() + ".wal";
+
+        {
+            let mut btree = BTree::<String, u8>::new(&file_path, 12, 1).unwrap();
+
+            for (i, key) in ["a", "b", "c"].iter().enumerate() {
+                btree.insert(key.to_string(), i as u8).unwrap();
+            }
+
+            btree.compact().unwrap();
+            btree.insert("d".to_owned(), 3).unwrap();
+        }
+
+        // record 1's key claims to be longer than the record, past the 16 byte stamp & record 0's 17 bytes
+        let mut bytes = fs::read(&file_path).unwrap();
+
+        bytes[16 + 17] = 0xff;
+        fs::write(&file_path, &bytes).unwrap();
+
+        let wal_bytes = fs::read(&wal_path).unwrap();
+        let mut btree = BTreeBuilder::new(&file_path, 12, 1).compaction_threads(2).open::<String, u8>().unwrap();
+
+        assert_eq!(*btree.compact().unwrap_err().downcast::<BTreeError>().unwrap(), BTreeError::CorruptTreeRecord{index: 1});
+        assert_eq!(*btree.compact_parallel().unwrap_err().downcast::<BTreeError>().unwrap(), BTreeError::CorruptTreeRecord{index: 1});
+
+        // the old tree file & WAL are left as they were
+        assert_eq!(fs::read(&file_path).unwrap(), bytes);
+        assert_eq!(fs::read(&wal_path).unwrap(), wal_bytes);
+    }
+
+    #[test]
+    fn load_corrupt_small_tree() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        {
+            let mut btree = BTree::<String, u8>::new(&file_path, 12, 1).unwrap();
+
+            for (i, key) in ["a", "b", "c"].iter().enumerate() {
+                btree.insert(key.to_string(), i as u8).unwrap();
+            }
+
+            btree.compact().unwrap();
+            btree.insert("d".to_owned(), 3).unwrap();
+        }
+
+        let mut bytes = fs::read(&file_path).unwrap();
+
+        bytes[16 + 17] = 0xff;
+        fs::write(&file_path, &bytes).unwrap();
+
+        // loading only part of the tree would have it answer for keys it hasn't loaded
+        assert_eq!(*BTreeBuilder::new(&file_path, 12, 1).small_tree_threshold(10).open::<String, u8>().err().unwrap().downcast::<BTreeError>().unwrap(),
+                   BTreeError::CorruptTreeRecord{index: 1});
+
+        let mut btree = BTreeBuilder::new(&file_path, 12, 1).small_tree_threshold(10).lazy_replay(true).open::<String, u8>().unwrap();
+
+        assert_eq!(*btree.ensure_replayed().unwrap_err().downcast::<BTreeError>().unwrap(), BTreeError::CorruptTreeRecord{index: 1});
+        assert!(! btree.all_in_memory);
+        assert!(btree.get(&"c".to_owned()).is_err());
+        assert!(btree.compact().is_err());
+        assert_eq!(fs::read(&file_path).unwrap(), bytes);
+    }
+
+    #[test]
+    fn torn_wal_tail() {
+        let mut btree = TempTree::<u32, u32>::new(4, 4).unwrap();
+
+        btree.insert(1, 1).unwrap();
+        btree.insert(2, 2).unwrap();
+
+        // a crash part way through appending the next record
+        let mut wal = OpenOptions::new().append(true).open(btree.path().to_owned() + ".wal").unwrap();
+
+        wal.write_all(&[1, 2, 3]).unwrap();
+        btree.reopen(|builder| builder).unwrap();
+
+        assert_eq!(btree.wal_file.count().unwrap(), 2);
+
+        btree.insert(7, 7).unwrap();
+        btree.reopen(|builder| builder).unwrap();
+
+        for key in &[1, 2, 7] {
+            assert_eq!(btree.get(key).unwrap(), Some([*key].iter().cloned().collect()), "key {}", key);
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn assert_invariants() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u32, u32>::new(&file_path, 4, 4).unwrap();
+
+        btree.assert_invariants();
+
+        for i in 0..50 {
+            btree.insert(i % 20, i).unwrap();
+            btree.delete(i % 20, i).unwrap();
+            btree.insert(i % 20, i).unwrap();
+        }
+
+        btree.assert_invariants();
+        btree.compact().unwrap();
+        btree.delete(3, 3).unwrap();
+        btree.assert_invariants();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "both in the mem_tree and deleted")]
+    fn assert_invariants_fails() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u32, u32>::new(&file_path, 4, 4).unwrap();
+
+        btree.insert(1, 1).unwrap();
+        btree.deleted.insert(1, 1);
+        btree.assert_invariants();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "the WAL holds 2 records, but 1 were written to it")]
+    fn assert_invariants_wal_count() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u32, u32>::new(&file_path, 4, 4).unwrap();
+
+        btree.insert(1, 1).unwrap();
+
+        // a whole record, written behind the tree's back
+        OpenOptions::new().append(true).open(file_path.to_owned() + ".wal").unwrap().write_all(&[0; 16]).unwrap();
+
+        btree.assert_invariants();
+    }
+
+    #[test]
+    fn insert_many_values() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        {
+            let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+            btree.insert(1, 9).unwrap();
+
+            assert_eq!(btree.insert_many_values(1, vec![3, 2, 3, 1]).unwrap(), 3);
+            assert_eq!(btree.insert_many_values(2, vec![]).unwrap(), 0);
+
+            // the values go in as one batch: start, 3 inserts, end
+            assert_eq!(btree.wal_file.count().unwrap(), 1 + 5);
+        }
+
+        let btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        assert_eq!(btree.get(&1).unwrap(), Some([1, 2, 3, 9].iter().cloned().collect()));
+        assert_eq!(btree.get(&2).unwrap(), None);
+    }
+
+    #[test]
+    fn remove_all_values() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        {
+            let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+            btree.insert_many_values(1, vec![1, 2]).unwrap();
+            btree.insert(2, 2).unwrap();
+            btree.compact().unwrap();
+            btree.insert(1, 3).unwrap();
+
+            // from memory & the tree file both
+            assert_eq!(btree.remove_all_values(&1).unwrap(), Some([1, 2, 3].iter().cloned().collect()));
+            assert_eq!(btree.remove_all_values(&1).unwrap(), None);
+            assert_eq!(btree.remove_all_values(&3).unwrap(), None);
+            assert_eq!(btree.get(&1).unwrap(), None);
+        }
+
+        let btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        assert_eq!(btree.get(&1).unwrap(), None);
+        assert_eq!(btree.get(&2).unwrap(), Some([2].iter().cloned().collect()));
+    }
+
+    #[test]
+    fn checksums() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+        let wal_path = file_path.to_owned() + ".wal";
+
+        assert!(BTreeBuilder::new(&file_path, 4, 4).checksum(ChecksumKind::None).open::<u32, u32>().is_err());
+
+        {
+            let mut btree = BTreeBuilder::new(&file_path, 4, 4).checksum(ChecksumKind::Crc32c).open::<u32, u32>().unwrap();
+
+            for i in 0..3 {
+                btree.insert(i, i).unwrap();
+            }
+
+            // each 12 byte record has its 4 byte CRC after it
+            assert_eq!(btree.wal_file.size().unwrap(), 3 * 16);
+            assert_eq!(btree.scrub_wal().unwrap(), WalScrub{checksum: ChecksumKind::Crc32c, records: 3, corrupt: vec![], torn_tail: None});
+        }
+
+        // a flipped bit in a value still decodes, but fails its checksum
+        let mut bytes = fs::read(&wal_path).unwrap();
+
+        bytes[16 + 16 + 11] ^= 1;
+        fs::write(&wal_path, &bytes).unwrap();
+
+        assert_eq!(WalReader::<u32, u32>::open(&wal_path, 4, 4).unwrap().filter(|record| record.is_err()).count(), 1);
+
+        // nothing after a bad record is replayed, or could be written after it
+        assert_eq!(*BTree::<u32, u32>::new(&file_path, 4, 4).err().unwrap().downcast::<BTreeError>().unwrap(),
+                   BTreeError::CorruptWalRecord{index: 1});
+        assert_eq!(fs::read(&wal_path).unwrap(), bytes);
+
+        // unless it is the last one, which is cut off as a crash mid-write could have left it
+        bytes[16 + 16 + 11] ^= 1;
+        bytes[16 + 32 + 11] ^= 1;
+        fs::write(&wal_path, &bytes).unwrap();
+
+        {
+            // the WAL is read with the checksum it's stamped with, whatever is asked for
+            let mut btree = BTreeBuilder::new(&file_path, 4, 4).checksum(ChecksumKind::Sha256).open::<u32, u32>().unwrap();
+
+            assert_eq!(btree.scrub_wal().unwrap(), WalScrub{checksum: ChecksumKind::Crc32c, records: 2, corrupt: vec![], torn_tail: None});
+            assert_eq!(btree.get(&1).unwrap(), Some([1].iter().cloned().collect()));
+            assert_eq!(btree.get(&2).unwrap(), None);
+
+            btree.insert(7, 7).unwrap();
+        }
+
+        {
+            let btree = BTree::<u32, u32>::new(&file_path, 4, 4).unwrap();
+
+            for key in &[0, 1, 7] {
+                assert_eq!(btree.get(key).unwrap(), Some([*key].iter().cloned().collect()), "key {}", key);
+            }
+        }
+
+        // an emptied WAL takes the checksum asked for
+        {
+            let mut btree = BTree::<u32, u32>::new(&file_path, 4, 4).unwrap();
+
+            btree.compact().unwrap();
+        }
+
+        let mut btree = BTreeBuilder::new(&file_path, 4, 4).checksum(ChecksumKind::Sha256).open::<u32, u32>().unwrap();
+
+        btree.insert(5, 5).unwrap();
+
+        assert_eq!(btree.wal_file.size().unwrap(), 12 + 32);
+        assert_eq!(btree.scrub_wal().unwrap().checksum, ChecksumKind::Sha256);
+
+        drop(btree);
+
+        let mut btree = BTreeBuilder::new(&file_path, 4, 4).without_checksums().open::<u32, u32>().unwrap();
+
+        btree.compact().unwrap();
+        drop(btree);
+
+        let mut btree = BTreeBuilder::new(&file_path, 4, 4).without_checksums().open::<u32, u32>().unwrap();
+
+        btree.insert(6, 6).unwrap();
+
+        assert_eq!(btree.wal_file.size().unwrap(), 12);
+        assert_eq!(btree.scrub_wal().unwrap().checksum, ChecksumKind::None);
+        assert_eq!(btree.get(&5).unwrap(), Some([5].iter().cloned().collect()));
+    }
+
+    #[test]
+    fn tree_file_checksums() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+        let wal_path = file_path.to_owned() + ".wal";
+
+        {
+            // both files are checked with CRC32C unless asked otherwise
+            let mut btree = BTree::<u32, u32>::new(&file_path, 4, 4).unwrap();
+
+            for i in 0..3 {
+                btree.insert(i, i).unwrap();
+            }
+
+            assert_eq!(btree.scrub_wal().unwrap().checksum, ChecksumKind::Crc32c);
+
+            btree.compact().unwrap();
+
+            assert_eq!(fs::metadata(&file_path).unwrap().len(), 16 + 3 * 12);
+            assert_eq!(btree.scrub_tree_file().unwrap(), TreeScrub{checksum: ChecksumKind::Crc32c, records: 3, corrupt: vec![]});
+        }
+
+        // a flipped bit in record 1's value still decodes, but fails its checksum
+        let mut bytes = fs::read(&file_path).unwrap();
+
+        bytes[16 + 12 + 7] ^= 1;
+        fs::write(&file_path, &bytes).unwrap();
+
+        {
+            let btree = BTree::<u32, u32>::new(&file_path, 4, 4).unwrap();
+
+            assert_eq!(btree.scrub_tree_file().unwrap(), TreeScrub{checksum: ChecksumKind::Crc32c, records: 3, corrupt: vec![1]});
+            assert_eq!(*btree.get(&1).unwrap_err().downcast::<BTreeError>().unwrap(), BTreeError::CorruptTreeRecord{index: 1});
+        }
+
+        fs::remove_file(&file_path).unwrap();
+        fs::remove_file(&wal_path).unwrap();
+
+        // a tree file without checksums has no stamp, as before checksums
+        {
+            let mut btree = BTreeBuilder::new(&file_path, 4, 4).without_checksums().open::<u32, u32>().unwrap();
+
+            for i in 0..3 {
+                btree.insert(i, i).unwrap();
+            }
+
+            btree.compact().unwrap();
+
+            assert_eq!(fs::metadata(&file_path).unwrap().len(), 3 * 8);
+        }
+
+        let mut btree = BTreeBuilder::new(&file_path, 4, 4).checksum(ChecksumKind::Xxh3).open::<u32, u32>().unwrap();
+
+        assert_eq!(btree.scrub_tree_file().unwrap(), TreeScrub{checksum: ChecksumKind::None, records: 3, corrupt: vec![]});
+        assert_eq!(btree.get(&2).unwrap(), Some([2].iter().cloned().collect()));
+
+        btree.insert(3, 3).unwrap();
+
+        // the emptied WAL, and the next tree file, take the checksum asked for
+        assert_eq!(btree.wal_file.size().unwrap(), 12 + 8);
+        assert_eq!(btree.scrub_wal().unwrap().checksum, ChecksumKind::Xxh3);
+
+        btree.compact().unwrap();
+
+        assert_eq!(fs::metadata(&file_path).unwrap().len(), 16 + 4 * (8 + 8));
+        assert_eq!(btree.scrub_tree_file().unwrap(), TreeScrub{checksum: ChecksumKind::Xxh3, records: 4, corrupt: vec![]});
+        assert_eq!(btree.get(&3).unwrap(), Some([3].iter().cloned().collect()));
+
+        // the stamp holds the record size, so opening with another one is caught
+        assert_eq!(*OnDiskBTree::<u32, u32>::open_read_only(&file_path, 4, 8).err().unwrap().downcast::<BTreeError>().unwrap(),
+                   BTreeError::TreeRecordSizeMismatch{expected: 12, found: 8});
+    }
+
+    #[test]
+    fn disk_full() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u32, u32>::new(&file_path, 4, 4).unwrap();
+
+        for i in 0..100 {
+            btree.insert(i, i).unwrap();
+        }
+
+        // a full disk turns inserts away, without the tree taking them in
+        btree.wal_file.set_disk(Box::new(FullDisk{fail_after: None, space: Some(4)}));
+
+        let e = btree.insert(100, 100).unwrap_err();
+
+        assert_eq!(*e.downcast::<BTreeError>().unwrap(), BTreeError::NoSpace{needed: 16, available: 4});
+        assert_eq!(btree.get(&100).unwrap(), None);
+
+        // and a compaction that wouldn't fit isn't started
+        let e = btree.compact().unwrap_err();
+
+        assert_eq!(*e.downcast::<BTreeError>().unwrap(), BTreeError::NoSpace{needed: 16 + 100 * 12, available: 4});
+        assert!(fs::metadata(file_path.to_owned() + ".new").is_err());
+        assert_eq!(btree.get(&99).unwrap().unwrap().len(), 1);
+
+        // once space is freed, the same writes go through
+        btree.wal_file.set_disk(Box::new(RealDisk));
+        btree.insert(100, 100).unwrap();
+        btree.compact().unwrap();
+
+        let btree = BTree::<u32, u32>::new(&file_path, 4, 4).unwrap();
+
+        for i in 0..101 {
+            assert!(btree.get(&i).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn compact_parallel() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+        let parallel_path = file_path.to_owned() + "_parallel";
+
+        let mut btree = BTree::<u32, u32>::new(&file_path, 4, 4).unwrap();
+        let mut parallel = BTreeBuilder::new(&parallel_path, 4, 4).compaction_threads(4).open::<u32, u32>().unwrap();
+
+        // while the tree file is empty the mem_tree's keys are split on
+        for i in 0..900 {
+            btree.insert(i * 7 % 1000, i).unwrap();
+            parallel.insert(i * 7 % 1000, i).unwrap();
+        }
+
+        let stats = btree.compact().unwrap();
+
+        assert_eq!(parallel.compact_parallel().unwrap().output_records, stats.output_records);
+
+        for i in 0..900 {
+            btree.insert(i, i * 2).unwrap();
+            parallel.insert(i, i * 2).unwrap();
+
+            if i % 3 == 0 {
+                btree.delete(i * 7 % 1000, i).unwrap();
+                parallel.delete(i * 7 % 1000, i).unwrap();
+            }
+        }
+
+        let stats = btree.compact().unwrap();
+        let parallel_stats = parallel.compact_parallel().unwrap();
+
+        assert_eq!((parallel_stats.input_mem_records, parallel_stats.input_disk_records, parallel_stats.output_records, parallel_stats.tombstones_dropped),
+                   (stats.input_mem_records, stats.input_disk_records, stats.output_records, stats.tombstones_dropped));
+        assert!(fs::read(&file_path).unwrap() == fs::read(&parallel_path).unwrap());
+        assert_eq!(btree.content_hash().unwrap(), parallel.content_hash().unwrap());
+
+        for i in 0..5 {
+            assert!(fs::metadata(format!("{}.new.{}", parallel_path, i)).is_err());
+        }
+
+        // and an empty tree
+        let empty_path = file_path.to_owned() + "_empty";
+        let mut empty = BTreeBuilder::new(&empty_path, 4, 4).compaction_threads(4).open::<u32, u32>().unwrap();
+
+        assert_eq!(empty.compact_parallel().unwrap().output_records, 0);
+    }
+
+    #[test]
+    fn watch_range() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u32, u32>::new(&file_path, 4, 4).unwrap();
+        let low = btree.watch_range(..10);
+        let middle = btree.watch_range(5..=20);
+
+        drop(btree.watch_range(..));
+
+        btree.insert(1, 1).unwrap();
+        btree.insert(7, 2).unwrap();
+        btree.insert(30, 3).unwrap();
+        btree.delete(7, 2).unwrap();
+
+        let mut batch = WriteBatch::new();
+
+        batch.insert(20, 4);
+        batch.insert(21, 5);
+        btree.apply(&batch).unwrap();
+        btree.delete_batch(vec![1]).unwrap();
+
+        assert_eq!(low.try_iter().collect::<Vec<_>>(), vec![WriteEvent::Insert{key: 1, value: 1},
+                                                           WriteEvent::Insert{key: 7, value: 2},
+                                                           WriteEvent::Delete{key: 7, value: 2},
+                                                           WriteEvent::Delete{key: 1, value: 1}]);
+        assert_eq!(middle.try_iter().collect::<Vec<_>>(), vec![WriteEvent::Insert{key: 7, value: 2},
+                                                              WriteEvent::Delete{key: 7, value: 2},
+                                                              WriteEvent::Insert{key: 20, value: 4}]);
+
+        // a hung up watcher is dropped at the next write in its range
+        assert_eq!(btree.watchers.len(), 2);
+
+        drop(low);
+        btree.insert(2, 6).unwrap();
+
+        assert_eq!(btree.watchers.len(), 1);
+    }
+
+    #[test]
+    fn compaction_hooks() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let hook_events = events.clone();
+
+        btree.register_compaction_hook(move |event| hook_events.lock().unwrap().push(event));
+
+        for i in 0..10 {
+            btree.insert(i, i).unwrap();
+        }
+
+        let wal_bytes = btree.wal_file.size().unwrap();
+
+        btree.compact().unwrap();
+
+        for i in 0..5 {
+            btree.delete(i, i).unwrap();
+        }
+
+        btree.compact().unwrap();
+
+        let events = events.lock().unwrap();
+
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0], CompactionEvent::BeforeCompaction{wal_bytes: wal_bytes});
+
+        // WAL records are bigger than tree file records, so both compactions shrink what is on disk
+        let freed: Vec<u64> = events.iter().map(|event| match *event {
+            CompactionEvent::AfterCompaction{bytes_freed, ..} => bytes_freed,
+            CompactionEvent::BeforeCompaction{..} => 0
+        }).collect();
+
+        assert!(freed[1] > 0 && freed[3] > 0);
+        assert!(match events[2] { CompactionEvent::BeforeCompaction{..} => true, _ => false });
+    }
+
+    /// Claims to be less than everything, itself included
+    #[derive(RustcEncodable, RustcDecodable, Clone, PartialEq, Eq)]
+    struct NeverEqual(u8);
+
+    impl PartialOrd for NeverEqual {
+        fn partial_cmp(&self, other: &NeverEqual) -> Option<Ordering> {
+            return Some(self.cmp(other));
+        }
+    }
+
+    impl Ord for NeverEqual {
+        fn cmp(&self, _other: &NeverEqual) -> Ordering {
+            return Ordering::Less;
+        }
+    }
+
+    /// Each beats the one before it, round in a circle, so the order isn't transitive
+    #[derive(RustcEncodable, RustcDecodable, Clone, PartialEq, Eq)]
+    struct RockPaperScissors(u8);
+
+    impl PartialOrd for RockPaperScissors {
+        fn partial_cmp(&self, other: &RockPaperScissors) -> Option<Ordering> {
+            return Some(self.cmp(other));
+        }
+    }
+
+    impl Ord for RockPaperScissors {
+        fn cmp(&self, other: &RockPaperScissors) -> Ordering {
+            if self.0 == other.0 {
+                return Ordering::Equal;
+            }
+
+            return if (self.0 + 1) % 3 == other.0 { Ordering::Less } else { Ordering::Greater };
+        }
+    }
+
+    #[test]
+    fn check_comparator() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        {
+            let mut btree: BTree<NeverEqual, u8> = BTreeBuilder::new(&file_path, 1, 1).check_comparator(true).open().unwrap();
+            let wal_size = btree.wal_file.size().unwrap();
+
+            let err = btree.insert(NeverEqual(1), 1).unwrap_err();
+
+            assert_eq!(err.downcast_ref::<BTreeError>(), Some(&BTreeError::ComparatorInconsistency));
+            assert_eq!(btree.wal_file.size().unwrap(), wal_size);
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree: BTree<RockPaperScissors, u8> = BTreeBuilder::new(&file_path, 1, 1).check_comparator(true).open().unwrap();
+
+        // each pair is ordered one way or the other, so the inserts get through
+        for i in 0..3 {
+            btree.insert(RockPaperScissors(i), 1).unwrap();
+        }
+
+        let err = btree.compact().unwrap_err();
+
+        assert_eq!(err.downcast_ref::<BTreeError>(), Some(&BTreeError::ComparatorInconsistency));
+        assert_eq!(btree.tree_file.count().unwrap(), 0);
+        assert!(fs::metadata(file_path.to_owned() + ".new").is_err());
+    }
+
+    #[test]
+    fn range_count_approx() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        {
+            let mut btree: BTree<u32, u8> = BTreeBuilder::new(&file_path, 4, 1).write_concern(WriteConcern::None).open().unwrap();
+
+            assert_eq!(btree.range_count_approx(..), 0);
+
+            // small enough to be counted exactly
+            for i in 0..100 {
+                btree.insert(i, 1).unwrap();
+            }
+
+            btree.compact().unwrap();
+
+            assert_eq!(btree.range_count_approx(10..20), 10);
+            assert_eq!(btree.range_count_approx(..=50), 51);
+            assert_eq!(btree.range_count_approx((Bound::Excluded(98), Bound::Unbounded)), 1);
+            assert_eq!(btree.range_count_approx((Bound::Included(20), Bound::Excluded(10))), 0);
+
+            for i in 100..20000 {
+                btree.insert(i, 1).unwrap();
+            }
+
+            btree.compact().unwrap();
+        }
+
+        // sampled again on open
+        let mut btree = BTree::<u32, u8>::new(&file_path, 4, 1).unwrap();
+
+        for &(start, end) in &[(0, 20000), (1000, 3000), (5000, 6000), (19000, 30000)] {
+            let estimate = btree.range_count_approx(start..end) as f64;
+            let actual = (::std::cmp::min(end, 20000) - start) as f64;
+
+            assert!((estimate - actual).abs() <= actual * 0.1, "{}..{}: {} vs {}", start, end, estimate, actual);
+        }
+
+        // the mem_tree is counted exactly
+        let before = btree.range_count_approx(30000..);
+
+        btree.insert(30000, 1).unwrap();
+        btree.insert(30001, 1).unwrap();
+
+        assert_eq!(btree.range_count_approx(30000..), before + 2);
+    }
+
+    #[test]
+    fn prefetch_range() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        // nothing on disk yet
+        btree.prefetch_range(..);
+
+        for i in 0..10 {
+            btree.insert(i, i).unwrap();
+        }
+
+        btree.compact().unwrap();
+
+        btree.prefetch_range(2..5);
+        btree.prefetch_range(8..);
+        btree.prefetch_range((Bound::Excluded(9), Bound::Unbounded));
+        btree.prefetch_range((Bound::Included(5), Bound::Excluded(2)));
+
+        assert!(btree.get(&3).unwrap().is_some());
+    }
+
+    #[test]
+    fn compact_on_open() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        {
+            let mut btree = BTree::<u32, u32>::new(&file_path, 4, 4).unwrap();
+
+            for i in 0..10 {
+                btree.insert(i, i).unwrap();
+            }
+        }
+
+        // 10 records of 16 bytes, with their checksums
+        let btree = BTreeBuilder::new(&file_path, 4, 4).compact_on_open_if_wal_exceeds(160).open::<u32, u32>().unwrap();
+
+        assert!(btree.last_compaction().is_none());
+        drop(btree);
+
+        let btree = BTreeBuilder::new(&file_path, 4, 4).lazy_replay(true).compact_on_open_if_wal_exceeds(159).open::<u32, u32>().unwrap();
+
+        assert_eq!(btree.last_compaction().unwrap().output_records, 10);
+        assert_eq!(btree.wal_file.size().unwrap(), 0);
+        assert_eq!(btree.get(&9).unwrap(), Some([9].iter().cloned().collect()));
+    }
+
+    #[test]
+    fn encoded_sizes() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        // strings & vecs have an 8 byte length in front
+        assert_eq!(encode_size(&7u32).unwrap(), 4);
+        assert_eq!(encode_size(&"hello".to_owned()).unwrap(), 13);
+        assert_eq!(encode_size(&vec![1u16, 2, 3]).unwrap(), 14);
+
+        let keys = vec!["a".to_owned(), "the longest key".to_owned(), "".to_owned()];
+        let key_size = max_encoded_size(&keys).unwrap();
+
+        assert_eq!(key_size, 23);
+        assert_eq!(max_encoded_size::<u8>(&[]).unwrap(), 0);
+
+        // the longest key just fits
+        let mut btree = BTree::<String, u8>::new(&file_path, key_size, 1).unwrap();
+
+        for key in keys {
+            btree.insert(key, 1).unwrap();
+        }
+
+        assert!(btree.insert("a key longer than those".to_owned(), 1).is_err());
+    }
+
+    #[test]
+    fn transform_into() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+        let dest_path = temp_dir.path().join("dest.btr").to_string_lossy().into_owned();
+
+        let mut btree = BTree::<u32, u32>::new(&file_path, 4, 4).unwrap();
+
+        for i in 0..10 {
+            btree.insert(i, i).unwrap();
+        }
+
+        btree.compact().unwrap();
+        btree.insert(10, 10).unwrap();
+        btree.insert(4, 40).unwrap();
+        btree.delete(3, 3).unwrap();
+
+        // keys out of order fail, leaving nothing behind
+        let dest = BTreeBuilder::new(&dest_path, 4, 8);
+
+        assert_eq!(*btree.transform_into::<u32, u64, _>(dest, |key, value| Some((10 - key, value as u64)), None).err().unwrap().downcast::<BTreeError>().unwrap(),
+                   BTreeError::KeysOutOfOrder);
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 2);
+
+        // even keys, as strings, where a key's values come out of order
+        let dest = BTreeBuilder::new(&dest_path, 16, 8);
+        let even = btree.transform_into(dest, |key, value| if key % 2 == 0 { Some((format!("{:02}", key), 100 - value as u64)) } else { None }, None).unwrap();
+
+        assert_eq!(even.to_sorted_vec().unwrap(), vec![("00".to_owned(), [100].iter().cloned().collect()),
+                                                      ("02".to_owned(), [98].iter().cloned().collect()),
+                                                      ("04".to_owned(), [60, 96].iter().cloned().collect()),
+                                                      ("06".to_owned(), [94].iter().cloned().collect()),
+                                                      ("08".to_owned(), [92].iter().cloned().collect()),
+                                                      ("10".to_owned(), [90].iter().cloned().collect())]);
+        assert_eq!(even.tree_file.count().unwrap(), 7);
+        assert_eq!(even.wal_file.count().unwrap(), 0);
+
+        // the tree already exists
+        assert!(btree.transform_into::<String, u64, _>(BTreeBuilder::new(&dest_path, 16, 8), |key, value| Some((key.to_string(), value as u64)), Some(32)).is_err());
+        drop(even);
+        fs::remove_file(&dest_path).unwrap();
+        fs::remove_file(dest_path.to_owned() + ".wal").unwrap();
+
+        // sorted on disk, 4 pairs a run, merging pairs mapped together
+        let dest = BTreeBuilder::new(&dest_path, 4, 4).merge_fan_in(2);
+        let reversed = btree.transform_into(dest, |key, value| Some((20 - key / 2, value % 3)), Some(32)).unwrap();
+
+        assert_eq!(reversed.tree_file.count().unwrap(), 10);
+        assert_eq!(reversed.get(&18).unwrap(), Some([1, 2].iter().cloned().collect()));
+        assert_eq!(reversed.get(&15).unwrap(), Some([1].iter().cloned().collect()));
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 4);
+    }
+
+    #[test]
+    fn bulk_load_unsorted() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        // 30 pairs in no order, with 5 of them given again in later runs
+        let mut pairs: Vec<(u32, u32)> = (0..30).map(|i| ((i * 7) % 11, (i * 13) % 5)).collect();
+        let expected: BTreeSet<(u32, u32)> = pairs.iter().cloned().collect();
+
+        pairs.extend(pairs[0..5].to_vec());
+        assert_eq!(expected.len(), 30);
+
+        // runs of 4 pairs: 9 runs, merged 3 at a time into 3 more, then into the tree file
+        let (btree, stats) = BTreeBuilder::new(&file_path, 4, 4).merge_fan_in(3).bulk_load_unsorted::<u32, u32, _>(pairs, 32).unwrap();
+
+        assert_eq!(stats.records, expected.len() as u64);
+        assert_eq!((stats.runs_created, stats.merge_passes), (12, 2));
+        assert_eq!(btree.tree_file.count().unwrap(), expected.len() as u64);
+        assert_eq!(btree.to_sorted_vec().unwrap().into_iter().flat_map(|(key, values)| values.into_iter().map(move |value| (key, value))).collect::<BTreeSet<_>>(),
+                   expected);
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 2);
+
+        // not over a tree that's there
+        assert!(BTreeBuilder::new(&file_path, 4, 4).bulk_load_unsorted::<u32, u32, _>(vec![(1, 1)], 32).is_err());
+        drop(btree);
+
+        // nothing to load is an empty tree
+        let empty_path = temp_dir.path().join("empty.btr").to_string_lossy().into_owned();
+        let (btree, stats) = BTreeBuilder::new(&empty_path, 4, 4).bulk_load_unsorted::<u32, u32, _>(vec![], 32).unwrap();
+
+        assert_eq!((stats.records, stats.runs_created, stats.merge_passes), (0, 0, 0));
+        assert!(btree.to_sorted_vec().unwrap().is_empty());
+    }
+
+    #[test]
+    fn node_path_for_key() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u32, u32>::new(&file_path, 4, 4).unwrap();
+
+        assert!(btree.node_path_for_key(&1).unwrap().is_empty());
+
+        for i in 0..7 {
+            btree.insert(i, i).unwrap();
+        }
+
+        btree.insert(3, 30).unwrap();
+        btree.compact().unwrap();
+
+        // records 0 1 2 3 3 4 5 6 are 12 bytes each, after the stamp: probes at records 4, 2 & 3, then 3's records & the one after
+        assert_eq!(btree.node_path_for_key(&3).unwrap(), vec![64, 40, 52, 52, 64, 76]);
+        assert_eq!(btree.node_path_for_key(&9).unwrap(), vec![64, 88, 100]);
+    }
+
+    #[test]
+    fn lazy_replay() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        {
+            let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+            btree.insert(1, 1).unwrap();
+            btree.delete(1, 1).unwrap();
+            btree.insert(2, 1).unwrap();
+        }
+
+        let mut btree: BTree<u8, u8> = BTreeBuilder::new(&file_path, 1, 1).lazy_replay(true).open().unwrap();
+
+        assert!(btree.mem_tree.size() == 0);
+
+        // writes made before the replay land after the old ones to the same keys
+        btree.insert(1, 1).unwrap();
+        btree.delete(2, 1).unwrap();
+        btree.insert(3, 1).unwrap();
+
+        let err = btree.get(&1).unwrap_err();
+
+        assert_eq!(err.downcast_ref::<BTreeError>(), Some(&BTreeError::NotReplayed));
+
+        btree.ensure_replayed().unwrap();
+
+        assert!(btree.get(&1).unwrap().is_some());
+        assert_eq!(btree.get(&2).unwrap(), None);
+        assert!(btree.get(&3).unwrap().is_some());
+
+        // a cursor or a compaction replays by itself
+        let mut btree: BTree<u8, u8> = BTreeBuilder::new(&file_path, 1, 1).lazy_replay(true).open().unwrap();
+
+        assert_eq!(btree.cursor().seek_to_first().unwrap(), Some((&1, &1)));
+
+        let mut btree: BTree<u8, u8> = BTreeBuilder::new(&file_path, 1, 1).lazy_replay(true).open().unwrap();
+
+        btree.compact().unwrap();
+
+        assert!(btree.tree_file.contains_key(&1).unwrap());
+        assert!(! btree.tree_file.contains_key(&2).unwrap());
+        assert!(btree.tree_file.contains_key(&3).unwrap());
+    }
+
+    #[test]
+    fn small_tree() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        {
+            let mut btree: BTree<u8, u8> = BTreeBuilder::new(&file_path, 1, 1).small_tree_threshold(3).open().unwrap();
+
+            btree.insert(1, 1).unwrap();
+            btree.insert(2, 1).unwrap();
+            btree.compact().unwrap();
+
+            assert!(btree.all_in_memory);
+            assert_eq!(btree.mem_tree.size(), 2);
+        }
+
+        let mut btree: BTree<u8, u8> = BTreeBuilder::new(&file_path, 1, 1).small_tree_threshold(3).open().unwrap();
+
+        // loaded on open
+        assert!(btree.all_in_memory);
+        assert_eq!(btree.mem_tree.size(), 2);
+
+        // reads don't touch the tree file, so emptying it behind the tree's back changes nothing
+        OpenOptions::new().write(true).open(&file_path).unwrap().set_len(0).unwrap();
+
+        assert_eq!(btree.get(&1).unwrap(), Some([1].iter().cloned().collect()));
+        assert!(btree.contains_value(&1).unwrap());
+
+        // and compacting writes it all back out from memory
+        btree.delete(2, 1).unwrap();
+        btree.insert(3, 1).unwrap();
+
+        let stats = btree.compact().unwrap();
+
+        assert_eq!(stats.input_disk_records, 0);
+        assert_eq!(btree.tree_file.count().unwrap(), 2);
+        assert_eq!(btree.get(&2).unwrap(), None);
+
+        // growing past the threshold goes back to the tree file
+        btree.insert(4, 1).unwrap();
+        btree.insert(5, 1).unwrap();
+        btree.compact().unwrap();
+
+        assert!(! btree.all_in_memory);
+        assert_eq!(btree.mem_tree.size(), 0);
+        assert_eq!(btree.get(&5).unwrap(), Some([1].iter().cloned().collect()));
+
+        // and shrinking under it comes back to memory
+        btree.delete(4, 1).unwrap();
+        btree.delete(5, 1).unwrap();
+        btree.compact().unwrap();
+
+        assert!(btree.all_in_memory);
+        assert_eq!(btree.mem_tree.size(), 2);
+    }
+
+    #[test]
+    fn lock_timeout() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+        let timeout = Duration::from_millis(20);
+
+        {
+            let _btree = BTree::<u8, u8>::open_with_lock_timeout(&file_path, timeout, 1, 1).unwrap();
+
+            let start = Instant::now();
+            let err = BTree::<u8, u8>::open_with_lock_timeout(&file_path, timeout, 1, 1).err().unwrap();
+
+            assert_eq!(err.downcast_ref::<BTreeError>(), Some(&BTreeError::LockTimeout));
+            assert!(start.elapsed() >= timeout);
+        }
+
+        // dropping the first one released the lock
+        { BTree::<u8, u8>::open_with_lock_timeout(&file_path, timeout, 1, 1).unwrap(); }
+    }
+
+    #[test]
+    fn reopen_with_different_sizes() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        { BTree::<u64, u64>::new(&file_path, 8, 8).unwrap(); }
+
+        let err = BTree::<u64, u64>::new(&file_path, 8, 16).err().unwrap();
+
+        assert_eq!(err.downcast_ref::<BTreeError>(), Some(&BTreeError::WalRecordSizeMismatch{expected: 24, found: 16}));
+    }
+
+    #[test]
+    fn unstamped_wal() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        // write a WAL the way it was done before stamps
+        {
+            let mut wal_file = RecordFile::<u8, u8>::new(&(file_path.to_owned() + ".wal"), 1, 1).unwrap();
+
+            wal_file.insert_record(&KeyValuePair{key: 2, value: 3}).unwrap();
+        }
+
+        let err = BTree::<u8, u8>::new(&file_path, 1, 1).err().unwrap();
+
+        assert_eq!(err.downcast_ref::<BTreeError>(), Some(&BTreeError::UnstampedWal));
+
+        let mut btree: BTree<u8, u8> = BTreeBuilder::new(&file_path, 1, 1).allow_unstamped_wal(true).open().unwrap();
+
+        assert!(btree.mem_tree.contains_key(&2));
+        assert!(btree.wal_file.count().unwrap() == 1);
+
+        // compacting empties the WAL and stamps it
+        btree.compact().unwrap();
+
+        { BTree::<u8, u8>::new(&file_path, 1, 1).unwrap(); }
+    }
+
+    #[test]
+    fn durable_directory() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        {
+            let mut btree: BTree<u8, u8> = BTreeBuilder::new(&file_path, 1, 1).durable_directory(true).open().unwrap();
+
+            btree.insert(1, 1).unwrap();
+            btree.compact().unwrap();
+        }
+
+        let btree: BTree<u8, u8> = BTreeBuilder::new(&file_path, 1, 1).durable_directory(true).open().unwrap();
+
+        assert!(btree.get(&1).unwrap().is_some());
+    }
+
+    #[test]
+    fn write_concern() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        assert!(BTree::<u8, u8>::new(&file_path, 1, 1).unwrap().write_concern == WriteConcern::WAL);
+
+        for write_concern in &[WriteConcern::None, WriteConcern::WAL, WriteConcern::Full] {
+            let mut btree: BTree<u8, u8> = BTreeBuilder::new(&file_path, 1, 1).write_concern(*write_concern).open().unwrap();
+
+            btree.insert(2, 3).unwrap();
+            btree.compact().unwrap();
+
+            assert!(btree.tree_file.contains_key(&2).unwrap());
+        }
+    }
+
+    #[test]
+    fn read_concern() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree: BTree<u8, u8> = BTreeBuilder::new(&file_path, 1, 1).read_concern(ReadConcern::DiskOnly).open().unwrap();
+
+        btree.insert(1, 2).unwrap();
+        btree.compact().unwrap();
+        btree.insert(1, 3).unwrap();
+
+        let disk: BTreeSet<u8> = [2].iter().cloned().collect();
+        let mem: BTreeSet<u8> = [3].iter().cloned().collect();
+        let both: BTreeSet<u8> = [2, 3].iter().cloned().collect();
+
+        assert_eq!(btree.get(&1).unwrap(), Some(disk));
+        assert!(! btree.contains_value(&3).unwrap());
+
+        btree.set_read_concern(ReadConcern::MemOnly);
+
+        assert_eq!(btree.get(&1).unwrap(), Some(mem));
+        assert!(! btree.contains_value(&2).unwrap());
+        assert!(btree.value_to_keys(&2).unwrap().is_empty());
+
+        btree.set_read_concern(ReadConcern::Merged);
 
-        remove_files(file_path); // remove files assuming it all went well
+        assert_eq!(btree.get(&1).unwrap(), Some(both));
+        assert!(btree.contains_value(&2).unwrap());
+        assert!(btree.contains_value(&3).unwrap());
     }
 }
@@ -0,0 +1,164 @@
+use ::{KeyType, ValueType, DurabilityToken, CompactionStats, WriteBatch};
+
+use multi_map::MultiMap;
+use wal_file::WALRecord;
+
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::time::Duration;
+
+/// A BTree that only lives in memory, with no tree file or WAL, for tests & caches.
+/// It has the same methods as BTree, so code can be written against either. Writes can't fail,
+/// nothing is ever on disk, and compacting does nothing. Everything is gone once it is dropped.
+pub struct MemoryOnlyBTree<K: KeyType, V: ValueType> {
+    mem_tree: MultiMap<K,V>,
+    last_lsn: u64,    // the count of writes, so tokens work as they do for a BTree
+    generation: u64,  // the count of compactions, which don't do anything
+}
+
+impl <K: KeyType, V: ValueType> MemoryOnlyBTree<K,V> {
+    pub fn new() -> MemoryOnlyBTree<K,V> {
+        return MemoryOnlyBTree{mem_tree: MultiMap::new(), last_lsn: 0, generation: 0};
+    }
+
+    /// Inserts a new key/value pair
+    pub fn insert(&mut self, key: K, value: V) -> Result<DurabilityToken, Box<Error>> {
+        self.mem_tree.insert(key, value);
+
+        return Ok(self.next_token());
+    }
+
+    /// Deletes a value from the set of values for a key
+    pub fn delete(&mut self, key: K, value: V) -> Result<DurabilityToken, Box<Error>> {
+        self.mem_tree.delete(key, value);
+
+        return Ok(self.next_token());
+    }
+
+    /// Applies all the writes in a batch, which all share one LSN
+    pub fn apply(&mut self, batch: &WriteBatch<K,V>) -> Result<DurabilityToken, Box<Error>> {
+        if batch.is_empty() {
+            return Ok(DurabilityToken(self.last_lsn));
+        }
+
+        for record in batch.records() {
+            match record.clone() {
+                WALRecord::Insert(kv) => { self.mem_tree.insert(kv.key, kv.value); },
+                WALRecord::Delete(kv) => { self.mem_tree.delete(kv.key, kv.value); },
+                _ => ()
+            }
+        }
+
+        return Ok(self.next_token());
+    }
+
+    /// Deletes every value of all the keys. Returns the number of keys that had values.
+    pub fn delete_batch<I: IntoIterator<Item=K>>(&mut self, keys: I) -> Result<u64, Box<Error>> {
+        let keys: BTreeSet<K> = keys.into_iter().collect();
+        let mut present = 0;
+
+        for key in keys {
+            let values: Vec<V> = match self.mem_tree.get(&key) {
+                Some(values) => values.cloned().collect(),
+                None => continue
+            };
+
+            for value in values {
+                self.mem_tree.delete(key.clone(), value);
+            }
+
+            present += 1;
+        }
+
+        if present > 0 {
+            self.next_token();
+        }
+
+        return Ok(present);
+    }
+
+    /// Returns the set of values for the key, or None if it has none
+    pub fn get(&self, key: &K) -> Result<Option<BTreeSet<V>>, Box<Error>> {
+        return Ok(self.mem_tree.get(key).map(|values| values.cloned().collect()));
+    }
+
+    /// Checks if any key maps to the value
+    pub fn contains_value(&self, value: &V) -> Result<bool, Box<Error>> {
+        return Ok(self.mem_tree.contains_value(value));
+    }
+
+    /// Returns, in order, all the keys that map to the value
+    pub fn value_to_keys(&self, value: &V) -> Result<Vec<K>, Box<Error>> {
+        return Ok(self.mem_tree.value_to_keys(value));
+    }
+
+    /// Does nothing, as there is nowhere to merge to
+    pub fn compact(&mut self) -> Result<CompactionStats, Box<Error>> {
+        self.generation += 1;
+
+        return Ok(CompactionStats{input_mem_records: 0,
+                                  input_disk_records: 0,
+                                  output_records: 0,
+                                  tombstones_dropped: 0,
+                                  bytes_written: 0,
+                                  duration: Duration::from_secs(0),
+                                  generation: self.generation});
+    }
+
+    /// Returns right away, as every write is as durable as it will ever be
+    pub fn wait_durable(&mut self, _token: DurabilityToken) -> Result<(), Box<Error>> {
+        Ok( () )
+    }
+
+    pub fn durable_lsn(&self) -> u64 {
+        return self.last_lsn;
+    }
+
+    fn next_token(&mut self) -> DurabilityToken {
+        self.last_lsn += 1;
+
+        return DurabilityToken(self.last_lsn);
+    }
+}
+
+impl <K: KeyType, V: ValueType> Default for MemoryOnlyBTree<K,V> {
+    fn default() -> MemoryOnlyBTree<K,V> {
+        return MemoryOnlyBTree::new();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use ::{MemoryOnlyBTree, WriteBatch};
+
+    #[test]
+    fn same_as_btree() {
+        let mut btree = MemoryOnlyBTree::<u8, u8>::new();
+
+        btree.insert(1, 1).unwrap();
+        btree.insert(1, 2).unwrap();
+        btree.insert(2, 1).unwrap();
+
+        let token = btree.delete(1, 2).unwrap();
+
+        btree.wait_durable(token).unwrap();
+
+        assert_eq!(btree.durable_lsn(), token.lsn());
+        assert_eq!(btree.get(&1).unwrap(), Some([1].iter().cloned().collect()));
+        assert_eq!(btree.value_to_keys(&1).unwrap(), vec![1, 2]);
+        assert_eq!(btree.compact().unwrap().generation, 1);
+
+        let mut batch = WriteBatch::new();
+
+        batch.insert(3, 1);
+        batch.delete(2, 1);
+
+        btree.apply(&batch).unwrap();
+
+        assert_eq!(btree.get(&2).unwrap(), None);
+        assert!(btree.contains_value(&1).unwrap());
+        assert_eq!(btree.delete_batch(vec![1, 2, 3]).unwrap(), 2);
+        assert!(! btree.contains_value(&1).unwrap());
+    }
+}
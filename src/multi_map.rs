@@ -7,6 +7,9 @@ use std::collections::btree_map::Entry::Occupied;
 use std::collections::btree_map;
 use std::collections::btree_set;
 use std::collections::btree_set::Iter;
+use std::collections::Bound::{Included, Excluded, Unbounded};
+use std::iter;
+use std::ops::RangeBounds;
 
 pub struct MultiMap<K: KeyType, V: ValueType> {
     multi_map: BTreeMap<K, BTreeSet<V>>,
@@ -25,10 +28,12 @@ impl <'a, K: KeyType, V: ValueType> MultiMap<K,V> {
     }
 
     pub fn insert(&mut self, key: K, value: V) -> usize {
-        self.count += 1;
-
         if let Some(set) = self.multi_map.get_mut(&key) {
-            set.insert(value);
+            // the set doesn't hold duplicates, so only count new values
+            if set.insert(value) {
+                self.count += 1;
+            }
+
             return self.count;
         }
         
@@ -37,6 +42,7 @@ impl <'a, K: KeyType, V: ValueType> MultiMap<K,V> {
         set.insert(value);
 
         self.multi_map.insert(key, set);
+        self.count += 1;
 
         return self.count;
     }
@@ -57,6 +63,19 @@ impl <'a, K: KeyType, V: ValueType> MultiMap<K,V> {
         }
     }
 
+    pub fn contains(&self, key: &K, value: &V) -> bool {
+        return self.multi_map.get(key).map_or(false, |set| set.contains(value));
+    }
+
+    pub fn contains_value(&self, value: &V) -> bool {
+        return self.multi_map.values().any(|set| set.contains(value));
+    }
+
+    /// Returns, in order, all the keys whose set holds the value
+    pub fn value_to_keys(&self, value: &V) -> Vec<K> {
+        return self.multi_map.iter().filter(|&(_, set)| set.contains(value)).map(|(key, _)| key.clone()).collect();
+    }
+
     /*
      * Might want to re-think this and return an Error
      * as there isn't a great way to tell the user that a
@@ -80,6 +99,79 @@ impl <'a, K: KeyType, V: ValueType> MultiMap<K,V> {
     pub fn size(&self) -> usize {
         return self.count;
     }
+
+    /// Counts the pairs whose key falls in the range
+    pub fn count_range<R: RangeBounds<K>>(&self, range: R) -> usize {
+        if is_backwards(&range) {
+            return 0;
+        }
+
+        return self.multi_map.range(range).map(|(_, set)| set.len()).sum();
+    }
+
+    /// Returns the pairs whose key falls in the range, in order
+    pub fn range_pairs<'b, R: RangeBounds<K>>(&'b self, range: R) -> Box<Iterator<Item=KeyValuePair<K,V>> + 'b> {
+        if is_backwards(&range) {
+            return Box::new(iter::empty());
+        }
+
+        return Box::new(self.multi_map.range(range).flat_map(|(key, set)| set.iter().map(move |value| KeyValuePair{key: key.clone(), value: value.clone()})));
+    }
+
+    /// Returns the keys, in order
+    pub fn keys(&self) -> btree_map::Keys<K, BTreeSet<V>> {
+        return self.multi_map.keys();
+    }
+
+    /// Returns the first pair whose key is at least `key`
+    pub fn seek(&self, key: &K) -> Option<(&K, &V)> {
+        return self.multi_map.range((Included(key), Unbounded)).next().map(|(k, set)| (k, set.iter().next().unwrap()));
+    }
+
+    /// Returns the smallest pair greater than (key, value), or the very first pair when given None
+    pub fn successor(&self, after: Option<(&K, &V)>) -> Option<(&K, &V)> {
+        let (key, value) = match after {
+            Some(kv) => kv,
+            None => return self.multi_map.iter().next().map(|(k, set)| (k, set.iter().next().unwrap()))
+        };
+
+        for (k, set) in self.multi_map.range((Included(key), Unbounded)) {
+            let found = if k == key { set.range((Excluded(value), Unbounded)).next() } else { set.iter().next() };
+
+            if let Some(v) = found {
+                return Some((k, v));
+            }
+        }
+
+        return None;
+    }
+
+    /// Returns the largest pair less than (key, value), or the very last pair when given None
+    pub fn predecessor(&self, before: Option<(&K, &V)>) -> Option<(&K, &V)> {
+        let (key, value) = match before {
+            Some(kv) => kv,
+            None => return self.multi_map.iter().next_back().map(|(k, set)| (k, set.iter().next_back().unwrap()))
+        };
+
+        for (k, set) in self.multi_map.range((Unbounded, Included(key))).rev() {
+            let found = if k == key { set.range((Unbounded, Excluded(value))).next_back() } else { set.iter().next_back() };
+
+            if let Some(v) = found {
+                return Some((k, v));
+            }
+        }
+
+        return None;
+    }
+}
+
+// BTreeMap::range panics on these rather than returning nothing
+fn is_backwards<K: Ord, R: RangeBounds<K>>(range: &R) -> bool {
+    return match (range.start_bound(), range.end_bound()) {
+        (Included(start), Included(end)) | (Included(start), Excluded(end)) | (Excluded(start), Included(end)) => start > end,
+        (Excluded(start), Excluded(end)) => start >= end,
+        _ => false
+    };
 }
 
 impl <'a, K: KeyType, V: ValueType> IntoIterator for &'a mut MultiMap<K,V> {
@@ -147,6 +239,10 @@ mod tests {
         assert!(mmap.insert(12, String::from("abc")) == 1);
         assert!(mmap.insert(23, String::from("abc")) == 2);
         assert!(mmap.insert(23, String::from("def")) == 3);
+        assert!(mmap.insert(23, String::from("def")) == 3); // already there
+
+        assert!(mmap.contains(&23, &String::from("def")));
+        assert!(! mmap.contains(&12, &String::from("def")));
 
         let mut it = mmap.into_iter();
 
@@ -204,6 +300,57 @@ mod tests {
 
         assert!(it.next() == None);
     }
-}
 
+    #[test]
+    fn test_contains_value() {
+        let mut mmap = MultiMap::<i32,String>::new();
+
+        mmap.insert(12, String::from("abc"));
+        mmap.insert(23, String::from("def"));
+
+        assert!(mmap.contains_value(&String::from("def")));
+        assert!(! mmap.contains_value(&String::from("ghi")));
+    }
 
+    #[test]
+    fn test_value_to_keys() {
+        let mut mmap = MultiMap::<i32,String>::new();
+
+        mmap.insert(23, String::from("abc"));
+        mmap.insert(12, String::from("abc"));
+        mmap.insert(23, String::from("def"));
+
+        assert!(mmap.value_to_keys(&String::from("abc")) == vec![12, 23]);
+        assert!(mmap.value_to_keys(&String::from("def")) == vec![23]);
+        assert!(mmap.value_to_keys(&String::from("ghi")).is_empty());
+    }
+
+    #[test]
+    fn test_successor_predecessor() {
+        let mut mmap = MultiMap::<i32,String>::new();
+
+        assert!(mmap.successor(None) == None);
+        assert!(mmap.predecessor(None) == None);
+
+        mmap.insert(12, String::from("abc"));
+        mmap.insert(23, String::from("abc"));
+        mmap.insert(23, String::from("def"));
+
+        let abc = String::from("abc");
+        let def = String::from("def");
+
+        assert!(mmap.successor(None) == Some((&12, &abc)));
+        assert!(mmap.successor(Some((&12, &abc))) == Some((&23, &abc)));
+        assert!(mmap.successor(Some((&23, &abc))) == Some((&23, &def)));
+        assert!(mmap.successor(Some((&23, &def))) == None);
+
+        assert!(mmap.predecessor(None) == Some((&23, &def)));
+        assert!(mmap.predecessor(Some((&23, &abc))) == Some((&12, &abc)));
+        assert!(mmap.predecessor(Some((&12, &abc))) == None);
+
+        // the pair doesn't have to be in the map
+        assert!(mmap.successor(Some((&15, &def))) == Some((&23, &abc)));
+        assert!(mmap.seek(&13) == Some((&23, &abc)));
+        assert!(mmap.seek(&24) == None);
+    }
+}
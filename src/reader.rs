@@ -0,0 +1,176 @@
+use ::{KeyType, ValueType, acquire_lock};
+use disk_btree::OnDiskBTree;
+
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs::{self, File};
+use std::time::Duration;
+
+/// Reads a tree from another process than the one writing to it. A writer never changes a tree file
+/// once it is in place: each compaction writes a whole new generation of the tree file beside it and
+/// renames it over the old one, which is atomic. So a reader always sees one whole generation, and
+/// keeps seeing it through its open handle after the writer has moved on, until it calls refresh.
+/// The WAL is changed in place, so readers leave it alone: they see the pairs compacted into the
+/// tree file, and none of the writes since. Nothing is ever written through a reader.
+///
+/// A reader holds a shared lock on the tree's lock file while it is open. Readers share it with
+/// each other, and with writers that don't lock, as is the default. A writer opened with a lock
+/// timeout wants the tree to itself, so it waits for the readers to go, and a reader can't be
+/// opened while such a writer has the tree.
+pub struct TreeReader<K: KeyType, V: ValueType> {
+    tree_file_path: String,
+    key_size: usize,
+    value_size: usize,
+    tree_file: OnDiskBTree<K,V>,
+    generation: Generation,  // which tree file the handle is to
+    _lock_file: File,        // holds the shared lock on the tree; released on drop
+}
+
+impl <K: KeyType, V: ValueType> TreeReader<K,V> {
+    /// Opens the tree file's current generation. The tree file has to exist already. Fails with
+    /// LockTimeout, without waiting, if a writer holds the tree's lock.
+    pub fn open(tree_file_path: &String, key_size: usize, value_size: usize) -> Result<TreeReader<K,V>, Box<Error>> {
+        // the tree file is checked for first, so nothing is created for a tree that isn't there
+        try!(fs::metadata(tree_file_path));
+
+        let lock_file = try!(acquire_lock(&(tree_file_path.to_owned() + ".lock"), Duration::from_secs(0), true));
+        let tree_file = try!(OnDiskBTree::open_read_only(tree_file_path, key_size, value_size));
+        let generation = try!(generation(&try!(tree_file.metadata())));
+
+        return Ok(TreeReader{tree_file_path: tree_file_path.to_owned(),
+                             key_size: key_size,
+                             value_size: value_size,
+                             tree_file: tree_file,
+                             generation: generation,
+                             _lock_file: lock_file});
+    }
+
+    /// Moves to the newest generation of the tree file, if a compaction has put one in place since
+    /// this was opened or last refreshed. Returns true if it moved.
+    pub fn refresh(&mut self) -> Result<bool, Box<Error>> {
+        let current = try!(generation(&try!(fs::metadata(&self.tree_file_path))));
+
+        if current == self.generation {
+            return Ok(false);
+        }
+
+        let tree_file = try!(OnDiskBTree::open_read_only(&self.tree_file_path, self.key_size, self.value_size));
+
+        // a compaction may have renamed another in between the metadata and the open
+        self.generation = try!(generation(&try!(tree_file.metadata())));
+        self.tree_file = tree_file;
+
+        return Ok(true);
+    }
+
+    /// Returns the key's values in the generation being read
+    pub fn get(&self, key: &K) -> Result<Option<BTreeSet<V>>, Box<Error>> {
+        let values = try!(self.tree_file.get_values(key));
+
+        return Ok(if values.is_empty() { None } else { Some(values) });
+    }
+
+    /// Returns the number of pairs in the generation being read
+    pub fn count(&self) -> Result<u64, Box<Error>> {
+        return self.tree_file.count();
+    }
+}
+
+/// Tells generations of the tree file apart: files renamed into place are new files, so different ones
+#[cfg(unix)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Generation(u64, u64);  // the device & inode
+
+#[cfg(unix)]
+fn generation(metadata: &fs::Metadata) -> Result<Generation, Box<Error>> {
+    use std::os::unix::fs::MetadataExt;
+
+    return Ok(Generation(metadata.dev(), metadata.ino()));
+}
+
+/// Without inodes, a new generation is told apart by when it was written
+#[cfg(not(unix))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Generation(::std::time::SystemTime, u64);  // modified time & size
+
+#[cfg(not(unix))]
+fn generation(metadata: &fs::Metadata) -> Result<Generation, Box<Error>> {
+    return Ok(Generation(try!(metadata.modified()), metadata.len()));
+}
+
+
+#[cfg(test)]
+mod tests {
+    use testutil::TempDir;
+    use ::{BTree, BTreeBuilder, BTreeError, TreeReader};
+
+    use std::fs;
+    use std::time::Duration;
+
+    #[test]
+    fn reads_whole_generations() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        // nothing is created for a tree that isn't there
+        assert!(TreeReader::<u32, u32>::open(&file_path, 4, 4).is_err());
+        assert!(fs::metadata(&file_path).is_err());
+
+        let mut writer = BTree::<u32, u32>::new(&file_path, 4, 4).unwrap();
+
+        writer.insert(1, 1).unwrap();
+        writer.compact().unwrap();
+        writer.insert(2, 2).unwrap();
+
+        // only what is compacted is seen
+        let mut reader = TreeReader::<u32, u32>::open(&file_path, 4, 4).unwrap();
+
+        assert_eq!(reader.get(&1).unwrap(), Some([1].iter().cloned().collect()));
+        assert_eq!(reader.get(&2).unwrap(), None);
+        assert!(! reader.refresh().unwrap());
+
+        writer.delete(1, 1).unwrap();
+        writer.compact().unwrap();
+
+        // the old generation is still readable until the reader refreshes
+        assert_eq!(reader.get(&1).unwrap(), Some([1].iter().cloned().collect()));
+        assert!(reader.refresh().unwrap());
+        assert_eq!(reader.get(&1).unwrap(), None);
+        assert_eq!(reader.get(&2).unwrap(), Some([2].iter().cloned().collect()));
+        assert_eq!(reader.count().unwrap(), 1);
+    }
+
+    #[test]
+    fn shares_the_lock() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut writer = BTree::<u32, u32>::new(&file_path, 4, 4).unwrap();
+
+        writer.insert(1, 1).unwrap();
+        writer.compact().unwrap();
+
+        // readers share the lock with each other, and with a writer that doesn't lock
+        let reader = TreeReader::<u32, u32>::open(&file_path, 4, 4).unwrap();
+        let other_reader = TreeReader::<u32, u32>::open(&file_path, 4, 4).unwrap();
+
+        assert_eq!(other_reader.get(&1).unwrap(), Some([1].iter().cloned().collect()));
+        drop(writer);
+
+        // but a writer that locks waits for them all to go
+        let open_locked = || BTreeBuilder::new(&file_path, 4, 4).lock_timeout(Duration::from_millis(20)).open::<u32, u32>();
+
+        assert_eq!(*open_locked().err().unwrap().downcast::<BTreeError>().unwrap(), BTreeError::LockTimeout);
+        drop(reader);
+        assert_eq!(*open_locked().err().unwrap().downcast::<BTreeError>().unwrap(), BTreeError::LockTimeout);
+        drop(other_reader);
+
+        let writer = open_locked().unwrap();
+
+        // and no reader opens while one has the tree
+        assert_eq!(*TreeReader::<u32, u32>::open(&file_path, 4, 4).err().unwrap().downcast::<BTreeError>().unwrap(), BTreeError::LockTimeout);
+        drop(writer);
+
+        assert!(TreeReader::<u32, u32>::open(&file_path, 4, 4).is_ok());
+    }
+}
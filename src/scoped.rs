@@ -0,0 +1,250 @@
+use ::{BTree, KeyType, ValueType, DurabilityToken, Cursor};
+
+use std::collections::{BTreeSet, Bound};
+use std::collections::Bound::{Included, Excluded, Unbounded};
+use std::error::Error;
+use std::ops::RangeBounds;
+
+/// Keys that can be split into a prefix and the rest. All the keys starting with
+/// a prefix must sort next to each other, right after the prefix itself.
+pub trait PrefixKey: KeyType {
+    /// Returns the key with the prefix in front of it
+    fn with_prefix(&self, prefix: &Self) -> Self;
+
+    /// Returns the rest of the key after the prefix, None if it doesn't start with the prefix
+    fn strip_prefix(&self, prefix: &Self) -> Option<Self>;
+}
+
+impl PrefixKey for String {
+    fn with_prefix(&self, prefix: &String) -> String {
+        return prefix.to_owned() + self;
+    }
+
+    fn strip_prefix(&self, prefix: &String) -> Option<String> {
+        return if self.starts_with(prefix.as_str()) { Some(self[prefix.len()..].to_owned()) } else { None };
+    }
+}
+
+impl PrefixKey for Vec<u8> {
+    fn with_prefix(&self, prefix: &Vec<u8>) -> Vec<u8> {
+        let mut key = prefix.clone();
+
+        key.extend_from_slice(self);
+
+        return key;
+    }
+
+    fn strip_prefix(&self, prefix: &Vec<u8>) -> Option<Vec<u8>> {
+        return if self.starts_with(prefix) { Some(self[prefix.len()..].to_vec()) } else { None };
+    }
+}
+
+/// A view of the keys in a BTree that start with a prefix, see BTree::scoped.
+/// Keys going in get the prefix put in front, and keys coming out have it taken off,
+/// so there is no key that can reach outside the prefix. Note that scopes whose prefixes
+/// are prefixes of one another ("a" and "ab") overlap, so end prefixes with a separator.
+pub struct ScopedTree<'a, K: PrefixKey + 'a, V: ValueType + 'a> {
+    tree: &'a mut BTree<K,V>,
+    prefix: K,
+}
+
+/// Walks the pairs of a ScopedTree in order, ending at the end of the range or the first error
+pub struct ScopedIter<'a, K: PrefixKey + 'a, V: ValueType + 'a> {
+    cursor: Cursor<'a,K,V>,
+    prefix: &'a K,
+    start: Bound<K>,  // the bounds of the range, with the prefix on
+    end: Bound<K>,
+    started: bool,
+    done: bool,  // None has been returned; the cursor would wrap back around to the first key
+}
+
+impl <'a, K: PrefixKey, V: ValueType> ScopedTree<'a,K,V> {
+    pub fn new(tree: &'a mut BTree<K,V>, prefix: K) -> ScopedTree<'a,K,V> {
+        return ScopedTree{tree: tree, prefix: prefix};
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Result<DurabilityToken, Box<Error>> {
+        return self.tree.insert(key.with_prefix(&self.prefix), value);
+    }
+
+    pub fn delete(&mut self, key: K, value: V) -> Result<DurabilityToken, Box<Error>> {
+        return self.tree.delete(key.with_prefix(&self.prefix), value);
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<BTreeSet<V>>, Box<Error>> {
+        return self.tree.get(&key.with_prefix(&self.prefix));
+    }
+
+    /// Returns an iterator over the pairs in the scope, with the prefix taken off the keys
+    pub fn iter(&mut self) -> ScopedIter<K,V> {
+        return self.range(..);
+    }
+
+    /// Returns an iterator over the pairs whose keys are in the range, with the prefix taken off the keys
+    pub fn range<R: RangeBounds<K>>(&mut self, range: R) -> ScopedIter<K,V> {
+        let prefix = &self.prefix;
+        let with_prefix = |bound: Bound<&K>| match bound {
+            Included(key) => Included(key.with_prefix(prefix)),
+            Excluded(key) => Excluded(key.with_prefix(prefix)),
+            Unbounded => Unbounded
+        };
+
+        return ScopedIter{start: with_prefix(range.start_bound()),
+                          end: with_prefix(range.end_bound()),
+                          cursor: Cursor::new(self.tree),
+                          prefix: prefix,
+                          started: false,
+                          done: false};
+    }
+}
+
+impl <'a, K: PrefixKey, V: ValueType> ScopedIter<'a,K,V> {
+    /// Moves the cursor to the next pair in the range, if there is one
+    fn advance(&mut self) -> Result<Option<(K,V)>, Box<Error>> {
+        if self.started {
+            // an unpositioned cursor would step to the first pair in the tree, outside the scope
+            if self.cursor.current().is_none() {
+                return Ok(None);
+            }
+
+            return Ok(try!(self.cursor.move_next()).map(|(key, value)| (key.clone(), value.clone())));
+        }
+
+        self.started = true;
+
+        let first = match self.start {
+            Included(ref start) | Excluded(ref start) => try!(self.cursor.seek(start)).map(|(key, value)| (key.clone(), value.clone())),
+            Unbounded => try!(self.cursor.seek(self.prefix)).map(|(key, value)| (key.clone(), value.clone()))
+        };
+
+        // the cursor walks pairs, so every value of an excluded start key has to be stepped over
+        let mut pair = first;
+
+        while let (&Some((ref key, _)), &Excluded(ref start)) = (&pair, &self.start) {
+            if key != start {
+                break;
+            }
+
+            pair = try!(self.cursor.move_next()).map(|(key, value)| (key.clone(), value.clone()));
+        }
+
+        return Ok(pair);
+    }
+}
+
+impl <'a, K: PrefixKey, V: ValueType> Iterator for ScopedIter<'a,K,V> {
+    type Item = (K,V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let (key, value) = match self.advance() {
+            Ok(Some(pair)) => pair,
+            _ => {
+                self.done = true;
+                return None;
+            }
+        };
+
+        let in_range = match self.end {
+            Included(ref end) => key <= *end,
+            Excluded(ref end) => key < *end,
+            Unbounded => true
+        };
+
+        match key.strip_prefix(self.prefix) {
+            Some(key) if in_range => Some((key, value)),
+            _ => {
+                self.done = true;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(unused_must_use)]
+mod tests {
+    use testutil::TempDir;
+    use ::BTree;
+
+    use std::collections::Bound::{Excluded, Unbounded};
+
+    #[test]
+    fn scopes_are_separate() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<String, u8>::new(&file_path, 20, 1).unwrap();
+
+        btree.insert("a".to_owned(), 1).unwrap();
+        btree.insert("users/z".to_owned(), 1).unwrap();
+        btree.compact().unwrap();
+        btree.insert("z".to_owned(), 1).unwrap();
+
+        {
+            let mut users = btree.scoped("users/".to_owned());
+
+            users.insert("bob".to_owned(), 2).unwrap();
+            users.insert("alice".to_owned(), 3).unwrap();
+
+            assert_eq!(users.get(&"z".to_owned()).unwrap(), Some([1].iter().cloned().collect()));
+            assert_eq!(users.get(&"a".to_owned()).unwrap(), None);
+        }
+
+        {
+            let mut posts = btree.scoped("posts/".to_owned());
+
+            posts.insert("bob".to_owned(), 4).unwrap();
+
+            assert_eq!(posts.iter().collect::<Vec<_>>(), vec![("bob".to_owned(), 4)]);
+        }
+
+        {
+            let mut users = btree.scoped("users/".to_owned());
+
+            users.delete("z".to_owned(), 1).unwrap();
+
+            assert_eq!(users.iter().collect::<Vec<_>>(), vec![("alice".to_owned(), 3), ("bob".to_owned(), 2)]);
+        }
+
+        assert_eq!(btree.get(&"users/bob".to_owned()).unwrap(), Some([2].iter().cloned().collect()));
+        assert_eq!(btree.get(&"posts/bob".to_owned()).unwrap(), Some([4].iter().cloned().collect()));
+        assert_eq!(btree.get(&"a".to_owned()).unwrap(), Some([1].iter().cloned().collect()));
+    }
+
+    #[test]
+    fn scoped_range() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<String, u8>::new(&file_path, 20, 1).unwrap();
+
+        btree.insert("a".to_owned(), 1).unwrap();
+
+        for &(key, value) in &[("alice", 1), ("bob", 2), ("bob", 3), ("carol", 4)] {
+            btree.insert("users/".to_owned() + key, value).unwrap();
+        }
+
+        btree.compact().unwrap();
+        btree.insert("users/dave".to_owned(), 5).unwrap();
+
+        let mut users = btree.scoped("users/".to_owned());
+        let pair = |key: &str, value: u8| (key.to_owned(), value);
+
+        // the scope ends the tree, and the iterator stays ended rather than going round to "a"
+        {
+            let mut iter = users.iter();
+
+            assert_eq!(iter.by_ref().count(), 5);
+            assert_eq!(iter.next(), None);
+        }
+
+        assert_eq!(users.range("bob".to_owned().."dave".to_owned()).collect::<Vec<_>>(), vec![pair("bob", 2), pair("bob", 3), pair("carol", 4)]);
+        assert_eq!(users.range((Excluded("bob".to_owned()), Unbounded)).collect::<Vec<_>>(), vec![pair("carol", 4), pair("dave", 5)]);
+        assert_eq!(users.range(..="alice".to_owned()).collect::<Vec<_>>(), vec![pair("alice", 1)]);
+        assert_eq!(users.range("e".to_owned()..).collect::<Vec<_>>(), vec![]);
+    }
+}
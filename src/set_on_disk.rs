@@ -0,0 +1,175 @@
+use ::{BTree, KeyType, DurabilityToken, Cursor, CompactionStats};
+
+use std::collections::Bound;
+use std::collections::Bound::{Included, Excluded, Unbounded};
+use std::error::Error;
+use std::ops::RangeBounds;
+
+/// A set of keys on disk: a BTree whose only value is (), with the values taken out of the API.
+/// The value takes up no room in the records, so the value size is 0.
+pub struct BTreeSetOnDisk<K: KeyType> {
+    tree: BTree<K, ()>,
+}
+
+/// Walks the keys of a BTreeSetOnDisk in order, ending at the end of the range or the first error
+pub struct SetIter<'a, K: KeyType + 'a> {
+    cursor: Cursor<'a,K,()>,
+    start: Bound<K>,
+    end: Bound<K>,
+    started: bool,
+    done: bool,  // None has been returned; the cursor would wrap back around to the first key
+}
+
+impl <K: KeyType> BTreeSetOnDisk<K> {
+    pub fn new(tree_file_path: &String, key_size: usize) -> Result<BTreeSetOnDisk<K>, Box<Error>> {
+        return Ok(BTreeSetOnDisk{tree: try!(BTree::new(tree_file_path, key_size, 0))});
+    }
+
+    /// Wraps a tree opened some other way, say with a BTreeBuilder
+    pub fn from_tree(tree: BTree<K, ()>) -> BTreeSetOnDisk<K> {
+        return BTreeSetOnDisk{tree: tree};
+    }
+
+    /// Returns the tree underneath, for everything the set doesn't wrap
+    pub fn tree(&mut self) -> &mut BTree<K, ()> {
+        return &mut self.tree;
+    }
+
+    pub fn insert(&mut self, key: K) -> Result<DurabilityToken, Box<Error>> {
+        return self.tree.insert(key, ());
+    }
+
+    pub fn remove(&mut self, key: K) -> Result<DurabilityToken, Box<Error>> {
+        return self.tree.delete(key, ());
+    }
+
+    pub fn contains(&self, key: &K) -> Result<bool, Box<Error>> {
+        return self.tree.contains_key(key);
+    }
+
+    pub fn compact(&mut self) -> Result<CompactionStats, Box<Error>> {
+        return self.tree.compact();
+    }
+
+    /// Returns an iterator over all the keys, in order
+    pub fn iter(&mut self) -> SetIter<K> {
+        return self.range(..);
+    }
+
+    /// Returns an iterator over the keys in the range, in order
+    pub fn range<R: RangeBounds<K>>(&mut self, range: R) -> SetIter<K> {
+        return SetIter{start: range.start_bound().cloned(),
+                       end: range.end_bound().cloned(),
+                       cursor: Cursor::new(&mut self.tree),
+                       started: false,
+                       done: false};
+    }
+}
+
+impl <'a, K: KeyType> SetIter<'a,K> {
+    /// Moves the cursor to the next key in the range, if there is one
+    fn advance(&mut self) -> Result<Option<K>, Box<Error>> {
+        if self.started {
+            // an unpositioned cursor would step to the first key, whatever the start of the range
+            if self.cursor.current().is_none() {
+                return Ok(None);
+            }
+
+            return Ok(try!(self.cursor.move_next()).map(|(key, _)| key.clone()));
+        }
+
+        self.started = true;
+
+        let first = match self.start {
+            Included(ref start) | Excluded(ref start) => try!(self.cursor.seek(start)).map(|(key, _)| key.clone()),
+            Unbounded => try!(self.cursor.seek_to_first()).map(|(key, _)| key.clone())
+        };
+
+        match (first, &self.start) {
+            (Some(ref key), &Excluded(ref start)) if key == start => (),
+            (first, _) => return Ok(first)
+        }
+
+        return Ok(try!(self.cursor.move_next()).map(|(key, _)| key.clone()));
+    }
+}
+
+impl <'a, K: KeyType> Iterator for SetIter<'a,K> {
+    type Item = K;
+
+    fn next(&mut self) -> Option<K> {
+        if self.done {
+            return None;
+        }
+
+        let key = match self.advance() {
+            Ok(Some(key)) => key,
+            _ => {
+                self.done = true;
+                return None;
+            }
+        };
+
+        let in_range = match self.end {
+            Included(ref end) => key <= *end,
+            Excluded(ref end) => key < *end,
+            Unbounded => true
+        };
+
+        if ! in_range {
+            self.done = true;
+            return None;
+        }
+
+        return Some(key);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use testutil::TempDir;
+    use ::BTreeSetOnDisk;
+
+    use std::collections::Bound::{Excluded, Included};
+
+    #[test]
+    fn set_on_disk() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        {
+            let mut set = BTreeSetOnDisk::<u8>::new(&file_path, 1).unwrap();
+
+            for key in &[5, 1, 3, 9] {
+                set.insert(*key).unwrap();
+            }
+
+            set.compact().unwrap();
+            set.insert(7).unwrap();
+            set.insert(3).unwrap();
+            set.remove(9).unwrap();
+        }
+
+        let mut set = BTreeSetOnDisk::<u8>::new(&file_path, 1).unwrap();
+
+        assert!(set.contains(&3).unwrap());
+        assert!(! set.contains(&9).unwrap());
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![1, 3, 5, 7]);
+        assert_eq!(set.range(3..7).collect::<Vec<_>>(), vec![3, 5]);
+        assert_eq!(set.range(..=5).collect::<Vec<_>>(), vec![1, 3, 5]);
+        assert_eq!(set.range((Excluded(3), Included(7))).collect::<Vec<_>>(), vec![5, 7]);
+        assert_eq!(set.range(8..).collect::<Vec<_>>(), vec![]);
+
+        // stays ended, rather than starting over from the first key
+        let mut range = set.range(3..);
+
+        assert_eq!(range.by_ref().collect::<Vec<_>>(), vec![3, 5, 7]);
+        assert_eq!(range.next(), None);
+
+        let mut range = set.range(8..);
+
+        assert_eq!(range.next(), None);
+        assert_eq!(range.next(), None);
+    }
+}
@@ -0,0 +1,121 @@
+use ::{BTree, Bytes, DurabilityToken, KeyType, ValueType, WriteBatch};
+
+use std::error::Error;
+use std::sync::{Mutex, MutexGuard};
+
+/// The bytes a SledCompatLayer hands back, standing in for sled's IVec
+pub type IVec = Vec<u8>;
+
+/// Wraps a BTree in the shape of sled::Tree, so code written against sled can be run against this
+/// tree with few changes. Like sled, keys have one value each: insert replaces whatever the key had,
+/// and the methods take &self, with a lock around the tree. Keys and values are byte strings; use
+/// Bytes for the key type so keys sort as their bytes do, as they do in sled.
+pub struct SledCompatLayer<K: KeyType = Bytes, V: ValueType = Vec<u8>> {
+    tree: Mutex<BTree<K,V>>,
+}
+
+impl <K, V> SledCompatLayer<K,V>
+    where K: KeyType + for<'a> From<&'a [u8]>,
+          V: ValueType + for<'a> From<&'a [u8]> + AsRef<[u8]> {
+    /// Wraps the tree. A key that already has several values is read as having its greatest one.
+    pub fn new(tree: BTree<K,V>) -> SledCompatLayer<K,V> {
+        return SledCompatLayer{tree: Mutex::new(tree)};
+    }
+
+    /// Returns the tree underneath
+    pub fn into_inner(self) -> BTree<K,V> {
+        return self.tree.into_inner().unwrap_or_else(|e| e.into_inner());
+    }
+
+    // a panic mid-write leaves the tree as it was or with the whole write, so a poisoned lock is fine to take
+    fn lock(&self) -> MutexGuard<BTree<K,V>> {
+        return self.tree.lock().unwrap_or_else(|e| e.into_inner());
+    }
+
+    /// Returns the key's value
+    pub fn get(&self, key: &[u8]) -> Result<Option<IVec>, Box<Error>> {
+        let values = try!(self.lock().get(&K::from(key)));
+
+        return Ok(values.and_then(|values| values.into_iter().next_back()).map(|value| value.as_ref().to_vec()));
+    }
+
+    /// Returns true if the key has a value
+    pub fn contains_key(&self, key: &[u8]) -> Result<bool, Box<Error>> {
+        return self.lock().contains_key(&K::from(key));
+    }
+
+    /// Sets the key's value, returning the one it replaced
+    pub fn insert(&self, key: &[u8], value: &[u8]) -> Result<Option<IVec>, Box<Error>> {
+        let key = K::from(key);
+        let mut tree = self.lock();
+        let old_values = try!(tree.get(&key)).unwrap_or_default();
+        let mut batch = WriteBatch::new();
+
+        // the old values go in the same batch, so a crash can't leave the key with none or two
+        for old_value in old_values.iter() {
+            batch.delete(key.clone(), old_value.clone());
+        }
+
+        batch.insert(key, V::from(value));
+        try!(tree.apply(&batch));
+
+        return Ok(old_values.into_iter().next_back().map(|value| value.as_ref().to_vec()));
+    }
+
+    /// Removes the key, returning its value
+    pub fn remove(&self, key: &[u8]) -> Result<Option<IVec>, Box<Error>> {
+        let key = K::from(key);
+        let mut tree = self.lock();
+        let old_value = try!(tree.get(&key)).and_then(|values| values.into_iter().next_back());
+
+        if old_value.is_some() {
+            try!(tree.delete_batch(vec![key]));
+        }
+
+        return Ok(old_value.map(|value| value.as_ref().to_vec()));
+    }
+
+    /// Waits for every write so far to be on disk, as sled's flush does
+    pub fn flush(&self) -> Result<(), Box<Error>> {
+        let mut tree = self.lock();
+        let token = DurabilityToken(tree.last_lsn);
+
+        return tree.wait_durable(token);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use testutil::TempDir;
+    use ::{BTree, Bytes, SledCompatLayer};
+
+    #[test]
+    fn like_sled() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        {
+            let tree = SledCompatLayer::new(BTree::<Bytes, Vec<u8>>::new(&file_path, 16, 16).unwrap());
+
+            assert_eq!(tree.insert(b"key", b"one").unwrap(), None);
+            assert_eq!(tree.insert(b"key", b"two").unwrap(), Some(b"one".to_vec()));
+            assert_eq!(tree.insert(&[0, 1], b"zero").unwrap(), None);
+            assert_eq!(tree.get(b"key").unwrap(), Some(b"two".to_vec()));
+            assert!(tree.contains_key(&[0, 1]).unwrap());
+
+            assert_eq!(tree.remove(&[0, 1]).unwrap(), Some(b"zero".to_vec()));
+            assert_eq!(tree.remove(&[0, 1]).unwrap(), None);
+            assert_eq!(tree.get(&[0, 1]).unwrap(), None);
+
+            tree.flush().unwrap();
+            tree.into_inner().compact().unwrap();
+        }
+
+        // one value per key, through a restart
+        let tree = SledCompatLayer::new(BTree::<Bytes, Vec<u8>>::new(&file_path, 16, 16).unwrap());
+
+        assert_eq!(tree.get(b"key").unwrap(), Some(b"two".to_vec()));
+        assert_eq!(tree.into_inner().to_sorted_vec().unwrap().len(), 1);
+    }
+}
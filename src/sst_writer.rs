@@ -0,0 +1,359 @@
+use std::error::Error;
+use std::io::Write;
+
+/// Data blocks are cut once they reach this many bytes, as LevelDB does by default
+const BLOCK_SIZE: usize = 4 * 1024;
+
+/// Entries between restart points, where a key is stored whole instead of sharing a prefix
+const RESTART_INTERVAL: usize = 16;
+
+/// Ends every table, little-endian
+const TABLE_MAGIC: u64 = 0xdb4775248b80fb57;
+
+/// The footer holds two block handles of up to 20 bytes each, padded, then the magic
+const FOOTER_SIZE: usize = 48;
+
+/// Tags the keys as plain values (type 1) written at sequence number 0, as sequence << 8 | type
+const VALUE_TAG: u64 = 1;
+
+/// Writes sorted entries out as a LevelDB table (an SSTable, the .ldb/.sst file format), uncompressed.
+/// The table is laid out as LevelDB writes it:
+/// |-------------------------------------------|
+/// | data block, then its 5 byte trailer       |
+/// |-------------------------------------------|
+/// | ...                                       |
+/// |-------------------------------------------|
+/// | metaindex block (empty) + trailer         |
+/// |-------------------------------------------|
+/// | index block + trailer                     |
+/// |-------------------------------------------|
+/// | footer: index handles, padding, magic     |
+/// |-------------------------------------------|
+/// A trailer is the compression type (0, none) & the masked CRC32C of the block and the type.
+/// Keys are stored as LevelDB's internal keys: the user key then an 8 byte sequence number & type tag.
+pub struct TableWriter<W: Write> {
+    writer: W,
+    offset: u64,             // bytes written so far
+    data_block: BlockBuilder,
+    index_block: BlockBuilder,
+    last_key: Vec<u8>,       // internal key of the last entry added
+    num_entries: u64,
+}
+
+impl <W: Write> TableWriter<W> {
+    pub fn new(writer: W) -> TableWriter<W> {
+        return TableWriter{writer: writer,
+                           offset: 0,
+                           data_block: BlockBuilder::new(RESTART_INTERVAL),
+                           index_block: BlockBuilder::new(1),
+                           last_key: Vec::new(),
+                           num_entries: 0};
+    }
+
+    /// Adds an entry; keys must be added in strictly increasing bytewise order
+    pub fn add(&mut self, key: &[u8], value: &[u8]) -> Result<(), Box<Error>> {
+        let mut internal_key = key.to_vec();
+
+        put_fixed64(&mut internal_key, VALUE_TAG);
+
+        if self.num_entries > 0 && key <= &self.last_key[..self.last_key.len() - 8] {
+            return Err(From::from("Table keys must be added in increasing order"));
+        }
+
+        self.data_block.add(&internal_key, value);
+        self.last_key = internal_key;
+        self.num_entries += 1;
+
+        if self.data_block.size() >= BLOCK_SIZE {
+            try!(self.flush_data_block());
+        }
+
+        Ok( () )
+    }
+
+    /// Writes out the last data block, the index & the footer, returning the size of the table in bytes
+    pub fn finish(mut self) -> Result<u64, Box<Error>> {
+        try!(self.flush_data_block());
+
+        let metaindex = BlockBuilder::new(RESTART_INTERVAL).finish();
+        let metaindex_handle = try!(self.write_block(&metaindex));
+
+        let index = ::std::mem::replace(&mut self.index_block, BlockBuilder::new(1)).finish();
+        let index_handle = try!(self.write_block(&index));
+
+        let mut footer = Vec::with_capacity(FOOTER_SIZE);
+
+        put_handle(&mut footer, metaindex_handle);
+        put_handle(&mut footer, index_handle);
+        footer.resize(FOOTER_SIZE - 8, 0);
+        put_fixed64(&mut footer, TABLE_MAGIC);
+
+        try!(self.writer.write_all(&footer));
+        try!(self.writer.flush());
+
+        return Ok(self.offset + footer.len() as u64);
+    }
+
+    /// Writes the data block, if it has anything in it, and indexes it by its last key
+    fn flush_data_block(&mut self) -> Result<(), Box<Error>> {
+        if self.data_block.is_empty() {
+            return Ok( () );
+        }
+
+        let block = ::std::mem::replace(&mut self.data_block, BlockBuilder::new(RESTART_INTERVAL)).finish();
+        let handle = try!(self.write_block(&block));
+        let mut encoded_handle = Vec::new();
+
+        put_handle(&mut encoded_handle, handle);
+        self.index_block.add(&self.last_key, &encoded_handle);
+
+        Ok( () )
+    }
+
+    /// Writes a finished block and its trailer, returning the block's offset & size
+    fn write_block(&mut self, block: &[u8]) -> Result<(u64, u64), Box<Error>> {
+        let mut trailer = vec![0];  // no compression
+        let crc = crc32c(crc32c(0, block), &trailer);
+
+        trailer.extend_from_slice(&[0; 4]);
+        put_fixed32_at(&mut trailer[1..], mask_crc(crc));
+
+        try!(self.writer.write_all(block));
+        try!(self.writer.write_all(&trailer));
+
+        let handle = (self.offset, block.len() as u64);
+
+        self.offset += (block.len() + trailer.len()) as u64;
+
+        return Ok(handle);
+    }
+}
+
+/// Builds a block: entries each sharing what prefix they can with the key before,
+/// then the offsets of the restart points, then how many of them there are
+struct BlockBuilder {
+    buff: Vec<u8>,
+    restarts: Vec<u32>,
+    restart_interval: usize,
+    counter: usize,        // entries since the last restart point
+    last_key: Vec<u8>,
+}
+
+impl BlockBuilder {
+    fn new(restart_interval: usize) -> BlockBuilder {
+        return BlockBuilder{buff: Vec::new(), restarts: vec![0], restart_interval: restart_interval, counter: 0, last_key: Vec::new()};
+    }
+
+    fn is_empty(&self) -> bool {
+        return self.buff.is_empty();
+    }
+
+    /// The size of the block if it were finished now
+    fn size(&self) -> usize {
+        return self.buff.len() + self.restarts.len() * 4 + 4;
+    }
+
+    fn add(&mut self, key: &[u8], value: &[u8]) {
+        let mut shared = 0;
+
+        if self.counter < self.restart_interval {
+            shared = self.last_key.iter().zip(key).take_while(|&(a, b)| a == b).count();
+        } else {
+            self.restarts.push(self.buff.len() as u32);
+            self.counter = 0;
+        }
+
+        put_varint(&mut self.buff, shared as u64);
+        put_varint(&mut self.buff, (key.len() - shared) as u64);
+        put_varint(&mut self.buff, value.len() as u64);
+        self.buff.extend_from_slice(&key[shared..]);
+        self.buff.extend_from_slice(value);
+
+        self.last_key = key.to_vec();
+        self.counter += 1;
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        for &restart in &self.restarts {
+            put_fixed32(&mut self.buff, restart);
+        }
+
+        let num_restarts = self.restarts.len() as u32;
+
+        put_fixed32(&mut self.buff, num_restarts);
+
+        return self.buff;
+    }
+}
+
+fn put_varint(buff: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buff.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+
+    buff.push(n as u8);
+}
+
+fn put_fixed32(buff: &mut Vec<u8>, n: u32) {
+    let mut bytes = [0; 4];
+
+    put_fixed32_at(&mut bytes, n);
+    buff.extend_from_slice(&bytes);
+}
+
+fn put_fixed32_at(buff: &mut [u8], n: u32) {
+    for (i, byte) in buff.iter_mut().take(4).enumerate() {
+        *byte = (n >> (8 * i)) as u8;
+    }
+}
+
+fn put_fixed64(buff: &mut Vec<u8>, n: u64) {
+    for i in 0..8 {
+        buff.push((n >> (8 * i)) as u8);
+    }
+}
+
+fn put_handle(buff: &mut Vec<u8>, (offset, size): (u64, u64)) {
+    put_varint(buff, offset);
+    put_varint(buff, size);
+}
+
+/// Extends crc, the CRC32C (Castagnoli) of what came before, over data
+pub fn crc32c(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+
+    for &byte in data {
+        crc ^= byte as u32;
+
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0x82f63b78 } else { crc >> 1 };
+        }
+    }
+
+    return !crc;
+}
+
+/// LevelDB stores CRCs rotated & offset, so a CRC of data holding CRCs doesn't come out trivially
+fn mask_crc(crc: u32) -> u32 {
+    return crc.rotate_right(15).wrapping_add(0xa282ead8);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use sst_writer::{TableWriter, crc32c, mask_crc, FOOTER_SIZE, TABLE_MAGIC};
+
+    fn get_varint(buff: &[u8], pos: &mut usize) -> u64 {
+        let mut n = 0;
+        let mut shift = 0;
+
+        loop {
+            let byte = buff[*pos];
+
+            *pos += 1;
+            n |= ((byte & 0x7f) as u64) << shift;
+            shift += 7;
+
+            if byte & 0x80 == 0 {
+                return n;
+            }
+        }
+    }
+
+    fn get_fixed32(buff: &[u8]) -> u32 {
+        return (0..4).fold(0, |n, i| n | (buff[i] as u32) << (8 * i));
+    }
+
+    /// Reads the block at the handle, checking its trailer, and returns its entries
+    fn read_block(table: &[u8], offset: usize, size: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let block = &table[offset..offset + size];
+        let trailer = &table[offset + size..offset + size + 5];
+
+        assert_eq!(trailer[0], 0);
+        assert_eq!(get_fixed32(&trailer[1..]), mask_crc(crc32c(crc32c(0, block), &trailer[..1])));
+
+        let num_restarts = get_fixed32(&block[size - 4..]) as usize;
+        let entries_end = size - 4 - num_restarts * 4;
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        let mut key: Vec<u8> = Vec::new();
+
+        while pos < entries_end {
+            let shared = get_varint(block, &mut pos) as usize;
+            let non_shared = get_varint(block, &mut pos) as usize;
+            let value_len = get_varint(block, &mut pos) as usize;
+
+            key.truncate(shared);
+            key.extend_from_slice(&block[pos..pos + non_shared]);
+            pos += non_shared;
+
+            entries.push((key.clone(), block[pos..pos + value_len].to_vec()));
+            pos += value_len;
+        }
+
+        return entries;
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32c(0, b"123456789"), 0xe3069283);
+        assert_eq!(crc32c(crc32c(0, b"1234"), b"56789"), 0xe3069283);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut table = Vec::new();
+        let mut expected = Vec::new();
+
+        let size = {
+            let mut writer = TableWriter::new(&mut table);
+
+            for i in 0..1000u32 {
+                let key = format!("key{:05}", i).into_bytes();
+                let value = vec![i as u8; (i % 7) as usize];
+
+                writer.add(&key, &value).unwrap();
+                expected.push((key, value));
+            }
+
+            assert!(writer.add(b"key00000", b"").is_err());
+
+            writer.finish().unwrap()
+        };
+
+        assert_eq!(size, table.len() as u64);
+
+        let footer = &table[table.len() - FOOTER_SIZE..];
+
+        assert_eq!(&footer[FOOTER_SIZE - 8..], &(0..8).map(|i| (TABLE_MAGIC >> (8 * i)) as u8).collect::<Vec<_>>()[..]);
+
+        let mut pos = 0;
+        let (metaindex_offset, metaindex_size) = (get_varint(footer, &mut pos), get_varint(footer, &mut pos));
+        let (index_offset, index_size) = (get_varint(footer, &mut pos), get_varint(footer, &mut pos));
+
+        assert!(read_block(&table, metaindex_offset as usize, metaindex_size as usize).is_empty());
+
+        let index = read_block(&table, index_offset as usize, index_size as usize);
+        let mut entries = Vec::new();
+
+        assert!(index.len() > 1);
+
+        for (last_key, handle) in index {
+            let mut pos = 0;
+            let (offset, size) = (get_varint(&handle, &mut pos), get_varint(&handle, &mut pos));
+            let block = read_block(&table, offset as usize, size as usize);
+
+            assert_eq!(block.last().unwrap().0, last_key);
+            entries.extend(block);
+        }
+
+        // each key carries the tag for a value at sequence number 0
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = entries.into_iter().map(|(mut key, value)| {
+            assert_eq!(key.split_off(key.len() - 8), vec![1, 0, 0, 0, 0, 0, 0, 0]);
+            (key, value)
+        }).collect();
+
+        assert!(entries == expected);
+    }
+}
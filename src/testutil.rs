@@ -0,0 +1,164 @@
+use ::{BTree, BTreeBuilder, KeyType, ValueType};
+
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// A directory under the system's temp directory that is removed, with everything in it, on drop
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Creates a directory no other TempDir, in this process or another, will use
+    pub fn new() -> Result<TempDir, Box<Error>> {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+        let name = format!("btree-{}-{}-{}", process::id(), NEXT_DIR.fetch_add(1, Ordering::SeqCst), nanos);
+        let path = env::temp_dir().join(name);
+
+        try!(fs::create_dir(&path));
+
+        return Ok(TempDir{path: path});
+    }
+
+    pub fn path(&self) -> &Path {
+        return &self.path;
+    }
+
+    /// Returns the path for a tree file in the directory; the tree puts its other files next to it
+    pub fn tree_path(&self) -> String {
+        return self.path.join("tree.btr").to_string_lossy().into_owned();
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// A BTree in a TempDir of its own, for tests; outside this crate it needs the `test-util` feature.
+/// It derefs to the BTree. On drop the tree is closed, then the whole directory is removed, so every
+/// file the tree made (WAL, lock, half written compactions) goes with it, even when the test panics.
+pub struct TempTree<K: KeyType, V: ValueType> {
+    tree: Option<BTree<K,V>>,
+    key_size: usize,
+    value_size: usize,
+    path: String,
+    dir: TempDir,
+}
+
+impl <K: KeyType, V: ValueType> TempTree<K,V> {
+    /// Opens a new tree with the default options
+    pub fn new(key_size: usize, value_size: usize) -> Result<TempTree<K,V>, Box<Error>> {
+        return TempTree::with_options(key_size, value_size, |builder| builder);
+    }
+
+    /// Opens a new tree with the options set on the builder by options
+    pub fn with_options<F: FnOnce(BTreeBuilder) -> BTreeBuilder>(key_size: usize, value_size: usize, options: F) -> Result<TempTree<K,V>, Box<Error>> {
+        let dir = try!(TempDir::new());
+        let path = dir.tree_path();
+        let tree = try!(options(BTreeBuilder::new(&path, key_size, value_size)).open());
+
+        return Ok(TempTree{tree: Some(tree), key_size: key_size, value_size: value_size, path: path, dir: dir});
+    }
+
+    /// Returns the path of the tree file
+    pub fn path(&self) -> &String {
+        return &self.path;
+    }
+
+    /// Returns the directory holding the tree file & all the tree's other files
+    pub fn dir(&self) -> &TempDir {
+        return &self.dir;
+    }
+
+    /// Closes the tree, then opens it again with the options set by options, as a restart would
+    pub fn reopen<F: FnOnce(BTreeBuilder) -> BTreeBuilder>(&mut self, options: F) -> Result<(), Box<Error>> {
+        self.tree = None;
+        self.tree = Some(try!(options(BTreeBuilder::new(&self.path, self.key_size, self.value_size)).open()));
+
+        Ok( () )
+    }
+}
+
+impl <K: KeyType, V: ValueType> Deref for TempTree<K,V> {
+    type Target = BTree<K,V>;
+
+    fn deref(&self) -> &BTree<K,V> {
+        return self.tree.as_ref().unwrap();
+    }
+}
+
+impl <K: KeyType, V: ValueType> DerefMut for TempTree<K,V> {
+    fn deref_mut(&mut self) -> &mut BTree<K,V> {
+        return self.tree.as_mut().unwrap();
+    }
+}
+
+impl <K: KeyType, V: ValueType> Drop for TempTree<K,V> {
+    fn drop(&mut self) {
+        // close the tree before the directory goes
+        self.tree = None;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use testutil::TempTree;
+    use ::{BTreeBuilder, WriteConcern};
+
+    use std::fs;
+    use std::panic;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    #[test]
+    fn removes_every_file() {
+        let dir: PathBuf;
+
+        {
+            let locked = |builder: BTreeBuilder| builder.lock_timeout(Duration::from_secs(1));
+            let mut tree = TempTree::<u8, u8>::with_options(1, 1, |builder| locked(builder).write_concern(WriteConcern::None)).unwrap();
+
+            tree.insert(1, 1).unwrap();
+            tree.compact().unwrap();
+            tree.reopen(locked).unwrap();
+            tree.insert(2, 1).unwrap();
+
+            assert!(tree.get(&1).unwrap().is_some());
+
+            // a compaction that failed part way leaves its new tree file behind
+            fs::write(tree.path().to_owned() + ".new", b"half").unwrap();
+
+            dir = tree.dir().path().to_path_buf();
+
+            let mut files: Vec<String> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned()).collect();
+
+            files.sort();
+
+            assert_eq!(files, vec!["tree.btr", "tree.btr.lock", "tree.btr.new", "tree.btr.wal"]);
+        }
+
+        assert!(! dir.exists());
+
+        // and when the test fails
+        let result = panic::catch_unwind(|| {
+            let tree = TempTree::<u8, u8>::new(1, 1).unwrap();
+
+            panic!("{}", tree.dir().path().display());
+        });
+
+        let message = result.unwrap_err().downcast::<String>().unwrap();
+
+        assert!(! PathBuf::from(*message).exists());
+    }
+}
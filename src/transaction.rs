@@ -0,0 +1,110 @@
+use ::{BTree, KeyType, ValueType, DurabilityToken};
+
+use std::error::Error;
+
+/// A transaction that has been prepared with BTree::prepare, waiting to be committed or aborted.
+/// It holds the tree until then; dropping it without doing either leaves the transaction prepared,
+/// and BTree::prepared_transaction gets it back.
+pub struct PreparedTransaction<'a, K: KeyType + 'a, V: ValueType + 'a> {
+    tree: &'a mut BTree<K,V>,
+    transaction_id: u128,
+}
+
+impl <'a, K: KeyType, V: ValueType> PreparedTransaction<'a,K,V> {
+    pub fn new(tree: &'a mut BTree<K,V>, transaction_id: u128) -> PreparedTransaction<'a,K,V> {
+        return PreparedTransaction{tree: tree, transaction_id: transaction_id};
+    }
+
+    pub fn transaction_id(&self) -> u128 {
+        return self.transaction_id;
+    }
+
+    /// Writes a Commit marker to the WAL and applies the transaction's writes.
+    /// The marker is made durable as the tree's write concern says, like any other write.
+    pub fn commit(self) -> Result<DurabilityToken, Box<Error>> {
+        return self.tree.finish_transaction(self.transaction_id, true);
+    }
+
+    /// Writes an Abort marker to the WAL and drops the transaction's writes
+    pub fn abort(self) -> Result<DurabilityToken, Box<Error>> {
+        return self.tree.finish_transaction(self.transaction_id, false);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use testutil::TempDir;
+    use ::{BTree, WriteBatch};
+
+    fn batch(key: u64, value: u64) -> WriteBatch<u64, u64> {
+        let mut batch = WriteBatch::new();
+
+        batch.insert(key, value);
+
+        return batch;
+    }
+
+    #[test]
+    fn two_phase_commit() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+        let big_id = u128::MAX - 1;
+
+        {
+            let mut btree = BTree::<u64, u64>::new(&file_path, 8, 8).unwrap();
+
+            btree.prepare(1, &batch(1, 1)).unwrap().commit().unwrap();
+            btree.prepare(big_id, &batch(2, 2)).unwrap();  // left prepared
+            btree.prepare(3, &batch(3, 3)).unwrap().abort().unwrap();
+
+            assert!(btree.prepare(big_id, &batch(4, 4)).is_err());
+
+            assert!(btree.get(&1).unwrap().is_some());
+            assert_eq!(btree.get(&2).unwrap(), None);
+            assert_eq!(btree.get(&3).unwrap(), None);
+        }
+
+        let mut btree = BTree::<u64, u64>::new(&file_path, 8, 8).unwrap();
+
+        assert!(btree.get(&1).unwrap().is_some());
+        assert_eq!(btree.get(&2).unwrap(), None);
+        assert_eq!(btree.get(&3).unwrap(), None);
+        assert_eq!(btree.prepared_transactions().unwrap(), vec![big_id]);
+
+        // a compaction empties the WAL, but keeps what is still prepared
+        btree.compact().unwrap();
+
+        assert!(btree.resize(16, 8).is_err());
+        assert!(btree.prepared_transaction(3).unwrap().is_none());
+
+        let mut btree = BTree::<u64, u64>::new(&file_path, 8, 8).unwrap();
+
+        assert_eq!(btree.prepared_transactions().unwrap(), vec![big_id]);
+
+        btree.prepared_transaction(big_id).unwrap().unwrap().commit().unwrap();
+
+        assert!(btree.get(&2).unwrap().is_some());
+        assert!(btree.prepared_transactions().unwrap().is_empty());
+
+        let btree = BTree::<u64, u64>::new(&file_path, 8, 8).unwrap();
+
+        assert!(btree.get(&1).unwrap().is_some());
+        assert!(btree.get(&2).unwrap().is_some());
+        assert_eq!(btree.get(&3).unwrap(), None);
+    }
+
+    #[test]
+    fn needs_room_for_the_id() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+        let mut batch = WriteBatch::new();
+
+        batch.insert(1, 1);
+
+        assert!(btree.prepare(1, &batch).is_err());
+        assert_eq!(btree.get(&1).unwrap(), None);
+    }
+}
@@ -0,0 +1,124 @@
+use ::{BTree, KeyType, ValueType, ReadConcern};
+
+use itertools::merge;
+
+use std::collections::BTreeSet;
+use std::collections::btree_set::Iter;
+use std::error::Error;
+
+/// The values of one key, borrowed from the BTree, see BTree::insert_and_get.
+/// Nothing is read up front: the mem_tree's values are borrowed as they are, and the
+/// tree file is only read by the methods that need the rest of the values.
+pub struct ValueSetView<'a, K: KeyType + 'a, V: ValueType + 'a> {
+    tree: &'a BTree<K,V>,
+    key: K,
+}
+
+/// Iterates, in order, over references to the values of a key, see BTree::with_values.
+/// Values in memory are borrowed from the mem_tree; values from the tree file are decoded
+/// into a buffer that lives only as long as the call.
+pub struct ValueRefs<'a, V: ValueType + 'a> {
+    iter: Box<Iterator<Item=&'a V> + 'a>,
+}
+
+impl <'a, V: ValueType> ValueRefs<'a,V> {
+    /// Merges the sorted values from memory with the sorted values from disk, which mustn't overlap
+    pub fn new(mem_values: Option<Iter<'a,V>>, disk_values: &'a [V]) -> ValueRefs<'a,V> {
+        return ValueRefs{iter: Box::new(merge(mem_values.into_iter().flatten(), disk_values.iter()))};
+    }
+}
+
+impl <'a, V: ValueType> Iterator for ValueRefs<'a,V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<&'a V> {
+        return self.iter.next();
+    }
+}
+
+impl <'a, K: KeyType, V: ValueType> ValueSetView<'a,K,V> {
+    pub fn new(tree: &'a BTree<K,V>, key: K) -> ValueSetView<'a,K,V> {
+        return ValueSetView{tree: tree, key: key};
+    }
+
+    pub fn key(&self) -> &K {
+        return &self.key;
+    }
+
+    /// Returns the values in the mem_tree, without looking at the tree file. After an insert these
+    /// include the inserted value, unless the insert set off a compaction that moved it to the tree file.
+    pub fn mem_values(&self) -> Option<Iter<V>> {
+        if self.tree.read_concern == ReadConcern::DiskOnly {
+            return None;
+        }
+
+        return self.tree.mem_tree.get(&self.key);
+    }
+
+    /// Checks for the value in the mem_tree, only reading the tree file if it isn't there
+    pub fn contains(&self, value: &V) -> Result<bool, Box<Error>> {
+        if self.tree.read_concern != ReadConcern::DiskOnly && self.tree.mem_tree.contains(&self.key, value) {
+            return Ok(true);
+        }
+
+        if ! self.tree.reads_disk() || self.tree.deleted.contains(&self.key, value) {
+            return Ok(false);
+        }
+
+        return Ok(try!(self.tree.tree_file.get_values(&self.key)).contains(value));
+    }
+
+    /// Reads all the values, merging the tree file's with the mem_tree's like BTree::get
+    pub fn to_set(&self) -> Result<BTreeSet<V>, Box<Error>> {
+        return Ok(try!(self.tree.get(&self.key)).unwrap_or_default());
+    }
+}
+
+
+#[cfg(test)]
+#[allow(unused_must_use)]
+mod tests {
+    use testutil::TempTree;
+    use ::ReadConcern;
+
+    #[test]
+    fn insert_and_get() {
+        let mut btree = TempTree::<u8, u8>::new(1, 1).unwrap();
+
+        btree.insert(1, 1).unwrap();
+        btree.insert(1, 2).unwrap();
+        btree.compact().unwrap();
+        btree.delete(1, 2).unwrap();
+
+        let view = btree.insert_and_get(1, 3).unwrap();
+
+        assert_eq!(*view.key(), 1);
+        assert_eq!(view.mem_values().unwrap().cloned().collect::<Vec<_>>(), vec![3]);
+        assert!(view.contains(&3).unwrap());
+        assert!(view.contains(&1).unwrap());
+        assert!(! view.contains(&2).unwrap());
+        assert_eq!(view.to_set().unwrap(), [1, 3].iter().cloned().collect());
+    }
+
+    #[test]
+    fn with_values() {
+        let mut btree = TempTree::<u8, String>::new(1, 10).unwrap();
+
+        btree.insert(1, "b".to_owned()).unwrap();
+        btree.insert(1, "d".to_owned()).unwrap();
+        btree.insert(1, "e".to_owned()).unwrap();
+        btree.compact().unwrap();
+        btree.insert(1, "a".to_owned()).unwrap();
+        btree.insert(1, "d".to_owned()).unwrap();
+        btree.delete(1, "e".to_owned()).unwrap();
+
+        let joined = btree.with_values(&1, |values| values.map(|v| v.as_str()).collect::<Vec<_>>().join(",")).unwrap();
+
+        assert_eq!(joined, Some("a,b,d".to_owned()));
+        assert_eq!(btree.with_values(&2, |values| values.count()).unwrap(), None);
+
+        btree.set_read_concern(ReadConcern::MemOnly);
+
+        assert_eq!(btree.with_values(&1, |values| values.count()).unwrap(), Some(2));
+    }
+}
@@ -0,0 +1,152 @@
+use ::{BTree, KeyType, ValueType, DurabilityToken, CompactionStats, WriteBatch};
+
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Bytes a Version adds to each value
+const VERSION_SIZE: usize = 16;
+
+/// When a version of a key's value was written. Versions of a key are ordered by lsn, which is
+/// the LSN of the write, or one more than the key's newest version when that is higher, as LSNs
+/// count from 0 again each time a tree is opened. The timestamp is milliseconds since the Unix epoch.
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub lsn: u64,
+    pub timestamp: u64,
+}
+
+/// A BTree that keeps the last few values written to each key, rather than a set of values.
+/// Each key's values are stored as (Version, value) pairs, so the whole history is in the tree:
+/// inserting a value adds a version and deletes the oldest ones past the limit, in one batch,
+/// and compaction drops the deleted versions from the tree file.
+pub struct VersionedBTree<K: KeyType, V: ValueType> {
+    tree: BTree<K, (Version, V)>,
+    keep_versions: usize,  // the most versions kept for a key
+}
+
+impl <K: KeyType, V: ValueType> VersionedBTree<K,V> {
+    /// Opens the tree, keeping up to keep_versions versions of each key. The value size is that of
+    /// V alone; room for the Version is added to it.
+    pub fn new(tree_file_path: &String, key_size: usize, value_size: usize, keep_versions: usize) -> Result<VersionedBTree<K,V>, Box<Error>> {
+        let tree = try!(BTree::new(tree_file_path, key_size, value_size + VERSION_SIZE));
+
+        return Ok(VersionedBTree::from_tree(tree, keep_versions));
+    }
+
+    /// Wraps a tree opened some other way, say with a BTreeBuilder
+    pub fn from_tree(tree: BTree<K, (Version, V)>, keep_versions: usize) -> VersionedBTree<K,V> {
+        return VersionedBTree{tree: tree, keep_versions: ::std::cmp::max(keep_versions, 1)};
+    }
+
+    /// Returns the tree underneath, for everything this doesn't wrap
+    pub fn tree(&mut self) -> &mut BTree<K, (Version, V)> {
+        return &mut self.tree;
+    }
+
+    /// Makes value the key's newest version, dropping the oldest versions past the limit
+    pub fn insert(&mut self, key: K, value: V) -> Result<DurabilityToken, Box<Error>> {
+        let versions: Vec<(Version, V)> = try!(self.tree.get(&key)).map_or_else(Vec::new, |versions| versions.into_iter().collect());
+        let newest_lsn = versions.last().map_or(0, |&(version, _)| version.lsn);
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() * 1000 + d.subsec_millis() as u64).unwrap_or(0);
+        let version = Version{lsn: ::std::cmp::max(self.tree.last_lsn + 1, newest_lsn + 1), timestamp: timestamp};
+
+        let mut batch = WriteBatch::new();
+        let dropped = (versions.len() + 1).saturating_sub(self.keep_versions);
+
+        for old in versions.into_iter().take(dropped) {
+            batch.delete(key.clone(), old);
+        }
+
+        batch.insert(key, (version, value));
+
+        return self.tree.apply(&batch);
+    }
+
+    /// Returns the key's newest value
+    pub fn get(&self, key: &K) -> Result<Option<V>, Box<Error>> {
+        return Ok(try!(self.tree.get(key)).and_then(|versions| versions.into_iter().next_back()).map(|(_, value)| value));
+    }
+
+    /// Returns the key's versions, newest first
+    pub fn get_versions(&self, key: &K) -> Result<Vec<(Version, V)>, Box<Error>> {
+        return Ok(try!(self.tree.get(key)).map_or_else(Vec::new, |versions| versions.into_iter().rev().take(self.keep_versions).collect()));
+    }
+
+    /// Deletes every version of the key
+    pub fn remove(&mut self, key: K) -> Result<u64, Box<Error>> {
+        return self.tree.delete_batch(vec![key]);
+    }
+
+    /// Trims every key to the version limit, in case the tree was written with a higher one, then compacts
+    pub fn compact(&mut self) -> Result<CompactionStats, Box<Error>> {
+        let mut batch = WriteBatch::new();
+
+        for (key, versions) in try!(self.tree.to_sorted_vec()) {
+            let dropped = versions.len().saturating_sub(self.keep_versions);
+
+            for old in versions.into_iter().take(dropped) {
+                batch.delete(key.clone(), old);
+            }
+        }
+
+        try!(self.tree.apply(&batch));
+
+        return self.tree.compact();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use testutil::TempDir;
+    use ::VersionedBTree;
+
+    #[test]
+    fn keeps_the_last_versions() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        {
+            let mut tree = VersionedBTree::<u8, u8>::new(&file_path, 1, 1, 3).unwrap();
+
+            for value in 1..6 {
+                tree.insert(1, value).unwrap();
+            }
+
+            tree.insert(2, 7).unwrap();
+
+            assert_eq!(tree.get(&1).unwrap(), Some(5));
+            assert_eq!(tree.get(&3).unwrap(), None);
+            assert_eq!(tree.get_versions(&1).unwrap().into_iter().map(|(_, value)| value).collect::<Vec<_>>(), vec![5, 4, 3]);
+        }
+
+        // versions keep going up after a restart, though LSNs start again
+        let mut tree = VersionedBTree::<u8, u8>::new(&file_path, 1, 1, 3).unwrap();
+
+        tree.insert(1, 6).unwrap();
+
+        let versions = tree.get_versions(&1).unwrap();
+
+        assert_eq!(versions.iter().map(|&(_, value)| value).collect::<Vec<_>>(), vec![6, 5, 4]);
+        assert!(versions[0].0 > versions[1].0 && versions[1].0 > versions[2].0);
+        assert!(versions[0].0.timestamp >= versions[1].0.timestamp);
+
+        tree.compact().unwrap();
+
+        assert_eq!(tree.tree().tree_file.count().unwrap(), 4);
+
+        // a lower limit trims at the next compaction
+        let mut tree = VersionedBTree::<u8, u8>::new(&file_path, 1, 1, 1).unwrap();
+
+        assert_eq!(tree.get_versions(&1).unwrap().len(), 1);
+
+        tree.compact().unwrap();
+
+        assert_eq!(tree.tree().tree_file.count().unwrap(), 2);
+
+        tree.remove(1).unwrap();
+
+        assert_eq!(tree.get(&1).unwrap(), None);
+        assert_eq!(tree.get(&2).unwrap(), Some(7));
+    }
+}
@@ -2,18 +2,40 @@ extern crate bincode;
 extern crate rustc_serialize;
 
 use bincode::SizeLimit;
-use bincode::rustc_serialize::{encode, decode};
+use bincode::rustc_serialize::{encode, decode_from, encoded_size, DecodingResult, EncodingError};
+
+use rustc_serialize::{Encodable, Decodable};
 
 use ::{KeyType, ValueType};
+use checksum::{self, ChecksumKind};
+use error::BTreeError;
 
 use std::error::Error;
+use std::fs;
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Write, ErrorKind, Seek, SeekFrom};
+use std::io::{Read, Write, ErrorKind, Seek, SeekFrom, BufReader};
 use std::io::Error as IOError;
 use std::marker::PhantomData;
+use std::collections::BTreeMap;
+use std::path::Path;
 use std::cmp::Ordering;
 
-#[derive(RustcEncodable, RustcDecodable, PartialEq)]
+/// How much of the file an iterator reads at a time
+const READ_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Written at the start of every WAL, followed by the key + value size as a big-endian u64,
+/// with the records' ChecksumKind in its top byte; WALs from before checksums have a 0 there
+const WAL_MAGIC: &'static [u8; 8] = b"BTreeWAL";
+const WAL_STAMP_SIZE: u64 = 16;
+
+/// Written at the start of a tree file whose records have checksums, in a stamp laid out as a WAL's is.
+/// A tree file without checksums has no stamp, as tree files from before checksums don't.
+const TREE_MAGIC: &'static [u8; 8] = b"BTreeDat";
+
+/// The bits of the stamp's u64 that hold the key + value size
+const STAMP_SIZE_MASK: u64 = (1 << 56) - 1;
+
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Clone)]
 pub struct KeyValuePair<K: KeyType, V: ValueType> {
     pub key: K,
     pub value: V,
@@ -29,37 +51,277 @@ impl <K: KeyType, V: ValueType> PartialOrd for KeyValuePair<K,V> {
     }
 }
 
-pub struct RecordFile<K: KeyType, V: ValueType> {
+/// A record in the WAL, either adding or removing a (key,value) pair,
+/// or marking the start or end of a batch of them. A prepared transaction is a batch
+/// that starts with Prepare instead of BatchStart, and is only replayed once a Commit
+/// for it follows. Transaction ids are split into their high & low 64 bits.
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Clone)]
+pub enum WALRecord<K: KeyType, V: ValueType> {
+    Insert(KeyValuePair<K,V>),
+    Delete(KeyValuePair<K,V>),
+    BatchStart,
+    BatchEnd,
+    Prepare(u64, u64),
+    Commit(u64, u64),
+    Abort(u64, u64),
+}
+
+/// Splits a transaction id into the high & low halves the WAL stores it as
+pub fn split_transaction_id(transaction_id: u128) -> (u64, u64) {
+    return ((transaction_id >> 64) as u64, transaction_id as u64);
+}
+
+pub fn join_transaction_id(high: u64, low: u64) -> u128 {
+    return (high as u128) << 64 | low as u128;
+}
+
+/// Anything stored in a RecordFile. Each record is padded out to the key size + value size,
+/// plus however many bytes the record's own encoding adds around the key & value.
+pub trait Record: Encodable + Decodable {
+    const OVERHEAD: usize;
+}
+
+impl <K: KeyType, V: ValueType> Record for KeyValuePair<K,V> {
+    const OVERHEAD: usize = 0;
+}
+
+impl <K: KeyType, V: ValueType> Record for WALRecord<K,V> {
+    const OVERHEAD: usize = 4; // bincode's u32 variant tag
+}
+
+pub struct RecordFile<K: KeyType, V: ValueType, R: Record = KeyValuePair<K,V>> {
     fd: File,  // the file
     key_size: usize,
     value_size: usize,
+    magic: Option<&'static [u8; 8]>, // what the file's stamp of the record size starts with, if it has one
+    checksum: ChecksumKind, // what follows each record to check it
+    record_start: u64,  // offset of the first record, past any stamp
+    bytes_written: u64, // bytes of records appended through this handle
+    records: u64,       // records the file should hold: those found on opening it, give or take what this handle has written since
+    torn_at: Option<u64>, // length to cut the file back to, after a failed append couldn't
+    disk: Box<Disk>,    // what appends are written through
     _k_marker: PhantomData<K>,
-    _v_marker: PhantomData<V>
+    _v_marker: PhantomData<V>,
+    _r_marker: PhantomData<R>
 }
 
-pub struct RecordFileIterator<'a, K: KeyType + 'a, V: ValueType + 'a> {
-    wal_file: &'a mut RecordFile<K,V>,  // the file
+/// Reads the records in order. A record that fails its checksum or won't decode is a CorruptWalRecord,
+/// and the file running out, even part way through a record, ends the records.
+pub struct RecordFileIterator<'a, K: KeyType + 'a, V: ValueType + 'a, R: Record + 'a = KeyValuePair<K,V>> {
+    wal_file: &'a RecordFile<K,V,R>,  // the file
+    reader: BufReader<&'a File>,      // reads the file a chunk at a time
+    buff: Vec<u8>,                    // holds the record being decoded
+    index: u64,                       // the index of the next record
 }
 
-impl <K: KeyType, V: ValueType> RecordFile<K,V> {
-    pub fn new(wal_file_path: &String, key_size: usize, value_size: usize) -> Result<RecordFile<K,V>, Box<Error>> {
-        let wal_file = try!(OpenOptions::new().read(true).write(true).create(true).open(wal_file_path));
+/// Where a RecordFile's appends are written, so a test can stand in a disk that runs out of space
+pub trait Disk: Send + Sync {
+    /// Writes all of buff to the file at its cursor
+    fn write_all(&mut self, fd: &mut File, buff: &[u8]) -> Result<(), IOError>;
 
-        return Ok(RecordFile{fd: wal_file,
+    /// Returns the bytes free on the file's filesystem, if they can be found out
+    fn available_space(&self, fd: &File) -> Option<u64>;
+}
+
+/// The disk the file is on
+pub struct RealDisk;
+
+impl Disk for RealDisk {
+    fn write_all(&mut self, fd: &mut File, buff: &[u8]) -> Result<(), IOError> {
+        return fd.write_all(buff);
+    }
+
+    fn available_space(&self, fd: &File) -> Option<u64> {
+        return available_space(fd);
+    }
+}
+
+impl <K: KeyType, V: ValueType, R: Record> RecordFile<K,V,R> {
+    pub fn new(wal_file_path: &String, key_size: usize, value_size: usize) -> Result<RecordFile<K,V,R>, Box<Error>> {
+        let wal_file = try!(OpenOptions::new().read(true).write(true).create(true).truncate(false).open(wal_file_path));
+        let mut record_file = RecordFile::from_file(wal_file, key_size, value_size);
+
+        try!(record_file.count_records());
+
+        return Ok(record_file);
+    }
+
+    /// Opens an existing file for reading only, so it is never created, and writes to it fail
+    pub fn open_read_only(file_path: &String, key_size: usize, value_size: usize) -> Result<RecordFile<K,V,R>, Box<Error>> {
+        let mut record_file = RecordFile::from_file(try!(File::open(file_path)), key_size, value_size);
+
+        try!(record_file.count_records());
+
+        return Ok(record_file);
+    }
+
+    /// Counts the whole records in the file as those it should hold, once where they start is known
+    fn count_records(&mut self) -> Result<(), Box<Error>> {
+        self.records = try!(self.size()) / self.record_size() as u64;
+
+        Ok( () )
+    }
+
+    fn from_file(fd: File, key_size: usize, value_size: usize) -> RecordFile<K,V,R> {
+        return RecordFile{fd: fd,
                           key_size: key_size,
                           value_size: value_size,
+                          magic: None,
+                          checksum: ChecksumKind::None,
+                          record_start: 0,
+                          bytes_written: 0,
+                          records: 0,
+                          torn_at: None,
+                          disk: Box::new(RealDisk),
                           _k_marker: PhantomData,
-                          _v_marker: PhantomData});
+                          _v_marker: PhantomData,
+                          _r_marker: PhantomData};
+    }
+
+    /// Opens a file whose records are preceded by a stamp of their size. A new file gets
+    /// stamped, an existing one has its stamp checked against key_size + value_size.
+    pub fn new_stamped(wal_file_path: &String, key_size: usize, value_size: usize) -> Result<RecordFile<K,V,R>, Box<Error>> {
+        return RecordFile::new_stamped_with_checksum(wal_file_path, key_size, value_size, None);
     }
 
+    /// Like new_stamped, with the records of a new file checked with checksum, or none if it is None.
+    /// An existing file is read with the checksum it is stamped with, unless it has no records yet,
+    /// when it is stamped again with checksum if there is one.
+    pub fn new_stamped_with_checksum(wal_file_path: &String, key_size: usize, value_size: usize, checksum: Option<ChecksumKind>) -> Result<RecordFile<K,V,R>, Box<Error>> {
+        let mut record_file = try!(RecordFile::new(wal_file_path, key_size, value_size));
+
+        record_file.magic = Some(WAL_MAGIC);
+        record_file.checksum = checksum.unwrap_or(ChecksumKind::None);
+
+        if try!(record_file.fd.metadata()).len() == 0 {
+            try!(record_file.write_stamp());
+            return Ok(record_file);
+        }
+
+        let mut stamp = [0; WAL_STAMP_SIZE as usize];
+
+        try!(record_file.fd.seek(SeekFrom::Start(0)));
+
+        // a file too short for a stamp, or without the magic, is from before stamps
+        if record_file.fd.read_exact(&mut stamp).is_err() || &stamp[0..8] != WAL_MAGIC {
+            return Err(From::from(BTreeError::UnstampedWal));
+        }
+
+        let (found, found_checksum) = try!(parse_stamp(&stamp));
+
+        if found != key_size + value_size {
+            return Err(From::from(BTreeError::WalRecordSizeMismatch{expected: key_size + value_size, found: found}));
+        }
+
+        record_file.record_start = WAL_STAMP_SIZE;
+
+        if checksum.is_some() && try!(record_file.is_new()) {
+            try!(record_file.reset(key_size, value_size));
+        } else {
+            record_file.checksum = found_checksum;
+        }
+
+        // a crash mid-append can leave part of a record on the end, which the next append would land after
+        let records_len = try!(record_file.size());
+        let torn = records_len % record_file.record_size() as u64;
+
+        if torn != 0 {
+            try!(record_file.fd.set_len(record_file.record_start + records_len - torn));
+        }
+
+        try!(record_file.count_records());
+
+        return Ok(record_file);
+    }
+
+    /// Opens a tree file, creating it if it is missing. A new file's records are checked with checksum,
+    /// and an existing one is read with the checksum it is stamped with, or none if it has no stamp.
+    pub fn new_tree_file(file_path: &String, key_size: usize, value_size: usize, checksum: ChecksumKind) -> Result<RecordFile<K,V,R>, Box<Error>> {
+        let mut record_file = try!(RecordFile::new(file_path, key_size, value_size));
+
+        if try!(record_file.fd.metadata()).len() == 0 {
+            if checksum != ChecksumKind::None {
+                record_file.magic = Some(TREE_MAGIC);
+                record_file.checksum = checksum;
+                try!(record_file.write_stamp());
+            }
+
+            return Ok(record_file);
+        }
+
+        try!(record_file.read_tree_stamp());
+
+        return Ok(record_file);
+    }
+
+    /// Opens an existing tree file for reading only, with the checksum it is stamped with, see open_read_only
+    pub fn open_tree_file_read_only(file_path: &String, key_size: usize, value_size: usize) -> Result<RecordFile<K,V,R>, Box<Error>> {
+        let mut record_file = try!(RecordFile::open_read_only(file_path, key_size, value_size));
+
+        try!(record_file.read_tree_stamp());
+
+        return Ok(record_file);
+    }
+
+    fn read_tree_stamp(&mut self) -> Result<(), Box<Error>> {
+        let mut stamp = [0; WAL_STAMP_SIZE as usize];
+
+        // a file too short for a stamp, or without the magic, is a bare run of records
+        if read_exact_at(&self.fd, &mut stamp, 0).is_err() || &stamp[0..8] != TREE_MAGIC {
+            return Ok( () );
+        }
+
+        let (found, checksum) = try!(parse_stamp(&stamp));
+
+        if found != self.key_size + self.value_size {
+            return Err(From::from(BTreeError::TreeRecordSizeMismatch{expected: self.key_size + self.value_size, found: found}));
+        }
+
+        self.magic = Some(TREE_MAGIC);
+        self.checksum = checksum;
+        self.record_start = WAL_STAMP_SIZE;
+
+        return self.count_records();
+    }
+
+    fn write_stamp(&mut self) -> Result<(), Box<Error>> {
+        let mut stamp = self.magic.unwrap_or(WAL_MAGIC).to_vec();
+        let found = (self.key_size + self.value_size) as u64 | (checksum::stamp_id(self.checksum) as u64) << 56;
+
+        stamp.extend_from_slice(&found.to_be_bytes());
+
+        try!(self.fd.seek(SeekFrom::Start(0)));
+        try!(self.fd.write_all(&stamp));
+
+        self.record_start = WAL_STAMP_SIZE;
+
+        Ok( () )
+    }
+
+    /// Returns true if there are no records in the file
     pub fn is_new(&self) -> Result<bool, Box<Error>> {
-        Ok(try!(self.fd.metadata()).len() == 0)
+        Ok(try!(self.fd.metadata()).len() <= self.record_start)
+    }
+
+    /// The size of a record on disk
+    pub fn record_size(&self) -> usize {
+        return self.payload_size() + self.checksum.size();
+    }
+
+    /// The size of a record's encoding, with its padding, but not its checksum
+    fn payload_size(&self) -> usize {
+        return self.key_size + self.value_size + R::OVERHEAD;
+    }
+
+    /// What the records are checked with
+    pub fn checksum(&self) -> ChecksumKind {
+        return self.checksum;
     }
 
     /// Returns the number of records in the WAL file
     pub fn count(&self) -> Result<u64, Box<Error>> {
-        let file_size = try!(self.fd.metadata()).len();
-        let rec_size: u64 = (self.key_size + self.value_size) as u64;
+        let file_size = try!(self.size());
+        let rec_size = self.record_size() as u64;
 
         if file_size % rec_size != 0 {
             Err(From::from(IOError::new(ErrorKind::InvalidData, "File size is NOT a multiple of key size + value size")))
@@ -68,78 +330,761 @@ impl <K: KeyType, V: ValueType> RecordFile<K,V> {
         }
     }
 
-    pub fn insert_record(&mut self, kv: &KeyValuePair<K,V>) -> Result<(), Box<Error>> {
-        // encode the record
-        let record_size = self.key_size + self.value_size;
-        let mut buff = try!(encode(&kv, SizeLimit::Bounded(record_size as u64)));
+    /// Returns the number of bytes taken up by records, not counting the stamp. A file cut short of
+    /// its stamp, say by being emptied behind the tree's back, has none.
+    pub fn size(&self) -> Result<u64, Box<Error>> {
+        Ok(try!(self.fd.metadata()).len().saturating_sub(self.record_start))
+    }
+
+    /// Drops every record from index on
+    pub fn truncate(&mut self, index: u64) -> Result<(), Box<Error>> {
+        try!(self.fd.set_len(self.record_start + index * self.record_size() as u64));
+
+        self.records = index;
+
+        Ok( () )
+    }
+
+    pub fn insert_record(&mut self, kv: &R) -> Result<(), Box<Error>> {
+        let buff = try!(self.encode_record(kv));
+
+        try!(self.insert_raw_record(buff));
+
+        Ok( () )
+    }
+
+    /// Appends all the records with a single write
+    pub fn insert_records(&mut self, records: &[R]) -> Result<(), Box<Error>> {
+        let mut buff = Vec::with_capacity(self.record_size() * records.len());
+
+        for record in records {
+            let mut record_buff = try!(self.encode_record(record));
+
+            try!(self.pad_record(&mut record_buff));
+
+            buff.extend(record_buff);
+        }
+
+        try!(self.append(&buff));
+
+        Ok( () )
+    }
+
+    /// Appends an already encoded record, padding it out to the record size.
+    /// Returns the number of bytes written.
+    pub fn insert_raw_record(&mut self, mut buff: Vec<u8>) -> Result<usize, Box<Error>> {
+        try!(self.pad_record(&mut buff));
+
+        return self.append(&buff);
+    }
+
+    /// Encodes a record, failing with RecordTooLarge if it won't fit in the record size
+    fn encode_record(&self, record: &R) -> Result<Vec<u8>, Box<Error>> {
+        let record_size = self.payload_size();
+
+        match encode(record, SizeLimit::Bounded(record_size as u64)) {
+            Ok(buff) => Ok(buff),
+            Err(EncodingError::SizeLimit) => Err(From::from(BTreeError::RecordTooLarge{size: encoded_size(record) as usize, limit: record_size})),
+            Err(e) => Err(From::from(e))
+        }
+    }
+
+    /// Pads an encoded record out to the record size, then adds its checksum
+    fn pad_record(&self, buff: &mut Vec<u8>) -> Result<(), Box<Error>> {
+        let record_size = self.payload_size();
 
         // padd it out to the max size
-        if buff.len() > self.key_size + self.value_size {
-            return Err(From::from(IOError::new(ErrorKind::InvalidData, "Key and value size are too large")));
+        if buff.len() > record_size {
+            return Err(From::from(BTreeError::RecordTooLarge{size: buff.len(), limit: record_size}));
         } else {
-            let diff = (self.key_size + self.value_size) - buff.len();
+            let diff = record_size - buff.len();
             buff.extend(vec![0; diff]);
         }
 
-        match self.fd.write_all(&buff) {
-            Ok(_) => Ok( () ),
-            Err(e) => Err(From::from(e))
+        let checksum = self.checksum.checksum(buff);
+
+        buff.extend(checksum);
+
+        Ok( () )
+    }
+
+    /// Writes the bytes at the end of the file, returning the number written. A write that fails
+    /// part way, say on a full disk, is cut off again, so the next append doesn't land after half a record
+    /// and leave the rest of the file unreadable. If even that fails, appends fail until it can be done.
+    fn append(&mut self, buff: &[u8]) -> Result<usize, Box<Error>> {
+        if let Some(good_len) = self.torn_at {
+            try!(self.fd.set_len(good_len));
+            self.torn_at = None;
+        }
+
+        // reads may have moved the cursor, so always append at the end
+        let good_len = try!(self.fd.seek(SeekFrom::End(0)));
+
+        if let Err(e) = self.disk.write_all(&mut self.fd, buff) {
+            if self.fd.set_len(good_len).is_err() {
+                self.torn_at = Some(good_len);
+            }
+
+            if e.kind() == ErrorKind::StorageFull {
+                return Err(From::from(BTreeError::NoSpace{needed: buff.len() as u64, available: self.available_space().unwrap_or(0)}));
+            }
+
+            return Err(From::from(e));
+        }
+
+        self.bytes_written += buff.len() as u64;
+        self.records += (buff.len() / self.record_size()) as u64;
+
+        Ok(buff.len())
+    }
+
+    /// Sets what appends are written through, say a disk that fills up, in place of the file's own
+    #[cfg(test)]
+    pub fn set_disk(&mut self, disk: Box<Disk>) {
+        self.disk = disk;
+    }
+
+    /// Returns the bytes free on the file's filesystem, if they can be found out
+    pub fn available_space(&self) -> Option<u64> {
+        return self.disk.available_space(&self.fd);
+    }
+
+    /// Returns the number of records the file should hold: what it held when opened, plus what's been
+    /// appended through this handle since, less what's been cut off. It differs from count only if the file
+    /// was written to some other way.
+    #[cfg(debug_assertions)]
+    pub fn expected_count(&self) -> u64 {
+        return self.records;
+    }
+
+    /// Returns the number of bytes of records appended through this handle, even if they've since been reset away
+    pub fn bytes_written(&self) -> u64 {
+        return self.bytes_written;
+    }
+
+    /// Counts the bytes appended through the handle to the file this one replaces as appended through this one
+    pub fn carry_bytes_written(&mut self, replaced: &RecordFile<K,V,R>) {
+        self.bytes_written += replaced.bytes_written;
+    }
+
+    /// Returns the metadata of the file this handle is to, which may since have been renamed over
+    pub fn metadata(&self) -> Result<fs::Metadata, Box<Error>> {
+        Ok(try!(self.fd.metadata()))
+    }
+
+    /// Returns another handle to the file, to sync it from somewhere else
+    pub fn try_clone_file(&self) -> Result<File, Box<Error>> {
+        Ok(try!(self.fd.try_clone()))
+    }
+
+    /// Flushes the file's data and metadata to disk
+    pub fn sync(&self) -> Result<(), Box<Error>> {
+        Ok(try!(self.fd.sync_all()))
+    }
+
+    /// Hints to the OS that records start up to end will be read soon, so it can start reading them in.
+    /// Returns straight away, without waiting for the reads
+    pub fn will_need(&self, start: u64, end: u64) {
+        let rec_size = self.record_size() as u64;
+
+        if end > start {
+            will_need(&self.fd, self.record_start + start * rec_size, (end - start) * rec_size);
+        }
+    }
+
+    /// Removes all the records from the file, and switches it over to the given sizes.
+    /// A stamped file gets a fresh stamp, even if it was opened as an unstamped legacy file.
+    pub fn reset(&mut self, key_size: usize, value_size: usize) -> Result<(), Box<Error>> {
+        try!(self.fd.set_len(0));
+        try!(self.fd.seek(SeekFrom::Start(0)));
+
+        self.torn_at = None;
+        self.records = 0;
+
+        self.key_size = key_size;
+        self.value_size = value_size;
+        self.record_start = 0;
+
+        if self.magic.is_some() {
+            try!(self.write_stamp());
+        }
+
+        Ok( () )
+    }
+
+    /// Reads the raw, still-encoded bytes of the record at `index`
+    pub fn read_record_bytes(&self, index: u64) -> Result<Vec<u8>, Box<Error>> {
+        let mut buff = vec![0; self.record_size()];
+
+        try!(read_exact_at(&self.fd, &mut buff, self.record_offset(index)));
+
+        Ok(buff)
+    }
+
+    /// The byte offset in the file of the record at `index`
+    pub fn record_offset(&self, index: u64) -> u64 {
+        return self.record_start + index * self.record_size() as u64;
+    }
+
+    /// The index of the record starting at the byte offset in the file, or None if no record could start there
+    pub fn record_index(&self, offset: u64) -> Option<u64> {
+        let rec_size = self.record_size() as u64;
+
+        if offset < self.record_start || (offset - self.record_start) % rec_size != 0 {
+            return None;
+        }
+
+        return Some((offset - self.record_start) / rec_size);
+    }
+
+    /// Reads and decodes the record at `index`
+    pub fn read_record(&self, index: u64) -> Result<R, Box<Error>> {
+        let buff = try!(self.read_record_bytes(index));
+
+        if ! self.checksum.verify(&buff) {
+            return Err(From::from(BTreeError::CorruptWalRecord{index: index}));
+        }
+
+        match decode_record(&buff) {
+            Ok(record) => Ok(record),
+            Err(_) => Err(From::from(BTreeError::CorruptWalRecord{index: index}))
         }
     }
 }
 
-impl <'a, K: KeyType, V: ValueType> IntoIterator for &'a mut RecordFile<K,V> {
-    type Item = KeyValuePair<K,V>;
-    type IntoIter = RecordFileIterator<'a, K,V>;
+impl <K: KeyType, V: ValueType> RecordFile<K,V,WALRecord<K,V>> {
+    /// Passes each insert & delete in the WAL that should be replayed to replay, in order.
+    /// A batch that never ended was cut off by a crash, so none of it is replayed. A prepared
+    /// transaction is replayed where its commit is, and dropped if it is aborted; the ones that
+    /// are neither are returned, with their writes, for whoever runs the transactions to decide.
+    /// A corrupt record fails the replay with CorruptWalRecord, as what comes after it can't be trusted,
+    /// and records appended after it would be lost. The last record is the exception: a crash part way
+    /// through writing it can leave it whole but wrong, so it is cut off the file instead.
+    pub fn replay_committed<F: FnMut(WALRecord<K,V>)>(&mut self, replay: F) -> Result<BTreeMap<u128, Vec<WALRecord<K,V>>>, Box<Error>> {
+        let count = try!(self.count());
+        let (prepared, torn) = try!(replay_records(try!(self.iter()), count, replay));
 
-    fn into_iter(self) -> Self::IntoIter {
-        // seek back to the start
-        self.fd.seek(SeekFrom::Start(0));
+        if let Some(index) = torn {
+            try!(self.truncate(index));
+        }
 
-        // create our iterator
-        RecordFileIterator{wal_file: self}
+        return Ok(prepared);
     }
 }
 
-impl <'a, K: KeyType, V: ValueType> Iterator for RecordFileIterator<'a,K,V> {
-    type Item = KeyValuePair<K,V>;
+/// Runs the records through the batches & transactions for replay_committed, returning the prepared
+/// transactions and the index of a corrupt last record, which the caller cuts off or skips
+fn replay_records<K, V, I, F>(records: I, count: u64, mut replay: F) -> Result<(BTreeMap<u128, Vec<WALRecord<K,V>>>, Option<u64>), Box<Error>>
+    where K: KeyType, V: ValueType, I: Iterator<Item=Result<WALRecord<K,V>, Box<Error>>>, F: FnMut(WALRecord<K,V>) {
+    let mut batch = None;        // records of a batch whose end we haven't reached yet
+    let mut transaction = None;  // the id of the transaction that batch is preparing, if it is one
+    let mut prepared = BTreeMap::new();
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let total_size = self.wal_file.key_size + self.wal_file.value_size;
-        let mut buff = vec![0; total_size];
+    for record in records {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => match e.downcast_ref::<BTreeError>() {
+                Some(&BTreeError::CorruptWalRecord{index}) if index + 1 == count => return Ok((prepared, Some(index))),
+                _ => return Err(e)
+            }
+        };
 
-        println!("Creating buffer: {}", total_size);
+        match record {
+            WALRecord::BatchStart => {
+                batch = Some(Vec::new());
+                transaction = None;
+            },
+            WALRecord::Prepare(high, low) => {
+                batch = Some(Vec::new());
+                transaction = Some(join_transaction_id(high, low));
+            },
+            WALRecord::BatchEnd => {
+                let records = batch.take().unwrap_or_else(Vec::new);
 
-        // attempt to read a buffer's worth and decode
-        match self.wal_file.fd.read_exact(&mut buff) {
-            Ok(_) => {
-                match decode(&buff) {
-                    Ok(record) => Some(record),
-                    Err(_) => None
+                match transaction.take() {
+                    Some(transaction_id) => { prepared.insert(transaction_id, records); },
+                    None => for record in records {
+                        replay(record);
+                    }
                 }
             },
-            Err(e) => {
-                println!("ERROR: {}", e);
-                None
+            WALRecord::Commit(high, low) => for record in prepared.remove(&join_transaction_id(high, low)).unwrap_or_else(Vec::new) {
+                replay(record);
+            },
+            WALRecord::Abort(high, low) => { prepared.remove(&join_transaction_id(high, low)); },
+            record => match batch {
+                Some(ref mut batch_records) => batch_records.push(record),
+                None => replay(record)
             }
         }
     }
+
+    return Ok((prepared, None));
+}
+
+impl <K: KeyType, V: ValueType, R: Record> RecordFile<K,V,R> {
+    /// Returns an iterator over the records, from the first
+    pub fn iter(&mut self) -> Result<RecordFileIterator<K,V,R>, Box<Error>> {
+        // seek back to the first record
+        try!(self.fd.seek(SeekFrom::Start(self.record_start)));
+
+        let record_size = self.record_size();
+        let reader = BufReader::with_capacity(READ_CHUNK_SIZE, &self.fd);
+
+        return Ok(RecordFileIterator{wal_file: self, reader: reader, buff: vec![0; record_size], index: 0});
+    }
+}
+
+impl <'a, K: KeyType, V: ValueType, R: Record> Iterator for RecordFileIterator<'a,K,V,R> {
+    type Item = Result<R, Box<Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // attempt to read a buffer's worth and decode
+        if let Err(e) = self.reader.read_exact(&mut self.buff) {
+            if e.kind() == ErrorKind::UnexpectedEof {
+                return None;
+            }
+
+            return Some(Err(From::from(e)));
+        }
+
+        let corrupt = BTreeError::CorruptWalRecord{index: self.index};
+
+        self.index += 1;
+
+        if ! self.wal_file.checksum.verify(&self.buff) {
+            return Some(Err(From::from(corrupt)));
+        }
+
+        match decode_record(&self.buff) {
+            Ok(record) => Some(Ok(record)),
+            Err(_) => Some(Err(From::from(corrupt)))
+        }
+    }
+}
+
+/// Reads the records of a WAL on its own, without opening the tree or needing its tree file,
+/// as when all that's left of a crashed tree is its WAL. Each record comes with its byte offset
+/// in the file. A record that doesn't decode is returned as an error and reading goes on past it;
+/// a record cut short at the end of the file, as a crash mid-write leaves, ends the records.
+/// The stamp isn't trusted to size anything: a record bigger than what's left of the file is a
+/// torn tail, found without reading or allocating it.
+pub struct WalReader<K: KeyType, V: ValueType> {
+    reader: BufReader<File>,
+    file_len: u64,           // the length of the file when it was opened
+    offset: u64,             // where the next record starts
+    record_size: usize,      // the size of a record on disk
+    checksum: ChecksumKind,  // what follows each record to check it
+    buff: Vec<u8>,           // holds the record being decoded, allocated with the first one
+    torn_tail: Option<u64>,  // offset of a partial record found at the end
+    _k_marker: PhantomData<K>,
+    _v_marker: PhantomData<V>,
+}
+
+impl <K: KeyType, V: ValueType> WalReader<K,V> {
+    /// Opens the WAL, checking its stamp against max_key_size + max_value_size
+    pub fn open(wal_file_path: &str, max_key_size: usize, max_value_size: usize) -> Result<WalReader<K,V>, Box<Error>> {
+        let (reader, found, checksum) = try!(WalReader::<K,V>::read_stamp(wal_file_path));
+
+        if found != max_key_size + max_value_size {
+            return Err(From::from(BTreeError::WalRecordSizeMismatch{expected: max_key_size + max_value_size, found: found}));
+        }
+
+        return WalReader::with_record_size(reader, found, checksum);
+    }
+
+    /// Opens the WAL with whatever record size it is stamped with
+    pub fn open_from_stamp(wal_file_path: &str) -> Result<WalReader<K,V>, Box<Error>> {
+        let (reader, found, checksum) = try!(WalReader::<K,V>::read_stamp(wal_file_path));
+
+        return WalReader::with_record_size(reader, found, checksum);
+    }
+
+    fn read_stamp(wal_file_path: &str) -> Result<(BufReader<File>, usize, ChecksumKind), Box<Error>> {
+        let mut reader = BufReader::with_capacity(READ_CHUNK_SIZE, try!(File::open(wal_file_path)));
+        let mut stamp = [0; WAL_STAMP_SIZE as usize];
+
+        // a WAL from before stamps holds bare pairs, not WALRecords
+        if reader.read_exact(&mut stamp).is_err() || &stamp[0..8] != WAL_MAGIC {
+            return Err(From::from(BTreeError::UnstampedWal));
+        }
+
+        let (found, checksum) = try!(parse_stamp(&stamp));
+
+        return Ok((reader, found, checksum));
+    }
+
+    fn with_record_size(reader: BufReader<File>, size: usize, checksum: ChecksumKind) -> Result<WalReader<K,V>, Box<Error>> {
+        let file_len = try!(reader.get_ref().metadata()).len();
+        let record_size = match size.checked_add(<WALRecord<K,V> as Record>::OVERHEAD + checksum.size()) {
+            Some(record_size) => record_size,
+            None => return Err(From::from(BTreeError::RecordTooLarge{size: size, limit: usize::MAX}))
+        };
+
+        return Ok(WalReader{reader: reader,
+                            file_len: file_len,
+                            offset: WAL_STAMP_SIZE,
+                            record_size: record_size,
+                            checksum: checksum,
+                            buff: Vec::new(),
+                            torn_tail: None,
+                            _k_marker: PhantomData,
+                            _v_marker: PhantomData});
+    }
+
+    /// The size of a record on disk
+    pub fn record_size(&self) -> usize {
+        return self.record_size;
+    }
+
+    /// What the records are checked with, as the WAL is stamped
+    pub fn checksum(&self) -> ChecksumKind {
+        return self.checksum;
+    }
+
+    /// The offset of the partial record the file ends with, once the records have all been read, if it has one
+    pub fn torn_tail(&self) -> Option<u64> {
+        return self.torn_tail;
+    }
+
+    /// Passes each insert & delete that should be replayed to replay, as RecordFile::replay_committed
+    /// does, and returns the prepared transactions. The file is only read: a corrupt last record is
+    /// skipped rather than cut off, so the WAL of a tree that is still open can be read this way.
+    pub fn replay_committed<F: FnMut(WALRecord<K,V>)>(&mut self, replay: F) -> Result<BTreeMap<u128, Vec<WALRecord<K,V>>>, Box<Error>> {
+        let count = self.file_len.saturating_sub(self.offset) / self.record_size as u64;
+        let records = self.by_ref().map(|record| record.map(|(_, record)| record).map_err(From::from));
+
+        return Ok(try!(replay_records(records, count, replay)).0);
+    }
+}
+
+impl <K: KeyType, V: ValueType> Iterator for WalReader<K,V> {
+    type Item = Result<(u64, WALRecord<K,V>), BTreeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.torn_tail.is_some() {
+            return None;
+        }
+
+        let offset = self.offset;
+
+        // don't allocate for a record the file is too short to hold
+        if offset >= self.file_len {
+            return None;
+        } else if self.file_len - offset < self.record_size as u64 {
+            self.torn_tail = Some(offset);
+            return None;
+        }
+
+        if self.buff.is_empty() {
+            self.buff = vec![0; self.record_size];
+        }
+
+        let mut read = 0;
+
+        // read_exact doesn't say how much it read before running out, which tells a torn record from the end
+        while read < self.buff.len() {
+            match self.reader.read(&mut self.buff[read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(_) => break
+            }
+        }
+
+        if read < self.buff.len() {
+            if read > 0 {
+                self.torn_tail = Some(offset);
+            }
+
+            return None;
+        }
+
+        self.offset += self.buff.len() as u64;
+
+        let corrupt = BTreeError::CorruptWalRecord{index: (offset - WAL_STAMP_SIZE) / self.buff.len() as u64};
+
+        if ! self.checksum.verify(&self.buff) {
+            return Some(Err(corrupt));
+        }
+
+        return match decode_record(&self.buff) {
+            Ok(record) => Some(Ok((offset, record))),
+            Err(_) => Some(Err(corrupt))
+        };
+    }
+}
+
+/// Splits the u64 after a stamp's magic into the key + value size & the checksum kind
+fn parse_stamp(stamp: &[u8; WAL_STAMP_SIZE as usize]) -> Result<(usize, ChecksumKind), Box<Error>> {
+    let mut found_bytes = [0; 8];
+
+    found_bytes.copy_from_slice(&stamp[8..]);
+
+    let found = u64::from_be_bytes(found_bytes);
+    let id = (found >> 56) as u8;
+
+    match checksum::from_stamp_id(id) {
+        Some(checksum) => Ok(((found & STAMP_SIZE_MASK) as usize, checksum)),
+        None => Err(From::from(BTreeError::UnknownChecksum{id: id}))
+    }
+}
+
+/// Decodes a record, never reading past the end of buff. A corrupt length prefix then
+/// fails to decode, rather than having the decoder try to allocate that much.
+pub fn decode_record<T: Decodable>(buff: &[u8]) -> DecodingResult<T> {
+    let mut reader = buff;
+
+    return decode_from(&mut reader, SizeLimit::Bounded(buff.len() as u64));
+}
+
+/// Reads from offset without moving the file's cursor, so threads can read through the same handle at once
+#[cfg(unix)]
+fn read_exact_at(fd: &File, buff: &mut [u8], offset: u64) -> Result<(), IOError> {
+    use std::os::unix::fs::FileExt;
+
+    return fd.read_exact_at(buff, offset);
+}
+
+/// Reads from offset by seeking, which isn't safe to do from more than one thread at a time
+#[cfg(not(unix))]
+fn read_exact_at(mut fd: &File, buff: &mut [u8], offset: u64) -> Result<(), IOError> {
+    try!(fd.seek(SeekFrom::Start(offset)));
+
+    return fd.read_exact(buff);
+}
+
+/// The bytes a tree file's stamp takes up, when its records are checked with checksum
+pub fn tree_stamp_size(checksum: ChecksumKind) -> u64 {
+    return if checksum == ChecksumKind::None { 0 } else { WAL_STAMP_SIZE };
+}
+
+/// Fsyncs the directory holding file_path, so files created in it or renamed into it
+/// are still there after a crash. Until then only the file's contents are durable, not its name.
+#[cfg(unix)]
+pub fn sync_dir(file_path: &str) -> Result<(), Box<Error>> {
+    let dir = match Path::new(file_path).parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new(".")
+    };
+
+    Ok(try!(try!(File::open(dir)).sync_all()))
+}
+
+/// Directories can't be opened as files to sync them here
+#[cfg(not(unix))]
+pub fn sync_dir(_file_path: &str) -> Result<(), Box<Error>> {
+    Ok( () )
+}
+
+/// Asks the kernel to read len bytes from offset into the page cache, in the background
+#[cfg(target_os = "linux")]
+fn will_need(fd: &File, offset: u64, len: u64) {
+    use std::os::unix::io::AsRawFd;
+
+    // only a hint, so a failure doesn't matter
+    unsafe { ::libc::posix_fadvise(fd.as_raw_fd(), offset as ::libc::off_t, len as ::libc::off_t, ::libc::POSIX_FADV_WILLNEED); }
+}
+
+/// Asks the kernel for the space left on the filesystem holding fd, for an unprivileged user
+#[cfg(unix)]
+fn available_space(fd: &File) -> Option<u64> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut stat: ::libc::statvfs = unsafe { ::std::mem::zeroed() };
+
+    if unsafe { ::libc::fstatvfs(fd.as_raw_fd(), &mut stat) } != 0 {
+        return None;
+    }
+
+    return Some((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64));
+}
+
+/// No way to ask for the space left here, so it's taken to be unknown
+#[cfg(not(unix))]
+fn available_space(_fd: &File) -> Option<u64> {
+    return None;
+}
+
+/// Nothing to hint with here, reads will just go to the disk when they're made
+#[cfg(not(target_os = "linux"))]
+fn will_need(_fd: &File, _offset: u64, _len: u64) {
+}
+
+/// Builds the encoding of a WALRecord::Insert from an encoded key & value: bincode writes
+/// the variant's index as a big-endian u32, then the pair's fields one after the other
+pub fn raw_insert_record(key_bytes: &[u8], value_bytes: &[u8]) -> Vec<u8> {
+    let mut buff = vec![0, 0, 0, 0];  // Insert is variant 0
+
+    buff.extend_from_slice(key_bytes);
+    buff.extend_from_slice(value_bytes);
+
+    return buff;
+}
+
+/// Rewrites a WAL from before stamps, whose records are bare inserted pairs,
+/// as a stamped WAL of WALRecords. The new WAL is written out and then renamed over the old one.
+pub fn upgrade_unstamped<K: KeyType, V: ValueType>(wal_file_path: &String, key_size: usize, value_size: usize, checksum: ChecksumKind) -> Result<RecordFile<K,V,WALRecord<K,V>>, Box<Error>> {
+    let new_wal_file_path = wal_file_path.to_owned() + ".new";
+
+    // clear out anything left over from a failed attempt
+    if let Err(e) = fs::remove_file(&new_wal_file_path) {
+        if e.kind() != ErrorKind::NotFound {
+            return Err(From::from(e));
+        }
+    }
+
+    // scoped so both files are closed before the rename
+    {
+        let mut old_wal_file = try!(RecordFile::<K,V>::new(wal_file_path, key_size, value_size));
+        let mut new_wal_file = try!(RecordFile::<K,V,WALRecord<K,V>>::new_stamped_with_checksum(&new_wal_file_path, key_size, value_size, Some(checksum)));
+
+        for kv in try!(old_wal_file.iter()) {
+            try!(new_wal_file.insert_record(&WALRecord::Insert(try!(kv))));
+        }
+
+        try!(new_wal_file.sync());
+    }
+
+    try!(fs::rename(&new_wal_file_path, wal_file_path));
+    try!(sync_dir(wal_file_path));
+
+    return RecordFile::new_stamped(wal_file_path, key_size, value_size);
 }
 
 
 #[cfg(test)]
 mod tests {
-    use tests::gen_temp_name;
+    use testutil::TempDir;
     use std::fs;
-    use wal_file::{RecordFile, KeyValuePair};
+    use bincode::SizeLimit;
+    use bincode::rustc_serialize::encode;
+    use wal_file::{RecordFile, KeyValuePair, WALRecord, WalReader, raw_insert_record, sync_dir};
+    use rand::{thread_rng, Rng};
+    use error::BTreeError;
+    use BTreeBuilder;
+
+    #[test]
+    fn sync_dir_of_file() {
+        let temp_dir = TempDir::new().unwrap();
+
+        sync_dir(&temp_dir.tree_path()).unwrap();
+        sync_dir("tree.btr").unwrap();  // in the current directory
+
+        let missing = temp_dir.path().join("missing").join("tree.btr");
+
+        assert!(sync_dir(&missing.to_string_lossy()).is_err());
+    }
+
+    #[test]
+    fn random_bytes() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        // an unstamped WAL of random records, whose length prefixes are mostly huge
+        let mut buff = vec![0; 44 * 1000];
+
+        thread_rng().fill_bytes(&mut buff);
+        fs::write(&file_path, &buff).unwrap();
+
+        let mut wal_file = RecordFile::<String, Vec<u64>, WALRecord<String, Vec<u64>>>::new(&file_path, 20, 20).unwrap();
+
+        for index in 0..wal_file.count().unwrap() {
+            if let Err(e) = wal_file.read_record(index) {
+                assert_eq!(*e.downcast::<BTreeError>().unwrap(), BTreeError::CorruptWalRecord{index: index});
+            }
+        }
+
+        // and replay fails at the first bad record
+        assert!(wal_file.replay_committed(|_| ()).is_err());
+    }
+
+    #[test]
+    fn wal_reader() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path() + ".wal";
+
+        {
+            let mut wal_file = RecordFile::<u32, u32, WALRecord<u32, u32>>::new_stamped(&file_path, 4, 4).unwrap();
+
+            wal_file.insert_record(&WALRecord::BatchStart).unwrap();
+            wal_file.insert_record(&WALRecord::Insert(KeyValuePair{key: 1, value: 2})).unwrap();
+            wal_file.insert_record(&WALRecord::Delete(KeyValuePair{key: 3, value: 4})).unwrap();
+        }
+
+        // a bad variant tag in the middle, then half a record at the end
+        let mut bytes = fs::read(&file_path).unwrap();
+
+        bytes[16 + 12 + 3] = 0xff;
+        bytes.extend_from_slice(&[0; 6]);
+        fs::write(&file_path, &bytes).unwrap();
+
+        let mut reader = WalReader::<u32, u32>::open(&file_path, 4, 4).unwrap();
+
+        assert_eq!(reader.record_size(), 12);
+        assert!(reader.next().unwrap().unwrap() == (16, WALRecord::BatchStart));
+        assert_eq!(reader.next().unwrap().err(), Some(BTreeError::CorruptWalRecord{index: 1}));
+        assert!(reader.next().unwrap().unwrap() == (40, WALRecord::Delete(KeyValuePair{key: 3, value: 4})));
+        assert!(reader.next().is_none());
+        assert_eq!(reader.torn_tail(), Some(52));
+
+        assert_eq!(WalReader::<u32, u32>::open_from_stamp(&file_path).unwrap().count(), 3);
+        assert_eq!(*WalReader::<u32, u32>::open(&file_path, 4, 8).err().unwrap().downcast::<BTreeError>().unwrap(),
+                   BTreeError::WalRecordSizeMismatch{expected: 12, found: 8});
+
+        // without a tree file, nor creating one
+        assert!(WalReader::<u32, u32>::open(&temp_dir.tree_path(), 4, 4).is_err());
+        assert!(fs::metadata(&temp_dir.tree_path()).is_err());
+    }
+
+    #[test]
+    fn record_limits() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path() + ".wal";
+
+        // a record that doesn't fit says how big it is
+        let mut wal_file = RecordFile::<String, String, WALRecord<String, String>>::new_stamped(&file_path, 8, 8).unwrap();
+        let kv = KeyValuePair{key: "a key that is too long".to_owned(), value: "value".to_owned()};
+
+        assert_eq!(*wal_file.insert_record(&WALRecord::Insert(kv)).unwrap_err().downcast::<BTreeError>().unwrap(),
+                   BTreeError::RecordTooLarge{size: 4 + 8 + 22 + 8 + 5, limit: 8 + 8 + 4});
+        assert_eq!(wal_file.count().unwrap(), 0);
+
+        // a stamp claiming huge records over a short file is a torn tail, not an allocation
+        let mut bytes = fs::read(&file_path).unwrap();
+
+        bytes[8..16].copy_from_slice(&(1u64 << 50).to_be_bytes());
+        bytes.extend_from_slice(&[0; 100]);
+        fs::write(&file_path, &bytes).unwrap();
+
+        let mut reader = WalReader::<String, String>::open_from_stamp(&file_path).unwrap();
+
+        assert!(reader.next().is_none());
+        assert_eq!(reader.torn_tail(), Some(16));
+
+        bytes[8..16].copy_from_slice(&u64::MAX.to_be_bytes());
+        fs::write(&file_path, &bytes).unwrap();
+
+        assert!(WalReader::<String, String>::open_from_stamp(&file_path).is_err());
+
+        // and the builder caps the sizes a tree can be opened with
+        let tree_dir = TempDir::new().unwrap();
+        let tree_path = tree_dir.tree_path();
+
+        assert_eq!(*BTreeBuilder::new(&tree_path, 1 << 20, 1 << 20).max_record_bytes(1 << 16).open::<String, String>().err().unwrap().downcast::<BTreeError>().unwrap(),
+                   BTreeError::RecordTooLarge{size: 2 << 20, limit: 1 << 16});
+        assert!(fs::metadata(&tree_path).is_err());
+        assert!(BTreeBuilder::new(&tree_path, 8, 8).max_record_bytes(16).open::<String, String>().is_ok());
+    }
 
     #[test]
     fn test_iterator() {
-        let temp_path = gen_temp_name();
+        let temp_dir = TempDir::new().unwrap();
+        let temp_path = temp_dir.tree_path();
         let file_path = temp_path.to_owned() + ".wal";
 
         // create a new blank file
-        let mut wal_file = RecordFile::new(&file_path, 20, 20).unwrap();
+        let mut wal_file = RecordFile::<String, String>::new(&file_path, 20, 20).unwrap();
 
         assert!(wal_file.is_new().unwrap());
 
@@ -151,18 +1096,26 @@ mod tests {
 
         assert!(wal_file.count().unwrap() == 2);
 
-        let mut wal_it = wal_file.into_iter();
+        let mut wal_it = wal_file.iter().unwrap();
 
-        let it_kv1 = wal_it.next().unwrap();
+        let it_kv1 = wal_it.next().unwrap().unwrap();
 
         assert!(kv1.key == it_kv1.key);
         assert!(kv1.value == it_kv1.value);
 
-        let it_kv2 = wal_it.next().unwrap();
+        let it_kv2 = wal_it.next().unwrap().unwrap();
 
         assert!(kv2.key == it_kv2.key);
         assert!(kv2.value == it_kv2.value);
+    }
+
+    #[test]
+    fn raw_insert_matches_encode() {
+        let kv = KeyValuePair{key: 3u32, value: "foo".to_owned()};
+
+        let key_bytes = encode(&kv.key, SizeLimit::Infinite).unwrap();
+        let value_bytes = encode(&kv.value, SizeLimit::Infinite).unwrap();
 
-        fs::remove_file(&file_path);
+        assert_eq!(raw_insert_record(&key_bytes, &value_bytes), encode(&WALRecord::Insert(kv), SizeLimit::Infinite).unwrap());
     }
 }
@@ -0,0 +1,127 @@
+use ::{KeyType, ValueType};
+
+use wal_file::{KeyValuePair, WALRecord};
+
+use std::slice::Iter;
+
+/// A set of inserts & deletes that are built up without touching a BTree,
+/// then applied to one all at once with BTree::apply. The writes are applied in the
+/// order they were added. A batch can be encoded to send somewhere else, and cleared to reuse it.
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Clone)]
+pub struct WriteBatch<K: KeyType, V: ValueType> {
+    records: Vec<WALRecord<K,V>>,
+}
+
+impl <K: KeyType, V: ValueType> WriteBatch<K,V> {
+    pub fn new() -> WriteBatch<K,V> {
+        return WriteBatch{records: Vec::new()};
+    }
+
+    /// Adds an insert of the pair to the batch
+    pub fn insert(&mut self, key: K, value: V) {
+        self.records.push(WALRecord::Insert(KeyValuePair{key: key, value: value}));
+    }
+
+    /// Adds a delete of the pair to the batch
+    pub fn delete(&mut self, key: K, value: V) {
+        self.records.push(WALRecord::Delete(KeyValuePair{key: key, value: value}));
+    }
+
+    /// Returns the number of writes in the batch
+    pub fn len(&self) -> usize {
+        return self.records.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.records.is_empty();
+    }
+
+    /// Removes all the writes from the batch
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    pub fn records(&self) -> Iter<WALRecord<K,V>> {
+        return self.records.iter();
+    }
+}
+
+impl <K: KeyType, V: ValueType> Default for WriteBatch<K,V> {
+    fn default() -> WriteBatch<K,V> {
+        return WriteBatch::new();
+    }
+}
+
+
+#[cfg(test)]
+#[allow(unused_must_use)]
+mod tests {
+    use bincode::SizeLimit;
+    use bincode::rustc_serialize::{encode, decode};
+    use testutil::TempDir;
+    use std::collections::BTreeSet;
+    use wal_file::{RecordFile, WALRecord, KeyValuePair};
+    use ::{BTree, WriteBatch};
+
+    #[test]
+    fn apply() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        btree.insert(1, 1).unwrap();
+
+        let mut batch = WriteBatch::<u8, u8>::new();
+
+        batch.insert(2, 1);
+        batch.insert(2, 2);
+        batch.delete(1, 1);
+
+        // send it over the wire
+        let buff = encode(&batch, SizeLimit::Infinite).unwrap();
+        let batch: WriteBatch<u8, u8> = decode(&buff).unwrap();
+
+        assert_eq!(batch.len(), 3);
+
+        btree.apply(&batch).unwrap();
+
+        let expected: BTreeSet<u8> = [1, 2].iter().cloned().collect();
+
+        assert_eq!(btree.get(&1).unwrap(), None);
+        assert_eq!(btree.get(&2).unwrap(), Some(expected.clone()));
+
+        // replayed from the WAL
+        let btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        assert_eq!(btree.get(&1).unwrap(), None);
+        assert_eq!(btree.get(&2).unwrap(), Some(expected));
+
+        let mut batch = batch;
+
+        batch.clear();
+
+        assert!(batch.is_empty());
+    }
+
+    #[test]
+    fn torn_batch() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.tree_path();
+
+        { BTree::<u8, u8>::new(&file_path, 1, 1).unwrap().insert(1, 1).unwrap(); }
+
+        // write a batch the way a crash would leave it, without its end marker
+        {
+            let mut wal_file = RecordFile::<u8, u8, WALRecord<u8, u8>>::new_stamped(&(file_path.to_owned() + ".wal"), 1, 1).unwrap();
+
+            wal_file.insert_record(&WALRecord::BatchStart).unwrap();
+            wal_file.insert_record(&WALRecord::Insert(KeyValuePair{key: 2, value: 1})).unwrap();
+        }
+
+        let btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        assert!(btree.get(&1).unwrap().is_some());
+        assert_eq!(btree.get(&2).unwrap(), None);
+    }
+}
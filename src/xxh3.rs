@@ -0,0 +1,245 @@
+const PRIME32_1: u64 = 0x9E3779B1;
+const PRIME32_2: u64 = 0x85EBCA77;
+const PRIME32_3: u64 = 0xC2B2AE3D;
+const PRIME64_1: u64 = 0x9E3779B185EBCA87;
+const PRIME64_2: u64 = 0xC2B2AE3D27D4EB4F;
+const PRIME64_3: u64 = 0x165667B19E3779F9;
+const PRIME64_4: u64 = 0x85EBCA77C2B2AE63;
+const PRIME64_5: u64 = 0x27D4EB2F165667C5;
+const PRIME_MX1: u64 = 0x165667919E3779F9;
+const PRIME_MX2: u64 = 0x9FB21C651E98DF25;
+
+const STRIPE_LEN: usize = 64;
+const SECRET_CONSUME_RATE: usize = 8;
+const MIDSIZE_MAX: usize = 240;
+
+const SECRET: [u8; 192] = [
+    0xb8, 0xfe, 0x6c, 0x39, 0x23, 0xa4, 0x4b, 0xbe, 0x7c, 0x01, 0x81, 0x2c, 0xf7, 0x21, 0xad, 0x1c,
+    0xde, 0xd4, 0x6d, 0xe9, 0x83, 0x90, 0x97, 0xdb, 0x72, 0x40, 0xa4, 0xa4, 0xb7, 0xb3, 0x67, 0x1f,
+    0xcb, 0x79, 0xe6, 0x4e, 0xcc, 0xc0, 0xe5, 0x78, 0x82, 0x5a, 0xd0, 0x7d, 0xcc, 0xff, 0x72, 0x21,
+    0xb8, 0x08, 0x46, 0x74, 0xf7, 0x43, 0x24, 0x8e, 0xe0, 0x35, 0x90, 0xe6, 0x81, 0x3a, 0x26, 0x4c,
+    0x3c, 0x28, 0x52, 0xbb, 0x91, 0xc3, 0x00, 0xcb, 0x88, 0xd0, 0x65, 0x8b, 0x1b, 0x53, 0x2e, 0xa3,
+    0x71, 0x64, 0x48, 0x97, 0xa2, 0x0d, 0xf9, 0x4e, 0x38, 0x19, 0xef, 0x46, 0xa9, 0xde, 0xac, 0xd8,
+    0xa8, 0xfa, 0x76, 0x3f, 0xe3, 0x9c, 0x34, 0x3f, 0xf9, 0xdc, 0xbb, 0xc7, 0xc7, 0x0b, 0x4f, 0x1d,
+    0x8a, 0x51, 0xe0, 0x4b, 0xcd, 0xb4, 0x59, 0x31, 0xc8, 0x9f, 0x7e, 0xc9, 0xd9, 0x78, 0x73, 0x64,
+    0xea, 0xc5, 0xac, 0x83, 0x34, 0xd3, 0xeb, 0xc3, 0xc5, 0x81, 0xa0, 0xff, 0xfa, 0x13, 0x63, 0xeb,
+    0x17, 0x0d, 0xdd, 0x51, 0xb7, 0xf0, 0xda, 0x49, 0xd3, 0x16, 0x55, 0x26, 0x29, 0xd4, 0x68, 0x9e,
+    0x2b, 0x16, 0xbe, 0x58, 0x7d, 0x47, 0xa1, 0xfc, 0x8f, 0xf8, 0xb8, 0xd1, 0x7a, 0xd0, 0x31, 0xce,
+    0x45, 0xcb, 0x3a, 0x8f, 0x95, 0x16, 0x04, 0x28, 0xaf, 0xd7, 0xfb, 0xca, 0xbb, 0x4b, 0x40, 0x7e,
+];
+
+/// Returns XXH3's 64-bit hash of the bytes, with the default secret and a seed of 0, as XXH3_64bits
+/// in xxHash 0.8 computes it. It reads the bytes 8 at a time, so it is fast without being vectorised.
+pub fn xxh3_64(data: &[u8]) -> u64 {
+    let len = data.len();
+
+    return match len {
+        0 => xxh64_avalanche(read64(&SECRET, 56) ^ read64(&SECRET, 64)),
+        1..=3 => hash_1to3(data),
+        4..=8 => hash_4to8(data),
+        9..=16 => hash_9to16(data),
+        17..=128 => hash_17to128(data),
+        129..=MIDSIZE_MAX => hash_129to240(data),
+        _ => hash_long(data)
+    };
+}
+
+fn read32(bytes: &[u8], at: usize) -> u64 {
+    let mut word = [0; 4];
+
+    word.copy_from_slice(&bytes[at..at + 4]);
+
+    return u32::from_le_bytes(word) as u64;
+}
+
+fn read64(bytes: &[u8], at: usize) -> u64 {
+    let mut word = [0; 8];
+
+    word.copy_from_slice(&bytes[at..at + 8]);
+
+    return u64::from_le_bytes(word);
+}
+
+/// Multiplies out to 128 bits and xors the two halves together
+fn mul128_fold64(a: u64, b: u64) -> u64 {
+    let product = (a as u128) * (b as u128);
+
+    return (product as u64) ^ ((product >> 64) as u64);
+}
+
+fn xxh64_avalanche(mut h: u64) -> u64 {
+    h ^= h >> 33;
+    h = h.wrapping_mul(PRIME64_2);
+    h ^= h >> 29;
+    h = h.wrapping_mul(PRIME64_3);
+    h ^= h >> 32;
+
+    return h;
+}
+
+fn avalanche(mut h: u64) -> u64 {
+    h ^= h >> 37;
+    h = h.wrapping_mul(PRIME_MX1);
+    h ^= h >> 32;
+
+    return h;
+}
+
+fn rrmxmx(mut h: u64, len: u64) -> u64 {
+    h ^= h.rotate_left(49) ^ h.rotate_left(24);
+    h = h.wrapping_mul(PRIME_MX2);
+    h ^= (h >> 35).wrapping_add(len);
+    h = h.wrapping_mul(PRIME_MX2);
+    h ^= h >> 28;
+
+    return h;
+}
+
+fn hash_1to3(data: &[u8]) -> u64 {
+    let len = data.len();
+    let combined = ((data[0] as u64) << 16) | ((data[len >> 1] as u64) << 24) | (data[len - 1] as u64) | ((len as u64) << 8);
+    let bitflip = read32(&SECRET, 0) ^ read32(&SECRET, 4);
+
+    return xxh64_avalanche(combined ^ bitflip);
+}
+
+fn hash_4to8(data: &[u8]) -> u64 {
+    let len = data.len();
+    let input = read32(data, len - 4).wrapping_add(read32(data, 0) << 32);
+    let bitflip = read64(&SECRET, 8) ^ read64(&SECRET, 16);
+
+    return rrmxmx(input ^ bitflip, len as u64);
+}
+
+fn hash_9to16(data: &[u8]) -> u64 {
+    let len = data.len();
+    let input_lo = read64(data, 0) ^ (read64(&SECRET, 24) ^ read64(&SECRET, 32));
+    let input_hi = read64(data, len - 8) ^ (read64(&SECRET, 40) ^ read64(&SECRET, 48));
+    let acc = (len as u64).wrapping_add(input_lo.swap_bytes())
+                          .wrapping_add(input_hi)
+                          .wrapping_add(mul128_fold64(input_lo, input_hi));
+
+    return avalanche(acc);
+}
+
+fn mix16(data: &[u8], at: usize, secret_at: usize) -> u64 {
+    return mul128_fold64(read64(data, at) ^ read64(&SECRET, secret_at), read64(data, at + 8) ^ read64(&SECRET, secret_at + 8));
+}
+
+fn hash_17to128(data: &[u8]) -> u64 {
+    let len = data.len();
+    let mut acc = (len as u64).wrapping_mul(PRIME64_1);
+
+    if len > 32 {
+        if len > 64 {
+            if len > 96 {
+                acc = acc.wrapping_add(mix16(data, 48, 96));
+                acc = acc.wrapping_add(mix16(data, len - 64, 112));
+            }
+
+            acc = acc.wrapping_add(mix16(data, 32, 64));
+            acc = acc.wrapping_add(mix16(data, len - 48, 80));
+        }
+
+        acc = acc.wrapping_add(mix16(data, 16, 32));
+        acc = acc.wrapping_add(mix16(data, len - 32, 48));
+    }
+
+    acc = acc.wrapping_add(mix16(data, 0, 0));
+    acc = acc.wrapping_add(mix16(data, len - 16, 16));
+
+    return avalanche(acc);
+}
+
+fn hash_129to240(data: &[u8]) -> u64 {
+    let len = data.len();
+    let mut acc = (len as u64).wrapping_mul(PRIME64_1);
+
+    for i in 0..8 {
+        acc = acc.wrapping_add(mix16(data, 16 * i, 16 * i));
+    }
+
+    acc = avalanche(acc);
+
+    // past the first 8 rounds the secret is read from 3 bytes in, and the last 16 bytes from near its end
+    for i in 8..len / 16 {
+        acc = acc.wrapping_add(mix16(data, 16 * i, 16 * (i - 8) + 3));
+    }
+
+    acc = acc.wrapping_add(mix16(data, len - 16, 136 - 17));
+
+    return avalanche(acc);
+}
+
+fn accumulate_512(acc: &mut [u64; 8], data: &[u8], at: usize, secret_at: usize) {
+    for i in 0..8 {
+        let data_val = read64(data, at + 8 * i);
+        let data_key = data_val ^ read64(&SECRET, secret_at + 8 * i);
+
+        acc[i ^ 1] = acc[i ^ 1].wrapping_add(data_val);
+        acc[i] = acc[i].wrapping_add((data_key & 0xFFFFFFFF).wrapping_mul(data_key >> 32));
+    }
+}
+
+fn scramble(acc: &mut [u64; 8]) {
+    let secret_at = SECRET.len() - STRIPE_LEN;
+
+    for (i, lane) in acc.iter_mut().enumerate() {
+        *lane ^= *lane >> 47;
+        *lane ^= read64(&SECRET, secret_at + 8 * i);
+        *lane = lane.wrapping_mul(PRIME32_1);
+    }
+}
+
+fn hash_long(data: &[u8]) -> u64 {
+    let len = data.len();
+    let mut acc = [PRIME32_3, PRIME64_1, PRIME64_2, PRIME64_3, PRIME64_4, PRIME32_2, PRIME64_5, PRIME32_1];
+    let stripes_per_block = (SECRET.len() - STRIPE_LEN) / SECRET_CONSUME_RATE;
+    let block_len = STRIPE_LEN * stripes_per_block;
+    let blocks = (len - 1) / block_len;
+
+    for block in 0..blocks {
+        for stripe in 0..stripes_per_block {
+            accumulate_512(&mut acc, data, block * block_len + stripe * STRIPE_LEN, stripe * SECRET_CONSUME_RATE);
+        }
+
+        scramble(&mut acc);
+    }
+
+    // the stripes of the last, partial, block, then the last 64 bytes, which can overlap them
+    let stripes = ((len - 1) - block_len * blocks) / STRIPE_LEN;
+
+    for stripe in 0..stripes {
+        accumulate_512(&mut acc, data, blocks * block_len + stripe * STRIPE_LEN, stripe * SECRET_CONSUME_RATE);
+    }
+
+    accumulate_512(&mut acc, data, len - STRIPE_LEN, SECRET.len() - STRIPE_LEN - 7);
+
+    let mut result = (len as u64).wrapping_mul(PRIME64_1);
+
+    for i in 0..4 {
+        result = result.wrapping_add(mul128_fold64(acc[2 * i] ^ read64(&SECRET, 11 + 16 * i), acc[2 * i + 1] ^ read64(&SECRET, 11 + 16 * i + 8)));
+    }
+
+    return avalanche(result);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use xxh3::xxh3_64;
+
+    #[test]
+    fn matches_xxhash() {
+        let data: Vec<u8> = (0..5000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+
+        // from xxHash's own XXH3_64bits, at a length in each of the size classes
+        let expected = [(0, 0x2d06800538d394c2), (3, 0xa1c4a8259b827291), (8, 0x79d02238b80e37b1),
+                        (16, 0x222e9aead6bddd51), (128, 0x421a9c905c6e66ba), (240, 0xb714c5fd22744964),
+                        (1024, 0x1fd15e7d36f5e1bc), (1025, 0xfe08e5a874d23fd2), (4097, 0x52606c941adb173b)];
+
+        for &(len, hash) in expected.iter() {
+            assert_eq!(xxh3_64(&data[..len]), hash, "length {}", len);
+        }
+    }
+}
@@ -0,0 +1,55 @@
+extern crate btree;
+extern crate rand;
+
+use btree::WriteConcern;
+use btree::testutil::TempTree;
+use rand::{thread_rng, Rng};
+
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+const RECORDS: u64 = 10000;
+
+#[test]
+fn reads_during_compaction() {
+    let mut btree = TempTree::<String, u64>::with_options(20, 8, |builder| builder.write_concern(WriteConcern::None)).unwrap();
+
+    for i in 0..RECORDS {
+        btree.insert(format!("key{}", i), i).unwrap();
+    }
+
+    // there's no lock-free handle, so readers share a read lock and compactions take the write lock
+    let btree = Arc::new(RwLock::new(btree));
+
+    let readers: Vec<_> = (0..8).map(|_| {
+        let btree = btree.clone();
+
+        thread::spawn(move || {
+            let mut rng = thread_rng();
+
+            for _ in 0..1000 {
+                let i = rng.gen_range(0, RECORDS);
+                let values = btree.read().unwrap().get(&format!("key{}", i)).unwrap();
+
+                assert_eq!(values, Some([i].iter().cloned().collect()));
+            }
+        })
+    }).collect();
+
+    let compactor = {
+        let btree = btree.clone();
+
+        thread::spawn(move || {
+            for _ in 0..10 {
+                btree.write().unwrap().compact().unwrap();
+                thread::yield_now();
+            }
+        })
+    };
+
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    compactor.join().unwrap();
+}
@@ -0,0 +1,35 @@
+extern crate btree;
+
+use btree::testutil::TempTree;
+
+use std::collections::BTreeSet;
+
+#[test]
+fn wal_replay_after_crash() {
+    let expected = |i: u32| Some([i * 2].iter().cloned().collect::<BTreeSet<u32>>());
+
+    // 1000 is exactly MAX_MEMORY_ITEMS, so nothing is compacted and it is all in the WAL
+    let mut btree = TempTree::<u32, u32>::new(4, 4).unwrap();
+
+    for i in 0..1000 {
+        btree.insert(i, i * 2).unwrap();
+    }
+
+    // closed without compacting, like a crash
+    btree.reopen(|builder| builder).unwrap();
+
+    assert_eq!(btree.estimate_bloat().unwrap().wal_entries, 1000);
+
+    for i in 0..1000 {
+        assert_eq!(btree.get(&i).unwrap(), expected(i));
+    }
+
+    btree.compact().unwrap();
+    btree.reopen(|builder| builder).unwrap();
+
+    assert_eq!(btree.estimate_bloat().unwrap().wal_entries, 0);
+
+    for i in 0..1000 {
+        assert_eq!(btree.get(&i).unwrap(), expected(i));
+    }
+}
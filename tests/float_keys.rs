@@ -0,0 +1,57 @@
+extern crate btree;
+
+use btree::{TotalOrdF64, TotalOrdF32};
+use btree::testutil::TempTree;
+
+use std::f64;
+use std::f32;
+
+#[test]
+fn f64_keys_in_total_order() {
+    // in IEEE 754 total order
+    let expected = [f64::NEG_INFINITY, -1.0, -f64::MIN_POSITIVE, -5e-324, -0.0,
+                    0.0, 5e-324, f64::MIN_POSITIVE / 2.0, f64::MIN_POSITIVE, 1.0, f64::MAX, f64::INFINITY, f64::NAN];
+
+    let mut btree = TempTree::<TotalOrdF64, u8>::new(8, 1).unwrap();
+
+    // half on disk, half in memory, inserted out of order
+    for (i, key) in expected.iter().rev().enumerate() {
+        btree.insert(TotalOrdF64(*key), 1).unwrap();
+
+        if i == expected.len() / 2 {
+            btree.compact().unwrap();
+        }
+    }
+
+    let pairs = btree.range_chunked(.., 100).next_chunk(&mut btree).unwrap();
+    let keys: Vec<u64> = pairs.iter().map(|&(key, _)| key.0.to_bits()).collect();
+
+    assert_eq!(keys, expected.iter().map(|key| key.to_bits()).collect::<Vec<_>>());
+
+    btree.compact().unwrap();
+    btree.reopen(|builder| builder).unwrap();
+
+    // -0.0 and 0.0 are different keys, and NaN can be found
+    assert!(btree.get(&TotalOrdF64(-0.0)).unwrap().is_some());
+    assert!(btree.get(&TotalOrdF64(0.0)).unwrap().is_some());
+    assert!(btree.get(&TotalOrdF64(f64::NAN)).unwrap().is_some());
+    assert!(btree.get(&TotalOrdF64(2.0)).unwrap().is_none());
+}
+
+#[test]
+fn f32_keys_in_total_order() {
+    let expected = [f32::NEG_INFINITY, -1.0, -1e-45, -0.0, 0.0, 1e-45, f32::MIN_POSITIVE, f32::INFINITY];
+
+    let mut btree = TempTree::<TotalOrdF32, u8>::new(4, 1).unwrap();
+
+    for key in expected.iter().rev() {
+        btree.insert(TotalOrdF32(*key), 1).unwrap();
+    }
+
+    btree.compact().unwrap();
+
+    let pairs = btree.range_chunked(.., 100).next_chunk(&mut btree).unwrap();
+    let keys: Vec<u32> = pairs.iter().map(|&(key, _)| key.0.to_bits()).collect();
+
+    assert_eq!(keys, expected.iter().map(|key| key.to_bits()).collect::<Vec<_>>());
+}
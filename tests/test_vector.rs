@@ -0,0 +1,29 @@
+extern crate btree;
+
+use btree::encode_test_vector;
+use btree::testutil::TempTree;
+
+use std::fs::File;
+use std::io::Read;
+
+#[test]
+fn tree_file_matches_test_vector() {
+    let vector = encode_test_vector();
+    let mut btree = TempTree::<u32, u32>::new(vector.key_size, vector.value_size).unwrap();
+
+    btree.insert(vector.key, vector.value).unwrap();
+    btree.compact().unwrap();
+
+    let mut contents = Vec::new();
+
+    File::open(btree.path()).unwrap().read_to_end(&mut contents).unwrap();
+
+    let hex: String = contents.iter().map(|b| format!("{:02x}", b)).collect();
+
+    assert_eq!(hex, vector.hex);
+
+    // and it reads back
+    btree.reopen(|builder| builder).unwrap();
+
+    assert!(btree.get(&vector.key).unwrap().unwrap().contains(&vector.value));
+}